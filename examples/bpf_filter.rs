@@ -0,0 +1,36 @@
+extern crate libnfqueue as nfq;
+
+use std::env;
+use nfq::bpf::Filter;
+use nfq::handle::{Handle, ProtocolFamily};
+use nfq::message::Message;
+use nfq::queue::{CopyMode, Verdict};
+
+/// Drops packets matching a pcap filter expression, given as the arguments like tcpdump's
+///
+/// e.g. `bpf_filter tcp port 443 and not net 10.0.0.0/8`
+fn main() {
+    let expression = env::args().skip(1).collect::<Vec<_>>().join(" ");
+    let filter = match Filter::new(&expression) {
+        Ok(filter) => filter,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let mut handle = Handle::new().ok().unwrap();
+    handle.bind(ProtocolFamily::INET).ok().unwrap();
+    handle.bind(ProtocolFamily::INET6).ok().unwrap();
+
+    let mut queue = handle.queue(0, move |message: &Message| match filter.matches(message) {
+        true => Verdict::Drop,
+        false => Verdict::Accept
+    }).ok().unwrap();
+    queue.set_mode(CopyMode::Packet(256)).ok().unwrap();
+
+    println!("Listening for packets...");
+    handle.start(256);
+
+    println!("...finished.");
+}
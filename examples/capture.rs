@@ -0,0 +1,34 @@
+extern crate libnfqueue as nfq;
+
+use nfq::bpf::Filter;
+use nfq::capture::{Capture, CaptureFile, Rotation};
+use nfq::handle::{Handle, ProtocolFamily};
+use nfq::message::Message;
+use nfq::queue::{CopyMode, Verdict};
+
+/// Drops telnet, recording every packet and its verdict to `verdicts.pcapng`
+///
+/// Files are rotated every 100MB, keeping five, and can be read with e.g.
+/// `tshark -r verdicts.pcapng -T fields -e frame.comment`.
+fn main() {
+    let mut file = CaptureFile::create("verdicts.pcapng").ok().unwrap();
+    file.set_rotation(Rotation { max_size: Some(100 << 20), max_age: None, keep: 5 });
+
+    let telnet = Filter::new("tcp dst port 23").ok().unwrap();
+    let capture = Capture::new(move |message: &Message| match telnet.matches(message) {
+        true => Verdict::Drop,
+        false => Verdict::Accept
+    }, file);
+
+    let mut handle = Handle::new().ok().unwrap();
+    handle.bind(ProtocolFamily::INET).ok().unwrap();
+    handle.bind(ProtocolFamily::INET6).ok().unwrap();
+
+    let mut queue = handle.queue(0, capture).ok().unwrap();
+    queue.set_mode(CopyMode::Packet(0xffff)).ok().unwrap();
+
+    println!("Listening for packets...");
+    handle.start(0xffff);
+
+    println!("...finished.");
+}
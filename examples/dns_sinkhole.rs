@@ -0,0 +1,70 @@
+extern crate libnfqueue as nfq;
+
+use std::net::IpAddr;
+use nfq::handle::{Handle, ProtocolFamily};
+use nfq::queue::{CopyMode, Verdict, VerdictHandler};
+use nfq::message::Message;
+use nfq::message::dns::{Dns, Response};
+use nfq::domain::DomainSet;
+
+/// Answers DNS queries for blocked names with a sinkhole address, in place of the resolver
+///
+/// Queue queries before routing, so the answers are routed back to the client,
+/// with e.g. `iptables -t mangle -A PREROUTING -p udp --dport 53 -j NFQUEUE --queue-num 0`.
+fn main() {
+    let mut handle = Handle::new().ok().unwrap();
+    handle.bind(ProtocolFamily::INET).ok().unwrap();
+
+    let sinkhole = Sinkhole {
+        // `ads.example.com` and its subdomains
+        blocked: DomainSet::parse(".ads.example.com").ok().unwrap(),
+        address: "0.0.0.0".parse().unwrap(),
+    };
+    let mut queue = handle.queue(0, sinkhole).ok().unwrap();
+    // The whole packet must be copied for it to be rewritten
+    queue.set_mode(CopyMode::Packet(0xffff)).ok().unwrap();
+
+    println!("Listening for packets...");
+    handle.start(0xffff);
+
+    println!("...finished.");
+}
+
+struct Sinkhole { blocked: DomainSet, address: IpAddr }
+
+impl VerdictHandler for Sinkhole {
+    fn decide(&mut self, message: &Message) -> Verdict {
+        let response = match message.decode() {
+            Ok(packet) => match Dns::new(packet.payload) {
+                Ok(ref query) if !query.is_response() => match query.questions.first() {
+                    Some(q) if self.blocked.contains(&q.name) => {
+                        println!("Sinkholing {} (ID: {})", q.name, message.header.id());
+                        Response::sinkhole(query, &[self.address], 60).build()
+                    },
+                    _ => return Verdict::Accept
+                },
+                _ => return Verdict::Accept
+            },
+            Err(_) => return Verdict::Accept
+        };
+
+        let mut packet = match message.mangle() {
+            Ok(packet) => packet,
+            Err(e) => {
+                println!("Failed to copy packet (ID: {}): {}", message.header.id(), e);
+                return Verdict::Accept;
+            }
+        };
+        let answered = match response {
+            Ok(response) => packet.reflect().and_then(|_| packet.set_payload(&response)),
+            Err(_) => return Verdict::Accept
+        };
+        match answered {
+            Ok(()) => Verdict::Accept,
+            Err(e) => {
+                println!("Failed to answer packet (ID: {}): {}", message.header.id(), e);
+                Verdict::Drop
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+extern crate libnfqueue as nfq;
+
+use nfq::handle::{Handle, ProtocolFamily};
+use nfq::queue::{CopyMode, Verdict, VerdictHandler};
+use nfq::message::{Message, Transport, TCP_SYN};
+
+/// Clamps the MSS of SYN packets
+///
+/// Queue SYNs with e.g. `iptables -A FORWARD -p tcp --syn -j NFQUEUE --queue-num 0`.
+fn main() {
+    let mut handle = Handle::new().ok().unwrap();
+    handle.bind(ProtocolFamily::INET).ok().unwrap();
+
+    let mut queue = handle.queue(0, Clamp { mss: 1360 }).ok().unwrap();
+    // The whole packet must be copied for it to be rewritten
+    queue.set_mode(CopyMode::Packet(0xffff)).ok().unwrap();
+
+    println!("Listening for packets...");
+    handle.start(0xffff);
+
+    println!("...finished.");
+}
+
+struct Clamp { mss: u16 }
+
+impl VerdictHandler for Clamp {
+    fn decide(&mut self, message: &Message) -> Verdict {
+        // Only SYNs offering a larger MSS are copied and rewritten, everything else is accepted as it is
+        match message.decode() {
+            Ok(packet) => match packet.transport {
+                Some(Transport::Tcp(tcp)) if tcp.has_flags(TCP_SYN) && tcp.mss().map_or(false, |mss| mss > self.mss) => (),
+                _ => return Verdict::Accept
+            },
+            Err(_) => return Verdict::Accept
+        }
+
+        match message.mangle() {
+            Ok(mut packet) => match packet.clamp_mss(self.mss) {
+                Ok(_) => println!("Clamped MSS of packet (ID: {}) to {}", message.header.id(), self.mss),
+                Err(e) => println!("Failed to clamp MSS of packet (ID: {}): {}", message.header.id(), e)
+            },
+            Err(e) => println!("Failed to copy packet (ID: {}): {}", message.header.id(), e)
+        }
+
+        Verdict::Accept
+    }
+}
@@ -0,0 +1,44 @@
+extern crate libnfqueue as nfq;
+
+use nfq::handle::{Handle, ProtocolFamily};
+use nfq::queue::{CopyMode, Verdict, VerdictHandler};
+use nfq::message::{Message, Transport};
+
+fn main() {
+    let mut handle = Handle::new().ok().unwrap();
+    handle.bind(ProtocolFamily::INET).ok().unwrap();
+
+    let mut queue = handle.queue(0, Redirect { from: 8080, to: 80 }).ok().unwrap();
+    // The whole packet must be copied for it to be rewritten
+    queue.set_mode(CopyMode::Packet(0xffff)).ok().unwrap();
+
+    println!("Listening for packets...");
+    handle.start(0xffff);
+
+    println!("...finished.");
+}
+
+/// Rewrites the destination port of TCP packets
+struct Redirect { from: u16, to: u16 }
+
+impl VerdictHandler for Redirect {
+    fn decide(&mut self, message: &Message) -> Verdict {
+        let matched = match message.decode() {
+            Ok(packet) => match packet.transport {
+                Some(Transport::Tcp(tcp)) => tcp.dst_port() == self.from,
+                _ => false
+            },
+            Err(_) => false
+        };
+
+        if matched {
+            match message.mangle() {
+                Ok(mut packet) => {
+                    let _ = packet.set_dst_port(self.to);
+                },
+                Err(e) => println!("Failed to rewrite packet (ID: {}): {}", message.header.id(), e)
+            }
+        }
+        Verdict::Accept
+    }
+}
@@ -0,0 +1,39 @@
+extern crate libnfqueue as nfq;
+
+use std::env;
+use std::process;
+use nfq::replay::{Replay, Trace};
+use nfq::rules::file;
+
+/// Replays a capture through the rules of a file, without a queue, and reports the verdicts
+///
+/// e.g. `replay rules.toml verdicts.pcapng`, exiting with an error if a verdict recorded
+/// by `capture::Capture` is no longer what the rules decide.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        println!("usage: replay RULES CAPTURE");
+        process::exit(2);
+    }
+    let mut engine = match file::load(&args[1]).map_err(|e| e.to_string())
+                                               .and_then(|r| r.compile().map_err(|e| e.to_string())) {
+        Ok(engine) => engine,
+        Err(e) => {
+            println!("{}: {}", args[1], e);
+            process::exit(2);
+        }
+    };
+    let trace = match Trace::load(&args[2]) {
+        Ok(trace) => trace,
+        Err(e) => {
+            println!("{}: {}", args[2], e);
+            process::exit(2);
+        }
+    };
+
+    let report = Replay::new().run(&mut engine, &trace);
+    println!("{}", report);
+    if !report.mismatches().is_empty() {
+        process::exit(1);
+    }
+}
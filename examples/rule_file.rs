@@ -0,0 +1,34 @@
+extern crate libnfqueue as nfq;
+
+use std::env;
+use std::time::Duration;
+use nfq::handle::{Handle, ProtocolFamily};
+use nfq::queue::CopyMode;
+use nfq::rules::file::RuleFile;
+
+/// Decides packets by the rules of a file, given as the first argument, see `rules::file`
+///
+/// Edit the file and send `SIGHUP`, or wait a couple of seconds, for the new rules to take effect.
+fn main() {
+    let path = env::args().nth(1).expect("usage: rule_file RULES");
+    let mut rules = match RuleFile::open(&path) {
+        Ok(rules) => rules,
+        Err(e) => {
+            println!("{}: {}", path, e);
+            return;
+        }
+    };
+    rules.set_watch(Duration::from_secs(2));
+
+    let mut handle = Handle::new().ok().unwrap();
+    handle.bind(ProtocolFamily::INET).ok().unwrap();
+
+    let mut queue = handle.queue(0, rules).ok().unwrap();
+    // Enough of each packet to find the server name of a ClientHello
+    queue.set_mode(CopyMode::Packet(4096)).ok().unwrap();
+
+    println!("Listening for packets...");
+    handle.start(4096);
+
+    println!("...finished.");
+}
@@ -0,0 +1,75 @@
+extern crate libnfqueue as nfq;
+
+use std::time::Instant;
+use nfq::handle::{Handle, ProtocolFamily};
+use nfq::queue::{CopyMode, Verdict};
+use nfq::message::Message;
+use nfq::quic::{ClientHelloAssembler, QuicError};
+use nfq::stream::{Side, Stream, StreamHandler, StreamReassembler, StreamVerdict};
+use nfq::tls::{ClientHello, TlsError};
+
+/// Blocks HTTPS and HTTP/3 to blocked hostnames, read from the SNI of their ClientHello
+///
+/// TCP connections are reset; QUIC Initials are dropped, so browsers fall back to TCP.
+/// Queue both with e.g. `iptables -A FORWARD -p tcp --dport 443 -j NFQUEUE --queue-num 0`
+/// and `iptables -A FORWARD -p udp --dport 443 -j NFQUEUE --queue-num 0`.
+fn main() {
+    let mut handle = Handle::new().ok().unwrap();
+    handle.bind(ProtocolFamily::INET).ok().unwrap();
+
+    let filter = SniFilter {
+        blocked: vec!["example.com".to_string()],
+        quic: ClientHelloAssembler::new(),
+    };
+    let mut queue = handle.queue(0, StreamReassembler::new(filter)).ok().unwrap();
+    // Whole segments must be copied for their streams to be inspected
+    queue.set_mode(CopyMode::Packet(0xffff)).ok().unwrap();
+
+    println!("Listening for packets...");
+    handle.start(0xffff);
+
+    println!("...finished.");
+}
+
+struct SniFilter { blocked: Vec<String>, quic: ClientHelloAssembler }
+
+impl SniFilter {
+    fn is_blocked(&self, hello: &ClientHello) -> bool {
+        match hello.server_name() {
+            Some(name) => self.blocked.iter().any(|b| name == b || name.ends_with(&format!(".{}", b))),
+            None => false
+        }
+    }
+}
+
+impl StreamHandler for SniFilter {
+    fn data(&mut self, stream: &Stream, side: Side) -> StreamVerdict {
+        if side != Side::Client {
+            return StreamVerdict::Continue;
+        }
+        match ClientHello::parse(stream.data(Side::Client)) {
+            Ok(ref hello) if self.is_blocked(hello) => {
+                println!("Resetting connection to {} ({})", hello.server_name().unwrap(), hello.ja4());
+                StreamVerdict::Reset
+            },
+            Err(TlsError::Incomplete) => StreamVerdict::Continue,
+            _ => StreamVerdict::Accept
+        }
+    }
+
+    fn other(&mut self, message: &Message) -> Verdict {
+        let packet = match message.decode() {
+            Ok(packet) => packet,
+            Err(_) => return Verdict::Accept
+        };
+        match self.quic.add(packet.payload, Instant::now()) {
+            Ok(ref hello) if self.is_blocked(hello) => {
+                println!("Dropping QUIC connection to {} ({})", hello.server_name().unwrap(), hello.ja4_quic());
+                Verdict::Drop
+            },
+            // Drop the first part of a ClientHello until the rest shows whether it is blocked
+            Err(QuicError::Tls(TlsError::Incomplete)) => Verdict::Drop,
+            _ => Verdict::Accept
+        }
+    }
+}
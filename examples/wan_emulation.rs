@@ -0,0 +1,41 @@
+extern crate libnfqueue as nfq;
+
+use std::time::Duration;
+use nfq::handle::{Handle, ProtocolFamily};
+use nfq::inject::Injector;
+use nfq::netem::{Config, Distribution, Loss, Netem};
+use nfq::queue::CopyMode;
+
+/// Emulates a lossy WAN link: 80ms +/- 20ms of latency, bursts of loss and the odd duplicate
+///
+/// Queue the traffic to impair with e.g. `iptables -A FORWARD -m mark ! --mark 0x2 -j NFQUEUE --queue-num 0`,
+/// the mark keeping duplicates from being queued again.
+fn main() {
+    let mut handle = Handle::new().ok().unwrap();
+    handle.bind(ProtocolFamily::INET).ok().unwrap();
+
+    let config = Config {
+        latency: Duration::from_millis(80),
+        jitter: Duration::from_millis(20),
+        distribution: Distribution::Normal,
+        loss: Loss::GilbertElliott { p: 0.01, r: 0.3, loss_bad: 0.5, loss_good: 0.0 },
+        reorder: 0.0,
+        duplicate: 0.001,
+    };
+    let mut netem = Netem::new(config);
+    match Injector::new().and_then(|mut i| i.set_mark(0x2).map(|_| i)) {
+        Ok(injector) => netem.set_injector(injector),
+        Err(e) => println!("Not duplicating packets: {}", e)
+    }
+
+    let mut queue = handle.queue(0, netem).ok().unwrap();
+    // Whole packets are copied to be duplicated
+    queue.set_mode(CopyMode::Packet(0xffff)).ok().unwrap();
+    // Up to a few seconds of traffic is held at once
+    queue.set_max_length(16384).ok().unwrap();
+
+    println!("Listening for packets...");
+    handle.start(0xffff);
+
+    println!("...finished.");
+}
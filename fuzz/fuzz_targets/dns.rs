@@ -0,0 +1,30 @@
+//! Parse DNS messages, and answer any queries among them
+#![no_main]
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use libfuzzer_sys::fuzz_target;
+use libnfqueue::message::dns::{Dns, Response};
+
+fuzz_target!(|data: &[u8]| {
+    let dns = match Dns::new(data) {
+        Ok(dns) => dns,
+        Err(_) => return
+    };
+    let _ = (dns.id(), dns.opcode(), dns.rcode(), dns.is_response());
+    for record in dns.answers.iter().chain(&dns.authorities).chain(&dns.additionals) {
+        let _ = record.address();
+        let _ = dns.target(record);
+    }
+    for offset in 0..data.len().min(64) {
+        let _ = dns.name_at(offset);
+    }
+
+    // Responses built from a parsed query parse again
+    let addresses = [IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), IpAddr::V6(Ipv6Addr::LOCALHOST)];
+    for response in &[Response::answering(&dns), Response::nxdomain(&dns), Response::sinkhole(&dns, &addresses, 60)] {
+        if let Ok(built) = response.build() {
+            let parsed = Dns::new(&built).unwrap();
+            assert_eq!(parsed.id(), dns.id());
+        }
+    }
+});
@@ -0,0 +1,21 @@
+//! Parse HTTP requests
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libnfqueue::http::Request;
+
+fuzz_target!(|data: &[u8]| {
+    let request = match Request::parse(data) {
+        Ok(request) => request,
+        Err(_) => return
+    };
+    let _ = request.is_complete();
+    if let Some(len) = request.header_len() {
+        assert!(len <= data.len());
+    }
+    let _ = request.host();
+    let _ = request.path();
+    let _ = request.query();
+    let _ = request.user_agent();
+    let _ = request.field("content-length");
+});
@@ -0,0 +1,58 @@
+//! Decode NFQUEUE packet messages as libnetfilter_queue reads them from the kernel
+//!
+//! Messages are handed to a handle as though received on its socket. They only reach the queue,
+//! and so the crate's `Message`, when the queue could be created, which needs root
+//! and the `nfnetlink_queue` module; otherwise only the netlink framing is decoded.
+#![no_main]
+
+use std::cell::RefCell;
+use libfuzzer_sys::fuzz_target;
+use libnfqueue::handle::Handle;
+use libnfqueue::message::Message;
+use libnfqueue::queue::{CopyMode, Queue, Verdict, VerdictHandler};
+use libnfqueue_fuzz::Netlink;
+
+/// Not bound by any rule, so the kernel never queues real packets to it
+const QUEUE: u16 = 65535;
+
+/// Reads everything from each message
+///
+/// The kernel refuses the verdicts, as it never queued the packets they name.
+struct Walk;
+
+impl VerdictHandler for Walk {
+    fn decide(&mut self, message: &Message) -> Verdict {
+        let _ = (message.header.id(), message.mark(), message.uid(), message.indev(),
+                 message.outdev(), message.timestamp());
+        if let Ok(packet) = message.decode() {
+            let _ = packet.verify_transport_checksum();
+        }
+        let _ = message.mangle().map(|mut buffer| buffer.set_ttl(1));
+        Verdict::Drop
+    }
+}
+
+thread_local! {
+    static HANDLE: RefCell<Option<(Option<Box<Queue<Walk>>>, Handle)>> = RefCell::new(None);
+}
+
+fuzz_target!(|input: (bool, Netlink, Vec<u8>)| {
+    let (raw, netlink, bytes) = input;
+    let mut data = match raw {
+        true => bytes,
+        false => netlink.build(QUEUE)
+    };
+    HANDLE.with(|cell| {
+        let mut opened = cell.borrow_mut();
+        if opened.is_none() {
+            let mut handle = Handle::new().expect("Failed to open handle");
+            let queue = handle.queue(QUEUE, Walk).ok().map(|mut queue| {
+                let _ = queue.set_mode(CopyMode::Packet(0xffff));
+                queue
+            });
+            *opened = Some((queue, handle));
+        }
+        let (_, ref mut handle) = *opened.as_mut().unwrap();
+        handle.handle_data(&mut data);
+    });
+});
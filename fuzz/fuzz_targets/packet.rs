@@ -0,0 +1,42 @@
+//! Decode raw packets, as copied from the kernel, through every layer
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libnfqueue::domain::requested_name;
+use libnfqueue::message::{Icmp, Ipv4, Ipv6, Network, Packet, PacketBuffer, Tcp, Transport, Udp};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Ipv4::new(data).map(|ip| (ip.options(), ip.is_fragment()));
+    let _ = Ipv6::new(data).map(|ip| (ip.flow_label(), ip.payload_len()));
+    let _ = Tcp::new(data).map(|tcp| (tcp.options_iter().count(), tcp.mss(), tcp.window_scale(),
+                                      tcp.timestamps(), tcp.sack_blocks()));
+    let _ = Udp::new(data).map(|udp| udp.length());
+    let _ = Icmp::new(data).map(|icmp| icmp.rest_of_header());
+
+    let packet = match Packet::new(data) {
+        Ok(packet) => packet,
+        Err(_) => return
+    };
+    assert!(packet.payload_offset() <= packet.data().len());
+    match packet.network {
+        Network::Ipv4(ip) => { let _ = ip.options(); },
+        Network::Ipv6(ip) => { let _ = ip.next_header(); },
+    }
+    match packet.transport {
+        Some(Transport::Tcp(tcp)) => { let _ = tcp.options_iter().count(); },
+        Some(Transport::Udp(udp)) => { let _ = udp.length(); },
+        Some(Transport::Icmp(icmp)) | Some(Transport::Icmpv6(icmp)) => { let _ = icmp.echo_seq(); },
+        None => ()
+    }
+    let _ = packet.verify_network_checksum();
+    let _ = packet.verify_transport_checksum();
+    let _ = requested_name(&packet);
+
+    // Recomputing the checksums of any complete packet leaves them correct
+    if let Ok(mut buffer) = PacketBuffer::new(data.to_vec()) {
+        buffer.data_mut();
+        let finished = Packet::new(buffer.finish()).unwrap();
+        assert_ne!(finished.verify_network_checksum(), Some(false));
+        assert_ne!(finished.verify_transport_checksum(), Some(false));
+    }
+});
@@ -0,0 +1,21 @@
+//! Parse QUIC Initial packets, decrypting them and reassembling their ClientHellos
+#![no_main]
+
+use std::time::Instant;
+use libfuzzer_sys::fuzz_target;
+use libnfqueue::quic::{self, ClientHelloAssembler, Initial};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Initial::parse_datagram(data);
+    if let Ok(hello) = quic::client_hello(data) {
+        let _ = hello.server_name();
+        let _ = hello.ja4_quic();
+    }
+
+    // Split in two, as a ClientHello spanning datagrams would be
+    let mut assembler = ClientHelloAssembler::new();
+    let now = Instant::now();
+    let half = data.len() / 2;
+    let _ = assembler.add(&data[..half], now);
+    let _ = assembler.add(&data[half..], now);
+});
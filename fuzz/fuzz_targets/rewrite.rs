@@ -0,0 +1,28 @@
+//! Rewrite well-formed packets, checking the checksums stay correct
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libnfqueue::message::{Packet, PacketBuffer};
+use libnfqueue_fuzz::{Rewrite, Spec};
+
+fuzz_target!(|input: (Spec, Vec<Rewrite>)| {
+    let (spec, rewrites) = input;
+    let data = spec.build();
+    let original = Packet::new(&data).expect("Generated an undecodable packet");
+    assert_ne!(original.verify_network_checksum(), Some(false), "Generated a bad IP checksum");
+    assert_ne!(original.verify_transport_checksum(), Some(false), "Generated a bad transport checksum");
+
+    let mut buffer = PacketBuffer::new(data.clone()).expect("Generated an incomplete packet");
+    for rewrite in &rewrites {
+        if !rewrite.apply(&mut buffer) {
+            continue;
+        }
+        // Pokes can break the packet, after which there is nothing to check
+        let finished = match Packet::new(buffer.finish()) {
+            Ok(packet) => packet,
+            Err(_) => return
+        };
+        assert_ne!(finished.verify_network_checksum(), Some(false), "{:?} left a bad IP checksum", rewrite);
+        assert_ne!(finished.verify_transport_checksum(), Some(false), "{:?} left a bad transport checksum", rewrite);
+    }
+});
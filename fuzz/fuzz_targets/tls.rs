@@ -0,0 +1,27 @@
+//! Parse TLS ClientHellos, from the start of a stream or as a bare handshake message
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libnfqueue::tls::ClientHello;
+
+fn walk(hello: &ClientHello) {
+    let _ = hello.server_name();
+    let _ = hello.alpn();
+    let _ = hello.supported_versions();
+    let _ = hello.supported_groups();
+    let _ = hello.ec_point_formats();
+    let _ = hello.signature_algorithms();
+    let _ = hello.max_version();
+    let _ = hello.ja3();
+    let _ = hello.ja4();
+    let _ = hello.ja4_quic();
+}
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(hello) = ClientHello::parse(data) {
+        walk(&hello);
+    }
+    if let Ok(hello) = ClientHello::parse_handshake(data) {
+        walk(&hello);
+    }
+});
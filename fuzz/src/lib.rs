@@ -0,0 +1,289 @@
+//! Structure-aware generators for the fuzz targets
+//!
+//! Random bytes rarely get past an IP header's lengths and checksums, so `Spec` builds a well-formed
+//! packet with correct checksums from the fuzzer's input, and `Rewrite` a change to make to it,
+//! leaving the fuzzer to explore the packets and rewrites themselves.
+//! `Netlink` likewise frames attributes as an NFQUEUE packet message.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use arbitrary::Arbitrary;
+use libnfqueue::message::checksum::{fill_network, fill_transport};
+use libnfqueue::message::PacketBuffer;
+
+// `message` also has libc's, of other types
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ICMPV6: u8 = 58;
+
+/// The most payload a generated packet carries
+const MAX_PAYLOAD: usize = 1400;
+
+/// An address of either family
+#[derive(Arbitrary, Clone, Copy, Debug)]
+pub enum Address {
+    V4([u8; 4]),
+    V6([u8; 16]),
+}
+
+impl Address {
+    pub fn ip(&self) -> IpAddr {
+        match *self {
+            Address::V4(a) => IpAddr::V4(Ipv4Addr::from(a)),
+            Address::V6(a) => IpAddr::V6(Ipv6Addr::from(a)),
+        }
+    }
+}
+
+/// The network layer of a generated packet
+#[derive(Arbitrary, Debug)]
+pub enum Network {
+    V4 {
+        saddr: [u8; 4],
+        daddr: [u8; 4],
+        tos: u8,
+        id: u16,
+        dont_fragment: bool,
+        ttl: u8,
+        /// Padded with end-of-list to a whole number of words, at most 40 bytes
+        options: Vec<u8>,
+    },
+    V6 {
+        saddr: [u8; 16],
+        daddr: [u8; 16],
+        traffic_class: u8,
+        flow_label: u32,
+        hop_limit: u8,
+    },
+}
+
+/// The transport layer of a generated packet
+#[derive(Arbitrary, Debug)]
+pub enum Transport {
+    Tcp {
+        src_port: u16,
+        dst_port: u16,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        window: u16,
+        /// Options as kind and data, padded with end-of-list, at most 40 bytes
+        options: Vec<(u8, Vec<u8>)>,
+    },
+    Udp {
+        src_port: u16,
+        dst_port: u16,
+        /// Leave the checksum disabled, over IPv4 only
+        no_checksum: bool,
+    },
+    /// ICMP over IPv4, ICMPv6 over IPv6
+    Icmp {
+        icmp_type: u8,
+        code: u8,
+        rest: u32,
+    },
+}
+
+/// A well-formed IP packet
+#[derive(Arbitrary, Debug)]
+pub struct Spec {
+    pub network: Network,
+    pub transport: Transport,
+    pub payload: Vec<u8>,
+}
+
+impl Spec {
+    /// The packet, with correct lengths and checksums
+    pub fn build(&self) -> Vec<u8> {
+        let payload = &self.payload[..self.payload.len().min(MAX_PAYLOAD)];
+        let v6 = match self.network { Network::V6 { .. } => true, Network::V4 { .. } => false };
+
+        let (protocol, mut segment) = match self.transport {
+            Transport::Tcp { src_port, dst_port, seq, ack, flags, window, ref options } => {
+                let mut encoded = Vec::new();
+                for &(kind, ref data) in options {
+                    match kind {
+                        0 | 1 => encoded.push(kind),
+                        _ => {
+                            let data = &data[..data.len().min(38)];
+                            encoded.push(kind);
+                            encoded.push(data.len() as u8 + 2);
+                            encoded.extend_from_slice(data);
+                        }
+                    }
+                }
+                encoded.truncate(40);
+                while encoded.len() % 4 != 0 {
+                    encoded.push(0);
+                }
+                let mut tcp = Vec::with_capacity(20 + encoded.len());
+                tcp.extend_from_slice(&src_port.to_be_bytes());
+                tcp.extend_from_slice(&dst_port.to_be_bytes());
+                tcp.extend_from_slice(&seq.to_be_bytes());
+                tcp.extend_from_slice(&ack.to_be_bytes());
+                tcp.push(((20 + encoded.len()) / 4 << 4) as u8);
+                tcp.push(flags);
+                tcp.extend_from_slice(&window.to_be_bytes());
+                tcp.extend_from_slice(&[0, 0, 0, 0]);
+                tcp.extend_from_slice(&encoded);
+                (IPPROTO_TCP, tcp)
+            },
+            Transport::Udp { src_port, dst_port, no_checksum } => {
+                let mut udp = Vec::with_capacity(8);
+                udp.extend_from_slice(&src_port.to_be_bytes());
+                udp.extend_from_slice(&dst_port.to_be_bytes());
+                udp.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+                // A zero checksum over IPv4 is left disabled by `fill_transport`
+                match no_checksum && !v6 {
+                    true => udp.extend_from_slice(&[0, 0]),
+                    false => udp.extend_from_slice(&[0xff, 0xff]),
+                }
+                (IPPROTO_UDP, udp)
+            },
+            Transport::Icmp { icmp_type, code, rest } => {
+                let mut icmp = vec![icmp_type, code, 0, 0];
+                icmp.extend_from_slice(&rest.to_be_bytes());
+                (if v6 { IPPROTO_ICMPV6 } else { IPPROTO_ICMP }, icmp)
+            },
+        };
+        segment.extend_from_slice(payload);
+
+        let mut packet = match self.network {
+            Network::V4 { saddr, daddr, tos, id, dont_fragment, ttl, ref options } => {
+                let mut options = options[..options.len().min(40)].to_vec();
+                while options.len() % 4 != 0 {
+                    options.push(0);
+                }
+                let header_len = 20 + options.len();
+                let mut ip = Vec::with_capacity(header_len + segment.len());
+                ip.push(0x40 | (header_len / 4) as u8);
+                ip.push(tos);
+                ip.extend_from_slice(&((header_len + segment.len()) as u16).to_be_bytes());
+                ip.extend_from_slice(&id.to_be_bytes());
+                ip.extend_from_slice(&[if dont_fragment { 0x40 } else { 0 }, 0]);
+                ip.push(ttl);
+                ip.push(protocol);
+                ip.extend_from_slice(&[0, 0]);
+                ip.extend_from_slice(&saddr);
+                ip.extend_from_slice(&daddr);
+                ip.extend_from_slice(&options);
+                ip
+            },
+            Network::V6 { saddr, daddr, traffic_class, flow_label, hop_limit } => {
+                let mut ip = Vec::with_capacity(40 + segment.len());
+                let first = 6 << 28 | (traffic_class as u32) << 20 | flow_label & 0x000f_ffff;
+                ip.extend_from_slice(&first.to_be_bytes());
+                ip.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+                ip.push(protocol);
+                ip.push(hop_limit);
+                ip.extend_from_slice(&saddr);
+                ip.extend_from_slice(&daddr);
+                ip
+            },
+        };
+        packet.extend_from_slice(&segment);
+
+        fill_network(&mut packet);
+        fill_transport(&mut packet);
+        packet
+    }
+}
+
+/// A change made to a `PacketBuffer`
+#[derive(Arbitrary, Debug)]
+pub enum Rewrite {
+    Saddr(Address),
+    Daddr(Address),
+    SrcPort(u16),
+    DstPort(u16),
+    Reflect,
+    Ttl(u8),
+    Dscp(u8),
+    Ecn(u8),
+    Splice { offset: u16, remove: u16, insert: Vec<u8> },
+    Payload(Vec<u8>),
+    Seq(u32),
+    Ack(u32),
+    TcpFlags(u8),
+    Reset,
+    ClampMss(u16),
+    SetTcpOption { kind: u8, data: Vec<u8> },
+    InsertTcpOption { kind: u8, data: Vec<u8> },
+    RemoveTcpOption(u8),
+    /// Overwrite a byte through `data_mut`, leaving the checksums to `finish`
+    Poke { offset: u16, value: u8 },
+}
+
+impl Rewrite {
+    /// Make the change, returning whether the buffer accepted it
+    pub fn apply(&self, buffer: &mut PacketBuffer) -> bool {
+        match *self {
+            Rewrite::Saddr(ref a) => buffer.set_saddr(a.ip()).is_ok(),
+            Rewrite::Daddr(ref a) => buffer.set_daddr(a.ip()).is_ok(),
+            Rewrite::SrcPort(port) => buffer.set_src_port(port).is_ok(),
+            Rewrite::DstPort(port) => buffer.set_dst_port(port).is_ok(),
+            Rewrite::Reflect => buffer.reflect().is_ok(),
+            Rewrite::Ttl(ttl) => buffer.set_ttl(ttl).is_ok(),
+            Rewrite::Dscp(dscp) => buffer.set_dscp(dscp).is_ok(),
+            Rewrite::Ecn(ecn) => buffer.set_ecn(ecn).is_ok(),
+            Rewrite::Splice { offset, remove, ref insert } =>
+                buffer.splice_payload(offset as usize, remove as usize, insert).is_ok(),
+            Rewrite::Payload(ref payload) => buffer.set_payload(payload).is_ok(),
+            Rewrite::Seq(seq) => buffer.set_seq(seq).is_ok(),
+            Rewrite::Ack(ack) => buffer.set_ack(ack).is_ok(),
+            Rewrite::TcpFlags(flags) => buffer.set_tcp_flags(flags).is_ok(),
+            Rewrite::Reset => buffer.make_reset().is_ok(),
+            Rewrite::ClampMss(mss) => buffer.clamp_mss(mss).is_ok(),
+            Rewrite::SetTcpOption { kind, ref data } => buffer.set_tcp_option(kind, data).is_ok(),
+            Rewrite::InsertTcpOption { kind, ref data } => buffer.insert_tcp_option(kind, data).is_ok(),
+            Rewrite::RemoveTcpOption(kind) => buffer.remove_tcp_option(kind).is_ok(),
+            Rewrite::Poke { offset, value } => {
+                let data = buffer.data_mut();
+                let len = data.len();
+                data[offset as usize % len] = value;
+                true
+            },
+        }
+    }
+}
+
+/// The netlink message type of a queued packet, `NFNL_SUBSYS_QUEUE << 8 | NFQNL_MSG_PACKET`
+const NFQNL_MSG_PACKET: u16 = 3 << 8;
+
+/// An NFQUEUE packet message, as the kernel would send it
+#[derive(Arbitrary, Debug)]
+pub struct Netlink {
+    pub flags: u16,
+    pub seq: u32,
+    pub family: u8,
+    /// Attributes as type and data, each padded to four bytes
+    pub attributes: Vec<(u16, Vec<u8>)>,
+}
+
+impl Netlink {
+    /// The message, addressed to `queue`
+    pub fn build(&self, queue: u16) -> Vec<u8> {
+        let mut message = Vec::new();
+        message.extend_from_slice(&[0; 4]);
+        message.extend_from_slice(&NFQNL_MSG_PACKET.to_ne_bytes());
+        message.extend_from_slice(&self.flags.to_ne_bytes());
+        message.extend_from_slice(&self.seq.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        // nfgenmsg: family, version, and the queue number in network order
+        message.push(self.family);
+        message.push(0);
+        message.extend_from_slice(&queue.to_be_bytes());
+        for &(kind, ref data) in &self.attributes {
+            let data = &data[..data.len().min(0xffff - 4)];
+            message.extend_from_slice(&((data.len() + 4) as u16).to_ne_bytes());
+            message.extend_from_slice(&kind.to_ne_bytes());
+            message.extend_from_slice(data);
+            while message.len() % 4 != 0 {
+                message.push(0);
+            }
+        }
+        let len = message.len() as u32;
+        message[..4].copy_from_slice(&len.to_ne_bytes());
+        message
+    }
+}
@@ -0,0 +1,875 @@
+//! Compiling pcap filter expressions
+//!
+//! Expressions are parsed into a tree of tests, each a few loads and a conditional jump,
+//! which is laid out from the end of the program backwards so that every jump's target is known
+//! when it is emitted. Jumps too far for the 8 bit offsets of conditional jumps go through an
+//! unconditional jump placed just after them.
+
+use std::net::IpAddr;
+use cidr::Cidr;
+use util::ip_bits;
+use super::*;
+
+/// What a matching packet's program returns, the most of it to keep
+const ACCEPT: u32 = 0x40000;
+
+const IPPROTO_ICMP: u32 = 1;
+const IPPROTO_TCP: u32 = 6;
+const IPPROTO_UDP: u32 = 17;
+const IPPROTO_ICMPV6: u32 = 58;
+const IPPROTO_SCTP: u32 = 132;
+
+/// Named constants, as in pcap-filter(7)
+const CONSTANTS: &[(&str, u32)] = &[
+    ("tcpflags", 13),
+    ("tcp-fin", 0x01), ("tcp-syn", 0x02), ("tcp-rst", 0x04), ("tcp-push", 0x08),
+    ("tcp-ack", 0x10), ("tcp-urg", 0x20), ("tcp-ece", 0x40), ("tcp-cwr", 0x80),
+    ("icmptype", 0), ("icmpcode", 1),
+    ("icmp-echoreply", 0), ("icmp-unreach", 3), ("icmp-sourcequench", 4), ("icmp-redirect", 5),
+    ("icmp-echo", 8), ("icmp-routeradvert", 9), ("icmp-routersolicit", 10), ("icmp-timxceed", 11),
+    ("icmp-paramprob", 12), ("icmp-tstamp", 13), ("icmp-tstampreply", 14),
+    ("icmp-ireq", 15), ("icmp-ireqreply", 16), ("icmp-maskreq", 17), ("icmp-maskreply", 18),
+    ("icmp6type", 0), ("icmp6code", 1),
+    ("icmp6-destinationunreach", 1), ("icmp6-packettoobig", 2), ("icmp6-timeexceeded", 3),
+    ("icmp6-parameterproblem", 4), ("icmp6-echo", 128), ("icmp6-echoreply", 129),
+    ("icmp6-routersolicit", 133), ("icmp6-routeradvert", 134),
+    ("icmp6-neighborsolicit", 135), ("icmp6-neighboradvert", 136), ("icmp6-redirect", 137),
+];
+
+/// Well known ports, by service name
+const SERVICES: &[(&str, u16)] = &[
+    ("ftp-data", 20), ("ftp", 21), ("ssh", 22), ("telnet", 23), ("smtp", 25), ("domain", 53),
+    ("bootps", 67), ("bootpc", 68), ("http", 80), ("www", 80), ("pop3", 110), ("ntp", 123),
+    ("imap", 143), ("snmp", 161), ("bgp", 179), ("https", 443), ("submission", 587),
+    ("imaps", 993), ("pop3s", 995),
+];
+
+/// A protocol, as a qualifier or in a load
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Proto {
+    Ip,
+    Ip6,
+    Tcp,
+    Udp,
+    Icmp,
+    Icmp6,
+}
+
+impl Proto {
+    fn of(word: &str) -> Option<Proto> {
+        match word {
+            "ip" => Some(Proto::Ip),
+            "ip6" => Some(Proto::Ip6),
+            "tcp" => Some(Proto::Tcp),
+            "udp" => Some(Proto::Udp),
+            "icmp" => Some(Proto::Icmp),
+            "icmp6" => Some(Proto::Icmp6),
+            _ => None
+        }
+    }
+
+    /// The transport protocol number
+    fn number(self) -> Option<u32> {
+        match self {
+            Proto::Tcp => Some(IPPROTO_TCP),
+            Proto::Udp => Some(IPPROTO_UDP),
+            Proto::Icmp => Some(IPPROTO_ICMP),
+            Proto::Icmp6 => Some(IPPROTO_ICMPV6),
+            Proto::Ip | Proto::Ip6 => None,
+        }
+    }
+
+    /// Whether the protocol is carried by IPv4 and by IPv6
+    fn families(self) -> (bool, bool) {
+        match self {
+            Proto::Ip | Proto::Icmp => (true, false),
+            Proto::Ip6 | Proto::Icmp6 => (false, true),
+            Proto::Tcp | Proto::Udp => (true, true),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Family {
+    V4,
+    V6,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Dir {
+    Src,
+    Dst,
+    Either,
+    Both,
+}
+
+/// A test of the packet, whose code is laid out by `Emitter::node`
+#[derive(Debug)]
+enum Node {
+    True,
+    False,
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+    /// Run the loads, then jump on the comparison of the accumulator
+    Test(Vec<Insn>, u16, u32),
+}
+
+fn and(a: Node, b: Node) -> Node {
+    match (a, b) {
+        (Node::True, n) | (n, Node::True) => n,
+        (a, b) => Node::And(Box::new(a), Box::new(b))
+    }
+}
+
+fn or(a: Node, b: Node) -> Node {
+    match (a, b) {
+        (Node::False, n) | (n, Node::False) => n,
+        (a, b) => Node::Or(Box::new(a), Box::new(b))
+    }
+}
+
+fn not(a: Node) -> Node {
+    match a {
+        Node::True => Node::False,
+        Node::False => Node::True,
+        Node::Not(a) => *a,
+        a => Node::Not(Box::new(a))
+    }
+}
+
+/// Combine the tests of the source and destination as a direction says
+fn directed<F: Fn(bool) -> Node>(dir: Dir, test: F) -> Node {
+    match dir {
+        Dir::Src => test(true),
+        Dir::Dst => test(false),
+        Dir::Either => or(test(true), test(false)),
+        Dir::Both => and(test(true), test(false)),
+    }
+}
+
+fn stmt(code: u16, k: u32) -> Insn {
+    Insn { code: code, jt: 0, jf: 0, k: k }
+}
+
+fn test(loads: Vec<Insn>, op: u16, k: u32) -> Node {
+    Node::Test(loads, op, k)
+}
+
+fn ipv4() -> Node {
+    test(vec![stmt(BPF_LD | BPF_B | BPF_ABS, 0), stmt(BPF_ALU | BPF_AND | BPF_K, 0xf0)], BPF_JEQ, 0x40)
+}
+
+fn ipv6() -> Node {
+    test(vec![stmt(BPF_LD | BPF_B | BPF_ABS, 0), stmt(BPF_ALU | BPF_AND | BPF_K, 0xf0)], BPF_JEQ, 0x60)
+}
+
+fn family(family: Family) -> Node {
+    match family {
+        Family::V4 => ipv4(),
+        Family::V6 => ipv6(),
+    }
+}
+
+/// The packet is of a transport protocol, and for IPv4 not a later fragment
+fn transport(family: Family, protocol: u32) -> Node {
+    match family {
+        Family::V4 => {
+            let protocol = test(vec![stmt(BPF_LD | BPF_B | BPF_ABS, 9)], BPF_JEQ, protocol);
+            let later_fragment = test(vec![stmt(BPF_LD | BPF_H | BPF_ABS, 6)], BPF_JSET, 0x1fff);
+            and(ipv4(), and(protocol, not(later_fragment)))
+        },
+        Family::V6 => and(ipv6(), test(vec![stmt(BPF_LD | BPF_B | BPF_ABS, 6)], BPF_JEQ, protocol)),
+    }
+}
+
+fn ip_proto(family: Family, protocol: u32) -> Node {
+    let offset = match family {
+        Family::V4 => 9,
+        Family::V6 => 6,
+    };
+    and(self::family(family), test(vec![stmt(BPF_LD | BPF_B | BPF_ABS, offset)], BPF_JEQ, protocol))
+}
+
+/// The address is in the network
+fn network(network: &Cidr, dir: Dir) -> Node {
+    let bits = ip_bits(&network.addr());
+    match network.addr() {
+        IpAddr::V4(_) => {
+            let mask = match network.prefix() {
+                0 => 0,
+                p => !0u32 << (32 - p as u32)
+            };
+            and(ipv4(), directed(dir, |src| {
+                let offset = if src { 12 } else { 16 };
+                let mut loads = vec![stmt(BPF_LD | BPF_W | BPF_ABS, offset)];
+                match mask {
+                    0 => return Node::True,
+                    0xffff_ffff => (),
+                    mask => loads.push(stmt(BPF_ALU | BPF_AND | BPF_K, mask))
+                }
+                test(loads, BPF_JEQ, bits as u32)
+            }))
+        },
+        IpAddr::V6(_) => {
+            let mask = match network.prefix() {
+                0 => 0,
+                p => !0u128 << (128 - p as u32)
+            };
+            and(ipv6(), directed(dir, |src| {
+                let base = if src { 8 } else { 24 };
+                (0..4).fold(Node::True, |node, word| {
+                    let shift = 96 - 32 * word;
+                    let (mask, value) = ((mask >> shift) as u32, (bits >> shift) as u32);
+                    let mut loads = vec![stmt(BPF_LD | BPF_W | BPF_ABS, base + 4 * word as u32)];
+                    match mask {
+                        0 => return node,
+                        0xffff_ffff => (),
+                        mask => loads.push(stmt(BPF_ALU | BPF_AND | BPF_K, mask))
+                    }
+                    and(node, test(loads, BPF_JEQ, value))
+                })
+            }))
+        }
+    }
+}
+
+/// A TCP, UDP or SCTP port is in a range
+fn ports(protocols: &[u32], dir: Dir, start: u16, end: u16) -> Node {
+    let in_range = |loads: Vec<Insn>| match start == end {
+        true => test(loads, BPF_JEQ, start as u32),
+        false => and(test(loads.clone(), BPF_JGE, start as u32), not(test(loads, BPF_JGT, end as u32)))
+    };
+    [Family::V4, Family::V6].iter().fold(Node::False, |node, &family| {
+        let protocol = protocols.iter().fold(Node::False, |node, &p| or(node, transport(family, p)));
+        let port = directed(dir, |src| {
+            let offset = if src { 0 } else { 2 };
+            in_range(match family {
+                Family::V4 => vec![stmt(BPF_LDX | BPF_B | BPF_MSH, 0), stmt(BPF_LD | BPF_H | BPF_IND, offset)],
+                Family::V6 => vec![stmt(BPF_LD | BPF_H | BPF_ABS, 40 + offset)],
+            })
+        });
+        or(node, and(protocol, port))
+    })
+}
+
+/// An arithmetic expression
+#[derive(Debug)]
+enum Value {
+    Const(u32),
+    Len,
+    /// A load of 1, 2 or 4 bytes from the start of a protocol's header
+    Load(Proto, Box<Value>, u8),
+    Binary(u16, Box<Value>, Box<Value>),
+}
+
+impl Value {
+    /// The protocols loaded from
+    fn protocols(&self, protocols: &mut Vec<Proto>) {
+        match *self {
+            Value::Const(_) | Value::Len => (),
+            Value::Load(proto, ref index, _) => {
+                if !protocols.contains(&proto) {
+                    protocols.push(proto);
+                }
+                index.protocols(protocols);
+            },
+            Value::Binary(_, ref a, ref b) => {
+                a.protocols(protocols);
+                b.protocols(protocols);
+            }
+        }
+    }
+}
+
+/// Code leaving a value in the accumulator, using scratch memory from `depth`
+fn value(v: &Value, family: Option<Family>, depth: u32, code: &mut Vec<Insn>) -> Result<(), FilterError> {
+    match *v {
+        Value::Const(k) => code.push(stmt(BPF_LD | BPF_IMM, k)),
+        Value::Len => code.push(stmt(BPF_LD | BPF_W | BPF_LEN, 0)),
+        Value::Load(proto, ref index, size) => {
+            let size = match size {
+                1 => BPF_B,
+                2 => BPF_H,
+                _ => BPF_W
+            };
+            // The header starts at a fixed offset, or at X once loaded
+            let fixed = match (proto, family) {
+                (Proto::Ip, _) | (Proto::Ip6, _) => Some(0),
+                (_, Some(Family::V6)) => Some(40),
+                _ => None
+            };
+            match (&**index, fixed) {
+                (&Value::Const(k), Some(base)) => code.push(stmt(BPF_LD | size | BPF_ABS, base + k)),
+                (&Value::Const(k), None) => {
+                    code.push(stmt(BPF_LDX | BPF_B | BPF_MSH, 0));
+                    code.push(stmt(BPF_LD | size | BPF_IND, k));
+                },
+                (index, fixed) => {
+                    value(index, family, depth, code)?;
+                    match fixed {
+                        Some(base) => code.push(stmt(BPF_ALU | BPF_ADD | BPF_K, base)),
+                        None => {
+                            code.push(stmt(BPF_LDX | BPF_B | BPF_MSH, 0));
+                            code.push(stmt(BPF_ALU | BPF_ADD | BPF_X, 0));
+                        }
+                    }
+                    code.push(stmt(BPF_MISC | BPF_TAX, 0));
+                    code.push(stmt(BPF_LD | size | BPF_IND, 0));
+                }
+            }
+        },
+        Value::Binary(op, ref a, ref b) => {
+            value(a, family, depth, code)?;
+            match **b {
+                Value::Const(k) => {
+                    if (op == BPF_DIV || op == BPF_MOD) && k == 0 {
+                        return Err(FilterError::Syntax("division by zero".to_string()));
+                    }
+                    code.push(stmt(BPF_ALU | op | BPF_K, k));
+                },
+                ref b => {
+                    operand(b, family, depth, code)?;
+                    code.push(stmt(BPF_ALU | op | BPF_X, 0));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Code leaving `v` in X and the accumulator as it was, using scratch memory from `depth`
+fn operand(v: &Value, family: Option<Family>, depth: u32, code: &mut Vec<Insn>) -> Result<(), FilterError> {
+    if depth >= BPF_MEMWORDS {
+        return Err(FilterError::TooComplex);
+    }
+    code.push(stmt(BPF_ST, depth));
+    value(v, family, depth + 1, code)?;
+    code.push(stmt(BPF_MISC | BPF_TAX, 0));
+    code.push(stmt(BPF_LD | BPF_MEM, depth));
+    Ok(())
+}
+
+/// A comparison of two values, for the IP versions the protocols loaded from allow
+fn relation(a: &Value, op: &str, b: &Value) -> Result<Node, FilterError> {
+    let mut protocols = Vec::new();
+    a.protocols(&mut protocols);
+    b.protocols(&mut protocols);
+    let (v4, v6) = protocols.iter().fold((true, true), |(v4, v6), p| {
+        let (p4, p6) = p.families();
+        (v4 && p4, v6 && p6)
+    });
+    let families = match protocols.is_empty() {
+        true => vec![None],
+        false => {
+            let mut families = Vec::new();
+            if v4 {
+                families.push(Some(Family::V4));
+            }
+            if v6 {
+                families.push(Some(Family::V6));
+            }
+            if families.is_empty() {
+                return Err(FilterError::Syntax("loads from both IPv4 and IPv6".to_string()));
+            }
+            families
+        }
+    };
+    let mut node = Node::False;
+    for family in families {
+        let mut code = Vec::new();
+        value(a, family, 0, &mut code)?;
+        let (jump, k) = match *b {
+            Value::Const(k) => (BPF_K, k),
+            ref b => {
+                operand(b, family, 0, &mut code)?;
+                (BPF_X, 0)
+            }
+        };
+        let compare = |op| test(code.clone(), op | jump, k);
+        let comparison = match op {
+            ">" => compare(BPF_JGT),
+            ">=" => compare(BPF_JGE),
+            "=" | "==" => compare(BPF_JEQ),
+            "!=" => not(compare(BPF_JEQ)),
+            "<" => not(compare(BPF_JGE)),
+            "<=" => not(compare(BPF_JGT)),
+            _ => unreachable!()
+        };
+        let guard = match family {
+            Some(family) => protocols.iter().fold(self::family(family), |node, p| match p.number() {
+                Some(n) => and(node, transport(family, n)),
+                None => node
+            }),
+            None => Node::True
+        };
+        node = or(node, and(guard, comparison));
+    }
+    Ok(node)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Punct(&'static str),
+}
+
+const PUNCTUATION: &[&str] = &[
+    "&&", "||", "!=", "==", "<=", ">=", "<<", ">>",
+    "(", ")", "[", "]", "&", "|", "!", "=", "<", ">", "+", "-", "*", "/", "%", "^", ":",
+];
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, FilterError> {
+    let mut tokens = Vec::new();
+    let mut depth = 0;
+    let mut rest = expression;
+    loop {
+        rest = rest.trim_start();
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => return Ok(tokens)
+        };
+        // Within brackets, `:` separates the size of a load rather than being part of an IPv6 address
+        let word = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' || c == '/' || (c == ':' && depth == 0);
+        if c.is_ascii_alphanumeric() || c == '_' || (c == ':' && depth == 0) {
+            let end = rest.find(|c| !word(c)).unwrap_or(rest.len());
+            tokens.push(Token::Word(rest[..end].to_string()));
+            rest = &rest[end..];
+            continue;
+        }
+        match PUNCTUATION.iter().find(|p| rest.starts_with(**p)) {
+            Some(p) => {
+                match *p {
+                    "[" => depth += 1,
+                    "]" => depth -= 1,
+                    _ => ()
+                }
+                tokens.push(Token::Punct(p));
+                rest = &rest[p.len()..];
+            },
+            None => return Err(FilterError::Syntax(format!("unexpected {}", c)))
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn expression(&mut self) -> Result<Node, FilterError> {
+        let mut node = self.conjunction()?;
+        while self.eat_word("or") || self.eat("||") {
+            node = or(node, self.conjunction()?);
+        }
+        Ok(node)
+    }
+
+    fn conjunction(&mut self) -> Result<Node, FilterError> {
+        let mut node = self.unary()?;
+        while self.eat_word("and") || self.eat("&&") {
+            node = and(node, self.unary()?);
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, FilterError> {
+        match self.eat_word("not") || self.eat("!") {
+            true => self.unary().map(not),
+            false => self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Node, FilterError> {
+        // A relation may start like a primitive or a parenthesised expression, so is tried first
+        let start = self.pos;
+        if let Some(node) = self.relation()? {
+            return Ok(node);
+        }
+        self.pos = start;
+        if self.eat("(") {
+            let node = self.expression()?;
+            self.expect(")")?;
+            return Ok(node);
+        }
+        self.primitive()
+    }
+
+    /// A relation, or `None` if what follows is not one up to its comparison
+    fn relation(&mut self) -> Result<Option<Node>, FilterError> {
+        let a = match self.arithmetic() {
+            Ok(a) => a,
+            Err(_) => return Ok(None)
+        };
+        let op = match self.peek() {
+            Some(Token::Punct(op)) if [">", ">=", "<", "<=", "=", "==", "!="].contains(&op) => op,
+            _ => return Ok(None)
+        };
+        self.pos += 1;
+        let b = self.arithmetic()?;
+        relation(&a, op, &b).map(Some)
+    }
+
+    /// Arithmetic, with the precedence of C
+    fn arithmetic(&mut self) -> Result<Value, FilterError> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Value, FilterError> {
+        const LEVELS: &[&[(&str, u16)]] = &[
+            &[("|", BPF_OR)],
+            &[("^", BPF_XOR)],
+            &[("&", BPF_AND)],
+            &[("<<", BPF_LSH), (">>", BPF_RSH)],
+            &[("+", BPF_ADD), ("-", BPF_SUB)],
+            &[("*", BPF_MUL), ("/", BPF_DIV), ("%", BPF_MOD)],
+        ];
+        if level == LEVELS.len() {
+            return self.operand();
+        }
+        let mut v = self.binary(level + 1)?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Punct(p)) => LEVELS[level].iter().find(|&&(s, _)| s == p).map(|&(_, op)| op),
+                _ => None
+            };
+            match op {
+                Some(op) => {
+                    self.pos += 1;
+                    v = Value::Binary(op, Box::new(v), Box::new(self.binary(level + 1)?));
+                },
+                None => return Ok(v)
+            }
+        }
+    }
+
+    fn operand(&mut self) -> Result<Value, FilterError> {
+        if self.eat("(") {
+            let v = self.arithmetic()?;
+            self.expect(")")?;
+            return Ok(v);
+        }
+        let word = match self.next() {
+            Some(Token::Word(word)) => word,
+            _ => return self.error("expected a value")
+        };
+        if word == "len" {
+            return Ok(Value::Len);
+        }
+        if let Some(proto) = Proto::of(&word) {
+            self.expect("[")?;
+            let index = self.arithmetic()?;
+            let size = match self.eat(":") {
+                true => match self.next() {
+                    Some(Token::Word(ref s)) if s == "1" || s == "2" || s == "4" => s.parse().unwrap(),
+                    _ => return self.error("expected a size of 1, 2 or 4")
+                },
+                false => 1
+            };
+            self.expect("]")?;
+            return Ok(Value::Load(proto, Box::new(index), size));
+        }
+        match number(&word).or_else(|| CONSTANTS.iter().find(|c| c.0 == word).map(|c| c.1)) {
+            Some(n) => Ok(Value::Const(n)),
+            None => Err(FilterError::Syntax(format!("unknown value {}", word)))
+        }
+    }
+
+    fn primitive(&mut self) -> Result<Node, FilterError> {
+        let word = match self.next() {
+            Some(Token::Word(word)) => word,
+            Some(Token::Punct(p)) => return Err(FilterError::Syntax(format!("unexpected {}", p))),
+            None => return self.error("unexpected end")
+        };
+        match &word[..] {
+            "less" => return Ok(not(test(vec![stmt(BPF_LD | BPF_W | BPF_LEN, 0)], BPF_JGT, self.integer()?))),
+            "greater" => return Ok(test(vec![stmt(BPF_LD | BPF_W | BPF_LEN, 0)], BPF_JGE, self.integer()?)),
+            "proto" => {
+                let p = self.protocol_number()?;
+                return Ok(or(ip_proto(Family::V4, p), ip_proto(Family::V6, p)));
+            },
+            _ => ()
+        }
+        let proto = Proto::of(&word);
+        if proto.is_some() && self.eat_word("proto") {
+            let p = self.protocol_number()?;
+            return match proto {
+                Some(Proto::Ip) => Ok(ip_proto(Family::V4, p)),
+                Some(Proto::Ip6) => Ok(ip_proto(Family::V6, p)),
+                _ => Err(FilterError::Syntax(format!("{} proto", word)))
+            };
+        }
+        let dir = match proto {
+            Some(_) => self.direction(),
+            None => {
+                self.pos -= 1;
+                self.direction()
+            }
+        };
+        let kind = match self.peek() {
+            Some(Token::Word(ref w)) if ["host", "net", "port", "portrange"].contains(&&w[..]) => {
+                self.pos += 1;
+                Some(w.clone())
+            },
+            _ => None
+        };
+        let kind = match (kind, dir, proto) {
+            (Some(kind), _, _) => kind,
+            // `src 10.0.0.1` is short for `src host 10.0.0.1`
+            (None, Some(_), _) => "host".to_string(),
+            (None, None, Some(proto)) => return Ok(match proto {
+                Proto::Ip => ipv4(),
+                Proto::Ip6 => ipv6(),
+                Proto::Icmp => ip_proto(Family::V4, IPPROTO_ICMP),
+                Proto::Icmp6 => ip_proto(Family::V6, IPPROTO_ICMPV6),
+                Proto::Tcp => or(ip_proto(Family::V4, IPPROTO_TCP), ip_proto(Family::V6, IPPROTO_TCP)),
+                Proto::Udp => or(ip_proto(Family::V4, IPPROTO_UDP), ip_proto(Family::V6, IPPROTO_UDP)),
+            }),
+            (None, None, None) => return Err(FilterError::Syntax(format!("unknown primitive {}", word)))
+        };
+        let dir = dir.unwrap_or(Dir::Either);
+        let arg = match self.next() {
+            Some(Token::Word(arg)) => arg,
+            _ => return self.error(&format!("expected a value after {}", kind))
+        };
+        match &kind[..] {
+            "host" | "net" => {
+                let cidr = match kind == "net" && self.eat_word("mask") {
+                    true => {
+                        let mask = match self.next() {
+                            Some(Token::Word(ref m)) => m.parse::<IpAddr>().ok(),
+                            _ => None
+                        };
+                        let prefix = match mask {
+                            Some(IpAddr::V4(m)) if u32::from(m).leading_ones() + u32::from(m).trailing_zeros() == 32 => {
+                                u32::from(m).leading_ones()
+                            },
+                            _ => return self.error("expected an IPv4 netmask")
+                        };
+                        arg.parse::<IpAddr>().ok().and_then(|a| Cidr::new(a, prefix as u8).ok())
+                    },
+                    false => arg.parse::<Cidr>().ok()
+                };
+                let cidr = match cidr {
+                    Some(cidr) if kind == "net" || cidr.prefix() == if cidr.is_ipv4() { 32 } else { 128 } => cidr,
+                    _ => return Err(FilterError::Syntax(format!("malformed {} {}", kind, arg)))
+                };
+                match (proto, cidr.is_ipv4()) {
+                    (None, _) | (Some(Proto::Ip), true) | (Some(Proto::Ip6), false) => Ok(network(&cidr, dir)),
+                    _ => Err(FilterError::Syntax(format!("{} {} {}", word, kind, arg)))
+                }
+            },
+            _ => {
+                let protocols: &[u32] = match proto {
+                    None => &[IPPROTO_TCP, IPPROTO_UDP, IPPROTO_SCTP],
+                    Some(Proto::Tcp) => &[IPPROTO_TCP],
+                    Some(Proto::Udp) => &[IPPROTO_UDP],
+                    Some(_) => return Err(FilterError::Syntax(format!("{} {}", word, kind)))
+                };
+                let range = match kind == "portrange" {
+                    true => arg.find('-').and_then(|dash| {
+                        match (port(&arg[..dash]), port(&arg[dash + 1..])) {
+                            (Some(start), Some(end)) if start <= end => Some((start, end)),
+                            _ => None
+                        }
+                    }),
+                    false => port(&arg).map(|p| (p, p))
+                };
+                match range {
+                    Some((start, end)) => Ok(ports(protocols, dir, start, end)),
+                    None => Err(FilterError::Syntax(format!("malformed {} {}", kind, arg)))
+                }
+            }
+        }
+    }
+
+    /// `src`, `dst`, `src or dst` or `src and dst`
+    fn direction(&mut self) -> Option<Dir> {
+        let dir = match self.peek() {
+            Some(Token::Word(ref w)) if w == "src" => Dir::Src,
+            Some(Token::Word(ref w)) if w == "dst" => Dir::Dst,
+            _ => return None
+        };
+        self.pos += 1;
+        let other = match dir {
+            Dir::Src => "dst",
+            _ => "src"
+        };
+        let combined = match (self.tokens.get(self.pos), self.tokens.get(self.pos + 1)) {
+            (Some(&Token::Word(ref c)), Some(&Token::Word(ref d))) if d == other && (c == "or" || c == "and") => {
+                Some(if c == "or" { Dir::Either } else { Dir::Both })
+            },
+            _ => None
+        };
+        match combined {
+            Some(dir) => {
+                self.pos += 2;
+                Some(dir)
+            },
+            None => Some(dir)
+        }
+    }
+
+    fn protocol_number(&mut self) -> Result<u32, FilterError> {
+        match self.next() {
+            Some(Token::Word(ref w)) => match Proto::of(w).and_then(|p| p.number()).or_else(|| number(w)) {
+                Some(n) if n <= 0xff => Ok(n),
+                _ => Err(FilterError::Syntax(format!("unknown protocol {}", w)))
+            },
+            _ => self.error("expected a protocol")
+        }
+    }
+
+    fn integer(&mut self) -> Result<u32, FilterError> {
+        match self.next() {
+            Some(Token::Word(ref w)) => match number(w) {
+                Some(n) => Ok(n),
+                None => Err(FilterError::Syntax(format!("expected a number, not {}", w)))
+            },
+            _ => self.error("expected a number")
+        }
+    }
+
+    fn peek(&self) -> Option<Token> {
+        self.tokens.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        match self.peek() {
+            Some(Token::Punct(p)) if p == punct => {
+                self.pos += 1;
+                true
+            },
+            _ => false
+        }
+    }
+
+    fn eat_word(&mut self, word: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(ref w)) if w == word => {
+                self.pos += 1;
+                true
+            },
+            _ => false
+        }
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), FilterError> {
+        match self.eat(punct) {
+            true => Ok(()),
+            false => self.error(&format!("expected {}", punct))
+        }
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, FilterError> {
+        let at = match self.tokens.get(self.pos) {
+            Some(&Token::Word(ref w)) => format!(" at {}", w),
+            Some(&Token::Punct(p)) => format!(" at {}", p),
+            None => String::new()
+        };
+        Err(FilterError::Syntax(format!("{}{}", message, at)))
+    }
+}
+
+fn number(s: &str) -> Option<u32> {
+    match s.starts_with("0x") || s.starts_with("0X") {
+        true => u32::from_str_radix(&s[2..], 16).ok(),
+        false => match s.chars().all(|c| c.is_ascii_digit()) {
+            true => s.parse().ok(),
+            false => None
+        }
+    }
+}
+
+fn port(s: &str) -> Option<u16> {
+    match number(s) {
+        Some(n) if n <= 0xffff => Some(n as u16),
+        Some(_) => None,
+        None => SERVICES.iter().find(|s2| s2.0 == s).map(|s| s.1)
+    }
+}
+
+/// Lays out code from the end of the program backwards
+///
+/// Instructions are indexed by their distance from the end.
+struct Emitter {
+    reversed: Vec<Insn>,
+}
+
+impl Emitter {
+    fn emit(&mut self, insn: Insn) -> usize {
+        self.reversed.push(insn);
+        self.reversed.len() - 1
+    }
+
+    /// Lay out a node jumping to `t` if it holds and `f` if not, returning its entry
+    fn node(&mut self, node: &Node, t: usize, f: usize) -> usize {
+        match *node {
+            Node::True => t,
+            Node::False => f,
+            Node::And(ref a, ref b) => {
+                let b = self.node(b, t, f);
+                self.node(a, b, f)
+            },
+            Node::Or(ref a, ref b) => {
+                let b = self.node(b, t, f);
+                self.node(a, t, b)
+            },
+            Node::Not(ref a) => self.node(a, f, t),
+            Node::Test(ref loads, op, k) => {
+                let (mut t, mut f) = (t, f);
+                // Place unconditional jumps for targets out of reach, until both are within it
+                loop {
+                    let here = self.reversed.len();
+                    if here - t - 1 > 0xff {
+                        t = self.emit(stmt(BPF_JMP | BPF_JA, (here - t - 1) as u32));
+                    } else if here - f - 1 > 0xff {
+                        f = self.emit(stmt(BPF_JMP | BPF_JA, (here - f - 1) as u32));
+                    } else {
+                        break;
+                    }
+                }
+                let here = self.reversed.len();
+                let jump = Insn { code: BPF_JMP | op, jt: (here - t - 1) as u8, jf: (here - f - 1) as u8, k: k };
+                let mut entry = self.emit(jump);
+                for &load in loads.iter().rev() {
+                    entry = self.emit(load);
+                }
+                entry
+            }
+        }
+    }
+}
+
+pub fn compile(expression: &str) -> Result<Vec<Insn>, FilterError> {
+    let tokens = tokenize(expression)?;
+    let node = match tokens.is_empty() {
+        true => Node::True,
+        false => {
+            let mut parser = Parser { tokens: tokens, pos: 0 };
+            let node = parser.expression()?;
+            if parser.pos < parser.tokens.len() {
+                return parser.error("unexpected");
+            }
+            node
+        }
+    };
+    let mut emitter = Emitter { reversed: Vec::new() };
+    let reject = emitter.emit(stmt(BPF_RET | BPF_K, 0));
+    let accept = emitter.emit(stmt(BPF_RET | BPF_K, ACCEPT));
+    let entry = emitter.node(&node, accept, reject);
+    if entry + 1 != emitter.reversed.len() {
+        let here = emitter.reversed.len();
+        emitter.emit(stmt(BPF_JMP | BPF_JA, (here - entry - 1) as u32));
+    }
+    let mut program = emitter.reversed;
+    program.reverse();
+    Ok(program)
+}
@@ -0,0 +1,317 @@
+//! Classic BPF filters
+//!
+//! `Filter` compiles pcap filter expressions, as understood by tcpdump, into classic BPF programs
+//! and runs them against queued packets. Queued packets start at their IP header, so programs are
+//! compiled for pcap's raw IP link type, `LINKTYPE_RAW`; programs compiled by other tools for that
+//! link type can be run with `Filter::from_program`.
+//!
+//! A filter is a predicate for `VerdictHandler`s, see `examples/bpf_filter.rs`, and rules, see `rules::Match::Filter`.
+//!
+//! Supported are the protocols `ip`, `ip6`, `tcp`, `udp`, `icmp` and `icmp6`,
+//! `[ip|ip6] proto P`, `[src|dst] host A`, `[src|dst] net N[/L]` or `net A mask M`,
+//! `[tcp|udp] [src|dst] port P` and `portrange P1-P2`, `less N` and `greater N`,
+//! relations of arithmetic on `len` and loads like `tcp[tcpflags] & (tcp-syn|tcp-ack) == tcp-syn`,
+//! and `and`, `or`, `not` and parentheses. IPv6 extension headers are not followed.
+mod compile;
+
+use std::error::Error as Base;
+use std::fmt;
+use std::str::FromStr;
+use message::Message;
+
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ST: u16 = 0x02;
+const BPF_STX: u16 = 0x03;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_MISC: u16 = 0x07;
+
+const BPF_W: u16 = 0x00;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+
+const BPF_IMM: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_MEM: u16 = 0x60;
+const BPF_LEN: u16 = 0x80;
+const BPF_MSH: u16 = 0xa0;
+
+const BPF_ADD: u16 = 0x00;
+const BPF_SUB: u16 = 0x10;
+const BPF_MUL: u16 = 0x20;
+const BPF_DIV: u16 = 0x30;
+const BPF_OR: u16 = 0x40;
+const BPF_AND: u16 = 0x50;
+const BPF_LSH: u16 = 0x60;
+const BPF_RSH: u16 = 0x70;
+const BPF_NEG: u16 = 0x80;
+const BPF_MOD: u16 = 0x90;
+const BPF_XOR: u16 = 0xa0;
+
+const BPF_JA: u16 = 0x00;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_JSET: u16 = 0x40;
+
+const BPF_K: u16 = 0x00;
+const BPF_X: u16 = 0x08;
+const BPF_A: u16 = 0x10;
+
+const BPF_TAX: u16 = 0x00;
+const BPF_TXA: u16 = 0x80;
+
+/// The number of words of scratch memory
+const BPF_MEMWORDS: u32 = 16;
+
+/// A classic BPF instruction, as in `struct sock_filter`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Insn {
+    /// The opcode
+    pub code: u16,
+    /// The offset of the next instruction if a conditional jump is taken
+    pub jt: u8,
+    /// The offset of the next instruction if a conditional jump is not taken
+    pub jf: u8,
+    /// The operand
+    pub k: u32,
+}
+
+/// Reasons a filter could not be compiled
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FilterError {
+    /// The expression is malformed
+    Syntax(String),
+    /// The expression needs more scratch memory than BPF has
+    TooComplex,
+    /// A program is malformed, e.g. jumping past its end
+    Program,
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FilterError::Syntax(ref message) => write!(f, "{}: {}", self.reason(), message),
+            _ => write!(f, "{}", self.reason()),
+        }
+    }
+}
+
+impl Base for FilterError {
+    fn description(&self) -> &str {
+        self.reason()
+    }
+}
+
+impl FilterError {
+    fn reason(&self) -> &'static str {
+        match *self {
+            FilterError::Syntax(_) => "Malformed filter expression",
+            FilterError::TooComplex => "Filter expression too complex",
+            FilterError::Program => "Malformed BPF program",
+        }
+    }
+}
+
+/// A classic BPF program over raw IP packets
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    program: Vec<Insn>,
+}
+
+impl Filter {
+    /// Compile a pcap filter expression
+    ///
+    /// The empty expression matches every packet.
+    pub fn new(expression: &str) -> Result<Filter, FilterError> {
+        compile::compile(expression).map(|program| Filter { program: program })
+    }
+
+    /// Use a program compiled elsewhere, e.g. the output of `tcpdump -dd` for a raw IP link
+    pub fn from_program(program: Vec<Insn>) -> Result<Filter, FilterError> {
+        match validate(&program) {
+            true => Ok(Filter { program: program }),
+            false => Err(FilterError::Program)
+        }
+    }
+
+    /// The program, e.g. to attach to a socket
+    pub fn program(&self) -> &[Insn] {
+        &self.program
+    }
+
+    /// Whether the program accepts a raw IP packet
+    pub fn matches_data(&self, data: &[u8]) -> bool {
+        run(&self.program, data) != 0
+    }
+
+    /// Whether the program accepts the message's packet
+    ///
+    /// Only the part of the packet that was copied is seen, and `len` is its length.
+    pub fn matches(&self, message: &Message) -> bool {
+        match message.data() {
+            Ok(data) => self.matches_data(data),
+            Err(_) => false
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Filter, FilterError> {
+        Filter::new(s)
+    }
+}
+
+/// Run a program over `data`, returning what it returns
+///
+/// As in the kernel, loads past the end of the data return zero, as does dividing by zero.
+pub fn run(program: &[Insn], data: &[u8]) -> u32 {
+    let load = |offset: u32, size: usize| -> Option<u32> {
+        let start = offset as usize;
+        let bytes = match start.checked_add(size) {
+            Some(end) if end <= data.len() => &data[start..end],
+            _ => return None
+        };
+        Some(bytes.iter().fold(0, |v, &b| v << 8 | b as u32))
+    };
+    let size = |code: u16| match code & 0x18 {
+        BPF_B => 1,
+        BPF_H => 2,
+        _ => 4
+    };
+    let (mut a, mut x) = (0u32, 0u32);
+    let mut mem = [0u32; BPF_MEMWORDS as usize];
+    let mut pc = 0;
+    while let Some(&insn) = program.get(pc) {
+        pc += 1;
+        let k = insn.k;
+        match insn.code & 0x07 {
+            BPF_LD => {
+                a = match insn.code & 0xe0 {
+                    BPF_IMM => k,
+                    BPF_ABS => match load(k, size(insn.code)) {
+                        Some(v) => v,
+                        None => return 0
+                    },
+                    BPF_IND => match load(x.wrapping_add(k), size(insn.code)) {
+                        Some(v) => v,
+                        None => return 0
+                    },
+                    BPF_MEM => match mem.get(k as usize) {
+                        Some(&v) => v,
+                        None => return 0
+                    },
+                    BPF_LEN => data.len() as u32,
+                    _ => return 0
+                }
+            },
+            BPF_LDX => {
+                x = match insn.code & 0xe0 {
+                    BPF_IMM => k,
+                    BPF_MEM => match mem.get(k as usize) {
+                        Some(&v) => v,
+                        None => return 0
+                    },
+                    BPF_LEN => data.len() as u32,
+                    BPF_MSH => match load(k, 1) {
+                        Some(v) => (v & 0xf) << 2,
+                        None => return 0
+                    },
+                    _ => return 0
+                }
+            },
+            BPF_ST | BPF_STX => {
+                let value = match insn.code & 0x07 {
+                    BPF_ST => a,
+                    _ => x
+                };
+                match mem.get_mut(k as usize) {
+                    Some(m) => *m = value,
+                    None => return 0
+                }
+            },
+            BPF_ALU => {
+                let v = match insn.code & BPF_X {
+                    BPF_X => x,
+                    _ => k
+                };
+                a = match insn.code & 0xf0 {
+                    BPF_ADD => a.wrapping_add(v),
+                    BPF_SUB => a.wrapping_sub(v),
+                    BPF_MUL => a.wrapping_mul(v),
+                    BPF_DIV if v == 0 => return 0,
+                    BPF_DIV => a / v,
+                    BPF_MOD if v == 0 => return 0,
+                    BPF_MOD => a % v,
+                    BPF_OR => a | v,
+                    BPF_AND => a & v,
+                    BPF_XOR => a ^ v,
+                    BPF_LSH => a.checked_shl(v).unwrap_or(0),
+                    BPF_RSH => a.checked_shr(v).unwrap_or(0),
+                    BPF_NEG => a.wrapping_neg(),
+                    _ => return 0
+                }
+            },
+            BPF_JMP => {
+                let v = match insn.code & BPF_X {
+                    BPF_X => x,
+                    _ => k
+                };
+                let taken = match insn.code & 0xf0 {
+                    BPF_JA => {
+                        pc += k as usize;
+                        continue;
+                    },
+                    BPF_JEQ => a == v,
+                    BPF_JGT => a > v,
+                    BPF_JGE => a >= v,
+                    BPF_JSET => a & v != 0,
+                    _ => return 0
+                };
+                pc += match taken {
+                    true => insn.jt as usize,
+                    false => insn.jf as usize
+                };
+            },
+            BPF_RET => return match insn.code & 0x18 {
+                BPF_A => a,
+                _ => k
+            },
+            _ => match insn.code & 0xf8 {
+                BPF_TAX => x = a,
+                BPF_TXA => a = x,
+                _ => return 0
+            }
+        }
+    }
+    0
+}
+
+/// Whether a program is well formed, as the kernel requires of socket filters
+fn validate(program: &[Insn]) -> bool {
+    let len = program.len();
+    if len == 0 || program[len - 1].code & 0x07 != BPF_RET {
+        return false;
+    }
+    program.iter().enumerate().all(|(pc, insn)| {
+        let mem = insn.k < BPF_MEMWORDS;
+        match insn.code & 0x07 {
+            BPF_LD | BPF_LDX if insn.code & 0xe0 == BPF_MEM => mem,
+            BPF_ST | BPF_STX => mem,
+            BPF_ALU => match insn.code & 0xf0 {
+                BPF_DIV | BPF_MOD => insn.code & BPF_X == BPF_X || insn.k != 0,
+                _ => true
+            },
+            BPF_JMP => match insn.code & 0xf0 {
+                BPF_JA => (pc + 1).checked_add(insn.k as usize).map(|t| t < len).unwrap_or(false),
+                _ => pc + 1 + (insn.jt.max(insn.jf) as usize) < len
+            },
+            _ => true
+        }
+    })
+}
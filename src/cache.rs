@@ -0,0 +1,159 @@
+//! Verdict caching
+//!
+//! Most handlers decide on a flow from its first packets, then return the same verdict for the rest.
+//! `VerdictCache` remembers the verdict per flow and answers later packets without calling the handler.
+//! A mark the handler sets with `message.set_mark` is remembered along with the verdict and set again,
+//! but a packet the handler rewrote is not: later packets of the flow pass unchanged.
+//!
+//! Packets answered from the cache are still queued to userspace.
+//! To stop the kernel queueing an accepted flow at all, have the cache mark its packets with `set_mark`,
+//! save the mark to the connection, and skip the queue for marked connections:
+//!
+//! ```text
+//! iptables -t mangle -A PREROUTING -j CONNMARK --restore-mark
+//! iptables -t mangle -A PREROUTING -m mark --mark 0x1 -j ACCEPT
+//! iptables -t mangle -A PREROUTING -j NFQUEUE --queue-num 0
+//! iptables -t mangle -A POSTROUTING -m mark --mark 0x1 -j CONNMARK --save-mark
+//! ```
+
+use std::time::{Duration, Instant};
+use flow::{FlowKey, FlowTable, Limits};
+use message::Message;
+use queue::{Verdict, VerdictHandler};
+
+#[derive(Clone, Copy, Debug)]
+struct Cached {
+    verdict: Verdict,
+    mark: Option<u32>,
+    until: Instant,
+}
+
+/// A `VerdictHandler` that remembers the verdicts of another per flow
+///
+/// Packets without a `FlowKey`, such as non-first fragments, are always passed to the handler.
+pub struct VerdictCache<V> {
+    handler: V,
+    flows: FlowTable<Option<Cached>>,
+    ttl: Duration,
+    policy: fn(&Message, Verdict) -> bool,
+    mark: Option<u32>,
+}
+
+impl<V: VerdictHandler> VerdictCache<V> {
+    /// Cache the verdicts of `handler` for a minute, tracking flows within the default `Limits`
+    pub fn new(handler: V) -> VerdictCache<V> {
+        VerdictCache::with_limits(handler, Limits::default())
+    }
+
+    /// Cache the verdicts of `handler` for a minute, tracking flows within `limits`
+    pub fn with_limits(handler: V, limits: Limits) -> VerdictCache<V> {
+        VerdictCache {
+            handler: handler,
+            flows: FlowTable::with_limits(limits),
+            ttl: Duration::from_secs(60),
+            policy: cache_all,
+            mark: None,
+        }
+    }
+
+    /// Set how long a verdict is remembered before the handler decides on the flow again
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// Set which verdicts are remembered, by the packet they were returned for
+    ///
+    /// By default every verdict is.
+    /// A handler that accepts packets while it has not yet decided on their flow,
+    /// such as the handshake of a connection it decides on by its first data,
+    /// needs a policy that excludes those packets.
+    pub fn set_policy(&mut self, policy: fn(&Message, Verdict) -> bool) {
+        self.policy = policy;
+    }
+
+    /// Mark the packets of flows cached as accepted
+    ///
+    /// This mark replaces any the handler set on them.
+    pub fn set_mark(&mut self, mark: u32) {
+        self.mark = Some(mark);
+    }
+
+    /// The wrapped handler
+    pub fn handler(&mut self) -> &mut V {
+        &mut self.handler
+    }
+
+    /// The verdict remembered for a flow
+    pub fn cached(&self, key: &FlowKey) -> Option<Verdict> {
+        self.flows.get(key).and_then(|f| f.data).map(|c| c.verdict)
+    }
+
+    /// Forget the verdict for a flow, so the handler decides on its next packet
+    ///
+    /// Returns the verdict that was remembered.
+    pub fn invalidate(&mut self, key: &FlowKey) -> Option<Verdict> {
+        self.flows.get_mut(key).and_then(|f| f.data.take()).map(|c| c.verdict)
+    }
+
+    /// Forget every verdict, such as after the handler's configuration changed
+    ///
+    /// Flows marked as accepted stay unqueued until their connections end.
+    pub fn clear(&mut self) {
+        for flow in self.flows.iter_mut() {
+            flow.data = None;
+        }
+    }
+
+    /// The number of flows tracked
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// Whether no flows are tracked
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    /// Give a cached verdict with its mark, or the cache's own if accepted
+    fn answer(&self, message: &Message, cached: Cached) -> Verdict {
+        if let Some(mark) = cached.mark {
+            message.set_mark(mark);
+        }
+        if let (Verdict::Accept, Some(mark)) = (cached.verdict, self.mark) {
+            message.set_mark(mark);
+        }
+        cached.verdict
+    }
+}
+
+impl<V: VerdictHandler> VerdictHandler for VerdictCache<V> {
+    fn decide(&mut self, message: &Message) -> Verdict {
+        let now = Instant::now();
+        self.flows.expire(now);
+        let packet = match message.decode() {
+            Ok(packet) => packet,
+            Err(_) => return self.handler.decide(message)
+        };
+        let flow = match self.flows.track(&packet, now, |_| None) {
+            Some((_, flow)) => flow,
+            None => return self.handler.decide(message)
+        };
+        if let Some(cached) = flow.data {
+            if now < cached.until {
+                return self.answer(message, cached);
+            }
+        }
+        let verdict = self.handler.decide(message);
+        flow.data = None;
+        if !(self.policy)(message, verdict) {
+            return verdict;
+        }
+        let cached = Cached { verdict: verdict, mark: message.take_mark(), until: now + self.ttl };
+        flow.data = Some(cached);
+        self.answer(message, cached)
+    }
+}
+
+fn cache_all(_: &Message, _: Verdict) -> bool {
+    true
+}
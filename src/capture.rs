@@ -0,0 +1,489 @@
+//! Packet capture
+//!
+//! `Capture` records what a `VerdictHandler` decided to a pcapng file, readable by Wireshark and tcpdump.
+//! Each packet is recorded as it was queued, with its timestamp, the interface it arrived on or was to leave by,
+//! its id, and a comment giving the verdict, the mark it left with and both interfaces, like
+//! `verdict=drop mark=0x00000010 in=eth0 out=eth1`. Packets start at their IP header,
+//! so they are recorded with the link type `LINKTYPE_RAW`, and IPv4 and IPv6 share a file.
+//!
+//! `CaptureFile` rotates files by size or age, and `Writer` writes pcapng to any `io::Write`.
+//! Captures can be run through handlers again with `replay::Replay`, which compares the verdicts to those recorded.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use libc::{c_char, c_uint};
+use error::Error;
+use message::Message;
+use queue::{PacketHandler, QueueHandle, Verdict, VerdictHandler};
+
+/// The pcapng link type of raw IPv4 and IPv6 packets
+pub const LINKTYPE_RAW: u16 = 101;
+
+const BLOCK_SECTION: u32 = 0x0a0d_0d0a;
+const BLOCK_INTERFACE: u32 = 1;
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const EPB_FLAGS: u16 = 2;
+const EPB_PACKETID: u16 = 5;
+
+const FLAG_INBOUND: u32 = 1;
+const FLAG_OUTBOUND: u32 = 2;
+
+/// Timestamps are in nanoseconds
+const TSRESOL_NANOS: u8 = 9;
+
+const IF_NAMESIZE: usize = 16;
+
+extern "C" {
+    fn if_indextoname(ifindex: c_uint, ifname: *mut c_char) -> *mut c_char;
+}
+
+/// A packet to record
+#[derive(Clone, Debug)]
+pub struct Record<'a> {
+    /// The packet, from its IP header, as far as it was copied
+    pub data: &'a [u8],
+    /// When the packet was received
+    pub timestamp: SystemTime,
+    /// The index of the interface the packet arrived on, if any
+    pub indev: Option<u32>,
+    /// The index of the interface the packet was to leave by, if known
+    pub outdev: Option<u32>,
+    /// The packet's id in its queue
+    pub id: u32,
+    /// The packet's mark
+    pub mark: u32,
+    /// The verdict on the packet, if one was given
+    pub verdict: Option<Verdict>,
+}
+
+impl<'a> Record<'a> {
+    /// A record of a queued message, yet without a verdict
+    ///
+    /// Packets the kernel did not timestamp are recorded at the current time.
+    pub fn of(message: &'a Message) -> Record<'a> {
+        Record {
+            data: message.data().unwrap_or(&[]),
+            timestamp: message.timestamp().unwrap_or_else(SystemTime::now),
+            indev: message.indev(),
+            outdev: message.outdev(),
+            id: message.header.id(),
+            mark: message.mark(),
+            verdict: None,
+        }
+    }
+
+    /// The length of the whole packet, from its IP header, which may exceed what was copied
+    fn original_len(&self) -> usize {
+        let data = self.data;
+        let len = match data.first().map(|b| b >> 4) {
+            Some(4) if data.len() >= 4 => (data[2] as usize) << 8 | data[3] as usize,
+            Some(6) if data.len() >= 6 => 40 + ((data[4] as usize) << 8 | data[5] as usize),
+            _ => 0
+        };
+        len.max(data.len())
+    }
+
+    fn annotation(&self, names: &mut HashMap<u32, String>) -> Annotation {
+        Annotation {
+            verdict: self.verdict,
+            mark: self.mark,
+            indev: self.indev.map(|index| interface_name(names, index)),
+            outdev: self.outdev.map(|index| interface_name(names, index)),
+        }
+    }
+}
+
+/// What the comment of a captured packet records
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Annotation {
+    /// The verdict on the packet, if one was given
+    pub verdict: Option<Verdict>,
+    /// The mark the packet left with
+    pub mark: u32,
+    /// The name of the interface the packet arrived on, if any
+    pub indev: Option<String>,
+    /// The name of the interface the packet was to leave by, if known
+    pub outdev: Option<String>,
+}
+
+impl Annotation {
+    /// Parse a packet comment written by `Writer`, e.g. `verdict=queue:3 mark=0x00000010 in=eth0`
+    ///
+    /// `None` if the comment has no mark, and so was not written by `Writer`.
+    pub fn parse(comment: &str) -> Option<Annotation> {
+        let mut annotation = Annotation { verdict: None, mark: 0, indev: None, outdev: None };
+        let mut marked = false;
+        for field in comment.split_whitespace() {
+            let (key, value) = match field.find('=') {
+                Some(i) => (&field[..i], &field[i + 1..]),
+                None => return None
+            };
+            match key {
+                "verdict" => annotation.verdict = Some(parse_verdict(value)?),
+                "mark" if value.starts_with("0x") => {
+                    annotation.mark = u32::from_str_radix(&value[2..], 16).ok()?;
+                    marked = true;
+                },
+                "in" => annotation.indev = Some(value.to_string()),
+                "out" => annotation.outdev = Some(value.to_string()),
+                _ => return None
+            }
+        }
+        match marked {
+            true => Some(annotation),
+            false => None
+        }
+    }
+}
+
+impl fmt::Display for Annotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(verdict) = self.verdict {
+            write!(f, "verdict={} ", verdict_name(verdict))?;
+        }
+        write!(f, "mark={:#010x}", self.mark)?;
+        if let Some(ref name) = self.indev {
+            write!(f, " in={}", name)?;
+        }
+        if let Some(ref name) = self.outdev {
+            write!(f, " out={}", name)?;
+        }
+        Ok(())
+    }
+}
+
+fn verdict_name(verdict: Verdict) -> String {
+    match verdict {
+        Verdict::Drop => "drop".to_string(),
+        Verdict::Accept => "accept".to_string(),
+        Verdict::Stolen => "stolen".to_string(),
+        Verdict::Queue(n) => format!("queue:{}", n),
+        Verdict::Repeat => "repeat".to_string(),
+        Verdict::Stop => "stop".to_string(),
+    }
+}
+
+fn parse_verdict(name: &str) -> Option<Verdict> {
+    match name {
+        "drop" => Some(Verdict::Drop),
+        "accept" => Some(Verdict::Accept),
+        "stolen" => Some(Verdict::Stolen),
+        "repeat" => Some(Verdict::Repeat),
+        "stop" => Some(Verdict::Stop),
+        n if n.starts_with("queue:") => n[6..].parse().ok().map(Verdict::Queue),
+        _ => None
+    }
+}
+
+/// The name of an interface, looked up once
+fn interface_name(names: &mut HashMap<u32, String>, index: u32) -> String {
+    names.entry(index).or_insert_with(|| {
+        let mut name = [0 as c_char; IF_NAMESIZE];
+        match unsafe { if_indextoname(index as c_uint, name.as_mut_ptr()) }.is_null() {
+            true => format!("if{}", index),
+            false => unsafe { CStr::from_ptr(name.as_ptr()) }.to_string_lossy().into_owned()
+        }
+    }).clone()
+}
+
+/// Writes packets to a pcapng section
+///
+/// An interface description is written before the first packet of each interface,
+/// and a single `unknown` interface holds packets that have neither.
+pub struct Writer<W: Write> {
+    out: W,
+    /// The pcapng interface ids, by interface index
+    interfaces: HashMap<Option<u32>, u32>,
+    names: HashMap<u32, String>,
+    written: u64,
+}
+
+impl<W: Write> Writer<W> {
+    /// Start a pcapng section on `out`
+    pub fn new(out: W) -> io::Result<Writer<W>> {
+        let mut writer = Writer { out: out, interfaces: HashMap::new(), names: HashMap::new(), written: 0 };
+        let mut body = Vec::new();
+        push32(&mut body, BYTE_ORDER_MAGIC);
+        push16(&mut body, 1);
+        push16(&mut body, 0);
+        // The section length is not known in advance
+        body.extend_from_slice(&(-1i64).to_ne_bytes());
+        option(&mut body, SHB_USERAPPL, concat!("libnfqueue ", env!("CARGO_PKG_VERSION")).as_bytes());
+        option(&mut body, OPT_END, &[]);
+        writer.block(BLOCK_SECTION, &body)?;
+        Ok(writer)
+    }
+
+    /// Write a packet
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let interface = self.interface(record.indev.or(record.outdev))?;
+        let since_epoch = record.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let nanos = since_epoch.as_secs() * 1_000_000_000 + since_epoch.subsec_nanos() as u64;
+        let flags = match (record.indev, record.outdev) {
+            (Some(_), _) => FLAG_INBOUND,
+            (None, Some(_)) => FLAG_OUTBOUND,
+            (None, None) => 0
+        };
+        let comment = record.annotation(&mut self.names).to_string();
+
+        let mut body = Vec::with_capacity(record.data.len() + comment.len() + 64);
+        push32(&mut body, interface);
+        push32(&mut body, (nanos >> 32) as u32);
+        push32(&mut body, nanos as u32);
+        push32(&mut body, record.data.len() as u32);
+        push32(&mut body, record.original_len() as u32);
+        body.extend_from_slice(record.data);
+        pad(&mut body);
+        option(&mut body, OPT_COMMENT, comment.as_bytes());
+        option(&mut body, EPB_FLAGS, &flags.to_ne_bytes());
+        option(&mut body, EPB_PACKETID, &(record.id as u64).to_ne_bytes());
+        option(&mut body, OPT_END, &[]);
+        self.block(BLOCK_ENHANCED_PACKET, &body)
+    }
+
+    /// The number of bytes written
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Flush the underlying writer
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// The underlying writer
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    /// The pcapng id of an interface, describing it first if it is new
+    fn interface(&mut self, index: Option<u32>) -> io::Result<u32> {
+        if let Some(&id) = self.interfaces.get(&index) {
+            return Ok(id);
+        }
+        let name = match index {
+            Some(index) => interface_name(&mut self.names, index),
+            None => "unknown".to_string()
+        };
+        let mut body = Vec::new();
+        push16(&mut body, LINKTYPE_RAW);
+        push16(&mut body, 0);
+        // No limit on the length captured
+        push32(&mut body, 0);
+        option(&mut body, IF_NAME, name.as_bytes());
+        option(&mut body, IF_TSRESOL, &[TSRESOL_NANOS]);
+        option(&mut body, OPT_END, &[]);
+        self.block(BLOCK_INTERFACE, &body)?;
+        let id = self.interfaces.len() as u32;
+        self.interfaces.insert(index, id);
+        Ok(id)
+    }
+
+    fn block(&mut self, kind: u32, body: &[u8]) -> io::Result<()> {
+        let len = (body.len() + 12) as u32;
+        let mut block = Vec::with_capacity(len as usize);
+        push32(&mut block, kind);
+        push32(&mut block, len);
+        block.extend_from_slice(body);
+        push32(&mut block, len);
+        self.out.write_all(&block)?;
+        self.written += len as u64;
+        Ok(())
+    }
+}
+
+fn push16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn push32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_ne_bytes());
+}
+
+/// Pad to a multiple of four bytes
+fn pad(buf: &mut Vec<u8>) {
+    while buf.len() % 4 != 0 {
+        buf.push(0);
+    }
+}
+
+fn option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    push16(buf, code);
+    push16(buf, value.len() as u16);
+    buf.extend_from_slice(value);
+    pad(buf);
+}
+
+/// When a `CaptureFile` starts a new file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rotation {
+    /// Start a new file once this many bytes were written, if set
+    pub max_size: Option<u64>,
+    /// Start a new file once the current one is this old, if set
+    pub max_age: Option<Duration>,
+    /// How many earlier files are kept, as `path.1` for the latest to `path.N` for the oldest
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    /// Never rotate, keeping ten files when rotated by hand
+    fn default() -> Rotation {
+        Rotation { max_size: None, max_age: None, keep: 10 }
+    }
+}
+
+/// A pcapng file, rotated by size or age
+///
+/// The current file is always at the path given, and the file is flushed after every packet,
+/// so it can be read while the queue runs.
+pub struct CaptureFile {
+    path: PathBuf,
+    writer: Writer<BufWriter<File>>,
+    opened: Instant,
+    rotation: Rotation,
+}
+
+impl CaptureFile {
+    /// Create, or truncate, the file at `path`
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<CaptureFile> {
+        let path = path.as_ref().to_path_buf();
+        let writer = Writer::new(BufWriter::new(File::create(&path)?))?;
+        Ok(CaptureFile { path: path, writer: writer, opened: Instant::now(), rotation: Rotation::default() })
+    }
+
+    /// Set when to start a new file
+    pub fn set_rotation(&mut self, rotation: Rotation) {
+        self.rotation = rotation;
+    }
+
+    /// The path of the current file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The number of bytes written to the current file
+    pub fn written(&self) -> u64 {
+        self.writer.written()
+    }
+
+    /// Write a packet, first starting a new file if the current one is due for rotation
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        if self.due(Instant::now()) {
+            self.rotate()?;
+        }
+        self.writer.write(record)?;
+        self.writer.flush()
+    }
+
+    /// Start a new file, shifting the earlier ones along
+    pub fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        let keep = self.rotation.keep;
+        match keep {
+            0 => fs::remove_file(&self.path)?,
+            keep => {
+                for n in (1..keep).rev() {
+                    if numbered(n).exists() {
+                        fs::rename(numbered(n), numbered(n + 1))?;
+                    }
+                }
+                fs::rename(&self.path, numbered(1))?;
+            }
+        }
+        self.writer = Writer::new(BufWriter::new(File::create(&self.path)?))?;
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    /// Whether the current file is due for rotation
+    fn due(&self, now: Instant) -> bool {
+        let full = self.rotation.max_size.map(|size| self.writer.written() >= size).unwrap_or(false);
+        let old = self.deadline().map(|at| now >= at).unwrap_or(false);
+        full || old
+    }
+
+    /// When the current file becomes too old, if ever
+    fn deadline(&self) -> Option<Instant> {
+        self.rotation.max_age.map(|age| self.opened + age)
+    }
+}
+
+/// A `PacketHandler` that records the packets a `VerdictHandler` decides on, and its verdicts
+///
+/// Packets are recorded as they are passed on, once the verdict is set, rewritten if they were.
+/// Failing to record a packet does not hold up its verdict; the error is kept for `last_error`.
+pub struct Capture<V> {
+    handler: V,
+    file: CaptureFile,
+    error: Option<io::Error>,
+}
+
+impl<V: VerdictHandler> Capture<V> {
+    /// Record the packets `handler` decides on to `file`
+    pub fn new(handler: V, file: CaptureFile) -> Capture<V> {
+        Capture { handler: handler, file: file, error: None }
+    }
+
+    /// The wrapped handler
+    pub fn handler(&mut self) -> &mut V {
+        &mut self.handler
+    }
+
+    /// The capture file
+    pub fn file(&mut self) -> &mut CaptureFile {
+        &mut self.file
+    }
+
+    /// Why the last packet could not be recorded, if it could not
+    pub fn last_error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+}
+
+impl<V: VerdictHandler> PacketHandler for Capture<V> {
+    fn handle(&mut self, hq: &QueueHandle, message: Result<&Message, &Error>) -> i32 {
+        let message = match message {
+            Ok(m) => m,
+            Err(_) => return 0
+        };
+        let verdict = self.handler.decide(message);
+        let (mut mangled, mark) = (message.take_mangled(), message.take_mark());
+        let _ = Verdict::set_rewritten_verdict(hq, message.header.id(), verdict, mark, mangled.as_mut());
+
+        // Recorded as it was passed on
+        let mut record = Record::of(message);
+        record.verdict = Some(verdict);
+        if let Some(mark) = mark {
+            record.mark = mark;
+        }
+        if let Some(ref buffer) = mangled {
+            record.data = buffer.data();
+        }
+        self.error = self.file.write(&record).err();
+        0
+    }
+
+    fn poll(&mut self, _: &QueueHandle, now: Instant) -> Option<Instant> {
+        if self.file.due(now) {
+            self.error = self.file.rotate().err();
+        }
+        self.file.deadline()
+    }
+}
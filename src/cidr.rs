@@ -0,0 +1,482 @@
+//! CIDR blocks
+//!
+//! `Cidr` is an IPv4 or IPv6 network, written like `10.0.0.0/8` or `2001:db8::/32`.
+//! `PrefixMap` maps networks to values in a path-compressed binary trie, finding the longest
+//! prefix containing an address in at most one step per bit of the address, whatever the number of
+//! prefixes. `PrefixSet` is a set of networks, loaded from plain text lists of the kind block lists
+//! are published as. To replace a set in use by a running queue, share it through a `shared::Shared`.
+
+use std::error::Error as Base;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::str::FromStr;
+use util::ip_bits;
+
+/// Reasons a CIDR block could not be parsed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CidrError {
+    /// The address is malformed
+    Address,
+    /// The prefix length is malformed, or too long for the address
+    Prefix,
+}
+
+impl fmt::Display for CidrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason())
+    }
+}
+
+impl Base for CidrError {
+    fn description(&self) -> &str {
+        self.reason()
+    }
+}
+
+impl CidrError {
+    fn reason(&self) -> &'static str {
+        match *self {
+            CidrError::Address => "Malformed address",
+            CidrError::Prefix => "Malformed prefix length",
+        }
+    }
+}
+
+/// An IPv4 or IPv6 network
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// The network of `prefix` bits containing `addr`
+    ///
+    /// Bits of `addr` past the prefix are cleared.
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Cidr, CidrError> {
+        let bits = width(&addr);
+        if prefix > bits {
+            return Err(CidrError::Prefix);
+        }
+        let addr = from_bits(&addr, ip_bits(&addr) & mask(prefix, bits));
+        Ok(Cidr { addr: addr, prefix: prefix })
+    }
+
+    /// The network containing only `addr`
+    pub fn host(addr: IpAddr) -> Cidr {
+        Cidr { addr: addr, prefix: width(&addr) }
+    }
+
+    /// The first address of the network
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// The prefix length
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// The last address of the network
+    pub fn last(&self) -> IpAddr {
+        let bits = width(&self.addr);
+        from_bits(&self.addr, ip_bits(&self.addr) | !mask(self.prefix, bits) & mask(bits, bits))
+    }
+
+    /// Whether the network contains `addr`
+    ///
+    /// IPv4 networks contain no IPv6 addresses, including IPv4-mapped ones.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, *addr) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => {
+                ip_bits(addr) & mask(self.prefix, width(addr)) == ip_bits(&self.addr)
+            },
+            _ => false
+        }
+    }
+
+    /// Whether this is an IPv4 network
+    pub fn is_ipv4(&self) -> bool {
+        match self.addr {
+            IpAddr::V4(_) => true,
+            IpAddr::V6(_) => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrError;
+
+    /// Parse a network like `10.0.0.0/8`, or a single address
+    fn from_str(s: &str) -> Result<Cidr, CidrError> {
+        let (addr, prefix) = match s.find('/') {
+            Some(slash) => (&s[..slash], Some(&s[slash + 1..])),
+            None => (s, None)
+        };
+        let addr: IpAddr = match addr.parse() {
+            Ok(addr) => addr,
+            Err(_) => return Err(CidrError::Address)
+        };
+        match prefix {
+            Some(prefix) => match prefix.parse() {
+                Ok(prefix) => Cidr::new(addr, prefix),
+                Err(_) => Err(CidrError::Prefix)
+            },
+            None => Ok(Cidr::host(addr))
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// The number of bits in an address
+fn width(addr: &IpAddr) -> u8 {
+    match *addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// An address of the same family as `like`, from an integer
+fn from_bits(like: &IpAddr, bits: u128) -> IpAddr {
+    match *like {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(bits as u32)),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(bits)),
+    }
+}
+
+/// The mask of the first `prefix` bits of a `bits` wide address
+fn mask(prefix: u8, bits: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        p => (!0u128 << (128 - p as u32)) >> (128 - bits as u32)
+    }
+}
+
+/// A map from networks to values, finding the longest prefix containing an address
+pub struct PrefixMap<V> {
+    v4: Option<Box<Node<V>>>,
+    v6: Option<Box<Node<V>>>,
+    len: usize,
+}
+
+/// A node of the trie, at a prefix with a value or where two branches part
+struct Node<V> {
+    /// The prefix, left aligned
+    key: u128,
+    len: u8,
+    value: Option<V>,
+    /// The subtries whose next bit after the prefix is zero and one
+    children: [Option<Box<Node<V>>>; 2],
+}
+
+impl<V> Default for PrefixMap<V> {
+    fn default() -> PrefixMap<V> {
+        PrefixMap::new()
+    }
+}
+
+impl<V> PrefixMap<V> {
+    /// An empty map
+    pub fn new() -> PrefixMap<V> {
+        PrefixMap { v4: None, v6: None, len: 0 }
+    }
+
+    /// Map `network` to `value`, returning the value it was mapped to, if any
+    pub fn insert(&mut self, network: Cidr, value: V) -> Option<V> {
+        let (key, len) = aligned(&network.addr, network.prefix);
+        let old = insert(self.root_mut(&network.addr), key, len, value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    /// Remove `network`, returning the value it was mapped to, if any
+    pub fn remove(&mut self, network: &Cidr) -> Option<V> {
+        let (key, len) = aligned(&network.addr, network.prefix);
+        let old = remove(self.root_mut(&network.addr), key, len);
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    /// The value `network` itself is mapped to
+    pub fn get(&self, network: &Cidr) -> Option<&V> {
+        let (key, len) = aligned(&network.addr, network.prefix);
+        let mut node = self.root(&network.addr);
+        while let Some(n) = node {
+            if n.len > len || !n.is_prefix_of(key) {
+                return None;
+            }
+            if n.len == len {
+                return n.value.as_ref();
+            }
+            node = n.children[bit(key, n.len)].as_deref();
+        }
+        None
+    }
+
+    /// The longest network containing `addr`, and its value
+    pub fn longest_match(&self, addr: &IpAddr) -> Option<(Cidr, &V)> {
+        let (key, _) = aligned(addr, width(addr));
+        let mut best = None;
+        let mut node = self.root(addr);
+        while let Some(n) = node {
+            if !n.is_prefix_of(key) {
+                break;
+            }
+            if let Some(ref value) = n.value {
+                best = Some((n.len, value));
+            }
+            if n.len >= width(addr) {
+                break;
+            }
+            node = n.children[bit(key, n.len)].as_deref();
+        }
+        best.map(|(len, value)| (Cidr::new(*addr, len).unwrap(), value))
+    }
+
+    /// Whether any network contains `addr`
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        self.longest_match(addr).is_some()
+    }
+
+    /// The networks and their values, IPv4 first, each network before those within it
+    pub fn entries(&self) -> Vec<(Cidr, &V)> {
+        let mut entries = Vec::with_capacity(self.len);
+        collect(&self.v4, true, &mut entries);
+        collect(&self.v6, false, &mut entries);
+        entries
+    }
+
+    /// The number of networks
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether there are no networks
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn root(&self, addr: &IpAddr) -> Option<&Node<V>> {
+        match *addr {
+            IpAddr::V4(_) => self.v4.as_deref(),
+            IpAddr::V6(_) => self.v6.as_deref(),
+        }
+    }
+
+    fn root_mut(&mut self, addr: &IpAddr) -> &mut Option<Box<Node<V>>> {
+        match *addr {
+            IpAddr::V4(_) => &mut self.v4,
+            IpAddr::V6(_) => &mut self.v6,
+        }
+    }
+}
+
+impl<V> Node<V> {
+    fn new(key: u128, len: u8, value: Option<V>) -> Box<Node<V>> {
+        Box::new(Node { key: key, len: len, value: value, children: [None, None] })
+    }
+
+    /// Whether the node's prefix is a prefix of `key`
+    fn is_prefix_of(&self, key: u128) -> bool {
+        (self.key ^ key) & mask(self.len, 128) == 0
+    }
+}
+
+fn insert<V>(slot: &mut Option<Box<Node<V>>>, key: u128, len: u8, value: V) -> Option<V> {
+    let node = match *slot {
+        Some(ref mut node) => node,
+        None => {
+            *slot = Some(Node::new(key, len, Some(value)));
+            return None;
+        }
+    };
+    let common = ((node.key ^ key).leading_zeros() as u8).min(node.len).min(len);
+    if common == node.len && common == len {
+        return mem::replace(&mut node.value, Some(value));
+    }
+    if common == node.len {
+        return insert(&mut node.children[bit(key, node.len)], key, len, value);
+    }
+    // The new prefix, or where it and the node's part, goes above the node
+    let old = mem::replace(node, Node::new(key & mask(common, 128), common, None));
+    let side = bit(old.key, common);
+    node.children[side] = Some(old);
+    match common == len {
+        true => node.value = Some(value),
+        false => node.children[1 - side] = Some(Node::new(key, len, Some(value)))
+    }
+    None
+}
+
+fn remove<V>(slot: &mut Option<Box<Node<V>>>, key: u128, len: u8) -> Option<V> {
+    let value = match *slot {
+        Some(ref mut node) if node.len <= len && node.is_prefix_of(key) => match node.len == len {
+            true => node.value.take(),
+            false => remove(&mut node.children[bit(key, node.len)], key, len)
+        },
+        _ => None
+    };
+    // Nodes left without a value are only kept where two branches part
+    let children = match *slot {
+        Some(ref node) if node.value.is_none() => node.children.iter().filter(|c| c.is_some()).count(),
+        _ => return value
+    };
+    if children < 2 {
+        let mut node = slot.take().unwrap();
+        *slot = node.children[0].take().or_else(|| node.children[1].take());
+    }
+    value
+}
+
+fn collect<'a, V>(node: &'a Option<Box<Node<V>>>, v4: bool, entries: &mut Vec<(Cidr, &'a V)>) {
+    if let Some(ref node) = *node {
+        if let Some(ref value) = node.value {
+            let network = match v4 {
+                true => Cidr { addr: IpAddr::V4(Ipv4Addr::from((node.key >> 96) as u32)), prefix: node.len },
+                false => Cidr { addr: IpAddr::V6(Ipv6Addr::from(node.key)), prefix: node.len },
+            };
+            entries.push((network, value));
+        }
+        collect(&node.children[0], v4, entries);
+        collect(&node.children[1], v4, entries);
+    }
+}
+
+/// An address aligned to the top of 128 bits, and a prefix length of it
+fn aligned(addr: &IpAddr, prefix: u8) -> (u128, u8) {
+    (ip_bits(addr) << (128 - width(addr) as u32), prefix)
+}
+
+/// The bit of `key` after the first `len`
+fn bit(key: u128, len: u8) -> usize {
+    (key >> (127 - len as u32) & 1) as usize
+}
+
+/// Reasons a list of networks could not be loaded
+#[derive(Debug)]
+pub enum ListError {
+    /// The list could not be read
+    Io(io::Error),
+    /// A line is not a network
+    Parse {
+        /// The line, from one
+        line: usize,
+        /// What is wrong
+        error: CidrError,
+    },
+}
+
+impl fmt::Display for ListError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ListError::Io(ref e) => write!(f, "{}: {}", self.reason(), e),
+            ListError::Parse { line, ref error } => write!(f, "line {}: {}", line, error),
+        }
+    }
+}
+
+impl Base for ListError {
+    fn description(&self) -> &str {
+        self.reason()
+    }
+}
+
+impl ListError {
+    fn reason(&self) -> &'static str {
+        match *self {
+            ListError::Io(_) => "Failed to read list",
+            ListError::Parse { .. } => "Malformed list",
+        }
+    }
+}
+
+/// A set of networks
+#[derive(Default)]
+pub struct PrefixSet {
+    map: PrefixMap<()>,
+}
+
+impl PrefixSet {
+    /// An empty set
+    pub fn new() -> PrefixSet {
+        PrefixSet { map: PrefixMap::new() }
+    }
+
+    /// Parse a list of networks, one a line
+    ///
+    /// Blank lines are ignored, as is anything after a `#` or `;`,
+    /// so lists like Spamhaus DROP and FireHOL's can be loaded as published.
+    pub fn parse(text: &str) -> Result<PrefixSet, ListError> {
+        let mut set = PrefixSet::new();
+        for (i, line) in text.lines().enumerate() {
+            let network = match line.find(|c| c == '#' || c == ';') {
+                Some(comment) => line[..comment].trim(),
+                None => line.trim()
+            };
+            if network.is_empty() {
+                continue;
+            }
+            match network.parse() {
+                Ok(network) => set.insert(network),
+                Err(e) => return Err(ListError::Parse { line: i + 1, error: e })
+            };
+        }
+        Ok(set)
+    }
+
+    /// Read and parse a list of networks, see `parse`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<PrefixSet, ListError> {
+        match fs::read_to_string(path) {
+            Ok(text) => PrefixSet::parse(&text),
+            Err(e) => Err(ListError::Io(e))
+        }
+    }
+
+    /// Add a network, returning whether it was not already in the set
+    pub fn insert(&mut self, network: Cidr) -> bool {
+        self.map.insert(network, ()).is_none()
+    }
+
+    /// Remove a network, returning whether it was in the set
+    pub fn remove(&mut self, network: &Cidr) -> bool {
+        self.map.remove(network).is_some()
+    }
+
+    /// Whether any network in the set contains `addr`
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        self.map.contains(addr)
+    }
+
+    /// The longest network in the set containing `addr`
+    pub fn longest_match(&self, addr: &IpAddr) -> Option<Cidr> {
+        self.map.longest_match(addr).map(|(network, _)| network)
+    }
+
+    /// The networks, IPv4 first, each network before those within it
+    pub fn networks(&self) -> Vec<Cidr> {
+        self.map.entries().into_iter().map(|(network, _)| network).collect()
+    }
+
+    /// The number of networks
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether there are no networks
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
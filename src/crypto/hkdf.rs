@@ -0,0 +1,41 @@
+//! HMAC-SHA-256 (RFC 2104) and HKDF (RFC 5869)
+
+use super::sha256;
+
+const BLOCK: usize = 64;
+
+/// The HMAC-SHA-256 of `data` under `key`
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; BLOCK];
+    match key.len() > BLOCK {
+        true => block[..32].copy_from_slice(&sha256(key)),
+        false => block[..key.len()].copy_from_slice(key),
+    }
+    let mut inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(data);
+    let mut outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+/// Extract a pseudorandom key from input keying material
+pub fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> [u8; 32] {
+    hmac_sha256(salt, ikm)
+}
+
+/// Expand a pseudorandom key into `len` bytes bound to `info`
+pub fn hkdf_expand(prk: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len + 32);
+    let mut previous: Vec<u8> = Vec::new();
+    let mut counter = 1u8;
+    while out.len() < len {
+        let mut input = previous.clone();
+        input.extend_from_slice(info);
+        input.push(counter);
+        previous = hmac_sha256(prk, &input).to_vec();
+        out.extend_from_slice(&previous);
+        counter += 1;
+    }
+    out.truncate(len);
+    out
+}
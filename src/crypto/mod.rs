@@ -0,0 +1,16 @@
+//! Hash functions and ciphers needed to inspect protocols
+//!
+//! Only what the parsers need is implemented, without any attempt at constant time:
+//! everything here processes traffic that is already visible on the wire.
+
+mod md5;
+mod sha256;
+mod hkdf;
+mod aes;
+mod gcm;
+
+pub use self::md5::md5;
+pub use self::sha256::sha256;
+pub use self::hkdf::{hkdf_extract, hkdf_expand};
+pub use self::aes::Aes128;
+pub use self::gcm::aes128_gcm_open;
@@ -0,0 +1,233 @@
+//! IDNA conversion of host names, after RFC 5891 and the Punycode of RFC 3492
+
+use super::DomainError;
+
+/// The longest name, in presentation form without the trailing dot
+const MAX_NAME: usize = 253;
+/// The longest label
+const MAX_LABEL: usize = 63;
+/// The prefix of labels holding Punycode
+const ACE_PREFIX: &str = "xn--";
+
+const BASE: u32 = 36;
+const TMIN: u32 = 1;
+const TMAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+
+/// Normalize a name to lower case ASCII, without the trailing dot
+///
+/// Labels with characters beyond ASCII are lower cased and encoded as Punycode.
+/// Only part of the UTS 46 mapping is applied: ideographic full stops separate labels,
+/// fullwidth forms of ASCII become ASCII and characters it ignores, such as soft hyphens, are removed.
+///
+/// Unicode normalization is not applied, so names must already be in NFC, as they almost always are.
+/// Combining diacritical marks, which NFC would compose with the letter before them, are rejected
+/// rather than encoded as they are, as are whitespace and control characters.
+pub fn to_ascii(name: &str) -> Result<String, DomainError> {
+    // Most names on the wire are already lower case ASCII
+    let simple = name.bytes().all(|b| match b {
+        b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' => true,
+        _ => false
+    });
+    let name = match simple {
+        true => name.trim_end_matches('.').to_string(),
+        false => {
+            let mut mapped = String::with_capacity(name.len());
+            for c in name.chars() {
+                match c {
+                    '\u{3002}' | '\u{ff0e}' | '\u{ff61}' => mapped.push('.'),
+                    '\u{ff01}'..='\u{ff5e}' => mapped.push((c as u32 - 0xfee0) as u8 as char),
+                    '\u{ad}' | '\u{200b}' | '\u{2060}' | '\u{feff}' | '\u{180b}'..='\u{180d}' | '\u{fe00}'..='\u{fe0f}' => (),
+                    c if is_combining(c) || c.is_whitespace() || c.is_control() => return Err(DomainError::Label),
+                    c => mapped.push(c)
+                }
+            }
+            let mapped = mapped.to_lowercase();
+            let mut ascii = String::with_capacity(mapped.len());
+            for label in mapped.trim_end_matches('.').split('.') {
+                if !ascii.is_empty() {
+                    ascii.push('.');
+                }
+                match label.is_ascii() {
+                    true => ascii.push_str(label),
+                    false => {
+                        ascii.push_str(ACE_PREFIX);
+                        match encode(label) {
+                            Some(encoded) => ascii.push_str(&encoded),
+                            None => return Err(DomainError::Label)
+                        }
+                    }
+                }
+            }
+            ascii
+        }
+    };
+    check(&name)?;
+    Ok(name)
+}
+
+/// Decode the Punycode labels of a normalized name, for display
+///
+/// Labels that do not decode are left as they are.
+pub fn to_unicode(name: &str) -> String {
+    name.split('.').map(|label| {
+        match label.starts_with(ACE_PREFIX) {
+            true => decode(&label[ACE_PREFIX.len()..]).unwrap_or_else(|| label.to_string()),
+            false => label.to_string()
+        }
+    }).collect::<Vec<_>>().join(".")
+}
+
+/// Whether `c` is in a block of combining diacritical marks
+fn is_combining(c: char) -> bool {
+    match c {
+        '\u{300}'..='\u{36f}' | '\u{1ab0}'..='\u{1aff}' | '\u{1dc0}'..='\u{1dff}' | '\u{20d0}'..='\u{20ff}' | '\u{fe20}'..='\u{fe2f}' => true,
+        _ => false
+    }
+}
+
+/// Check the labels and length of a lower case ASCII name
+fn check(name: &str) -> Result<(), DomainError> {
+    if name.is_empty() {
+        return Err(DomainError::Empty);
+    }
+    if name.len() > MAX_NAME {
+        return Err(DomainError::TooLong);
+    }
+    for label in name.split('.') {
+        let valid = label.bytes().all(|b| match b {
+            b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => true,
+            _ => false
+        });
+        if !valid || label.is_empty() {
+            return Err(DomainError::Label);
+        }
+        if label.len() > MAX_LABEL {
+            return Err(DomainError::TooLong);
+        }
+    }
+    Ok(())
+}
+
+fn adapt(delta: u32, points: u32, first: bool) -> u32 {
+    let mut delta = match first {
+        true => delta / DAMP,
+        false => delta / 2
+    };
+    delta += delta / points;
+    let mut k = 0;
+    while delta > ((BASE - TMIN) * TMAX) / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+    k + (BASE - TMIN + 1) * delta / (delta + SKEW)
+}
+
+/// The threshold of the digit at `k`
+fn threshold(k: u32, bias: u32) -> u32 {
+    match k {
+        k if k <= bias => TMIN,
+        k if k >= bias + TMAX => TMAX,
+        k => k - bias
+    }
+}
+
+fn digit(d: u32) -> char {
+    match d {
+        0..=25 => (b'a' + d as u8) as char,
+        _ => (b'0' + (d - 26) as u8) as char
+    }
+}
+
+fn digit_value(c: u8) -> Option<u32> {
+    match c {
+        b'a'..=b'z' => Some((c - b'a') as u32),
+        b'A'..=b'Z' => Some((c - b'A') as u32),
+        b'0'..=b'9' => Some((c - b'0') as u32 + 26),
+        _ => None
+    }
+}
+
+/// Encode a label as Punycode, without the ACE prefix
+fn encode(label: &str) -> Option<String> {
+    let input: Vec<u32> = label.chars().map(|c| c as u32).collect();
+    let mut output: String = label.chars().filter(|c| c.is_ascii()).collect();
+    let basic = output.len() as u32;
+    if basic > 0 {
+        output.push('-');
+    }
+    let (mut n, mut delta, mut bias) = (INITIAL_N, 0u32, INITIAL_BIAS);
+    let mut handled = basic;
+    while (handled as usize) < input.len() {
+        let m = match input.iter().cloned().filter(|&c| c >= n).min() {
+            Some(m) => m,
+            None => return None
+        };
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+        for &c in &input {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = threshold(k, bias);
+                    if q < t {
+                        break;
+                    }
+                    output.push(digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(digit(q));
+                bias = adapt(delta, handled + 1, handled == basic);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta = delta.checked_add(1)?;
+        n = n.checked_add(1)?;
+    }
+    Some(output)
+}
+
+/// Decode a Punycode label, without the ACE prefix
+fn decode(label: &str) -> Option<String> {
+    let (basic, extended) = match label.rfind('-') {
+        Some(i) => (&label[..i], &label[i + 1..]),
+        None => ("", label)
+    };
+    if !basic.is_ascii() {
+        return None;
+    }
+    let mut output: Vec<char> = basic.chars().collect();
+    let (mut n, mut i, mut bias) = (INITIAL_N, 0u32, INITIAL_BIAS);
+    let mut digits = extended.bytes();
+    while digits.len() > 0 {
+        let start = i;
+        let mut w = 1u32;
+        let mut k = BASE;
+        loop {
+            let d = digit_value(digits.next()?)?;
+            i = i.checked_add(d.checked_mul(w)?)?;
+            let t = threshold(k, bias);
+            if d < t {
+                break;
+            }
+            w = w.checked_mul(BASE - t)?;
+            k += BASE;
+        }
+        let len = output.len() as u32 + 1;
+        bias = adapt(i - start, len, start == 0);
+        n = n.checked_add(i / len)?;
+        i %= len;
+        output.insert(i as usize, ::std::char::from_u32(n)?);
+        i += 1;
+    }
+    Some(output.into_iter().collect())
+}
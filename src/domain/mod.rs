@@ -0,0 +1,467 @@
+//! Domain name matching
+//!
+//! `DomainMap` maps name patterns to values in a trie of labels, read from the top level domain down,
+//! finding the most specific pattern matching a name in one step per label, whatever the number of patterns.
+//! A pattern is an exact name, `example.com`, the subdomains of a name, `*.example.com`,
+//! or a name and its subdomains, `.example.com`. `DomainSet` is a set of patterns,
+//! loaded from plain text lists or hosts files of the kind block lists are published as.
+//!
+//! Names are compared in their ASCII form, see `to_ascii`, so lists may be written in Unicode.
+//! `PublicSuffixList` finds the registrable part of a name, and catches patterns, like `*.co.uk`,
+//! that would match every site under a public suffix.
+//!
+//! `requested_name` takes the name a packet asks for from DNS, TLS, QUIC or HTTP.
+//! To replace a set in use by a running queue, share it through a `shared::Shared`.
+mod idna;
+mod suffix;
+
+use std::collections::HashMap;
+use std::error::Error as Base;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use http::Request;
+use message::dns::{Dns, DNS_PORT};
+use message::{Packet, Transport};
+use quic;
+use tls::ClientHello;
+pub use self::idna::{to_ascii, to_unicode};
+pub use self::suffix::PublicSuffixList;
+
+/// Reasons a name or pattern is malformed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DomainError {
+    /// The name is empty
+    Empty,
+    /// A label is empty or has characters not allowed in host names
+    Label,
+    /// A label is longer than 63 characters, or the name longer than 253
+    TooLong,
+    /// A wildcard is not the whole of the first label
+    Wildcard,
+    /// The pattern matches the sites of a public suffix, such as `*.co.uk`
+    PublicSuffix,
+}
+
+impl fmt::Display for DomainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason())
+    }
+}
+
+impl Base for DomainError {
+    fn description(&self) -> &str {
+        self.reason()
+    }
+}
+
+impl DomainError {
+    fn reason(&self) -> &'static str {
+        match *self {
+            DomainError::Empty => "Empty name",
+            DomainError::Label => "Malformed label",
+            DomainError::TooLong => "Name too long",
+            DomainError::Wildcard => "Misplaced wildcard",
+            DomainError::PublicSuffix => "Pattern covers a public suffix",
+        }
+    }
+}
+
+/// A pattern of names
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Pattern {
+    /// Just the name, written `example.com`
+    Exact(String),
+    /// The subdomains of the name but not the name, written `*.example.com`
+    Subdomains(String),
+    /// The name and its subdomains, written `.example.com`
+    Suffix(String),
+}
+
+impl Pattern {
+    /// The name of the pattern, in ASCII form
+    pub fn name(&self) -> &str {
+        match *self {
+            Pattern::Exact(ref name) | Pattern::Subdomains(ref name) | Pattern::Suffix(ref name) => name
+        }
+    }
+
+    /// Whether the pattern matches a name in ASCII form, see `to_ascii`
+    pub fn matches(&self, name: &str) -> bool {
+        let subdomain = |suffix: &str| {
+            name.len() > suffix.len() && name.ends_with(suffix) && name.as_bytes()[name.len() - suffix.len() - 1] == b'.'
+        };
+        match *self {
+            Pattern::Exact(ref n) => n == name,
+            Pattern::Subdomains(ref n) => subdomain(n),
+            Pattern::Suffix(ref n) => n == name || subdomain(n),
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = DomainError;
+
+    fn from_str(s: &str) -> Result<Pattern, DomainError> {
+        let (name, pattern): (&str, fn(String) -> Pattern) = match s {
+            s if s.starts_with("*.") => (&s[2..], Pattern::Subdomains),
+            s if s.starts_with('.') => (&s[1..], Pattern::Suffix),
+            s => (s, Pattern::Exact)
+        };
+        match name.contains('*') {
+            true => Err(DomainError::Wildcard),
+            false => to_ascii(name).map(pattern)
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Pattern::Exact(ref name) => write!(f, "{}", name),
+            Pattern::Subdomains(ref name) => write!(f, "*.{}", name),
+            Pattern::Suffix(ref name) => write!(f, ".{}", name),
+        }
+    }
+}
+
+/// A map from name patterns to values, finding the most specific pattern matching a name
+///
+/// A pattern with more labels is more specific, and an exact name or subdomains pattern
+/// is more specific than a suffix pattern of the same name.
+pub struct DomainMap<V> {
+    root: Node<V>,
+    len: usize,
+}
+
+/// A node of the trie, at a name
+struct Node<V> {
+    /// The nodes of the names one label longer, by that label
+    children: HashMap<String, Node<V>>,
+    exact: Option<V>,
+    subdomains: Option<V>,
+    suffix: Option<V>,
+}
+
+impl<V> Node<V> {
+    fn new() -> Node<V> {
+        Node { children: HashMap::new(), exact: None, subdomains: None, suffix: None }
+    }
+
+    fn slot(&mut self, pattern: &Pattern) -> &mut Option<V> {
+        match *pattern {
+            Pattern::Exact(_) => &mut self.exact,
+            Pattern::Subdomains(_) => &mut self.subdomains,
+            Pattern::Suffix(_) => &mut self.suffix,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.exact.is_none() && self.subdomains.is_none() && self.suffix.is_none()
+    }
+}
+
+impl<V> Default for DomainMap<V> {
+    fn default() -> DomainMap<V> {
+        DomainMap::new()
+    }
+}
+
+impl<V> DomainMap<V> {
+    /// An empty map
+    pub fn new() -> DomainMap<V> {
+        DomainMap { root: Node::new(), len: 0 }
+    }
+
+    /// Map `pattern` to `value`, returning the value it was mapped to, if any
+    pub fn insert(&mut self, pattern: Pattern, value: V) -> Option<V> {
+        let mut node = &mut self.root;
+        for label in pattern.name().rsplit('.') {
+            node = node.children.entry(label.to_string()).or_insert_with(Node::new);
+        }
+        let old = mem::replace(node.slot(&pattern), Some(value));
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    /// Remove `pattern`, returning the value it was mapped to, if any
+    pub fn remove(&mut self, pattern: &Pattern) -> Option<V> {
+        let labels: Vec<&str> = pattern.name().rsplit('.').collect();
+        let old = remove(&mut self.root, &labels, pattern);
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    /// The value `pattern` is mapped to, if any
+    pub fn get(&self, pattern: &Pattern) -> Option<&V> {
+        let mut node = &self.root;
+        for label in pattern.name().rsplit('.') {
+            node = match node.children.get(label) {
+                Some(child) => child,
+                None => return None
+            };
+        }
+        match *pattern {
+            Pattern::Exact(_) => node.exact.as_ref(),
+            Pattern::Subdomains(_) => node.subdomains.as_ref(),
+            Pattern::Suffix(_) => node.suffix.as_ref(),
+        }
+    }
+
+    /// The most specific pattern matching `name`, and its value
+    ///
+    /// The name is normalized with `to_ascii` first, and matches nothing if that fails.
+    pub fn lookup(&self, name: &str) -> Option<(Pattern, &V)> {
+        let name = match to_ascii(name) {
+            Ok(name) => name,
+            Err(_) => return None
+        };
+        let mut best = None;
+        let mut node = &self.root;
+        // Where the name of `node` starts in `name`
+        let mut start = name.len();
+        for label in name.rsplit('.') {
+            node = match node.children.get(label) {
+                Some(child) => child,
+                None => break
+            };
+            start = start.saturating_sub(label.len() + 1);
+            let at = match start {
+                0 => 0,
+                start => start + 1
+            };
+            let candidate = match (at, &node.exact, &node.subdomains, &node.suffix) {
+                (0, &Some(ref value), _, _) => Some((Pattern::Exact(name.clone()), value)),
+                (at, _, &Some(ref value), _) if at > 0 => Some((Pattern::Subdomains(name[at..].to_string()), value)),
+                (at, _, _, &Some(ref value)) => Some((Pattern::Suffix(name[at..].to_string()), value)),
+                _ => None
+            };
+            if candidate.is_some() {
+                best = candidate;
+            }
+        }
+        best
+    }
+
+    /// Whether any pattern matches `name`
+    pub fn contains(&self, name: &str) -> bool {
+        self.lookup(name).is_some()
+    }
+
+    /// The patterns and their values, each name before the names below it
+    pub fn entries(&self) -> Vec<(Pattern, &V)> {
+        let mut entries = Vec::with_capacity(self.len);
+        collect(&self.root, &mut Vec::new(), &mut entries);
+        entries
+    }
+
+    /// The number of patterns
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether there are no patterns
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Remove `pattern` below `node`, where `labels` are the labels of its name still to follow
+///
+/// Nodes left with neither values nor children are dropped on the way back up.
+fn remove<V>(node: &mut Node<V>, labels: &[&str], pattern: &Pattern) -> Option<V> {
+    let (label, rest) = match labels.split_first() {
+        Some(split) => split,
+        None => return node.slot(pattern).take()
+    };
+    let (old, empty) = match node.children.get_mut(*label) {
+        Some(child) => {
+            let old = remove(child, rest, pattern);
+            (old, child.is_empty())
+        },
+        None => return None
+    };
+    if empty {
+        node.children.remove(*label);
+    }
+    old
+}
+
+fn collect<'a, V>(node: &'a Node<V>, labels: &mut Vec<&'a str>, entries: &mut Vec<(Pattern, &'a V)>) {
+    if !labels.is_empty() {
+        let name = labels.iter().rev().cloned().collect::<Vec<_>>().join(".");
+        if let Some(ref value) = node.exact {
+            entries.push((Pattern::Exact(name.clone()), value));
+        }
+        if let Some(ref value) = node.subdomains {
+            entries.push((Pattern::Subdomains(name.clone()), value));
+        }
+        if let Some(ref value) = node.suffix {
+            entries.push((Pattern::Suffix(name), value));
+        }
+    }
+    let mut children: Vec<_> = node.children.iter().collect();
+    children.sort_by(|a, b| a.0.cmp(b.0));
+    for (label, child) in children {
+        labels.push(label);
+        collect(child, labels, entries);
+        labels.pop();
+    }
+}
+
+/// Reasons a list of patterns could not be loaded
+#[derive(Debug)]
+pub enum ListError {
+    /// The list could not be read
+    Io(io::Error),
+    /// A line is not a pattern
+    Parse {
+        /// The line, from one
+        line: usize,
+        /// What is wrong
+        error: DomainError,
+    },
+}
+
+impl fmt::Display for ListError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ListError::Io(ref e) => write!(f, "{}: {}", self.reason(), e),
+            ListError::Parse { line, ref error } => write!(f, "line {}: {}", line, error),
+        }
+    }
+}
+
+impl Base for ListError {
+    fn description(&self) -> &str {
+        self.reason()
+    }
+}
+
+impl ListError {
+    fn reason(&self) -> &'static str {
+        match *self {
+            ListError::Io(_) => "Failed to read list",
+            ListError::Parse { .. } => "Malformed list",
+        }
+    }
+}
+
+/// A set of name patterns
+#[derive(Default)]
+pub struct DomainSet {
+    map: DomainMap<()>,
+}
+
+impl DomainSet {
+    /// An empty set
+    pub fn new() -> DomainSet {
+        DomainSet { map: DomainMap::new() }
+    }
+
+    /// Parse a list of patterns, one a line
+    ///
+    /// Blank lines are ignored, as is anything after a `#`.
+    /// Lines of hosts files, like `0.0.0.0 ads.example.com`, add their names as exact patterns,
+    /// so block lists published for `/etc/hosts` can be loaded as they are.
+    pub fn parse(text: &str) -> Result<DomainSet, ListError> {
+        let mut set = DomainSet::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(comment) => &line[..comment],
+                None => line
+            };
+            let mut fields = line.split_whitespace();
+            let (first, rest): (Option<&str>, Vec<&str>) = (fields.next(), fields.collect());
+            let patterns = match first {
+                None => continue,
+                Some(address) if !rest.is_empty() && address.parse::<IpAddr>().is_ok() => rest,
+                Some(pattern) => {
+                    let mut patterns = rest;
+                    patterns.insert(0, pattern);
+                    patterns
+                }
+            };
+            for pattern in patterns {
+                match pattern.parse() {
+                    Ok(pattern) => set.insert(pattern),
+                    Err(e) => return Err(ListError::Parse { line: i + 1, error: e })
+                };
+            }
+        }
+        Ok(set)
+    }
+
+    /// Read and parse a list of patterns, see `parse`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<DomainSet, ListError> {
+        match fs::read_to_string(path) {
+            Ok(text) => DomainSet::parse(&text),
+            Err(e) => Err(ListError::Io(e))
+        }
+    }
+
+    /// Add a pattern, returning whether it was not already in the set
+    pub fn insert(&mut self, pattern: Pattern) -> bool {
+        self.map.insert(pattern, ()).is_none()
+    }
+
+    /// Remove a pattern, returning whether it was in the set
+    pub fn remove(&mut self, pattern: &Pattern) -> bool {
+        self.map.remove(pattern).is_some()
+    }
+
+    /// Whether any pattern in the set matches `name`
+    pub fn contains(&self, name: &str) -> bool {
+        self.map.contains(name)
+    }
+
+    /// The most specific pattern in the set matching `name`
+    pub fn lookup(&self, name: &str) -> Option<Pattern> {
+        self.map.lookup(name).map(|(pattern, _)| pattern)
+    }
+
+    /// The patterns, each name before the names below it
+    pub fn patterns(&self) -> Vec<Pattern> {
+        self.map.entries().into_iter().map(|(pattern, _)| pattern).collect()
+    }
+
+    /// The number of patterns
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether there are no patterns
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+/// The name a packet asks for, in ASCII form
+///
+/// This is the first question of a DNS query over UDP, the server name of a TLS ClientHello
+/// in a TCP segment or QUIC Initial, or the host of an HTTP request.
+/// Only what is in this packet is seen; ClientHellos split across segments need a `stream::StreamHandler`.
+pub fn requested_name(packet: &Packet) -> Option<String> {
+    let name = match packet.transport {
+        Some(Transport::Udp(ref udp)) if udp.dst_port() == DNS_PORT => match Dns::new(packet.payload) {
+            Ok(ref query) if !query.is_response() => query.questions.first().map(|q| q.name.clone()),
+            _ => None
+        },
+        Some(Transport::Udp(_)) => quic::client_hello(packet.payload).ok()
+            .and_then(|hello| hello.server_name().map(|name| name.to_string())),
+        Some(Transport::Tcp(_)) => match ClientHello::parse(packet.payload) {
+            Ok(hello) => hello.server_name().map(|name| name.to_string()),
+            Err(_) => Request::parse(packet.payload).ok().and_then(|request| request.host()).map(|host| host.to_string())
+        },
+        _ => None
+    };
+    name.and_then(|name| to_ascii(&name).ok())
+}
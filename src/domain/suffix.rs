@@ -0,0 +1,165 @@
+//! The public suffix list, see <https://publicsuffix.org/list/>
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use super::{to_ascii, DomainError, ListError, Pattern};
+
+/// The public suffixes, under which anyone may register names
+///
+/// Without any rules, every top level domain is a public suffix, as the list's algorithm prescribes.
+/// Load the list itself, as published at <https://publicsuffix.org/list/public_suffix_list.dat>,
+/// to know of suffixes like `co.uk`.
+#[derive(Default)]
+pub struct PublicSuffixList {
+    root: Node,
+}
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    /// The name is a public suffix
+    rule: bool,
+    /// The names one label longer are public suffixes
+    wildcard: bool,
+    /// The name is not a public suffix, despite a wildcard above it
+    exception: bool,
+}
+
+impl PublicSuffixList {
+    /// A list without rules
+    pub fn new() -> PublicSuffixList {
+        PublicSuffixList { root: Node::default() }
+    }
+
+    /// Parse a list in the format of the public suffix list
+    ///
+    /// Lines starting `//` are comments, and only the first word of other lines is read.
+    pub fn parse(text: &str) -> Result<PublicSuffixList, ListError> {
+        let mut list = PublicSuffixList::new();
+        for (i, line) in text.lines().enumerate() {
+            let rule = match line.split_whitespace().next() {
+                Some(rule) if !rule.starts_with("//") => rule,
+                _ => continue
+            };
+            if let Err(e) = list.add(rule) {
+                return Err(ListError::Parse { line: i + 1, error: e });
+            }
+        }
+        Ok(list)
+    }
+
+    /// Read and parse a list, see `parse`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<PublicSuffixList, ListError> {
+        match fs::read_to_string(path) {
+            Ok(text) => PublicSuffixList::parse(&text),
+            Err(e) => Err(ListError::Io(e))
+        }
+    }
+
+    /// Add a rule, like `co.uk`, `*.ck` or `!www.ck`
+    pub fn add(&mut self, rule: &str) -> Result<(), DomainError> {
+        let (name, wildcard, exception) = match rule {
+            r if r.starts_with('!') => (&r[1..], false, true),
+            r if r.starts_with("*.") => (&r[2..], true, false),
+            r => (r, false, false)
+        };
+        if name.contains('*') {
+            return Err(DomainError::Wildcard);
+        }
+        let name = to_ascii(name)?;
+        let mut node = &mut self.root;
+        for label in name.rsplit('.') {
+            node = node.children.entry(label.to_string()).or_insert_with(Node::default);
+        }
+        match (wildcard, exception) {
+            (true, _) => node.wildcard = true,
+            (_, true) => node.exception = true,
+            _ => node.rule = true
+        }
+        Ok(())
+    }
+
+    /// The number of labels of the public suffix of an ASCII name
+    fn suffix_labels(&self, name: &str) -> usize {
+        // The implicit rule `*` makes every top level domain a public suffix
+        let mut labels = 1;
+        let mut node = &self.root;
+        for (depth, label) in name.rsplit('.').enumerate() {
+            let child = node.children.get(label);
+            if let Some(child) = child {
+                if child.exception {
+                    return depth;
+                }
+            }
+            if node.wildcard {
+                labels = labels.max(depth + 1);
+            }
+            node = match child {
+                Some(child) => child,
+                None => break
+            };
+            if node.rule {
+                labels = labels.max(depth + 1);
+            }
+        }
+        labels
+    }
+
+    /// The public suffix of a name, like `co.uk` for `www.example.co.uk`
+    pub fn public_suffix(&self, name: &str) -> Option<String> {
+        let name = match to_ascii(name) {
+            Ok(name) => name,
+            Err(_) => return None
+        };
+        let labels = self.suffix_labels(&name);
+        Some(last_labels(&name, labels).to_string())
+    }
+
+    /// Whether a name is a public suffix
+    pub fn is_public_suffix(&self, name: &str) -> bool {
+        match to_ascii(name) {
+            Ok(name) => self.suffix_labels(&name) >= name.split('.').count(),
+            Err(_) => false
+        }
+    }
+
+    /// The registrable domain of a name, its public suffix and one label more,
+    /// like `example.co.uk` for `www.example.co.uk`
+    ///
+    /// `None` if the name is itself a public suffix.
+    pub fn registrable_domain(&self, name: &str) -> Option<String> {
+        let name = match to_ascii(name) {
+            Ok(name) => name,
+            Err(_) => return None
+        };
+        let labels = self.suffix_labels(&name) + 1;
+        match labels <= name.split('.').count() {
+            true => Some(last_labels(&name, labels).to_string()),
+            false => None
+        }
+    }
+
+    /// Check that a pattern does not match the sites of a public suffix
+    ///
+    /// `*.co.uk` and `.co.uk` match every site registered under `co.uk`, which is rarely meant,
+    /// so lists can be checked with this before use. Exact patterns always pass.
+    pub fn check(&self, pattern: &Pattern) -> Result<(), DomainError> {
+        match *pattern {
+            Pattern::Exact(_) => Ok(()),
+            Pattern::Subdomains(ref name) | Pattern::Suffix(ref name) => match self.is_public_suffix(name) {
+                true => Err(DomainError::PublicSuffix),
+                false => Ok(())
+            }
+        }
+    }
+}
+
+/// The last `labels` labels of a name
+fn last_labels(name: &str, labels: usize) -> &str {
+    match name.rmatch_indices('.').nth(labels.saturating_sub(1)) {
+        Some((dot, _)) if labels > 0 => &name[dot + 1..],
+        _ if labels > 0 => name,
+        _ => ""
+    }
+}
@@ -11,9 +11,11 @@ pub enum Reason {
     CreateQueue,
     SetQueueMode,
     SetQueueMaxlen,
+    SetQueueFlags,
     SetVerdict,
     GetHeader,
     GetPayload,
+    Mangle,
 }
 
 pub struct Error {
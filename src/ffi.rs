@@ -69,6 +69,9 @@ extern {
                         range: uint32_t) -> c_int;
     pub fn nfq_set_queue_maxlen(handle: *mut nfq_q_handle,
                                 queuelen: uint32_t) -> c_int;
+    pub fn nfq_set_queue_flags(handle: *mut nfq_q_handle,
+                               mask: uint32_t,
+                               flags: uint32_t) -> c_int;
 
     // Iterating through a queue
     pub fn nfq_fd(handle: *mut nfq_handle) -> c_int;
@@ -82,8 +85,19 @@ extern {
                            verdict: uint32_t,
                            data_len: uint32_t,
                            buf: *const c_uchar) -> c_int;
+    pub fn nfq_set_verdict2(handle: *mut nfq_q_handle,
+                            id: uint32_t,
+                            verdict: uint32_t,
+                            mark: uint32_t,
+                            data_len: uint32_t,
+                            buf: *const c_uchar) -> c_int;
 
     // Parsing the message
     pub fn nfq_get_msg_packet_hdr(nfad: *mut nfq_data) -> *const nfqnl_msg_packet_hdr;
     pub fn nfq_get_payload  (nfad: *mut nfq_data, data: *mut *mut c_uchar) -> c_int;
+    pub fn nfq_get_nfmark(nfad: *mut nfq_data) -> uint32_t;
+    pub fn nfq_get_uid(nfad: *mut nfq_data, uid: *mut uint32_t) -> c_int;
+    pub fn nfq_get_indev(nfad: *mut nfq_data) -> uint32_t;
+    pub fn nfq_get_outdev(nfad: *mut nfq_data) -> uint32_t;
+    pub fn nfq_get_timestamp(nfad: *mut nfq_data, tv: *mut timeval) -> c_int;
 }
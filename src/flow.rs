@@ -0,0 +1,521 @@
+//! Flow tracking
+//!
+//! `FlowTable` groups packets into flows by their addresses, ports and protocol in either direction,
+//! keeps state of the caller's choosing per flow, follows the TCP state machine,
+//! and forgets flows once idle for a timeout depending on their state, or to make room for new ones.
+//! `FlowTracker` runs a `FlowHandler` over a table as a `VerdictHandler`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::{Values, ValuesMut};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use message::{Message, Packet, Transport, TCP_ACK, TCP_FIN, TCP_RST, TCP_SYN};
+use message::{IPPROTO_ICMP, IPPROTO_ICMPV6, IPPROTO_TCP, IPPROTO_UDP};
+use queue::{Verdict, VerdictHandler};
+use stream::Side;
+
+/// The identity of a flow, the same for packets in both directions
+///
+/// For ICMP echo requests and replies the ports are both the echo identifier;
+/// other ICMP messages, and protocols without ports, have ports of zero.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FlowKey {
+    /// The transport protocol
+    pub protocol: u8,
+    /// The lower of the two endpoints
+    pub lower: SocketAddr,
+    /// The higher of the two endpoints
+    pub upper: SocketAddr,
+}
+
+impl FlowKey {
+    /// The flow of a decoded packet
+    ///
+    /// `None` for non-first fragments, and TCP, UDP or ICMP packets whose transport header was not copied.
+    pub fn of(packet: &Packet) -> Option<FlowKey> {
+        endpoints(packet).map(|(source, destination)| FlowKey::new(packet.protocol, source, destination))
+    }
+
+    /// The flow between two endpoints, in either order
+    pub fn new(protocol: u8, a: SocketAddr, b: SocketAddr) -> FlowKey {
+        match a <= b {
+            true => FlowKey { protocol: protocol, lower: a, upper: b },
+            false => FlowKey { protocol: protocol, lower: b, upper: a },
+        }
+    }
+}
+
+/// The source and destination of a packet, with the ports described by `FlowKey`
+fn endpoints(packet: &Packet) -> Option<(SocketAddr, SocketAddr)> {
+    let (sport, dport) = match packet.transport {
+        Some(Transport::Tcp(ref tcp)) => (tcp.src_port(), tcp.dst_port()),
+        Some(Transport::Udp(ref udp)) => (udp.src_port(), udp.dst_port()),
+        Some(Transport::Icmp(ref icmp)) => match icmp.icmp_type() {
+            0 | 8 => (icmp.echo_id(), icmp.echo_id()),
+            _ => (0, 0)
+        },
+        Some(Transport::Icmpv6(ref icmp)) => match icmp.icmp_type() {
+            128 | 129 => (icmp.echo_id(), icmp.echo_id()),
+            _ => (0, 0)
+        },
+        None => match packet.protocol {
+            IPPROTO_TCP | IPPROTO_UDP | IPPROTO_ICMP | IPPROTO_ICMPV6 => return None,
+            _ if packet.fragment.map(|f| f.offset > 0).unwrap_or(false) => return None,
+            _ => (0, 0)
+        }
+    };
+    Some((SocketAddr::new(packet.network.saddr(), sport), SocketAddr::new(packet.network.daddr(), dport)))
+}
+
+/// The state of a TCP connection, as in nf_conntrack
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TcpState {
+    /// The client sent a SYN
+    SynSent,
+    /// The server answered with a SYN-ACK
+    SynReceived,
+    /// The handshake completed, or the connection was picked up midstream
+    Established,
+    /// One side sent a FIN
+    FinWait,
+    /// The other side acknowledged the first FIN
+    CloseWait,
+    /// Both sides sent a FIN
+    LastAck,
+    /// The second FIN was acknowledged
+    TimeWait,
+    /// A RST was sent
+    Closed,
+}
+
+impl TcpState {
+    /// The state after a segment with `flags` from `side`, given which side sent the first FIN
+    fn next(&self, side: Side, flags: u8, first_fin: Option<Side>) -> TcpState {
+        let (syn, ack, fin) = (flags & TCP_SYN != 0, flags & TCP_ACK != 0, flags & TCP_FIN != 0);
+        let from_closer = first_fin == Some(side);
+        match *self {
+            _ if flags & TCP_RST != 0 => TcpState::Closed,
+            TcpState::SynSent if side == Side::Server && syn && ack => TcpState::SynReceived,
+            TcpState::SynReceived if side == Side::Client && ack && !syn => match fin {
+                true => TcpState::FinWait,
+                false => TcpState::Established
+            },
+            TcpState::Established if fin => TcpState::FinWait,
+            TcpState::FinWait | TcpState::CloseWait if fin && !from_closer => TcpState::LastAck,
+            TcpState::FinWait if ack && !from_closer => TcpState::CloseWait,
+            TcpState::LastAck if ack && from_closer => TcpState::TimeWait,
+            state => state
+        }
+    }
+
+    /// Whether a SYN may start a new connection over this one
+    fn is_closed(&self) -> bool {
+        match *self {
+            TcpState::TimeWait | TcpState::Closed => true,
+            _ => false
+        }
+    }
+}
+
+/// How long flows are remembered without packets, and how many at once
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// The number of flows tracked at once, beyond which the least recently seen is evicted
+    pub max_flows: usize,
+    /// TCP connections in the handshake
+    pub tcp_handshake: Duration,
+    /// Established TCP connections
+    pub tcp_established: Duration,
+    /// TCP connections after the first FIN
+    pub tcp_closing: Duration,
+    /// TCP connections after a RST
+    pub tcp_closed: Duration,
+    /// UDP flows that have only seen packets from one side
+    pub udp: Duration,
+    /// UDP flows that have seen a reply
+    pub udp_replied: Duration,
+    /// Flows of other protocols
+    pub other: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_flows: 65536,
+            tcp_handshake: Duration::from_secs(120),
+            tcp_established: Duration::from_secs(5 * 24 * 60 * 60),
+            tcp_closing: Duration::from_secs(120),
+            tcp_closed: Duration::from_secs(10),
+            udp: Duration::from_secs(30),
+            udp_replied: Duration::from_secs(120),
+            other: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A tracked flow, with state `T` kept by the caller
+#[derive(Debug)]
+pub struct Flow<T> {
+    key: FlowKey,
+    client: SocketAddr,
+    tcp_state: Option<TcpState>,
+    first_fin: Option<Side>,
+    created: Instant,
+    last_seen: Instant,
+    packets: [u64; 2],
+    bytes: [u64; 2],
+    /// Used to order flows by when they were last seen
+    tick: u64,
+    expiry: Instant,
+    /// The caller's state for this flow
+    pub data: T,
+}
+
+impl<T> Flow<T> {
+    /// The flow's key
+    pub fn key(&self) -> FlowKey {
+        self.key
+    }
+
+    /// The endpoint that sent the first packet seen, or the SYN of a TCP connection
+    pub fn client(&self) -> SocketAddr {
+        self.client
+    }
+
+    /// The other endpoint
+    pub fn server(&self) -> SocketAddr {
+        match self.client == self.key.lower {
+            true => self.key.upper,
+            false => self.key.lower
+        }
+    }
+
+    /// The state of a TCP connection, `None` for other protocols
+    pub fn tcp_state(&self) -> Option<TcpState> {
+        self.tcp_state
+    }
+
+    /// Whether the server has sent anything
+    pub fn is_replied(&self) -> bool {
+        self.packets[1] > 0
+    }
+
+    /// When the first packet was seen
+    pub fn created(&self) -> Instant {
+        self.created
+    }
+
+    /// When the last packet was seen
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+
+    /// When the flow is forgotten unless another packet is seen
+    pub fn expiry(&self) -> Instant {
+        self.expiry
+    }
+
+    /// The number of packets `side` sent
+    pub fn packets(&self, side: Side) -> u64 {
+        self.packets[index(side)]
+    }
+
+    /// The number of bytes `side` sent, counting whole IP datagrams
+    pub fn bytes(&self, side: Side) -> u64 {
+        self.bytes[index(side)]
+    }
+
+    fn timeout(&self, limits: &Limits) -> Duration {
+        match self.tcp_state {
+            Some(TcpState::SynSent) | Some(TcpState::SynReceived) => limits.tcp_handshake,
+            Some(TcpState::Established) => limits.tcp_established,
+            Some(TcpState::Closed) => limits.tcp_closed,
+            Some(_) => limits.tcp_closing,
+            None if self.key.protocol == IPPROTO_UDP => match self.is_replied() {
+                true => limits.udp_replied,
+                false => limits.udp
+            },
+            None => limits.other
+        }
+    }
+}
+
+fn index(side: Side) -> usize {
+    match side {
+        Side::Client => 0,
+        Side::Server => 1,
+    }
+}
+
+/// A table of flows, each with state `T`
+///
+/// Flows end when they expire, are evicted to make room for a new flow,
+/// or a TCP connection is opened again after closing.
+/// Ended flows are handed back by `expire`, so their state can be finished with.
+pub struct FlowTable<T> {
+    flows: HashMap<FlowKey, Flow<T>>,
+    /// Flows by when they were last seen
+    lru: BTreeMap<u64, FlowKey>,
+    /// Flows by when they expire
+    expiries: BTreeMap<(Instant, u64), FlowKey>,
+    /// Flows ended by `track`, awaiting `expire`
+    ended: Vec<Flow<T>>,
+    next_tick: u64,
+    limits: Limits,
+}
+
+impl<T> FlowTable<T> {
+    /// Track flows within the default `Limits`
+    pub fn new() -> FlowTable<T> {
+        FlowTable::with_limits(Limits::default())
+    }
+
+    /// Track flows within `limits`
+    pub fn with_limits(limits: Limits) -> FlowTable<T> {
+        FlowTable {
+            flows: HashMap::new(),
+            lru: BTreeMap::new(),
+            expiries: BTreeMap::new(),
+            ended: Vec::new(),
+            next_tick: 0,
+            limits: limits,
+        }
+    }
+
+    /// Account a packet to its flow, returning the flow and which side sent the packet
+    ///
+    /// A new flow's state is created by `start`.
+    /// `None` if the packet has no `FlowKey`.
+    pub fn track<F>(&mut self, packet: &Packet, now: Instant, start: F) -> Option<(Side, &mut Flow<T>)>
+        where F: FnOnce(&FlowKey) -> T {
+        let (source, destination) = match endpoints(packet) {
+            Some(endpoints) => endpoints,
+            None => return None
+        };
+        let key = FlowKey::new(packet.protocol, source, destination);
+        let flags = match packet.transport {
+            Some(Transport::Tcp(ref tcp)) => Some(tcp.flags()),
+            _ => None
+        };
+
+        let reopened = match (self.flows.get(&key), flags) {
+            (Some(flow), Some(flags)) => {
+                let closed = flow.tcp_state.map(|s| s.is_closed()).unwrap_or(false);
+                closed && flags & (TCP_SYN | TCP_ACK) == TCP_SYN
+            },
+            _ => false
+        };
+        if reopened {
+            let flow = self.remove(&key).unwrap();
+            self.ended.push(flow);
+        }
+        if !self.flows.contains_key(&key) {
+            while self.flows.len() >= self.limits.max_flows && self.evict_oldest() {}
+            let (client, state) = match flags {
+                // The SYN was missed, so the client is the destination
+                Some(flags) if flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK =>
+                    (destination, Some(TcpState::SynReceived)),
+                Some(flags) if flags & TCP_RST != 0 => (source, Some(TcpState::Closed)),
+                Some(flags) if flags & TCP_SYN != 0 => (source, Some(TcpState::SynSent)),
+                Some(_) => (source, Some(TcpState::Established)),
+                None => (source, None)
+            };
+            let flow = Flow {
+                key: key,
+                client: client,
+                tcp_state: state,
+                first_fin: None,
+                created: now,
+                last_seen: now,
+                packets: [0, 0],
+                bytes: [0, 0],
+                tick: 0,
+                expiry: now,
+                data: start(&key),
+            };
+            self.flows.insert(key, flow);
+        }
+
+        let limits = self.limits;
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        let flow = self.flows.get_mut(&key).unwrap();
+        self.lru.remove(&flow.tick);
+        self.expiries.remove(&(flow.expiry, flow.tick));
+
+        let side = match source == flow.client {
+            true => Side::Client,
+            false => Side::Server
+        };
+        if let (Some(state), Some(flags)) = (flow.tcp_state, flags) {
+            let next = state.next(side, flags, flow.first_fin);
+            if next == TcpState::FinWait && state != TcpState::FinWait {
+                flow.first_fin = Some(side);
+            }
+            flow.tcp_state = Some(next);
+        }
+        flow.packets[index(side)] += 1;
+        flow.bytes[index(side)] += packet.datagram_len() as u64;
+        flow.last_seen = now;
+        flow.tick = tick;
+        flow.expiry = now + flow.timeout(&limits);
+        self.lru.insert(tick, key);
+        self.expiries.insert((flow.expiry, tick), key);
+        Some((side, flow))
+    }
+
+    /// The flow with `key`
+    pub fn get(&self, key: &FlowKey) -> Option<&Flow<T>> {
+        self.flows.get(key)
+    }
+
+    /// The flow with `key`, to change its state
+    pub fn get_mut(&mut self, key: &FlowKey) -> Option<&mut Flow<T>> {
+        self.flows.get_mut(key)
+    }
+
+    /// Stop tracking the flow with `key`
+    pub fn remove(&mut self, key: &FlowKey) -> Option<Flow<T>> {
+        let flow = self.flows.remove(key);
+        if let Some(ref flow) = flow {
+            self.lru.remove(&flow.tick);
+            self.expiries.remove(&(flow.expiry, flow.tick));
+        }
+        flow
+    }
+
+    /// Remove the flows that expired by `now`, returning them with those ended since the last call
+    pub fn expire(&mut self, now: Instant) -> Vec<Flow<T>> {
+        let mut ended = ::std::mem::replace(&mut self.ended, Vec::new());
+        loop {
+            let key = match self.expiries.iter().next() {
+                Some((&(expiry, _), key)) if expiry <= now => *key,
+                _ => break
+            };
+            ended.push(self.remove(&key).unwrap());
+        }
+        ended
+    }
+
+    /// When the next flow expires
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.expiries.keys().next().map(|&(expiry, _)| expiry)
+    }
+
+    /// The flows tracked
+    pub fn iter(&self) -> Values<'_, FlowKey, Flow<T>> {
+        self.flows.values()
+    }
+
+    /// The flows tracked, to change their state
+    pub fn iter_mut(&mut self) -> ValuesMut<'_, FlowKey, Flow<T>> {
+        self.flows.values_mut()
+    }
+
+    /// The number of flows tracked
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// Whether no flows are tracked
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        let oldest = self.lru.values().next().cloned();
+        match oldest {
+            Some(key) => {
+                let flow = self.remove(&key).unwrap();
+                self.ended.push(flow);
+                true
+            },
+            None => false
+        }
+    }
+}
+
+impl<T> Default for FlowTable<T> {
+    fn default() -> FlowTable<T> {
+        FlowTable::new()
+    }
+}
+
+/// Decides on packets with the state of their flow
+pub trait FlowHandler {
+    /// The state kept per flow
+    type State;
+
+    /// Create the state of a new flow, from its first packet
+    fn start(&mut self, key: &FlowKey, message: &Message) -> Self::State;
+
+    /// Decide on a packet from `side` of `flow`
+    fn decide(&mut self, flow: &mut Flow<Self::State>, side: Side, message: &Message) -> Verdict;
+
+    /// Finish with a flow that ended
+    fn end(&mut self, flow: Flow<Self::State>) {
+        let _ = flow;
+    }
+
+    /// Decide on a packet that is not part of a flow, such as a non-first fragment or an undecodable packet
+    fn other(&mut self, message: &Message) -> Verdict {
+        let _ = message;
+        Verdict::Accept
+    }
+}
+
+/// A `VerdictHandler` that tracks flows for a `FlowHandler`
+///
+/// Flows are expired as packets arrive, so an idle flow is handed to `FlowHandler::end` with the next packet.
+/// Wrap in a `reassembly::Reassembler` to track fragmented datagrams.
+pub struct FlowTracker<H: FlowHandler> {
+    handler: H,
+    table: FlowTable<H::State>,
+}
+
+impl<H: FlowHandler> FlowTracker<H> {
+    /// Track flows for `handler` within the default `Limits`
+    pub fn new(handler: H) -> FlowTracker<H> {
+        FlowTracker::with_limits(handler, Limits::default())
+    }
+
+    /// Track flows for `handler` within `limits`
+    pub fn with_limits(handler: H, limits: Limits) -> FlowTracker<H> {
+        FlowTracker { handler: handler, table: FlowTable::with_limits(limits) }
+    }
+
+    /// The wrapped handler
+    pub fn handler(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// The flows tracked
+    pub fn table(&mut self) -> &mut FlowTable<H::State> {
+        &mut self.table
+    }
+}
+
+impl<H: FlowHandler> VerdictHandler for FlowTracker<H> {
+    fn decide(&mut self, message: &Message) -> Verdict {
+        let now = Instant::now();
+        for flow in self.table.expire(now) {
+            self.handler.end(flow);
+        }
+        let packet = match message.decode() {
+            Ok(packet) => packet,
+            Err(_) => return self.handler.other(message)
+        };
+        let verdict = {
+            let handler = &mut self.handler;
+            match self.table.track(&packet, now, |key| handler.start(key, message)) {
+                Some((side, flow)) => handler.decide(flow, side, message),
+                None => handler.other(message)
+            }
+        };
+        // Flows evicted or reopened by this packet
+        for flow in self.table.expire(now) {
+            self.handler.end(flow);
+        }
+        verdict
+    }
+}
@@ -3,17 +3,29 @@
 //! Analagous to <http://netfilter.org/projects/libnetfilter_queue/doxygen/group__LibrarySetup.html>
 
 use libc::*;
+use std::cell::RefCell;
+use std::io;
 use std::mem;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use error::*;
-use queue::{Queue, PacketHandler};
+use queue::{self, Queue, PacketHandler, Pollers};
 use message::Payload;
+use mock::{Mock, Call};
 use lock::NFQ_LOCK as LOCK;
 
 use ffi::*;
 
+// Missing from libc
+const MSG_DONTWAIT: c_int = 0x40;
+
+/// The most `step` reads at a time, enough for a whole packet and its netlink attributes
+const STEP_LENGTH: usize = 0xffff + 4096;
+
 /// Protocol Family
 ///
 /// NFQueue will only deal with IP, so only those families are made available.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ProtocolFamily {
     /// IPv4 Address Family
     INET = AF_INET as isize,
@@ -21,16 +33,31 @@ pub enum ProtocolFamily {
     INET6 = AF_INET6 as isize
 }
 
+/// Where a `Handle`'s packets come from
+enum Backend {
+    /// The kernel, through libnetfilter_queue
+    Netfilter(*mut nfq_handle),
+    /// Packets injected in tests
+    Memory(Mock)
+}
+
 /// A handle into NFQueue
 ///
 /// This is needed for library setup.
-pub struct Handle { ptr: *mut nfq_handle }
+pub struct Handle {
+    backend: Backend,
+    pollers: Pollers,
+    // What `step` reads into, allocated when first needed
+    step_buffer: Vec<u8>
+}
 
 impl Drop for Handle {
     fn drop(&mut self) {
-        let ret = unsafe { nfq_close(self.ptr) };
-        if ret != 0 {
-            panic!("Failed to close NFQHandle");
+        if let Backend::Netfilter(ptr) = self.backend {
+            let ret = unsafe { nfq_close(ptr) };
+            if ret != 0 {
+                panic!("Failed to close NFQHandle");
+            }
         }
     }
 }
@@ -46,15 +73,29 @@ impl Handle {
         if ptr.is_null() {
             Err(error(Reason::OpenHandle, "Failed to allocate handle", None))
         } else {
-            Ok(Handle{ ptr: ptr })
+            Ok(Handle{ backend: Backend::Netfilter(ptr), pollers: Rc::new(RefCell::new(Vec::new())), step_buffer: Vec::new() })
         }
     }
 
+    /// Open a handle whose queues are fed by `mock` instead of the kernel
+    ///
+    /// Needs neither root nor the `nfnetlink_queue` module, see `mock`.
+    pub fn in_memory(mock: &Mock) -> Handle {
+        Handle { backend: Backend::Memory(mock.clone()), pollers: Rc::new(RefCell::new(Vec::new())), step_buffer: Vec::new() }
+    }
+
     /// Bind the handle to a `ProtocolFamily`
     pub fn bind(&mut self, proto: ProtocolFamily) -> Result<(), Error> {
+        let ptr = match self.backend {
+            Backend::Netfilter(ptr) => ptr,
+            Backend::Memory(ref mock) => {
+                mock.call(Call::Bind(proto));
+                return Ok(());
+            }
+        };
         let _lock = LOCK.lock().unwrap();
 
-        let res = unsafe { nfq_bind_pf(self.ptr, proto as uint16_t) };
+        let res = unsafe { nfq_bind_pf(ptr, proto as uint16_t) };
         if res < 0 {
             Err(error(Reason::Bind, "Failed to bind handle", Some(res)))
         } else {
@@ -66,9 +107,16 @@ impl Handle {
     ///
     /// This should usually be avoided, as it may attach other programs from the `ProtocolFamily`.
     pub fn unbind(&mut self, proto: ProtocolFamily) -> Result<(), Error> {
+        let ptr = match self.backend {
+            Backend::Netfilter(ptr) => ptr,
+            Backend::Memory(ref mock) => {
+                mock.call(Call::Unbind(proto));
+                return Ok(());
+            }
+        };
         let _lock = LOCK.lock().unwrap();
 
-        let res = unsafe { nfq_unbind_pf(self.ptr, proto as uint16_t) };
+        let res = unsafe { nfq_unbind_pf(ptr, proto as uint16_t) };
         if res < 0 {
             Err(error(Reason::Unbind, "Failed to unbind handle", Some(res)))
         } else {
@@ -80,7 +128,10 @@ impl Handle {
     pub fn queue<F: PacketHandler>(&mut self,
                                    queue_number: u16,
                                    handler: F) -> Result<Box<Queue<F>>, Error> {
-        Queue::new(self.ptr, queue_number as uint16_t, handler)
+        match self.backend {
+            Backend::Netfilter(ptr) => Queue::new(ptr, queue_number as uint16_t, handler, self.pollers.clone()),
+            Backend::Memory(ref mock) => Queue::in_memory(mock, queue_number, handler, self.pollers.clone())
+        }
     }
 
     /// Start listening using any attached queues
@@ -88,17 +139,42 @@ impl Handle {
     /// This will only listen on queues attached with `queue_builder`.
     /// `length` determines the amount of a packet to grab from the queue at a time.
     /// If you are using `queue::Queue::CopyMode(SIZE)` it must match `SIZE`.
+    ///
+    /// Between packets, the queues' handlers are polled as their timers come due.
+    ///
+    /// An in-memory handle returns once every injected packet has been handled.
     pub fn start(&mut self, length: u16) {
+        let ptr = match self.backend {
+            Backend::Netfilter(ptr) => ptr,
+            Backend::Memory(_) => {
+                while self.step() {}
+                return;
+            }
+        };
         unsafe {
             let buffer: *mut c_void = malloc(mem::size_of::<c_char>() as u64 * length as u64);
             if buffer.is_null() {
                 panic!("Failed to allocate packet buffer");
             }
-            let fd = nfq_fd(self.ptr);
+            let fd = nfq_fd(ptr);
+            let mut timeout = None;
 
             loop {
+                let wait = queue::poll_all(&self.pollers, Instant::now()).map(|deadline| {
+                    let now = Instant::now();
+                    match deadline > now {
+                        true => deadline - now,
+                        false => Duration::new(0, 0)
+                    }
+                });
+                if wait != timeout {
+                    set_receive_timeout(fd, wait);
+                    timeout = wait;
+                }
+
                 match recv(fd, buffer, length as u64, 0) {
-                    rv if rv >=0 => { nfq_handle_packet(self.ptr, buffer as *mut c_char, rv as i32); },
+                    rv if rv >=0 => { nfq_handle_packet(ptr, buffer as *mut c_char, rv as i32); },
+                    _ if timed_out() => (),
                     _ => { break; }
                 }
             }
@@ -116,4 +192,65 @@ impl Handle {
         let bytes = mem::size_of::<P>() as u16;
         self.start(bytes * 8)
     }
+
+    /// Handle the next packet, if one is waiting, then poll the queues
+    ///
+    /// Returns whether there was a packet, so `while handle.step() {}` handles all of them.
+    /// The queues are polled even when there was none, releasing any `Deferred` verdicts now due.
+    /// Unlike `start`, this never waits for the kernel, so a loop calling it can stop when it likes.
+    pub fn step(&mut self) -> bool {
+        let stepped = match self.backend {
+            Backend::Netfilter(ptr) => unsafe {
+                if self.step_buffer.is_empty() {
+                    self.step_buffer = vec![0u8; STEP_LENGTH];
+                }
+                let buffer = &mut self.step_buffer;
+                let fd = nfq_fd(ptr);
+                match recv(fd, buffer.as_mut_ptr() as *mut c_void, STEP_LENGTH as u64, MSG_DONTWAIT) {
+                    rv if rv >= 0 => {
+                        nfq_handle_packet(ptr, buffer.as_mut_ptr() as *mut c_char, rv as i32);
+                        true
+                    },
+                    _ => false
+                }
+            },
+            Backend::Memory(ref mock) => mock.step()
+        };
+        queue::poll_all(&self.pollers, Instant::now());
+        stepped
+    }
+
+    #[cfg(feature = "fuzzing")]
+    #[doc(hidden)]
+    /// Decode netlink messages as if read from the kernel, dispatching packets to the queues
+    ///
+    /// Used to fuzz message decoding. Returns whether the messages were understood.
+    pub fn handle_data(&mut self, data: &mut [u8]) -> bool {
+        match self.backend {
+            Backend::Netfilter(ptr) => unsafe { nfq_handle_packet(ptr, data.as_mut_ptr() as *mut c_char, data.len() as c_int) >= 0 },
+            Backend::Memory(_) => false
+        }
+    }
+}
+
+/// Set how long `recv` waits for a packet, `None` waiting forever
+unsafe fn set_receive_timeout(fd: c_int, timeout: Option<Duration>) {
+    let tv = match timeout {
+        // A zero timeout would wait forever
+        Some(t) => timeval {
+            tv_sec: t.as_secs() as time_t,
+            tv_usec: ::std::cmp::max(t.subsec_nanos() / 1000, if t.as_secs() == 0 { 1 } else { 0 }) as suseconds_t
+        },
+        None => timeval { tv_sec: 0, tv_usec: 0 }
+    };
+    setsockopt(fd, SOL_SOCKET, SO_RCVTIMEO, &tv as *const timeval as *const c_void,
+               mem::size_of::<timeval>() as socklen_t);
+}
+
+/// Whether the last `recv` returned without a packet because of its timeout or a signal
+fn timed_out() -> bool {
+    match io::Error::last_os_error().raw_os_error() {
+        Some(EAGAIN) | Some(EINTR) => true,
+        _ => false
+    }
 }
@@ -0,0 +1,251 @@
+//! HTTP/1.x request parsing
+//!
+//! `Request::parse` reads the request line and header fields from the start of a TCP payload,
+//! so it can be called on the first segment of a request from a `VerdictHandler`.
+//! Header fields continuing in later segments are reported by `Request::is_complete`;
+//! a `stream::StreamReassembler` can hold the segments until they have arrived.
+
+use std::error::Error as Base;
+use std::fmt;
+use std::str;
+
+/// Reasons a request could not be parsed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HttpError {
+    /// The request line has not arrived in full
+    Incomplete,
+    /// The data does not start with an HTTP/1.x request line
+    NotHttp,
+    /// A header field is malformed
+    Malformed,
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason())
+    }
+}
+
+impl Base for HttpError {
+    fn description(&self) -> &str {
+        self.reason()
+    }
+}
+
+impl HttpError {
+    fn reason(&self) -> &'static str {
+        match *self {
+            HttpError::Incomplete => "Incomplete request line",
+            HttpError::NotHttp => "Not an HTTP/1.x request",
+            HttpError::Malformed => "Malformed header field",
+        }
+    }
+}
+
+/// A header field
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Field<'a> {
+    /// The field name, in the case it was sent
+    pub name: &'a str,
+    /// The field value, without surrounding whitespace
+    pub value: &'a [u8],
+}
+
+/// The request line and header fields of an HTTP/1.x request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request<'a> {
+    /// The method, such as `GET`
+    pub method: &'a str,
+    /// The request target, such as `/index.html?q=1` or `http://example.com/`
+    pub target: &'a str,
+    /// The minor version, 0 for HTTP/1.0 and 1 for HTTP/1.1
+    pub version: u8,
+    /// The header fields that arrived, in order
+    pub fields: Vec<Field<'a>>,
+    /// The length of the request line and header section, once it is complete
+    header_len: Option<usize>,
+}
+
+impl<'a> Request<'a> {
+    /// Parse the start of a request
+    ///
+    /// Only the request line needs to be present; header fields are parsed as far as whole lines arrived.
+    pub fn parse(data: &'a [u8]) -> Result<Request<'a>, HttpError> {
+        let (line, mut rest) = match split_line(data) {
+            Some(split) => split,
+            None if looks_like_request(data) => return Err(HttpError::Incomplete),
+            None => return Err(HttpError::NotHttp)
+        };
+        let mut request = match request_line(line) {
+            Some(request) => request,
+            None => return Err(HttpError::NotHttp)
+        };
+        while let Some((line, next)) = split_line(rest) {
+            if line.is_empty() {
+                request.header_len = Some(data.len() - next.len());
+                break;
+            }
+            request.fields.push(field(line)?);
+            rest = next;
+        }
+        Ok(request)
+    }
+
+    /// Whether the whole header section arrived
+    pub fn is_complete(&self) -> bool {
+        self.header_len.is_some()
+    }
+
+    /// The length of the request line and header section, up to the body, once complete
+    pub fn header_len(&self) -> Option<usize> {
+        self.header_len
+    }
+
+    /// The value of the first header field named `name`, compared case-insensitively
+    pub fn field(&self, name: &str) -> Option<&'a [u8]> {
+        self.fields.iter().find(|f| f.name.eq_ignore_ascii_case(name)).map(|f| f.value)
+    }
+
+    /// The host the request is for, without the port
+    ///
+    /// Taken from an absolute-form target, or else the `Host` field.
+    /// Host names are case-insensitive, so compare them with `eq_ignore_ascii_case`.
+    pub fn host(&self) -> Option<&'a str> {
+        let authority = match self.authority() {
+            Some(authority) => authority,
+            None => match self.field("Host").and_then(|h| str::from_utf8(h).ok()) {
+                Some(host) => host,
+                None => return None
+            }
+        };
+        // Strip the port, minding the colons of bracketed IPv6 literals
+        let host = match authority.starts_with('[') {
+            true => match authority.find(']') {
+                Some(end) => &authority[..end + 1],
+                None => authority
+            },
+            false => authority.split(':').next().unwrap_or(authority)
+        };
+        match host.is_empty() {
+            true => None,
+            false => Some(host)
+        }
+    }
+
+    /// The path of the target, without the query
+    pub fn path(&self) -> &'a str {
+        let target = match self.scheme_end() {
+            Some(start) => {
+                let rest = &self.target[start..];
+                match rest.find('/') {
+                    Some(slash) => &rest[slash..],
+                    None => "/"
+                }
+            },
+            None => self.target
+        };
+        target.split('?').next().unwrap_or(target)
+    }
+
+    /// The query of the target, after the `?`
+    pub fn query(&self) -> Option<&'a str> {
+        self.target.find('?').map(|q| &self.target[q + 1..])
+    }
+
+    /// The value of the `User-Agent` field
+    pub fn user_agent(&self) -> Option<&'a str> {
+        self.field("User-Agent").and_then(|u| str::from_utf8(u).ok())
+    }
+
+    /// The authority of an absolute-form target, or of the target of a CONNECT
+    fn authority(&self) -> Option<&'a str> {
+        if self.method == "CONNECT" {
+            return Some(self.target);
+        }
+        self.scheme_end().map(|start| {
+            let rest = &self.target[start..];
+            let end = rest.find(|c| c == '/' || c == '?').unwrap_or(rest.len());
+            let authority = &rest[..end];
+            // Drop any userinfo
+            authority.rsplit('@').next().unwrap_or(authority)
+        })
+    }
+
+    /// Where the authority of an absolute-form target starts
+    fn scheme_end(&self) -> Option<usize> {
+        self.target.find("://").map(|i| i + 3)
+    }
+}
+
+/// Split off a line ending in CRLF or a bare LF
+fn split_line(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    data.iter().position(|&b| b == b'\n').map(|end| {
+        let line = &data[..end];
+        let line = match line.last() {
+            Some(&b'\r') => &line[..line.len() - 1],
+            _ => line
+        };
+        (line, &data[end + 1..])
+    })
+}
+
+/// Whether an unterminated line could still become a request line
+fn looks_like_request(data: &[u8]) -> bool {
+    let method_len = data.iter().take_while(|&&b| is_token(b)).count();
+    method_len > 0 && (method_len == data.len() || data[method_len] == b' ') || data.is_empty()
+}
+
+fn request_line(line: &[u8]) -> Option<Request<'_>> {
+    let line = match str::from_utf8(line) {
+        Ok(line) => line,
+        Err(_) => return None
+    };
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v), None) => (m, t, v),
+        _ => return None
+    };
+    if method.is_empty() || !method.bytes().all(is_token) || target.is_empty() || !target.bytes().all(|b| b > 0x20 && b < 0x7f) {
+        return None;
+    }
+    let version = match version {
+        "HTTP/1.0" => 0,
+        "HTTP/1.1" => 1,
+        _ => return None
+    };
+    Some(Request { method: method, target: target, version: version, fields: Vec::new(), header_len: None })
+}
+
+fn field(line: &[u8]) -> Result<Field<'_>, HttpError> {
+    let colon = match line.iter().position(|&b| b == b':') {
+        Some(colon) if colon > 0 => colon,
+        _ => return Err(HttpError::Malformed)
+    };
+    // Whitespace before the colon, or obsolete line folding, is rejected as in RFC 7230
+    let name = &line[..colon];
+    if !name.iter().all(|&b| is_token(b)) {
+        return Err(HttpError::Malformed);
+    }
+    let mut value = &line[colon + 1..];
+    while let Some((&b, rest)) = value.split_first() {
+        match b {
+            b' ' | b'\t' => value = rest,
+            _ => break
+        }
+    }
+    while let Some((&b, rest)) = value.split_last() {
+        match b {
+            b' ' | b'\t' => value = rest,
+            _ => break
+        }
+    }
+    Ok(Field { name: str::from_utf8(name).unwrap(), value: value })
+}
+
+/// Whether `b` may appear in a method or field name
+fn is_token(b: u8) -> bool {
+    match b {
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => true,
+        _ => b.is_ascii_alphanumeric()
+    }
+}
@@ -0,0 +1,97 @@
+//! Packet injection
+//!
+//! `Injector` sends whole IP packets through raw sockets, e.g. to duplicate a queued packet.
+//! Injected packets traverse the `OUTPUT` chain, so a queue on that chain sees them again
+//! unless they are told apart, e.g. with `set_mark` and `-m mark ! --mark 0x2 -j NFQUEUE`.
+//! Opening the sockets needs `CAP_NET_RAW`, and marking them `CAP_NET_ADMIN`.
+
+use libc::*;
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::ptr;
+use message::Packet;
+
+const IPV6_HDRINCL: c_int = 36;
+const SO_MARK: c_int = 36;
+
+/// Raw sockets to send IPv4 and IPv6 packets, headers included
+pub struct Injector {
+    v4: c_int,
+    v6: c_int,
+}
+
+impl Drop for Injector {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.v4);
+            close(self.v6);
+        }
+    }
+}
+
+impl Injector {
+    /// Open the raw sockets
+    pub fn new() -> io::Result<Injector> {
+        unsafe {
+            let v4 = socket(AF_INET, SOCK_RAW, IPPROTO_RAW);
+            if v4 < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let v6 = socket(AF_INET6, SOCK_RAW, IPPROTO_RAW);
+            if v6 < 0 {
+                let e = io::Error::last_os_error();
+                close(v4);
+                return Err(e);
+            }
+            let injector = Injector { v4: v4, v6: v6 };
+            setsockopt_int(v6, IPPROTO_IPV6, IPV6_HDRINCL, 1)?;
+            Ok(injector)
+        }
+    }
+
+    /// Mark every packet sent
+    pub fn set_mark(&mut self, mark: u32) -> io::Result<()> {
+        unsafe {
+            setsockopt_int(self.v4, SOL_SOCKET, SO_MARK, mark as c_int)?;
+            setsockopt_int(self.v6, SOL_SOCKET, SO_MARK, mark as c_int)
+        }
+    }
+
+    /// Send a raw IP packet to its destination
+    pub fn send(&self, data: &[u8]) -> io::Result<()> {
+        let packet = match Packet::new(data) {
+            Ok(packet) => packet,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{}", e)))
+        };
+        let sent = unsafe {
+            match packet.network.daddr() {
+                IpAddr::V4(addr) => {
+                    let mut sin: sockaddr_in = mem::zeroed();
+                    sin.sin_family = AF_INET as sa_family_t;
+                    sin.sin_addr.s_addr = u32::from(addr).to_be();
+                    sendto(self.v4, data.as_ptr() as *const c_void, data.len() as size_t, 0,
+                           &sin as *const sockaddr_in as *const sockaddr, mem::size_of::<sockaddr_in>() as socklen_t)
+                },
+                IpAddr::V6(addr) => {
+                    let mut sin6: sockaddr_in6 = mem::zeroed();
+                    sin6.sin6_family = AF_INET6 as sa_family_t;
+                    ptr::copy_nonoverlapping(addr.octets().as_ptr(), &mut sin6.sin6_addr as *mut in6_addr as *mut u8, 16);
+                    sendto(self.v6, data.as_ptr() as *const c_void, data.len() as size_t, 0,
+                           &sin6 as *const sockaddr_in6 as *const sockaddr, mem::size_of::<sockaddr_in6>() as socklen_t)
+                }
+            }
+        };
+        match sent {
+            n if n < 0 => Err(io::Error::last_os_error()),
+            _ => Ok(())
+        }
+    }
+}
+
+unsafe fn setsockopt_int(fd: c_int, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+    match setsockopt(fd, level, name, &value as *const c_int as *const c_void, mem::size_of::<c_int>() as socklen_t) {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error())
+    }
+}
@@ -14,10 +14,33 @@ mod ffi;
 mod error;
 mod util;
 mod lock;
+mod crypto;
+mod timer;
 
 pub mod handle;
 pub mod queue;
 pub mod message;
+pub mod seqadj;
+pub mod reassembly;
+pub mod stream;
+pub mod tls;
+pub mod http;
+pub mod quic;
+pub mod flow;
+pub mod cache;
+pub mod ratelimit;
+pub mod inject;
+pub mod netem;
+pub mod cidr;
+pub mod rules;
+pub mod bpf;
+pub mod shared;
+pub mod domain;
+pub mod capture;
+pub mod replay;
+pub mod mock;
+#[cfg(feature = "netns")]
+pub mod netns;
 
-//#[cfg(test)]
-//mod test;
+#[cfg(test)]
+mod test;
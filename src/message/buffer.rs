@@ -0,0 +1,527 @@
+//! Owned packet buffers for rewriting packets
+//!
+//! Analagous to the `pktb` and mangling functions of libnetfilter_queue.
+
+use std::error::Error as Base;
+use std::fmt;
+use std::net::IpAddr;
+use util::*;
+use super::checksum;
+use super::packet::*;
+use super::tcp_options::*;
+
+/// The largest TCP header, options included
+const TCP_MAX_HEADER: usize = 60;
+
+/// Reasons a `PacketBuffer` could not be rewritten
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MangleError {
+    /// The packet could not be decoded
+    Decode(DecodeError),
+    /// The address family does not match the packet's
+    Family,
+    /// The packet has no such field, e.g. ports of an ICMP message or of a non-first fragment
+    Field,
+    /// The TCP header has no room for more options
+    Space,
+    /// The rewritten packet would be longer than its length field allows
+    Length,
+    /// The range lies outside the payload
+    Range,
+    /// The datagram is a fragment, whose length cannot change without moving the fragments after it
+    Fragment,
+}
+
+impl fmt::Display for MangleError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            MangleError::Decode(ref e) => write!(formatter, "{}: {}", self.reason(), e),
+            _ => formatter.write_str(self.reason())
+        }
+    }
+}
+
+impl Base for MangleError {
+    fn description(&self) -> &str {
+        self.reason()
+    }
+}
+
+impl MangleError {
+    fn reason(&self) -> &'static str {
+        match *self {
+            MangleError::Decode(_) => "Failed to decode packet",
+            MangleError::Family => "Address family does not match the packet",
+            MangleError::Field => "Packet has no such field",
+            MangleError::Space => "No room for TCP options",
+            MangleError::Length => "Packet too long",
+            MangleError::Range => "Range outside the payload",
+            MangleError::Fragment => "Cannot resize a fragment",
+        }
+    }
+}
+
+/// An owned, complete copy of a packet that can be rewritten and returned with a verdict
+///
+/// Bytes changed through `data_mut` leave the checksums stale;
+/// they are recomputed by `finish`, which is called when the buffer is sent with a verdict.
+pub struct PacketBuffer {
+    data: Vec<u8>,
+    stale: bool,
+}
+
+impl PacketBuffer {
+    /// Take ownership of a raw IP packet
+    ///
+    /// The whole datagram must be present, as the kernel replaces the queued packet with the returned one.
+    pub fn new(data: Vec<u8>) -> Result<PacketBuffer, DecodeError> {
+        match Packet::new(&data) {
+            Ok(ref packet) if packet.is_complete() => (),
+            Ok(_) => return Err(DecodeError::Truncated),
+            Err(e) => return Err(e)
+        }
+        Ok(PacketBuffer { data: data, stale: false })
+    }
+
+    /// The packet as it currently stands
+    ///
+    /// Checksums may be stale until `finish` is called.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Raw mutable access to the packet
+    ///
+    /// The IP header and transport checksums will be recomputed in full by `finish`.
+    /// Lengths are not adjusted, so the length fields must be kept consistent with the data.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        self.stale = true;
+        &mut self.data
+    }
+
+    /// Decode the packet as it currently stands
+    ///
+    /// Only fails if the packet was made undecodable through `data_mut`.
+    pub fn decode(&self) -> Result<Packet<'_>, DecodeError> {
+        Packet::new(&self.data)
+    }
+
+    /// Whether checksums will be recomputed by `finish`
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// Recompute stale checksums and return the finished packet
+    pub fn finish(&mut self) -> &[u8] {
+        if self.stale {
+            checksum::fill_network(&mut self.data);
+            checksum::fill_transport(&mut self.data);
+            self.stale = false;
+        }
+        &self.data
+    }
+
+    /// Consume the buffer, returning the finished packet
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.finish();
+        self.data
+    }
+
+    /// Set the source address, keeping the IPv4 header and transport checksums correct
+    pub fn set_saddr(&mut self, addr: IpAddr) -> Result<(), MangleError> {
+        self.set_addr(addr, 12, 8)
+    }
+
+    /// Set the destination address, keeping the IPv4 header and transport checksums correct
+    pub fn set_daddr(&mut self, addr: IpAddr) -> Result<(), MangleError> {
+        self.set_addr(addr, 16, 24)
+    }
+
+    /// Set the TCP or UDP source port, keeping the transport checksum correct
+    pub fn set_src_port(&mut self, port: u16) -> Result<(), MangleError> {
+        self.set_port(port, 0)
+    }
+
+    /// Set the TCP or UDP destination port, keeping the transport checksum correct
+    pub fn set_dst_port(&mut self, port: u16) -> Result<(), MangleError> {
+        self.set_port(port, 2)
+    }
+
+    /// Swap the source and destination addresses, and ports if any, turning the packet back to its sender
+    ///
+    /// Together with `set_payload`, this answers a request in place, e.g. with a `dns::Response`.
+    /// The TTL is reset to 64, as the reply starts afresh.
+    /// The kernel only routes the reply back if the packet was queued before the routing decision, as in `PREROUTING`.
+    pub fn reflect(&mut self) -> Result<(), MangleError> {
+        let (saddr, daddr, ports) = match self.decode() {
+            Ok(p) => (p.network.saddr(), p.network.daddr(), p.transport.and_then(|t| match (t.src_port(), t.dst_port()) {
+                (Some(s), Some(d)) => Some((s, d)),
+                _ => None
+            })),
+            Err(e) => return Err(MangleError::Decode(e))
+        };
+        self.set_saddr(daddr)?;
+        self.set_daddr(saddr)?;
+        if let Some((sport, dport)) = ports {
+            self.set_src_port(dport)?;
+            self.set_dst_port(sport)?;
+        }
+        self.set_ttl(64)
+    }
+
+    /// Set the TTL of IPv4 or the hop limit of IPv6, keeping the IPv4 header checksum correct
+    pub fn set_ttl(&mut self, ttl: u8) -> Result<(), MangleError> {
+        match self.layout()?.ipv4 {
+            true => {
+                let new = [ttl, self.data[9]];
+                self.replace_network(8, &new);
+            },
+            false => self.data[7] = ttl,
+        }
+        Ok(())
+    }
+
+    /// Set the differentiated services code point, keeping the IPv4 header checksum correct
+    ///
+    /// Only the low six bits of `dscp` are used.
+    pub fn set_dscp(&mut self, dscp: u8) -> Result<(), MangleError> {
+        let ecn = self.traffic_class()? & 0x03;
+        self.set_traffic_class((dscp << 2) | ecn)
+    }
+
+    /// Set the explicit congestion notification bits, keeping the IPv4 header checksum correct
+    ///
+    /// Only the low two bits of `ecn` are used.
+    pub fn set_ecn(&mut self, ecn: u8) -> Result<(), MangleError> {
+        let dscp = self.traffic_class()? & 0xfc;
+        self.set_traffic_class(dscp | (ecn & 0x03))
+    }
+
+    /// Replace `remove` bytes of the application payload at `offset` with `insert`
+    ///
+    /// The IP and UDP lengths are adjusted, and checksums recomputed by `finish`.
+    /// Changing the length of a TCP payload shifts the sequence numbers of the rest of the connection,
+    /// see `seqadj::SeqAdjust`.
+    /// A fragment cannot change length, as the offsets of the fragments following it would no longer fit.
+    pub fn splice_payload(&mut self, offset: usize, remove: usize, insert: &[u8]) -> Result<(), MangleError> {
+        let (start, len) = match self.decode() {
+            Ok(p) => (p.payload_offset(), p.payload.len()),
+            Err(e) => return Err(MangleError::Decode(e))
+        };
+        if offset + remove > len {
+            return Err(MangleError::Range);
+        }
+        self.splice(start + offset, remove, insert)
+    }
+
+    /// Replace the whole application payload
+    pub fn set_payload(&mut self, payload: &[u8]) -> Result<(), MangleError> {
+        let len = match self.decode() {
+            Ok(p) => p.payload.len(),
+            Err(e) => return Err(MangleError::Decode(e))
+        };
+        self.splice_payload(0, len, payload)
+    }
+
+    /// Set the TCP sequence number, keeping the checksum correct
+    pub fn set_seq(&mut self, seq: u32) -> Result<(), MangleError> {
+        let start = self.tcp_offset()?;
+        self.replace_transport(start + 4, &be32_bytes(seq))
+    }
+
+    /// Set the TCP acknowledgement number, keeping the checksum correct
+    pub fn set_ack(&mut self, ack: u32) -> Result<(), MangleError> {
+        let start = self.tcp_offset()?;
+        self.replace_transport(start + 8, &be32_bytes(ack))
+    }
+
+    /// Set the TCP flags, see the `TCP_*` constants
+    pub fn set_tcp_flags(&mut self, flags: u8) -> Result<(), MangleError> {
+        let start = self.tcp_offset()?;
+        self.replace_transport(start + 13, &[flags])
+    }
+
+    /// Turn a TCP segment into a reset from the same sender
+    ///
+    /// The payload is removed and the sequence number kept,
+    /// so the receiver accepts the reset if it expected this segment next.
+    /// The acknowledgement is kept if the segment carried one.
+    pub fn make_reset(&mut self) -> Result<(), MangleError> {
+        let ack = self.tcp()?.flags() & TCP_ACK;
+        self.set_payload(&[])?;
+        self.set_tcp_flags(TCP_RST | ack)
+    }
+
+    /// Lower the maximum segment size option of a SYN to at most `mss`
+    ///
+    /// Returns whether the option was changed.
+    /// A SYN without the option is left alone, as the peer then assumes the protocol minimum.
+    pub fn clamp_mss(&mut self, mss: u16) -> Result<bool, MangleError> {
+        let offset = {
+            let tcp = self.tcp()?;
+            match (tcp.has_flags(TCP_SYN), tcp.option(TCPOPT_MSS)) {
+                (true, Some(o)) if o.data.len() == 2 && be16(o.data, 0) > mss => o.offset,
+                _ => return Ok(false)
+            }
+        };
+        let at = self.tcp_offset()? + 20 + offset + 2;
+        self.replace_transport(at, &[(mss >> 8) as u8, mss as u8])?;
+        Ok(true)
+    }
+
+    /// Set the first TCP option of `kind` to `data`, inserting it if there is none
+    ///
+    /// An option of the same size is overwritten in place, otherwise the options are rebuilt
+    /// and the TCP data offset and IP length are adjusted.
+    pub fn set_tcp_option(&mut self, kind: u8, data: &[u8]) -> Result<(), MangleError> {
+        let (offset, size) = match self.tcp()?.option(kind) {
+            Some(o) => (o.offset, o.data.len()),
+            None => return self.insert_tcp_option(kind, data)
+        };
+        if size == data.len() {
+            let at = self.tcp_offset()? + 20 + offset + 2;
+            return self.replace_transport(at, data);
+        }
+        let mut options = self.tcp_options_without(|o| o.offset == offset)?;
+        push_option(&mut options, kind, data);
+        self.set_tcp_options(options)
+    }
+
+    /// Append a TCP option, growing the TCP header
+    ///
+    /// Padding at the end of the existing options is reused.
+    pub fn insert_tcp_option(&mut self, kind: u8, data: &[u8]) -> Result<(), MangleError> {
+        let mut options = self.tcp_options_without(|_| false)?;
+        push_option(&mut options, kind, data);
+        self.set_tcp_options(options)
+    }
+
+    /// Overwrite every TCP option of `kind` with `NOP`s, keeping the header length
+    ///
+    /// Returns whether any option was removed.
+    pub fn remove_tcp_option(&mut self, kind: u8) -> Result<bool, MangleError> {
+        let found: Vec<(usize, usize)> = self.tcp()?.options_iter()
+            .filter(|o| o.kind == kind)
+            .map(|o| (o.offset, o.size()))
+            .collect();
+        let start = self.tcp_offset()? + 20;
+        for &(offset, size) in found.iter() {
+            self.replace_transport(start + offset, &vec![TCPOPT_NOP; size])?;
+        }
+        Ok(!found.is_empty())
+    }
+
+    /// Replace the TCP options with `options`, padded to a multiple of four bytes
+    fn set_tcp_options(&mut self, mut options: Vec<u8>) -> Result<(), MangleError> {
+        while options.len() % 4 != 0 {
+            options.push(TCPOPT_EOL);
+        }
+        if options.len() + 20 > TCP_MAX_HEADER {
+            return Err(MangleError::Space);
+        }
+        let old = {
+            let tcp = self.tcp()?;
+            // A data offset past the end of the segment leaves nothing to splice the options into
+            if tcp.header_len() > tcp.data().len() {
+                return Err(MangleError::Decode(DecodeError::Truncated));
+            }
+            tcp.header_len() - 20
+        };
+        let start = self.tcp_offset()?;
+        self.splice(start + 20, old, &options)?;
+        let offset = (((options.len() + 20) / 4) as u8) << 4;
+        self.data[start + 12] = offset | (self.data[start + 12] & 0x0f);
+        Ok(())
+    }
+
+    /// The TCP options up to the end of the option list, leaving out those matching `skip`
+    fn tcp_options_without<F: Fn(&TcpOption) -> bool>(&self, skip: F) -> Result<Vec<u8>, MangleError> {
+        let tcp = self.tcp()?;
+        let raw = tcp.options();
+        let mut options = Vec::with_capacity(raw.len());
+        for option in tcp.options_iter().filter(|o| !skip(o)) {
+            options.extend_from_slice(&raw[option.offset..option.offset + option.size()]);
+        }
+        Ok(options)
+    }
+
+    /// Replace `remove` bytes at `offset` with `insert`, adjusting the IP and UDP lengths
+    ///
+    /// Checksums are left stale.
+    fn splice(&mut self, offset: usize, remove: usize, insert: &[u8]) -> Result<(), MangleError> {
+        let layout = self.layout()?;
+        if layout.fragment && insert.len() != remove {
+            return Err(MangleError::Fragment);
+        }
+        // Link layer padding past the end of the datagram is not counted in its length
+        let len = layout.datagram_len + insert.len() - remove;
+        let limit = match layout.ipv4 {
+            true => 0xffff,
+            false => 40 + 0xffff
+        };
+        if len > limit {
+            return Err(MangleError::Length);
+        }
+        self.data.splice(offset..offset + remove, insert.iter().cloned());
+        match layout.ipv4 {
+            true => write16(&mut self.data, 2, len as u16),
+            false => write16(&mut self.data, 4, (len - 40) as u16)
+        }
+        match (layout.protocol, layout.transport) {
+            (IPPROTO_UDP, Some(t)) if offset >= t => write16(&mut self.data, t + 4, (len - t) as u16),
+            _ => ()
+        }
+        self.stale = true;
+        Ok(())
+    }
+
+    /// Overwrite bytes covered by the transport checksum, updating it
+    fn replace_transport(&mut self, offset: usize, new: &[u8]) -> Result<(), MangleError> {
+        let layout = self.layout()?;
+        let at = match layout.checksum {
+            Some(at) => at,
+            None => return Err(MangleError::Field)
+        };
+        // Incremental updates work on whole 16-bit words
+        let start = offset & !1;
+        let end = ::std::cmp::min((offset + new.len() + 1) & !1, self.data.len());
+        let old = self.data[start..end].to_vec();
+        self.data[offset..offset + new.len()].copy_from_slice(new);
+        if !self.stale {
+            let updated = self.data[start..end].to_vec();
+            self.update_transport_checksum(at, layout.protocol, &old, &updated);
+        }
+        Ok(())
+    }
+
+    fn tcp_offset(&self) -> Result<usize, MangleError> {
+        self.tcp()?;
+        Ok(self.layout()?.transport.unwrap())
+    }
+
+    fn tcp(&self) -> Result<Tcp<'_>, MangleError> {
+        match self.decode() {
+            Ok(Packet { transport: Some(Transport::Tcp(tcp)), .. }) => Ok(tcp),
+            Ok(_) => Err(MangleError::Field),
+            Err(e) => Err(MangleError::Decode(e))
+        }
+    }
+
+    fn traffic_class(&self) -> Result<u8, MangleError> {
+        match self.decode() {
+            Ok(Packet { network: Network::Ipv4(ip), .. }) => Ok(ip.data()[1]),
+            Ok(Packet { network: Network::Ipv6(ip), .. }) => Ok(ip.traffic_class()),
+            Err(e) => Err(MangleError::Decode(e))
+        }
+    }
+
+    fn set_traffic_class(&mut self, class: u8) -> Result<(), MangleError> {
+        match self.layout()?.ipv4 {
+            true => {
+                let new = [self.data[0], class];
+                self.replace_network(0, &new);
+            },
+            false => {
+                self.data[0] = 0x60 | (class >> 4);
+                self.data[1] = (class << 4) | (self.data[1] & 0x0f);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_addr(&mut self, addr: IpAddr, ipv4_offset: usize, ipv6_offset: usize) -> Result<(), MangleError> {
+        let layout = self.layout()?;
+        let (offset, octets) = match (layout.ipv4, addr) {
+            (true, IpAddr::V4(a)) => (ipv4_offset, a.octets().to_vec()),
+            (false, IpAddr::V6(a)) => (ipv6_offset, a.octets().to_vec()),
+            _ => return Err(MangleError::Family)
+        };
+        // ICMP has no pseudo-header, every other checksum covers the addresses
+        match layout.checksum {
+            Some(at) if layout.protocol != IPPROTO_ICMP => {
+                let old = self.data[offset..offset + octets.len()].to_vec();
+                self.update_transport_checksum(at, layout.protocol, &old, &octets);
+            },
+            _ => ()
+        }
+        match layout.ipv4 {
+            true => self.replace_network(offset, &octets),
+            false => self.data[offset..offset + octets.len()].copy_from_slice(&octets),
+        }
+        Ok(())
+    }
+
+    fn set_port(&mut self, port: u16, field: usize) -> Result<(), MangleError> {
+        let layout = self.layout()?;
+        let (offset, at) = match (layout.protocol, layout.transport, layout.checksum) {
+            (IPPROTO_TCP, Some(offset), Some(at)) | (IPPROTO_UDP, Some(offset), Some(at)) => (offset + field, at),
+            _ => return Err(MangleError::Field)
+        };
+        let new = [(port >> 8) as u8, port as u8];
+        let old = [self.data[offset], self.data[offset + 1]];
+        self.update_transport_checksum(at, layout.protocol, &old, &new);
+        self.data[offset..offset + 2].copy_from_slice(&new);
+        Ok(())
+    }
+
+    /// Replace bytes of the IPv4 header at an even `offset`, updating the header checksum
+    fn replace_network(&mut self, offset: usize, new: &[u8]) {
+        let value = checksum::update_bytes(be16(&self.data, 10), &self.data[offset..offset + new.len()], new);
+        write16(&mut self.data, 10, value);
+        self.data[offset..offset + new.len()].copy_from_slice(new);
+    }
+
+    /// Update the transport checksum at `at` for bytes covered by it changing from `old` to `new`
+    fn update_transport_checksum(&mut self, at: usize, protocol: u8, old: &[u8], new: &[u8]) {
+        let current = be16(&self.data, at);
+        let value = match (protocol, checksum::update_bytes(current, old, new)) {
+            // A zero UDP checksum means none was computed
+            (IPPROTO_UDP, _) if current == 0 => return,
+            (IPPROTO_UDP, 0) => 0xffff,
+            (_, value) => value
+        };
+        write16(&mut self.data, at, value);
+    }
+
+    fn layout(&self) -> Result<Layout, MangleError> {
+        let packet = match self.decode() {
+            Ok(p) => p,
+            Err(e) => return Err(MangleError::Decode(e))
+        };
+        let transport = packet.transport.map(|_| packet.transport_offset());
+        Ok(Layout {
+            ipv4: match packet.network { Network::Ipv4(_) => true, Network::Ipv6(_) => false },
+            protocol: packet.protocol,
+            transport: transport,
+            checksum: transport.and_then(|t| checksum::transport_checksum_offset(packet.protocol).map(|c| t + c)),
+            datagram_len: packet.datagram_len(),
+            fragment: packet.fragment.is_some(),
+        })
+    }
+}
+
+/// Where the fields touched by rewrites live in a packet
+struct Layout {
+    ipv4: bool,
+    protocol: u8,
+    /// Offset of the transport header, if it is in this packet
+    transport: Option<usize>,
+    /// Offset of the transport checksum, if it is in this packet
+    checksum: Option<usize>,
+    /// The length of the datagram, as stated by the IP header
+    datagram_len: usize,
+    /// Whether the datagram is a fragment
+    fragment: bool,
+}
+
+fn be32_bytes(value: u32) -> [u8; 4] {
+    [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+fn push_option(options: &mut Vec<u8>, kind: u8, data: &[u8]) {
+    options.push(kind);
+    if kind != TCPOPT_EOL && kind != TCPOPT_NOP {
+        options.push((data.len() + 2) as u8);
+        options.extend_from_slice(data);
+    }
+}
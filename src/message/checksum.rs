@@ -0,0 +1,172 @@
+//! Internet checksums
+//!
+//! Full computation and verification of IPv4 header, TCP, UDP, ICMP and ICMPv6 checksums,
+//! and incremental updates following [RFC 1624](https://tools.ietf.org/html/rfc1624).
+
+use std::net::IpAddr;
+use util::*;
+use super::packet::*;
+
+/// Add `data` to a running one's complement sum
+///
+/// `data` is summed as big-endian 16-bit words, an odd trailing byte is padded with zero.
+/// Only the last chunk of a checksummed region may have an odd length.
+pub fn sum(data: &[u8], initial: u32) -> u32 {
+    let mut sum = initial as u64;
+    for chunk in data.chunks(2) {
+        sum += match chunk.len() {
+            2 => be16(chunk, 0) as u64,
+            _ => (chunk[0] as u64) << 8
+        };
+    }
+    fold(sum)
+}
+
+/// Fold a running sum and complement it into a checksum
+pub fn finish(sum: u32) -> u16 {
+    !(fold(sum as u64) as u16)
+}
+
+/// Compute the checksum of `data`
+pub fn checksum(data: &[u8]) -> u16 {
+    finish(sum(data, 0))
+}
+
+/// The running sum of a TCP, UDP or ICMPv6 pseudo-header
+///
+/// `length` is the length of the transport header and payload.
+/// Mixing address families sums nothing but the protocol and length.
+pub fn pseudo_header(saddr: IpAddr, daddr: IpAddr, protocol: u8, length: u32) -> u32 {
+    let mut total = 0;
+    match (saddr, daddr) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            total = sum(&s.octets(), total);
+            total = sum(&d.octets(), total);
+        },
+        (IpAddr::V6(s), IpAddr::V6(d)) => {
+            total = sum(&s.octets(), total);
+            total = sum(&d.octets(), total);
+        },
+        _ => ()
+    }
+    fold(total as u64 + protocol as u64 + (length >> 16) as u64 + (length & 0xffff) as u64)
+}
+
+/// Update `checksum` after a 16-bit word of the checksummed data changed from `old` to `new`
+///
+/// This is equation 3 of RFC 1624, which never produces a negative zero.
+pub fn update(checksum: u16, old: u16, new: u16) -> u16 {
+    finish(fold(!checksum as u64 + !old as u64 + new as u64))
+}
+
+/// Update `checksum` after a 32-bit word of the checksummed data changed from `old` to `new`
+pub fn update_u32(checksum: u16, old: u32, new: u32) -> u16 {
+    let checksum = update(checksum, (old >> 16) as u16, (new >> 16) as u16);
+    update(checksum, old as u16, new as u16)
+}
+
+/// Update `checksum` after the bytes `old` were replaced with `new`
+///
+/// The replaced bytes must start at an even offset into the checksummed data and be of equal length.
+pub fn update_bytes(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    assert_eq!(old.len(), new.len());
+    let removed = !(sum(old, 0) as u16);
+    finish(fold(!checksum as u64 + removed as u64 + sum(new, 0) as u64))
+}
+
+/// Offset of the checksum field in the header of a transport `protocol`
+pub fn transport_checksum_offset(protocol: u8) -> Option<usize> {
+    match protocol {
+        IPPROTO_TCP => Some(16),
+        IPPROTO_UDP => Some(6),
+        IPPROTO_ICMP | IPPROTO_ICMPV6 => Some(2),
+        _ => None
+    }
+}
+
+impl<'a> Packet<'a> {
+    /// Verify the IPv4 header checksum
+    ///
+    /// `None` for IPv6, which has no header checksum, or if the header was not copied in full.
+    pub fn verify_network_checksum(&self) -> Option<bool> {
+        match self.network {
+            Network::Ipv4(ref ip) if self.extents.network == Extent::Full =>
+                Some(checksum(&ip.data()[..ip.header_len()]) == 0),
+            _ => None
+        }
+    }
+
+    /// Verify the TCP, UDP, ICMP or ICMPv6 checksum
+    ///
+    /// `None` if the datagram was not copied in full, is a fragment, or carries another protocol.
+    /// A zero UDP checksum over IPv4 means no checksum was computed, and always verifies.
+    pub fn verify_transport_checksum(&self) -> Option<bool> {
+        match self.transport {
+            _ if !self.is_complete() || self.fragment.is_some() => None,
+            Some(Transport::Udp(ref udp)) if udp.checksum() == 0 => match self.network {
+                Network::Ipv4(_) => Some(true),
+                Network::Ipv6(_) => Some(false),
+            },
+            Some(_) => Some(finish(self.transport_sum()) == 0),
+            None => None
+        }
+    }
+
+    /// The running sum over the pseudo-header (where the protocol has one) and the transport layer
+    fn transport_sum(&self) -> u32 {
+        let segment = &self.data()[self.transport_offset()..];
+        let initial = match self.protocol {
+            IPPROTO_ICMP => 0,
+            _ => pseudo_header(self.network.saddr(), self.network.daddr(), self.protocol, segment.len() as u32)
+        };
+        sum(segment, initial)
+    }
+}
+
+/// Recompute the IPv4 header checksum of a packet in place
+///
+/// Does nothing for IPv6 packets, or if the header is truncated.
+pub fn fill_network(data: &mut [u8]) {
+    let header_len = match Packet::new(data) {
+        Ok(Packet { network: Network::Ipv4(ip), extents: Extents { network: Extent::Full, .. }, .. }) => ip.header_len(),
+        _ => return
+    };
+    data[10] = 0;
+    data[11] = 0;
+    let value = checksum(&data[..header_len]);
+    write16(data, 10, value);
+}
+
+/// Recompute the TCP, UDP, ICMP or ICMPv6 checksum of a packet in place
+///
+/// Does nothing if the datagram is incomplete, is a fragment, or carries another protocol.
+/// A zero UDP checksum over IPv4 is left disabled.
+pub fn fill_transport(data: &mut [u8]) {
+    let (offset, udp) = match Packet::new(data) {
+        Ok(ref packet) if packet.transport.is_some() && packet.is_complete() && packet.fragment.is_none() => {
+            let offset = packet.transport_offset() + transport_checksum_offset(packet.protocol).unwrap();
+            let ipv4 = match packet.network { Network::Ipv4(_) => true, Network::Ipv6(_) => false };
+            if packet.protocol == IPPROTO_UDP && ipv4 && be16(data, offset) == 0 {
+                return;
+            }
+            (offset, packet.protocol == IPPROTO_UDP)
+        },
+        _ => return
+    };
+    write16(data, offset, 0);
+    let value = match (Packet::new(data).map(|p| finish(p.transport_sum())), udp) {
+        // A computed UDP checksum of zero is transmitted as all ones
+        (Ok(0), true) => 0xffff,
+        (Ok(value), _) => value,
+        (Err(_), _) => return
+    };
+    write16(data, offset, value);
+}
+
+#[inline]
+fn fold(mut sum: u64) -> u32 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u32
+}
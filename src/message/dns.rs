@@ -0,0 +1,547 @@
+//! DNS messages
+//!
+//! `Dns` is a view over the UDP payload of a DNS query or response, following RFC 1035 and RFC 6891 (EDNS0).
+//! `Response` synthesizes answers, such as NXDOMAIN or sinkhole addresses for blocked names;
+//! a query can be answered in place with `PacketBuffer::reflect` and `PacketBuffer::set_payload`.
+
+use std::error::Error as Base;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use util::*;
+
+/// The well known DNS port
+pub const DNS_PORT: u16 = 53;
+
+/// IPv4 address record
+pub const TYPE_A: u16 = 1;
+/// Name server record
+pub const TYPE_NS: u16 = 2;
+/// Canonical name record
+pub const TYPE_CNAME: u16 = 5;
+/// Start of authority record
+pub const TYPE_SOA: u16 = 6;
+/// Pointer record
+pub const TYPE_PTR: u16 = 12;
+/// Mail exchange record
+pub const TYPE_MX: u16 = 15;
+/// Text record
+pub const TYPE_TXT: u16 = 16;
+/// IPv6 address record
+pub const TYPE_AAAA: u16 = 28;
+/// EDNS0 option pseudo-record
+pub const TYPE_OPT: u16 = 41;
+/// HTTPS service binding record
+pub const TYPE_HTTPS: u16 = 65;
+/// Query for any record
+pub const TYPE_ANY: u16 = 255;
+
+/// The Internet class
+pub const CLASS_IN: u16 = 1;
+
+/// No error
+pub const RCODE_NOERROR: u8 = 0;
+/// The query was malformed
+pub const RCODE_FORMERR: u8 = 1;
+/// The server failed to answer
+pub const RCODE_SERVFAIL: u8 = 2;
+/// The name does not exist
+pub const RCODE_NXDOMAIN: u8 = 3;
+/// The server refused to answer
+pub const RCODE_REFUSED: u8 = 5;
+
+/// The offset of the first question name, the usual target of compression pointers
+const QUESTION_OFFSET: usize = 12;
+/// The longest name on the wire
+const MAX_NAME: usize = 255;
+/// The size that fits any path without EDNS0
+const MAX_UDP: usize = 512;
+
+/// Reasons a DNS message could not be read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DnsError {
+    /// The message ended early
+    Truncated,
+    /// A name was too long, or its compression pointers looped
+    Name,
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason())
+    }
+}
+
+impl Base for DnsError {
+    fn description(&self) -> &str {
+        self.reason()
+    }
+}
+
+impl DnsError {
+    fn reason(&self) -> &'static str {
+        match *self {
+            DnsError::Truncated => "Truncated DNS message",
+            DnsError::Name => "Malformed DNS name",
+        }
+    }
+}
+
+/// A question
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Question {
+    /// The name asked about, without the trailing dot
+    pub name: String,
+    /// The record type asked for, see the `TYPE_*` constants
+    pub qtype: u16,
+    /// The class, see `CLASS_IN`
+    pub qclass: u16,
+}
+
+/// A resource record
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record<'a> {
+    /// The owner name, without the trailing dot
+    pub name: String,
+    /// The record type, see the `TYPE_*` constants
+    pub rtype: u16,
+    /// The class, see `CLASS_IN`
+    pub class: u16,
+    /// The time to live in seconds
+    pub ttl: u32,
+    /// The record data
+    pub data: &'a [u8],
+    /// The offset of the record data in the message, for names compressed against the message
+    pub data_offset: usize,
+}
+
+impl<'a> Record<'a> {
+    /// The address of an A or AAAA record
+    pub fn address(&self) -> Option<IpAddr> {
+        match (self.rtype, self.data.len()) {
+            (TYPE_A, 4) => Some(IpAddr::V4(Ipv4Addr::new(self.data[0], self.data[1], self.data[2], self.data[3]))),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(self.data);
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            },
+            _ => None
+        }
+    }
+}
+
+/// The EDNS0 parameters of the OPT pseudo-record
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Edns<'a> {
+    /// The largest UDP payload the sender can receive
+    pub udp_size: u16,
+    /// The upper eight bits of the extended response code
+    pub extended_rcode: u8,
+    /// The EDNS version
+    pub version: u8,
+    /// Whether DNSSEC records are wanted
+    pub dnssec_ok: bool,
+    /// The options, as code and data
+    pub options: Vec<(u16, &'a [u8])>,
+}
+
+/// A view of a DNS message
+///
+/// Names are decompressed and returned without the trailing dot,
+/// with bytes that are not printable ASCII, dots within labels and backslashes escaped as in zone files.
+#[derive(Clone, Debug)]
+pub struct Dns<'a> {
+    data: &'a [u8],
+    /// The questions
+    pub questions: Vec<Question>,
+    /// The answer section
+    pub answers: Vec<Record<'a>>,
+    /// The authority section
+    pub authorities: Vec<Record<'a>>,
+    /// The additional section, without the OPT pseudo-record
+    pub additionals: Vec<Record<'a>>,
+    /// The EDNS0 parameters, if the message had an OPT pseudo-record
+    pub edns: Option<Edns<'a>>,
+}
+
+impl<'a> Dns<'a> {
+    /// Parse a DNS message, such as a UDP payload
+    pub fn new(data: &'a [u8]) -> Result<Dns<'a>, DnsError> {
+        if data.len() < 12 {
+            return Err(DnsError::Truncated);
+        }
+        let mut dns = Dns {
+            data: data,
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+            edns: None,
+        };
+        let mut reader = Reader::new(data);
+        reader.bytes(12);
+        for _ in 0..be16(data, 4) {
+            let name = dns.read_name(&mut reader)?;
+            match (reader.u16(), reader.u16()) {
+                (Some(qtype), Some(qclass)) => dns.questions.push(Question { name: name, qtype: qtype, qclass: qclass }),
+                _ => return Err(DnsError::Truncated)
+            }
+        }
+        for _ in 0..be16(data, 6) {
+            let record = dns.read_record(&mut reader)?;
+            dns.answers.push(record);
+        }
+        for _ in 0..be16(data, 8) {
+            let record = dns.read_record(&mut reader)?;
+            dns.authorities.push(record);
+        }
+        for _ in 0..be16(data, 10) {
+            let record = dns.read_record(&mut reader)?;
+            match record.rtype {
+                TYPE_OPT if dns.edns.is_none() => dns.edns = Some(edns(&record)),
+                _ => dns.additionals.push(record)
+            }
+        }
+        Ok(dns)
+    }
+
+    /// The raw message
+    pub fn data(&self) -> &'a [u8] { self.data }
+
+    /// The message id
+    pub fn id(&self) -> u16 { be16(self.data, 0) }
+
+    /// The raw flags
+    pub fn flags(&self) -> u16 { be16(self.data, 2) }
+
+    /// Whether the message is a response
+    pub fn is_response(&self) -> bool { self.data[2] & 0x80 != 0 }
+
+    /// The kind of query, 0 for a standard query
+    pub fn opcode(&self) -> u8 { (self.data[2] >> 3) & 0x0f }
+
+    /// Whether the answer is authoritative
+    pub fn authoritative(&self) -> bool { self.data[2] & 0x04 != 0 }
+
+    /// Whether the message was truncated to fit
+    pub fn truncated(&self) -> bool { self.data[2] & 0x02 != 0 }
+
+    /// Whether recursion was desired
+    pub fn recursion_desired(&self) -> bool { self.data[2] & 0x01 != 0 }
+
+    /// Whether recursion is available
+    pub fn recursion_available(&self) -> bool { self.data[3] & 0x80 != 0 }
+
+    /// The response code, see the `RCODE_*` constants, extended by EDNS0
+    pub fn rcode(&self) -> u16 {
+        let extended = self.edns.as_ref().map(|e| e.extended_rcode).unwrap_or(0);
+        (extended as u16) << 4 | (self.data[3] & 0x0f) as u16
+    }
+
+    /// Decompress the name at `offset`, such as within the data of a CNAME, NS or PTR record
+    pub fn name_at(&self, offset: usize) -> Result<String, DnsError> {
+        let mut reader = Reader::new(self.data);
+        if reader.bytes(offset).is_none() {
+            return Err(DnsError::Truncated);
+        }
+        self.read_name(&mut reader)
+    }
+
+    /// The name a CNAME, NS or PTR record points to
+    pub fn target(&self, record: &Record) -> Option<String> {
+        match record.rtype {
+            TYPE_CNAME | TYPE_NS | TYPE_PTR => self.name_at(record.data_offset).ok(),
+            _ => None
+        }
+    }
+
+    fn read_record(&self, reader: &mut Reader<'a>) -> Result<Record<'a>, DnsError> {
+        let name = self.read_name(reader)?;
+        let (rtype, class, ttl) = match (reader.u16(), reader.u16(), reader.u32()) {
+            (Some(rtype), Some(class), Some(ttl)) => (rtype, class, ttl),
+            _ => return Err(DnsError::Truncated)
+        };
+        let data_offset = reader.position() + 2;
+        match reader.vec16() {
+            Some(data) => Ok(Record { name: name, rtype: rtype, class: class, ttl: ttl, data: data, data_offset: data_offset }),
+            None => Err(DnsError::Truncated)
+        }
+    }
+
+    /// Read a possibly compressed name, leaving `reader` after it
+    fn read_name(&self, reader: &mut Reader<'a>) -> Result<String, DnsError> {
+        let mut name = String::new();
+        let mut wire_len = 0;
+        // Where the name continues after a compression pointer
+        let mut jumped: Option<Reader<'a>> = None;
+        let mut current = Reader::new(self.data);
+        current.bytes(reader.position());
+        loop {
+            // After following a pointer, the name must end before it, so running into it means a loop
+            let short = match jumped {
+                Some(_) => DnsError::Name,
+                None => DnsError::Truncated
+            };
+            let len = match current.u8() {
+                Some(len) => len,
+                None => return Err(short)
+            };
+            match len & 0xc0 {
+                0x00 if len == 0 => break,
+                0x00 => {
+                    let label = match current.bytes(len as usize) {
+                        Some(label) => label,
+                        None => return Err(short)
+                    };
+                    wire_len += label.len() + 1;
+                    if wire_len > MAX_NAME {
+                        return Err(DnsError::Name);
+                    }
+                    if !name.is_empty() {
+                        name.push('.');
+                    }
+                    push_label(&mut name, label);
+                },
+                0xc0 => {
+                    let low = match current.u8() {
+                        Some(low) => low,
+                        None => return Err(short)
+                    };
+                    let target = ((len & 0x3f) as usize) << 8 | low as usize;
+                    // Pointers must point backwards, which also rules out loops
+                    let here = current.position() - 2;
+                    if target >= here {
+                        return Err(DnsError::Name);
+                    }
+                    if jumped.is_none() {
+                        jumped = Some(current);
+                    }
+                    current = Reader::new(&self.data[..here]);
+                    current.bytes(target);
+                },
+                _ => return Err(DnsError::Name)
+            }
+        }
+        let end = match jumped {
+            Some(after) => after.position(),
+            None => current.position()
+        };
+        reader.bytes(end - reader.position());
+        Ok(name)
+    }
+}
+
+fn push_label(name: &mut String, label: &[u8]) {
+    for &b in label {
+        match b {
+            b'.' | b'\\' => {
+                name.push('\\');
+                name.push(b as char);
+            },
+            0x21..=0x7e => name.push(b as char),
+            _ => name.push_str(&format!("\\{:03}", b))
+        }
+    }
+}
+
+fn edns<'a>(record: &Record<'a>) -> Edns<'a> {
+    let mut options = Vec::new();
+    let mut reader = Reader::new(record.data);
+    while let (Some(code), Some(data)) = (reader.u16(), reader.vec16()) {
+        options.push((code, data));
+    }
+    Edns {
+        udp_size: record.class,
+        extended_rcode: (record.ttl >> 24) as u8,
+        version: (record.ttl >> 16) as u8,
+        dnssec_ok: record.ttl & 0x8000 != 0,
+        options: options,
+    }
+}
+
+/// Encode a name in wire format, without compression
+///
+/// Labels are split at unescaped dots, and escapes are decoded as written by `Dns`.
+pub fn encode_name(name: &str) -> Result<Vec<u8>, DnsError> {
+    let mut wire = Vec::new();
+    let mut label = Vec::new();
+    let mut chars = name.trim_end_matches('.').bytes();
+    let mut labels = Vec::new();
+    while let Some(b) = chars.next() {
+        match b {
+            b'.' => labels.push(::std::mem::replace(&mut label, Vec::new())),
+            b'\\' => {
+                let first = chars.next();
+                match first {
+                    Some(d) if d.is_ascii_digit() => {
+                        let digits = [d, chars.next().unwrap_or(0), chars.next().unwrap_or(0)];
+                        match ::std::str::from_utf8(&digits).ok().and_then(|s| s.parse::<u8>().ok()) {
+                            Some(value) => label.push(value),
+                            None => return Err(DnsError::Name)
+                        }
+                    },
+                    Some(c) => label.push(c),
+                    None => return Err(DnsError::Name)
+                }
+            },
+            _ => label.push(b)
+        }
+    }
+    if !label.is_empty() || !labels.is_empty() {
+        labels.push(label);
+    }
+    for label in labels {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsError::Name);
+        }
+        wire.push(label.len() as u8);
+        wire.extend_from_slice(&label);
+    }
+    wire.push(0);
+    match wire.len() > MAX_NAME {
+        true => Err(DnsError::Name),
+        false => Ok(wire)
+    }
+}
+
+/// A DNS response under construction
+///
+/// Built to answer the first question of a query.
+/// Answers owned by the question name are compressed against it.
+#[derive(Clone, Debug)]
+pub struct Response {
+    id: u16,
+    /// The opcode and recursion desired bits of the query
+    query_flags: u8,
+    question: Option<Question>,
+    rcode: u8,
+    authoritative: bool,
+    answers: Vec<(String, u16, u32, Vec<u8>)>,
+    /// The largest UDP payload the client can receive, from its EDNS0 parameters
+    udp_size: Option<u16>,
+}
+
+impl Response {
+    /// Start a response to `query`, with no answers and `RCODE_NOERROR`
+    pub fn answering(query: &Dns) -> Response {
+        Response {
+            id: query.id(),
+            query_flags: query.data[2] & 0x79,
+            question: query.questions.first().cloned(),
+            rcode: RCODE_NOERROR,
+            authoritative: false,
+            answers: Vec::new(),
+            udp_size: query.edns.as_ref().map(|e| e.udp_size),
+        }
+    }
+
+    /// A response saying the name asked about does not exist
+    pub fn nxdomain(query: &Dns) -> Response {
+        let mut response = Response::answering(query);
+        response.set_rcode(RCODE_NXDOMAIN);
+        response
+    }
+
+    /// A response answering with `addresses`, typically those of a sinkhole
+    ///
+    /// Only the addresses matching the question's type are included,
+    /// so a sinkhole without IPv6 answers AAAA questions with no records.
+    pub fn sinkhole(query: &Dns, addresses: &[IpAddr], ttl: u32) -> Response {
+        let mut response = Response::answering(query);
+        let (name, qtype) = match response.question {
+            Some(ref q) => (q.name.clone(), q.qtype),
+            None => return response
+        };
+        for &address in addresses {
+            match (qtype, address) {
+                (TYPE_A, IpAddr::V4(_)) | (TYPE_AAAA, IpAddr::V6(_)) | (TYPE_ANY, _) =>
+                    response.add_address(&name, address, ttl),
+                _ => ()
+            }
+        }
+        response
+    }
+
+    /// Set the response code, see the `RCODE_*` constants
+    pub fn set_rcode(&mut self, rcode: u8) {
+        self.rcode = rcode & 0x0f;
+    }
+
+    /// Set whether the answer is authoritative
+    pub fn set_authoritative(&mut self, authoritative: bool) {
+        self.authoritative = authoritative;
+    }
+
+    /// Add an answer record of `rtype` with raw `data`
+    pub fn add_answer(&mut self, name: &str, rtype: u16, ttl: u32, data: &[u8]) {
+        self.answers.push((name.to_string(), rtype, ttl, data.to_vec()));
+    }
+
+    /// Add an A or AAAA record
+    pub fn add_address(&mut self, name: &str, address: IpAddr, ttl: u32) {
+        match address {
+            IpAddr::V4(a) => self.add_answer(name, TYPE_A, ttl, &a.octets()),
+            IpAddr::V6(a) => self.add_answer(name, TYPE_AAAA, ttl, &a.octets()),
+        }
+    }
+
+    /// Encode the response
+    ///
+    /// Answers that would not fit the client's UDP payload size are left out and the truncated bit set,
+    /// so the client retries over TCP.
+    /// An OPT pseudo-record is included if the query had one.
+    pub fn build(&self) -> Result<Vec<u8>, DnsError> {
+        let limit = self.udp_size.map(|s| ::std::cmp::max(s as usize, MAX_UDP)).unwrap_or(MAX_UDP);
+        let mut out = vec![0; 12];
+        write16(&mut out, 0, self.id);
+        out[2] = 0x80 | self.query_flags | if self.authoritative { 0x04 } else { 0 };
+        out[3] = 0x80 | self.rcode;
+
+        let question_name = match self.question {
+            Some(ref q) => {
+                out.extend_from_slice(&encode_name(&q.name)?);
+                push16(&mut out, q.qtype);
+                push16(&mut out, q.qclass);
+                write16(&mut out, 4, 1);
+                Some(q.name.to_lowercase())
+            },
+            None => None
+        };
+
+        // An OPT record takes 11 bytes without options
+        let reserved = match self.udp_size { Some(_) => 11, None => 0 };
+        let mut count = 0;
+        for &(ref name, rtype, ttl, ref data) in self.answers.iter() {
+            let mut record = match question_name {
+                Some(ref q) if *q == name.to_lowercase() => vec![0xc0 | (QUESTION_OFFSET >> 8) as u8, QUESTION_OFFSET as u8],
+                _ => encode_name(name)?
+            };
+            push16(&mut record, rtype);
+            push16(&mut record, CLASS_IN);
+            push16(&mut record, (ttl >> 16) as u16);
+            push16(&mut record, ttl as u16);
+            push16(&mut record, data.len() as u16);
+            record.extend_from_slice(data);
+            if out.len() + record.len() + reserved > limit {
+                out[2] |= 0x02;
+                break;
+            }
+            out.extend_from_slice(&record);
+            count += 1;
+        }
+        write16(&mut out, 6, count);
+
+        if let Some(size) = self.udp_size {
+            out.push(0);
+            push16(&mut out, TYPE_OPT);
+            push16(&mut out, size);
+            out.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+            write16(&mut out, 10, 1);
+        }
+        Ok(out)
+    }
+}
+
+fn push16(out: &mut Vec<u8>, value: u16) {
+    out.push((value >> 8) as u8);
+    out.push(value as u8);
+}
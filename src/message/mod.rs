@@ -0,0 +1,306 @@
+//! Message parsing
+//!
+//! Analagous to <http://netfilter.org/projects/libnetfilter_queue/doxygen/group__Parsing.html>
+mod packet;
+mod buffer;
+mod tcp_options;
+pub mod checksum;
+pub mod dns;
+
+use libc::*;
+use std::cell::{Cell, RefCell, RefMut};
+use std::mem;
+use std::slice;
+use std::ptr::{null, null_mut};
+use std::net::Ipv4Addr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use error::*;
+use util::*;
+use ffi::*;
+pub use ffi::nfqnl_msg_packet_hdr as Header;
+pub use self::packet::*;
+// Named, as libc's glob has protocol numbers of the same names
+pub use self::packet::{IPPROTO_ICMP, IPPROTO_ICMPV6, IPPROTO_TCP, IPPROTO_UDP};
+pub use self::buffer::{PacketBuffer, MangleError};
+pub use self::tcp_options::*;
+
+/// Structs impl'ing `Payload` must be sized correctly for the payload data that mill be transmuted to it
+pub trait Payload {}
+
+#[allow(dead_code)]
+#[allow(missing_docs)]
+#[repr(C)]
+/// A `Payload` to fetch and parse an IP packet header
+pub struct IPHeader {
+    pub version_and_header_raw: u8,
+    pub dscp_raw: u8,
+    pub total_length_raw: u16,
+    pub id_raw: u16,
+    pub flags_and_offset_raw: u16,
+    pub ttl_raw: u8,
+    pub protocol_raw: u8,
+    pub checksum_raw: u16,
+    pub saddr_raw: u32,
+    pub daddr_raw: u32
+}
+
+impl IPHeader {
+    /// Parse the source address
+    pub fn saddr(&self) -> Ipv4Addr {
+        addr_to_ipv4(&self.saddr_raw)
+    }
+
+    /// Parse the destination address
+    pub fn daddr(&self) -> Ipv4Addr {
+        addr_to_ipv4(&self.daddr_raw)
+    }
+}
+
+#[inline]
+fn addr_to_ipv4(src: &u32) -> Ipv4Addr {
+    let octets: [u8; 4] = unsafe { mem::transmute(*src) };
+    Ipv4Addr::new(u8::from_be(octets[0]),
+                  u8::from_be(octets[1]),
+                  u8::from_be(octets[2]),
+                  u8::from_be(octets[3]))
+}
+
+impl Payload for IPHeader {}
+
+/// What the kernel tells of a packet besides its data, for messages made with `Message::with_metadata`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// The packet's mark
+    pub mark: u32,
+    /// The index of the interface the packet arrived on, if any
+    pub indev: Option<u32>,
+    /// The index of the interface the packet will leave by, if known
+    pub outdev: Option<u32>,
+    /// The uid of the local socket that sent the packet, if known
+    pub uid: Option<u32>,
+    /// When the packet was received, if known
+    pub timestamp: Option<SystemTime>,
+}
+
+/// The packet message
+pub struct Message<'a> {
+    /// A raw pointer to the queue data
+    pub raw: *mut nfgenmsg,
+    /// A raw pointer to the packet data
+    pub ptr: *mut nfq_data,
+    /// The `Message` header
+    ///
+    /// A verdict cannot be set without the packet's id
+    /// parsed from the header.
+    /// For convenience, the header is always parsed into the message.
+    pub header: &'a Header,
+    data: Option<&'a [u8]>,
+    metadata: Metadata,
+    mangled: RefCell<Option<PacketBuffer>>,
+    mark: Cell<Option<u32>>
+}
+
+impl<'a> Drop for Message<'a> {
+    fn drop(&mut self) {}
+}
+
+impl<'a> Message<'a> {
+    #[doc(hidden)]
+    pub fn new(raw: *mut nfgenmsg, ptr: *mut nfq_data) -> Result<Message<'a>, Error> {
+        let header = unsafe {
+            let ptr = nfq_get_msg_packet_hdr(ptr);
+            match as_ref(&ptr) {
+                Some(h) => h,
+                None => return Err(error(Reason::GetHeader, "Failed to get header", None))
+            }
+        };
+        Ok(Message {
+            raw: raw,
+            ptr: ptr,
+            header: header,
+            data: None,
+            metadata: Metadata::default(),
+            mangled: RefCell::new(None),
+            mark: Cell::new(None)
+        })
+    }
+
+    /// A message for a packet that was not read from the queue, such as a reassembled one
+    ///
+    /// `data` is the raw IP packet, and `header` identifies the packet any verdict applies to.
+    pub fn from_data(header: &'a Header, data: &'a [u8]) -> Message<'a> {
+        Message::with_metadata(header, data, Metadata::default())
+    }
+
+    /// A message for a packet that was not read from the queue, with what the kernel would have told of it
+    ///
+    /// Used to replay captured packets, see `replay::Replay`.
+    pub fn with_metadata(header: &'a Header, data: &'a [u8], metadata: Metadata) -> Message<'a> {
+        Message {
+            raw: null_mut(),
+            ptr: null_mut(),
+            header: header,
+            data: Some(data),
+            metadata: metadata,
+            mangled: RefCell::new(None),
+            mark: Cell::new(None)
+        }
+    }
+
+    /// Parse the `IPHeader` from the message
+    ///
+    /// When parsing `IPHeader` from a message, the `Queue`'s `CopyMode` and the `Handle` should be sized to the `IPHeader`.
+    /// The best way to do this is with the `queue_builder.set_copy_mode_sized_to_payload`
+    /// and `handle.start_sized_to_payload` methods.
+    /// See `examples/get_addrs.rs`.
+    pub unsafe fn ip_header(&self) -> Result<&IPHeader, Error> {
+        self.payload::<IPHeader>()
+    }
+
+    /// The packet data copied to userspace
+    ///
+    /// This is the raw IP packet, cut short at the range given to `CopyMode::Packet`.
+    /// No data is copied with `CopyMode::None` or `CopyMode::Metadata`.
+    pub fn data(&self) -> Result<&[u8], Error> {
+        if let Some(data) = self.data {
+            return Ok(data);
+        }
+        let mut data: *mut c_uchar = null_mut();
+        let len = unsafe { nfq_get_payload(self.ptr, &mut data) };
+        if len < 0 || data.is_null() {
+            return Err(error(Reason::GetPayload, "Failed to get payload", Some(len)));
+        }
+        Ok(unsafe { slice::from_raw_parts(data, len as usize) })
+    }
+
+    /// Decode the network, transport and application layers of the packet
+    ///
+    /// Decoding only fails when not even the IP header could be read;
+    /// layers cut short by the copy range are recorded in `Packet::extents`.
+    pub fn decode(&self) -> Result<Packet<'_>, DecodeError> {
+        match self.data() {
+            Ok(data) => Packet::new(data),
+            Err(_) => Err(DecodeError::Payload)
+        }
+    }
+
+    /// Rewrite the packet
+    ///
+    /// The first call copies the packet into a `PacketBuffer`, later calls return the same buffer.
+    /// When a `VerdictHandler` rewrites a packet, the buffer is returned with its verdict,
+    /// after any stale checksums are recomputed.
+    /// The whole packet must have been copied, so the copy range of `CopyMode::Packet` must cover it.
+    pub fn mangle(&self) -> Result<RefMut<'_, PacketBuffer>, Error> {
+        if self.mangled.borrow().is_none() {
+            let data = self.data()?;
+            let buffer = match PacketBuffer::new(data.to_vec()) {
+                Ok(b) => b,
+                Err(e) => return Err(error(Reason::Mangle, &format!("Failed to copy packet: {}", e), None))
+            };
+            *self.mangled.borrow_mut() = Some(buffer);
+        }
+        Ok(RefMut::map(self.mangled.borrow_mut(), |b| b.as_mut().unwrap()))
+    }
+
+    /// Take the buffer rewritten through `mangle`, if any
+    ///
+    /// Further calls to `mangle` start again from the original packet.
+    pub fn take_mangled(&self) -> Option<PacketBuffer> {
+        self.mangled.borrow_mut().take()
+    }
+
+    /// The packet's mark, zero if unmarked
+    pub fn mark(&self) -> u32 {
+        match self.ptr.is_null() {
+            true => self.metadata.mark,
+            false => unsafe { nfq_get_nfmark(self.ptr) }
+        }
+    }
+
+    /// The uid of the local socket that sent the packet
+    ///
+    /// Only queued with the packet once enabled by `queue.set_uid_gid(true)`.
+    pub fn uid(&self) -> Option<u32> {
+        if self.ptr.is_null() {
+            return self.metadata.uid;
+        }
+        let mut uid: uint32_t = 0;
+        match unsafe { nfq_get_uid(self.ptr, &mut uid) } {
+            r if r > 0 => Some(uid),
+            _ => None
+        }
+    }
+
+    /// The index of the interface the packet arrived on, if any
+    pub fn indev(&self) -> Option<u32> {
+        match self.ptr.is_null() {
+            true => self.metadata.indev,
+            false => match unsafe { nfq_get_indev(self.ptr) } {
+                0 => None,
+                index => Some(index)
+            }
+        }
+    }
+
+    /// The index of the interface the packet will leave by, if known
+    pub fn outdev(&self) -> Option<u32> {
+        match self.ptr.is_null() {
+            true => self.metadata.outdev,
+            false => match unsafe { nfq_get_outdev(self.ptr) } {
+                0 => None,
+                index => Some(index)
+            }
+        }
+    }
+
+    /// When the packet was received, if the kernel timestamped it
+    pub fn timestamp(&self) -> Option<SystemTime> {
+        if self.ptr.is_null() {
+            return self.metadata.timestamp;
+        }
+        let mut tv = timeval { tv_sec: 0, tv_usec: 0 };
+        match unsafe { nfq_get_timestamp(self.ptr, &mut tv) } {
+            0 if tv.tv_sec > 0 => Some(UNIX_EPOCH + Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000)),
+            _ => None
+        }
+    }
+
+    /// Set the packet's mark along with the verdict a `VerdictHandler` returns
+    pub fn set_mark(&self, mark: u32) {
+        self.mark.set(Some(mark));
+    }
+
+    /// Take the mark given to `set_mark`, if any
+    pub fn take_mark(&self) -> Option<u32> {
+        self.mark.take()
+    }
+
+    /// Parse a sized `Payload` from the message
+    ///
+    /// The size of the `Payload` must be equal to the value that `handle.start` was called with.
+    /// The best way to do this is with the `queue_builder.set_copy_mode_sized_to_payload`
+    /// and `handle.start_sized_to_payload` methods.
+    /// See `examples/get_addrs.rs`.
+    pub unsafe fn payload<A: Payload>(&self) -> Result<&A, Error> {
+        if let Some(data) = self.data {
+            // Unlike the kernel's, a slice given to `from_data` may start anywhere
+            if data.as_ptr() as usize % mem::align_of::<A>() != 0 {
+                return Err(error(Reason::GetPayload, "Payload is not aligned", None));
+            }
+            return match data.len() >= mem::size_of::<A>() {
+                true => Ok(&*(data.as_ptr() as *const A)),
+                false => Err(error(Reason::GetPayload, "Failed to get payload", None))
+            };
+        }
+        let data: *const A = null();
+        let ptr: *mut *mut A = &mut (data as *mut A);
+        let _ = match nfq_get_payload(self.ptr, ptr as *mut *mut c_uchar) {
+            -1 => return Err(error(Reason::GetPayload, "Failed to get payload", Some(-1))),
+            _ => ()
+        };
+        match as_ref(&data) {
+            Some(payload) => Ok(payload),
+            None => Err(error(Reason::GetPayload, "Failed to get payload", None))
+        }
+    }
+}
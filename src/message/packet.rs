@@ -0,0 +1,596 @@
+//! Layered packet decoding
+//!
+//! Decodes the network, transport and application layers of a queued packet in a single pass.
+//! Decoding is tolerant of truncated copies (see `queue::CopyMode::Packet`):
+//! every layer that was copied is exposed, and `Packet::extents` records how much of each layer was available.
+
+use std::error::Error as Base;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use util::*;
+
+/// IP protocol number of ICMP
+pub const IPPROTO_ICMP: u8 = 1;
+/// IP protocol number of TCP
+pub const IPPROTO_TCP: u8 = 6;
+/// IP protocol number of UDP
+pub const IPPROTO_UDP: u8 = 17;
+/// IP protocol number of ICMPv6
+pub const IPPROTO_ICMPV6: u8 = 58;
+
+pub(crate) const IPV6_HOP_BY_HOP: u8 = 0;
+pub(crate) const IPV6_ROUTING: u8 = 43;
+pub(crate) const IPV6_FRAGMENT: u8 = 44;
+pub(crate) const IPV6_AUTH: u8 = 51;
+pub(crate) const IPV6_DEST_OPTS: u8 = 60;
+
+/// TCP `FIN` flag
+pub const TCP_FIN: u8 = 0x01;
+/// TCP `SYN` flag
+pub const TCP_SYN: u8 = 0x02;
+/// TCP `RST` flag
+pub const TCP_RST: u8 = 0x04;
+/// TCP `PSH` flag
+pub const TCP_PSH: u8 = 0x08;
+/// TCP `ACK` flag
+pub const TCP_ACK: u8 = 0x10;
+/// TCP `URG` flag
+pub const TCP_URG: u8 = 0x20;
+/// TCP `ECE` flag
+pub const TCP_ECE: u8 = 0x40;
+/// TCP `CWR` flag
+pub const TCP_CWR: u8 = 0x80;
+
+/// Reasons a packet could not be decoded at all
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The payload could not be fetched from the message, e.g. with `CopyMode::Metadata`
+    Payload,
+    /// The payload is shorter than the fixed network header
+    Truncated,
+    /// The IP version nibble is neither 4 nor 6
+    Version(u8),
+    /// The network header is inconsistent, e.g. an IPv4 header length below 20 bytes
+    Malformed,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            DecodeError::Version(v) => write!(formatter, "{} ({})", self.reason(), v),
+            _ => formatter.write_str(self.reason())
+        }
+    }
+}
+
+impl Base for DecodeError {
+    fn description(&self) -> &str {
+        self.reason()
+    }
+}
+
+impl DecodeError {
+    fn reason(&self) -> &'static str {
+        match *self {
+            DecodeError::Payload => "Failed to get payload",
+            DecodeError::Truncated => "Payload shorter than the network header",
+            DecodeError::Version(_) => "Unknown IP version",
+            DecodeError::Malformed => "Malformed network header",
+        }
+    }
+}
+
+/// How much of a layer was present in the copied payload
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Extent {
+    /// The whole layer was copied
+    Full,
+    /// The layer was cut short by the copy range
+    Partial,
+    /// None of the layer was copied, or the layer does not exist in this packet
+    Missing,
+}
+
+/// The `Extent` of each layer of a decoded `Packet`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extents {
+    /// The IP header, including options and IPv6 extension headers
+    pub network: Extent,
+    /// The transport header, including TCP options
+    pub transport: Extent,
+    /// The application payload following the transport header
+    pub payload: Extent,
+}
+
+/// Fragmentation details of a fragmented IP datagram
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fragment {
+    /// The fragment identification field
+    pub id: u32,
+    /// Offset of this fragment's data in the reassembled datagram, in bytes
+    pub offset: u16,
+    /// Whether more fragments follow this one
+    pub more: bool,
+}
+
+/// A read-only view of an IPv4 header
+#[derive(Clone, Copy, Debug)]
+pub struct Ipv4<'a> { data: &'a [u8] }
+
+impl<'a> Ipv4<'a> {
+    /// View `data` as an IPv4 packet
+    ///
+    /// Only the fixed 20 byte header needs to be present.
+    pub fn new(data: &'a [u8]) -> Result<Ipv4<'a>, DecodeError> {
+        if data.len() < 20 {
+            return Err(DecodeError::Truncated);
+        }
+        let ip = Ipv4 { data: data };
+        match ip.version() {
+            4 => (),
+            v => return Err(DecodeError::Version(v))
+        }
+        if ip.header_len() < 20 {
+            return Err(DecodeError::Malformed);
+        }
+        Ok(ip)
+    }
+
+    /// The bytes of the packet that were copied, starting at the IPv4 header
+    pub fn data(&self) -> &'a [u8] { self.data }
+    /// The IP version, always 4
+    pub fn version(&self) -> u8 { self.data[0] >> 4 }
+    /// The header length in bytes, including options
+    pub fn header_len(&self) -> usize { ((self.data[0] & 0x0f) as usize) * 4 }
+    /// The differentiated services code point
+    pub fn dscp(&self) -> u8 { self.data[1] >> 2 }
+    /// The explicit congestion notification bits
+    pub fn ecn(&self) -> u8 { self.data[1] & 0x03 }
+    /// The length of the whole datagram in bytes, as stated by the header
+    pub fn total_len(&self) -> u16 { be16(self.data, 2) }
+    /// The identification field
+    pub fn id(&self) -> u16 { be16(self.data, 4) }
+    /// Whether the don't fragment flag is set
+    pub fn dont_fragment(&self) -> bool { self.data[6] & 0x40 != 0 }
+    /// Whether the more fragments flag is set
+    pub fn more_fragments(&self) -> bool { self.data[6] & 0x20 != 0 }
+    /// The fragment offset in bytes
+    pub fn fragment_offset(&self) -> u16 { (be16(self.data, 6) & 0x1fff) << 3 }
+    /// The time to live
+    pub fn ttl(&self) -> u8 { self.data[8] }
+    /// The protocol of the encapsulated transport layer
+    pub fn protocol(&self) -> u8 { self.data[9] }
+    /// The header checksum
+    pub fn checksum(&self) -> u16 { be16(self.data, 10) }
+    /// The source address
+    pub fn saddr(&self) -> Ipv4Addr { Ipv4Addr::new(self.data[12], self.data[13], self.data[14], self.data[15]) }
+    /// The destination address
+    pub fn daddr(&self) -> Ipv4Addr { Ipv4Addr::new(self.data[16], self.data[17], self.data[18], self.data[19]) }
+
+    /// The header options that were copied
+    pub fn options(&self) -> &'a [u8] {
+        clip(self.data, 20, self.header_len())
+    }
+
+    /// Whether this datagram is a fragment of a larger one
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments() || self.fragment_offset() != 0
+    }
+}
+
+/// A read-only view of an IPv6 header
+#[derive(Clone, Copy, Debug)]
+pub struct Ipv6<'a> { data: &'a [u8] }
+
+impl<'a> Ipv6<'a> {
+    /// View `data` as an IPv6 packet
+    ///
+    /// Only the fixed 40 byte header needs to be present.
+    pub fn new(data: &'a [u8]) -> Result<Ipv6<'a>, DecodeError> {
+        if data.len() < 40 {
+            return Err(DecodeError::Truncated);
+        }
+        match data[0] >> 4 {
+            6 => Ok(Ipv6 { data: data }),
+            v => Err(DecodeError::Version(v))
+        }
+    }
+
+    /// The bytes of the packet that were copied, starting at the IPv6 header
+    pub fn data(&self) -> &'a [u8] { self.data }
+    /// The IP version, always 6
+    pub fn version(&self) -> u8 { self.data[0] >> 4 }
+    /// The traffic class
+    pub fn traffic_class(&self) -> u8 { ((be16(self.data, 0) >> 4) & 0xff) as u8 }
+    /// The differentiated services code point
+    pub fn dscp(&self) -> u8 { self.traffic_class() >> 2 }
+    /// The explicit congestion notification bits
+    pub fn ecn(&self) -> u8 { self.traffic_class() & 0x03 }
+    /// The flow label
+    pub fn flow_label(&self) -> u32 { be32(self.data, 0) & 0x000f_ffff }
+    /// The length of everything following the fixed header, as stated by the header
+    pub fn payload_len(&self) -> u16 { be16(self.data, 4) }
+    /// The type of the header following the fixed header
+    pub fn next_header(&self) -> u8 { self.data[6] }
+    /// The hop limit
+    pub fn hop_limit(&self) -> u8 { self.data[7] }
+    /// The source address
+    pub fn saddr(&self) -> Ipv6Addr { ipv6_at(self.data, 8) }
+    /// The destination address
+    pub fn daddr(&self) -> Ipv6Addr { ipv6_at(self.data, 24) }
+}
+
+/// A read-only view of a TCP header
+#[derive(Clone, Copy, Debug)]
+pub struct Tcp<'a> { data: &'a [u8] }
+
+impl<'a> Tcp<'a> {
+    /// View `data` as a TCP segment
+    ///
+    /// Only the fixed 20 byte header needs to be present.
+    pub fn new(data: &'a [u8]) -> Result<Tcp<'a>, DecodeError> {
+        if data.len() < 20 {
+            return Err(DecodeError::Truncated);
+        }
+        let tcp = Tcp { data: data };
+        if tcp.header_len() < 20 {
+            return Err(DecodeError::Malformed);
+        }
+        Ok(tcp)
+    }
+
+    /// The bytes of the segment that were copied, starting at the TCP header
+    pub fn data(&self) -> &'a [u8] { self.data }
+    /// The source port
+    pub fn src_port(&self) -> u16 { be16(self.data, 0) }
+    /// The destination port
+    pub fn dst_port(&self) -> u16 { be16(self.data, 2) }
+    /// The sequence number
+    pub fn seq(&self) -> u32 { be32(self.data, 4) }
+    /// The acknowledgement number
+    pub fn ack(&self) -> u32 { be32(self.data, 8) }
+    /// The header length in bytes, including options
+    pub fn header_len(&self) -> usize { ((self.data[12] >> 4) as usize) * 4 }
+    /// The flags, see the `TCP_*` constants
+    pub fn flags(&self) -> u8 { self.data[13] }
+    /// Whether all of the given `TCP_*` flags are set
+    pub fn has_flags(&self, flags: u8) -> bool { self.flags() & flags == flags }
+    /// The receive window
+    pub fn window(&self) -> u16 { be16(self.data, 14) }
+    /// The checksum
+    pub fn checksum(&self) -> u16 { be16(self.data, 16) }
+    /// The urgent pointer
+    pub fn urgent(&self) -> u16 { be16(self.data, 18) }
+
+    /// The options that were copied
+    pub fn options(&self) -> &'a [u8] {
+        clip(self.data, 20, self.header_len())
+    }
+}
+
+/// A read-only view of a UDP header
+#[derive(Clone, Copy, Debug)]
+pub struct Udp<'a> { data: &'a [u8] }
+
+impl<'a> Udp<'a> {
+    /// View `data` as a UDP datagram
+    pub fn new(data: &'a [u8]) -> Result<Udp<'a>, DecodeError> {
+        if data.len() < 8 {
+            return Err(DecodeError::Truncated);
+        }
+        Ok(Udp { data: data })
+    }
+
+    /// The bytes of the datagram that were copied, starting at the UDP header
+    pub fn data(&self) -> &'a [u8] { self.data }
+    /// The source port
+    pub fn src_port(&self) -> u16 { be16(self.data, 0) }
+    /// The destination port
+    pub fn dst_port(&self) -> u16 { be16(self.data, 2) }
+    /// The length of header and payload, as stated by the header
+    pub fn length(&self) -> u16 { be16(self.data, 4) }
+    /// The checksum, zero if unused over IPv4
+    pub fn checksum(&self) -> u16 { be16(self.data, 6) }
+}
+
+/// A read-only view of an ICMP or ICMPv6 header
+#[derive(Clone, Copy, Debug)]
+pub struct Icmp<'a> { data: &'a [u8] }
+
+impl<'a> Icmp<'a> {
+    /// View `data` as an ICMP message
+    pub fn new(data: &'a [u8]) -> Result<Icmp<'a>, DecodeError> {
+        if data.len() < 8 {
+            return Err(DecodeError::Truncated);
+        }
+        Ok(Icmp { data: data })
+    }
+
+    /// The bytes of the message that were copied, starting at the ICMP header
+    pub fn data(&self) -> &'a [u8] { self.data }
+    /// The message type
+    pub fn icmp_type(&self) -> u8 { self.data[0] }
+    /// The message code
+    pub fn code(&self) -> u8 { self.data[1] }
+    /// The checksum
+    pub fn checksum(&self) -> u16 { be16(self.data, 2) }
+    /// The type specific four bytes following the checksum
+    pub fn rest_of_header(&self) -> u32 { be32(self.data, 4) }
+    /// The identifier of an echo request or reply
+    pub fn echo_id(&self) -> u16 { be16(self.data, 4) }
+    /// The sequence number of an echo request or reply
+    pub fn echo_seq(&self) -> u16 { be16(self.data, 6) }
+}
+
+/// The network layer of a `Packet`
+#[derive(Clone, Copy, Debug)]
+pub enum Network<'a> {
+    /// An IPv4 packet
+    Ipv4(Ipv4<'a>),
+    /// An IPv6 packet
+    Ipv6(Ipv6<'a>),
+}
+
+impl<'a> Network<'a> {
+    /// The source address
+    pub fn saddr(&self) -> IpAddr {
+        match *self {
+            Network::Ipv4(ref ip) => IpAddr::V4(ip.saddr()),
+            Network::Ipv6(ref ip) => IpAddr::V6(ip.saddr()),
+        }
+    }
+
+    /// The destination address
+    pub fn daddr(&self) -> IpAddr {
+        match *self {
+            Network::Ipv4(ref ip) => IpAddr::V4(ip.daddr()),
+            Network::Ipv6(ref ip) => IpAddr::V6(ip.daddr()),
+        }
+    }
+
+    /// The TTL of IPv4 or the hop limit of IPv6
+    pub fn ttl(&self) -> u8 {
+        match *self {
+            Network::Ipv4(ref ip) => ip.ttl(),
+            Network::Ipv6(ref ip) => ip.hop_limit(),
+        }
+    }
+}
+
+/// The transport layer of a `Packet`
+#[derive(Clone, Copy, Debug)]
+pub enum Transport<'a> {
+    /// A TCP segment
+    Tcp(Tcp<'a>),
+    /// A UDP datagram
+    Udp(Udp<'a>),
+    /// An ICMP message
+    Icmp(Icmp<'a>),
+    /// An ICMPv6 message
+    Icmpv6(Icmp<'a>),
+}
+
+impl<'a> Transport<'a> {
+    /// The source port of TCP and UDP
+    pub fn src_port(&self) -> Option<u16> {
+        match *self {
+            Transport::Tcp(ref tcp) => Some(tcp.src_port()),
+            Transport::Udp(ref udp) => Some(udp.src_port()),
+            _ => None
+        }
+    }
+
+    /// The destination port of TCP and UDP
+    pub fn dst_port(&self) -> Option<u16> {
+        match *self {
+            Transport::Tcp(ref tcp) => Some(tcp.dst_port()),
+            Transport::Udp(ref udp) => Some(udp.dst_port()),
+            _ => None
+        }
+    }
+}
+
+/// A packet decoded into its layers
+#[derive(Clone, Copy, Debug)]
+pub struct Packet<'a> {
+    /// The network layer
+    pub network: Network<'a>,
+    /// The protocol of the transport layer, after any IPv6 extension headers
+    pub protocol: u8,
+    /// The transport layer
+    ///
+    /// `None` if the protocol is not understood, the fixed transport header was not copied,
+    /// or this is a non-first fragment.
+    pub transport: Option<Transport<'a>>,
+    /// The application payload that was copied
+    pub payload: &'a [u8],
+    /// Fragmentation details, if this is a fragment
+    pub fragment: Option<Fragment>,
+    /// How much of each layer was copied
+    pub extents: Extents,
+    transport_offset: usize,
+    payload_offset: usize,
+    datagram_len: usize,
+}
+
+impl<'a> Packet<'a> {
+    /// Decode a raw IP packet
+    pub fn new(data: &'a [u8]) -> Result<Packet<'a>, DecodeError> {
+        match data.first() {
+            None => Err(DecodeError::Truncated),
+            Some(b) if b >> 4 == 4 => Ipv4::new(data).and_then(decode_ipv4),
+            Some(b) if b >> 4 == 6 => Ipv6::new(data).and_then(decode_ipv6),
+            Some(b) => Err(DecodeError::Version(b >> 4)),
+        }
+    }
+
+    /// The whole packet as copied
+    pub fn data(&self) -> &'a [u8] {
+        match self.network {
+            Network::Ipv4(ref ip) => ip.data(),
+            Network::Ipv6(ref ip) => ip.data(),
+        }
+    }
+
+    /// Offset of the transport header from the start of the packet
+    pub fn transport_offset(&self) -> usize { self.transport_offset }
+
+    /// Offset of the application payload from the start of the packet
+    pub fn payload_offset(&self) -> usize { self.payload_offset }
+
+    /// The length of the whole datagram, as stated by the IP header
+    pub fn datagram_len(&self) -> usize { self.datagram_len }
+
+    /// Whether the whole datagram was copied
+    pub fn is_complete(&self) -> bool {
+        self.data().len() >= self.datagram_len
+    }
+}
+
+fn decode_ipv4(ip: Ipv4<'_>) -> Result<Packet<'_>, DecodeError> {
+    let header_len = ip.header_len();
+    let datagram_len = ip.total_len() as usize;
+    if datagram_len < header_len {
+        return Err(DecodeError::Malformed);
+    }
+    // Anything past the stated total length is not part of the datagram
+    let ip = Ipv4 { data: clip(ip.data(), 0, datagram_len) };
+    let fragment = match ip.is_fragment() {
+        true => Some(Fragment { id: ip.id() as u32, offset: ip.fragment_offset(), more: ip.more_fragments() }),
+        false => None
+    };
+    let network = match ip.data().len() >= header_len {
+        true => Extent::Full,
+        false => Extent::Partial
+    };
+    Ok(decode_transport(Network::Ipv4(ip), ip.protocol(), network, header_len, datagram_len, fragment))
+}
+
+fn decode_ipv6(ip: Ipv6<'_>) -> Result<Packet<'_>, DecodeError> {
+    // A zero payload length is only used by jumbograms, whose length is in a hop-by-hop option
+    let datagram_len = match ip.payload_len() {
+        0 => ip.data().len(),
+        len => 40 + len as usize
+    };
+    let ip = Ipv6 { data: clip(ip.data(), 0, datagram_len) };
+    let data = ip.data();
+    let mut next = ip.next_header();
+    let mut offset = 40;
+    let mut fragment = None;
+    let mut network = Extent::Full;
+
+    loop {
+        let len = match next {
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DEST_OPTS if data.len() >= offset + 2 =>
+                (data[offset + 1] as usize + 1) * 8,
+            IPV6_AUTH if data.len() >= offset + 2 => (data[offset + 1] as usize + 2) * 4,
+            IPV6_FRAGMENT if data.len() >= offset + 8 => {
+                let f = Fragment {
+                    id: be32(data, offset + 4),
+                    offset: be16(data, offset + 2) & 0xfff8,
+                    more: data[offset + 3] & 0x01 != 0,
+                };
+                fragment = Some(f);
+                // Only the first fragment carries the headers following the fragment header
+                if f.offset != 0 {
+                    next = data[offset];
+                    offset += 8;
+                    break;
+                }
+                8
+            },
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DEST_OPTS | IPV6_AUTH | IPV6_FRAGMENT => {
+                network = Extent::Partial;
+                break;
+            },
+            _ => break
+        };
+        if data.len() < offset + len {
+            network = Extent::Partial;
+            break;
+        }
+        next = data[offset];
+        offset += len;
+    }
+
+    // A fragment header with neither offset nor more fragments is an atomic fragment
+    let fragment = fragment.and_then(|f: Fragment| match f.offset != 0 || f.more {
+        true => Some(f),
+        false => None
+    });
+    Ok(decode_transport(Network::Ipv6(ip), next, network, offset, datagram_len, fragment))
+}
+
+fn decode_transport(network: Network<'_>, protocol: u8, network_extent: Extent, offset: usize,
+                    datagram_len: usize, fragment: Option<Fragment>) -> Packet<'_> {
+    let data = match network {
+        Network::Ipv4(ref ip) => ip.data(),
+        Network::Ipv6(ref ip) => ip.data(),
+    };
+    let rest = clip(data, offset, data.len());
+    let non_first = fragment.map(|f| f.offset != 0).unwrap_or(false);
+    let known = match protocol {
+        IPPROTO_TCP | IPPROTO_UDP | IPPROTO_ICMP | IPPROTO_ICMPV6 => true,
+        _ => false
+    };
+
+    let transport = match protocol {
+        _ if network_extent != Extent::Full || non_first => None,
+        IPPROTO_TCP => Tcp::new(rest).ok().map(Transport::Tcp),
+        IPPROTO_UDP => Udp::new(rest).ok().map(Transport::Udp),
+        IPPROTO_ICMP => Icmp::new(rest).ok().map(Transport::Icmp),
+        IPPROTO_ICMPV6 => Icmp::new(rest).ok().map(Transport::Icmpv6),
+        _ => None
+    };
+    let header_len = match transport {
+        Some(Transport::Tcp(ref tcp)) => tcp.header_len(),
+        Some(_) => 8,
+        None => 0
+    };
+
+    // Where the application payload starts, if everything in front of it was copied
+    let (transport_extent, payload_offset) = match transport {
+        _ if network_extent != Extent::Full => (Extent::Missing, None),
+        // Without a transport header to skip, everything after the network header is payload
+        _ if non_first || !known => (Extent::Missing, Some(offset)),
+        None if rest.is_empty() => (Extent::Missing, None),
+        None => (Extent::Partial, None),
+        Some(_) if rest.len() < header_len => (Extent::Partial, None),
+        Some(_) => (Extent::Full, Some(offset + header_len)),
+    };
+    let payload = clip(data, payload_offset.unwrap_or(data.len()), data.len());
+    let payload_extent = match payload_offset {
+        Some(_) if data.len() >= datagram_len => Extent::Full,
+        Some(_) if !payload.is_empty() => Extent::Partial,
+        _ => Extent::Missing
+    };
+
+    Packet {
+        network: network,
+        protocol: protocol,
+        transport: transport,
+        payload: payload,
+        fragment: fragment,
+        extents: Extents {
+            network: network_extent,
+            transport: transport_extent,
+            payload: payload_extent,
+        },
+        transport_offset: offset,
+        payload_offset: payload_offset.unwrap_or(data.len()),
+        datagram_len: datagram_len,
+    }
+}
+
+#[inline]
+fn clip(data: &[u8], start: usize, end: usize) -> &[u8] {
+    let end = ::std::cmp::min(end, data.len());
+    let start = ::std::cmp::min(start, end);
+    &data[start..end]
+}
+
+#[inline]
+fn ipv6_at(data: &[u8], offset: usize) -> Ipv6Addr {
+    Ipv6Addr::new(be16(data, offset), be16(data, offset + 2), be16(data, offset + 4), be16(data, offset + 6),
+                  be16(data, offset + 8), be16(data, offset + 10), be16(data, offset + 12), be16(data, offset + 14))
+}
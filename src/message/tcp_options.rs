@@ -0,0 +1,127 @@
+//! TCP option parsing
+
+use util::*;
+use super::packet::Tcp;
+
+/// End of option list
+pub const TCPOPT_EOL: u8 = 0;
+/// No operation, used for padding
+pub const TCPOPT_NOP: u8 = 1;
+/// Maximum segment size
+pub const TCPOPT_MSS: u8 = 2;
+/// Window scale
+pub const TCPOPT_WINDOW_SCALE: u8 = 3;
+/// Selective acknowledgements permitted
+pub const TCPOPT_SACK_PERMITTED: u8 = 4;
+/// Selective acknowledgement blocks
+pub const TCPOPT_SACK: u8 = 5;
+/// Timestamps
+pub const TCPOPT_TIMESTAMP: u8 = 8;
+
+/// A single TCP option
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcpOption<'a> {
+    /// The option kind, see the `TCPOPT_*` constants
+    pub kind: u8,
+    /// The option data, without the kind and length bytes
+    pub data: &'a [u8],
+    /// Offset of the option from the start of the options
+    pub offset: usize,
+}
+
+impl<'a> TcpOption<'a> {
+    /// The size of the option on the wire, including kind and length bytes
+    pub fn size(&self) -> usize {
+        match self.kind {
+            TCPOPT_EOL | TCPOPT_NOP => 1,
+            _ => self.data.len() + 2
+        }
+    }
+}
+
+/// An iterator over the options of a TCP header
+///
+/// Iteration stops at the end of option list, or at the first malformed option.
+pub struct TcpOptions<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> TcpOptions<'a> {
+    /// Iterate over raw option bytes
+    pub fn new(data: &'a [u8]) -> TcpOptions<'a> {
+        TcpOptions { data: data, offset: 0 }
+    }
+}
+
+impl<'a> Iterator for TcpOptions<'a> {
+    type Item = TcpOption<'a>;
+
+    fn next(&mut self) -> Option<TcpOption<'a>> {
+        let offset = self.offset;
+        let kind = match self.data.get(offset) {
+            Some(&TCPOPT_EOL) | None => return None,
+            Some(&kind) => kind
+        };
+        if kind == TCPOPT_NOP {
+            self.offset += 1;
+            return Some(TcpOption { kind: kind, data: &[], offset: offset });
+        }
+        let len = match self.data.get(offset + 1) {
+            Some(&len) if len >= 2 && offset + len as usize <= self.data.len() => len as usize,
+            _ => {
+                self.offset = self.data.len();
+                return None;
+            }
+        };
+        self.offset += len;
+        Some(TcpOption { kind: kind, data: &self.data[offset + 2..offset + len], offset: offset })
+    }
+}
+
+impl<'a> Tcp<'a> {
+    /// Iterate over the options that were copied
+    pub fn options_iter(&self) -> TcpOptions<'a> {
+        TcpOptions::new(self.options())
+    }
+
+    /// Find the first option of `kind`
+    pub fn option(&self, kind: u8) -> Option<TcpOption<'a>> {
+        self.options_iter().find(|o| o.kind == kind)
+    }
+
+    /// The maximum segment size option
+    pub fn mss(&self) -> Option<u16> {
+        match self.option(TCPOPT_MSS) {
+            Some(o) if o.data.len() == 2 => Some(be16(o.data, 0)),
+            _ => None
+        }
+    }
+
+    /// The window scale option
+    pub fn window_scale(&self) -> Option<u8> {
+        match self.option(TCPOPT_WINDOW_SCALE) {
+            Some(o) if o.data.len() == 1 => Some(o.data[0]),
+            _ => None
+        }
+    }
+
+    /// The timestamp value and echo reply of the timestamps option
+    pub fn timestamps(&self) -> Option<(u32, u32)> {
+        match self.option(TCPOPT_TIMESTAMP) {
+            Some(o) if o.data.len() == 8 => Some((be32(o.data, 0), be32(o.data, 4))),
+            _ => None
+        }
+    }
+
+    /// The left and right edges of the selective acknowledgement blocks
+    pub fn sack_blocks(&self) -> Vec<(u32, u32)> {
+        match self.option(TCPOPT_SACK) {
+            Some(o) => o.data.chunks(8)
+                .filter(|b| b.len() == 8)
+                .map(|b| (be32(b, 0), be32(b, 4)))
+                .collect(),
+            None => Vec::new()
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! An in-memory backend for testing handlers
+//!
+//! A `Handle` opened with `Handle::in_memory` has no netlink socket behind it, and needs neither root
+//! nor the `nfnetlink_queue` module. Packets are injected into its queues with `Mock::inject`,
+//! handed to their handlers one at a time by `handle.step()`, and the verdicts set on them,
+//! with any mark or rewritten packet, are read back with `Mock::verdicts`.
+//! Calls configuring the handle and its queues are recorded rather than sent, see `Mock::calls`.
+//!
+//! ```text
+//! let mock = Mock::new();
+//! let mut handle = Handle::in_memory(&mock);
+//! let mut queue = handle.queue(0, handler).unwrap();
+//! queue.set_mode(CopyMode::Packet(4096)).unwrap();
+//!
+//! let id = mock.inject(0, &packet);
+//! while handle.step() {}
+//! assert_eq!(mock.verdict(id).unwrap().verdict, Verdict::Drop);
+//! ```
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use libc::c_void;
+use handle::ProtocolFamily;
+use message::{Header, Message, Metadata};
+use queue::{CopyMode, Verdict};
+
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+
+/// A call made to configure the handle or a queue
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Call {
+    /// `handle.bind()`
+    Bind(ProtocolFamily),
+    /// `handle.unbind()`
+    Unbind(ProtocolFamily),
+    /// `handle.queue()`, with the queue number
+    CreateQueue(u16),
+    /// `queue.set_mode()`, with the queue number
+    SetMode(u16, CopyMode),
+    /// `queue.set_max_length()`, with the queue number
+    SetMaxLength(u16, u32),
+    /// `queue.set_uid_gid()`, with the queue number
+    SetUidGid(u16, bool),
+}
+
+/// A verdict as it would have been set
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Decision {
+    /// The id of the packet
+    pub id: u32,
+    /// The verdict set
+    pub verdict: Verdict,
+    /// The mark set with the verdict, if any
+    pub mark: Option<u32>,
+    /// The packet that replaced it, if it was rewritten
+    pub payload: Option<Vec<u8>>,
+}
+
+/// A packet waiting to be handled
+struct Pending {
+    queue: u16,
+    id: u32,
+    hook: u8,
+    data: Vec<u8>,
+    metadata: Metadata,
+}
+
+/// A queue created on the handle
+struct Bound {
+    queue: *mut c_void,
+    deliver: fn(*mut c_void, &Message) -> i32,
+    mode: Option<CopyMode>,
+    max_length: Option<u32>,
+}
+
+struct State {
+    pending: VecDeque<Pending>,
+    queues: HashMap<u16, Bound>,
+    calls: Vec<Call>,
+    verdicts: Vec<(u16, Decision)>,
+    outstanding: Vec<(u16, u32)>,
+    next_id: u32,
+    hook: u8,
+    dropped: usize,
+}
+
+/// The packets and queues of in-memory `Handle`s
+///
+/// Clones share the same state, so a test can keep one while the handle holds another.
+#[derive(Clone)]
+pub struct Mock {
+    state: Rc<RefCell<State>>,
+}
+
+impl Default for Mock {
+    fn default() -> Mock {
+        Mock::new()
+    }
+}
+
+impl Mock {
+    /// A backend with no packets or queues, queueing packets at `NF_INET_PRE_ROUTING`
+    pub fn new() -> Mock {
+        Mock {
+            state: Rc::new(RefCell::new(State {
+                pending: VecDeque::new(),
+                queues: HashMap::new(),
+                calls: Vec::new(),
+                verdicts: Vec::new(),
+                outstanding: Vec::new(),
+                next_id: 1,
+                hook: 0,
+                dropped: 0,
+            }))
+        }
+    }
+
+    /// Set the netfilter hook later packets seem to be queued at
+    pub fn set_hook(&self, hook: u8) {
+        self.state.borrow_mut().hook = hook;
+    }
+
+    /// Queue an IP packet to `queue`, returning its id
+    ///
+    /// Ids count up from one across all queues, as they would for a single `Handle`.
+    pub fn inject(&self, queue: u16, data: &[u8]) -> u32 {
+        self.inject_with(queue, data, Metadata::default())
+    }
+
+    /// Queue an IP packet to `queue` with the mark, interfaces or uid the kernel would have given it
+    pub fn inject_with(&self, queue: u16, data: &[u8], metadata: Metadata) -> u32 {
+        let mut state = self.state.borrow_mut();
+        let id = state.next_id;
+        state.next_id += 1;
+        let hook = state.hook;
+        state.pending.push_back(Pending { queue: queue, id: id, hook: hook, data: data.to_vec(), metadata: metadata });
+        id
+    }
+
+    /// The number of packets injected but not yet handled
+    pub fn pending(&self) -> usize {
+        self.state.borrow().pending.len()
+    }
+
+    /// The number of packets dropped unhandled
+    ///
+    /// A packet is dropped when no queue has its number, or when the queue already holds
+    /// the length given to `set_max_length`, as the kernel would do.
+    pub fn dropped(&self) -> usize {
+        self.state.borrow().dropped
+    }
+
+    /// The verdicts set on packets from `queue`, in the order they were set
+    pub fn verdicts(&self, queue: u16) -> Vec<Decision> {
+        self.state.borrow().verdicts.iter().filter(|v| v.0 == queue).map(|v| v.1.clone()).collect()
+    }
+
+    /// The last verdict set on the packet with `id`, if any
+    pub fn verdict(&self, id: u32) -> Option<Decision> {
+        self.state.borrow().verdicts.iter().rev().find(|v| v.1.id == id).map(|v| v.1.clone())
+    }
+
+    /// The calls made configuring handles and queues, in the order they were made
+    pub fn calls(&self) -> Vec<Call> {
+        self.state.borrow().calls.clone()
+    }
+
+    /// The copy-mode last set on `queue`, if it exists and one was set
+    pub fn mode(&self, queue: u16) -> Option<CopyMode> {
+        self.state.borrow().queues.get(&queue).and_then(|q| q.mode)
+    }
+
+    /// The max-length last set on `queue`, if it exists and one was set
+    pub fn max_length(&self, queue: u16) -> Option<u32> {
+        self.state.borrow().queues.get(&queue).and_then(|q| q.max_length)
+    }
+
+    /// Forget all verdicts and calls recorded so far
+    pub fn clear(&self) {
+        let mut state = self.state.borrow_mut();
+        state.verdicts.clear();
+        state.calls.clear();
+    }
+
+    #[doc(hidden)]
+    /// Record a call made to configure the handle or a queue
+    pub fn call(&self, call: Call) {
+        let mut state = self.state.borrow_mut();
+        match call {
+            Call::SetMode(queue, mode) => {
+                if let Some(q) = state.queues.get_mut(&queue) {
+                    q.mode = Some(mode);
+                }
+            },
+            Call::SetMaxLength(queue, length) => {
+                if let Some(q) = state.queues.get_mut(&queue) {
+                    q.max_length = Some(length);
+                }
+            },
+            _ => ()
+        }
+        state.calls.push(call);
+    }
+
+    #[doc(hidden)]
+    /// Add a queue, returning whether it was added, as it is not if the number is taken
+    pub fn bind_queue(&self, number: u16, queue: *mut c_void,
+                      deliver: fn(*mut c_void, &Message) -> i32) -> bool {
+        let mut state = self.state.borrow_mut();
+        state.calls.push(Call::CreateQueue(number));
+        if state.queues.contains_key(&number) {
+            return false;
+        }
+        state.queues.insert(number, Bound { queue: queue, deliver: deliver, mode: None, max_length: None });
+        true
+    }
+
+    #[doc(hidden)]
+    /// Remove a queue, keeping the verdicts set on it
+    pub fn unbind_queue(&self, number: u16) {
+        self.state.borrow_mut().queues.remove(&number);
+    }
+
+    #[doc(hidden)]
+    /// Record a verdict set on a packet from `queue`
+    pub fn record(&self, queue: u16, decision: Decision) {
+        let mut state = self.state.borrow_mut();
+        state.outstanding.retain(|p| *p != (queue, decision.id));
+        state.verdicts.push((queue, decision));
+    }
+
+    #[doc(hidden)]
+    /// Hand the next packet to its queue's handler, returning whether there was one
+    pub fn step(&self) -> bool {
+        let (packet, bound) = {
+            let mut state = self.state.borrow_mut();
+            let packet = match state.pending.pop_front() {
+                Some(p) => p,
+                None => return false
+            };
+            let bound = state.queues.get(&packet.queue).map(|q| (q.queue, q.deliver, q.mode, q.max_length));
+            (packet, bound)
+        };
+        let (queue, deliver, mode, max_length) = match bound {
+            Some(b) => b,
+            None => {
+                self.state.borrow_mut().dropped += 1;
+                return true;
+            }
+        };
+        // Packets still awaiting a verdict hold their place in the queue
+        if let Some(length) = max_length {
+            if self.outstanding(packet.queue) >= length as usize {
+                self.state.borrow_mut().dropped += 1;
+                return true;
+            }
+        }
+
+        let data = match mode {
+            Some(CopyMode::None) | Some(CopyMode::Metadata) => &packet.data[..0],
+            Some(CopyMode::Packet(range)) if (range as usize) < packet.data.len() => &packet.data[..range as usize],
+            _ => &packet.data[..]
+        };
+        let hw_protocol = match packet.data.first().map(|b| b >> 4) {
+            Some(6) => ETH_P_IPV6,
+            _ => ETH_P_IP
+        };
+        let header = Header { packet_id: packet.id.to_be(), hw_protocol: hw_protocol.to_be(), hook: packet.hook };
+        self.state.borrow_mut().outstanding.push((packet.queue, packet.id));
+        let message = Message::with_metadata(&header, data, packet.metadata);
+        deliver(queue, &message);
+        true
+    }
+
+    /// The number of packets handed to `queue` still awaiting a verdict
+    fn outstanding(&self, queue: u16) -> usize {
+        self.state.borrow().outstanding.iter().filter(|p| p.0 == queue).count()
+    }
+}
@@ -0,0 +1,227 @@
+//! Network emulation
+//!
+//! `Netem` impairs queued packets like the netem qdisc, for emulating WAN links in userspace:
+//! it holds them with `Deferred` verdicts to add latency and jitter, drops them at random or in bursts,
+//! lets some overtake those held, and sends duplicates through an `inject::Injector`.
+//! Held packets are kept on a timer wheel of millisecond ticks, so thousands can be held
+//! and each is released within a millisecond of its time.
+//! The queue's max-length must allow for every packet held at once.
+
+use std::time::{Duration, Instant};
+use error::Error;
+use inject::Injector;
+use message::Message;
+use queue::{Deferred, PacketHandler, QueueHandle, Verdict};
+use timer::TimerWheel;
+use util::{duration, seconds, Rng};
+
+/// The distribution of jitter around the latency
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Distribution {
+    /// Evenly spread within the jitter either side of the latency
+    Uniform,
+    /// Normally distributed with the jitter as standard deviation
+    Normal,
+    /// Pareto distributed, mostly a little early with a long tail of late packets, scaled by the jitter
+    Pareto,
+}
+
+/// How packets are lost
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Loss {
+    /// No packets are lost
+    None,
+    /// Each packet is lost with this probability
+    Random(f64),
+    /// Losses come in bursts, following the Gilbert-Elliott model of a link alternating between a good and a bad state
+    GilbertElliott {
+        /// The probability of moving from the good to the bad state, per packet
+        p: f64,
+        /// The probability of moving from the bad to the good state, per packet
+        r: f64,
+        /// The probability of losing a packet in the bad state
+        loss_bad: f64,
+        /// The probability of losing a packet in the good state
+        loss_good: f64,
+    },
+}
+
+/// The impairments of a `Netem`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    /// The delay added to every packet, before jitter
+    pub latency: Duration,
+    /// The spread of the delay around the latency
+    pub jitter: Duration,
+    /// How the delay is spread
+    pub distribution: Distribution,
+    /// How packets are lost
+    pub loss: Loss,
+    /// The probability of a packet being sent without delay, overtaking those held
+    pub reorder: f64,
+    /// The probability of a packet being duplicated, if an `Injector` is set
+    pub duplicate: f64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            latency: Duration::from_secs(0),
+            jitter: Duration::from_secs(0),
+            distribution: Distribution::Uniform,
+            loss: Loss::None,
+            reorder: 0.0,
+            duplicate: 0.0,
+        }
+    }
+}
+
+struct Held {
+    deferred: Deferred,
+    /// A copy of the packet to inject as its duplicate
+    duplicate: Option<Vec<u8>>,
+}
+
+/// A `PacketHandler` that delays, drops, reorders and duplicates packets
+pub struct Netem {
+    config: Config,
+    rng: Rng,
+    /// Whether the Gilbert-Elliott model is in the bad state
+    bad: bool,
+    held: TimerWheel<Held>,
+    injector: Option<Injector>,
+}
+
+impl Netem {
+    /// Impair packets as `config` says, at random
+    pub fn new(config: Config) -> Netem {
+        Netem::with_rng(config, Rng::from_time())
+    }
+
+    /// Impair packets as `config` says, with a reproducible sequence of random choices
+    pub fn with_seed(config: Config, seed: u64) -> Netem {
+        Netem::with_rng(config, Rng::new(seed))
+    }
+
+    fn with_rng(config: Config, rng: Rng) -> Netem {
+        Netem {
+            config: config,
+            rng: rng,
+            bad: false,
+            held: TimerWheel::new(Instant::now(), Duration::from_millis(1), 4096),
+            injector: None,
+        }
+    }
+
+    /// Change the impairments, for packets arriving from now on
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    /// The impairments
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    /// Send duplicates through `injector`
+    ///
+    /// Packets are only duplicated once an injector is set,
+    /// and the copy range of `CopyMode::Packet` covers whole packets.
+    pub fn set_injector(&mut self, injector: Injector) {
+        self.injector = Some(injector);
+    }
+
+    /// The number of packets held
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    /// Whether the next packet is lost
+    fn lose(&mut self) -> bool {
+        match self.config.loss {
+            Loss::None => false,
+            Loss::Random(p) => self.rng.chance(p),
+            Loss::GilbertElliott { p, r, loss_bad, loss_good } => {
+                let switch = match self.bad {
+                    true => r,
+                    false => p
+                };
+                if self.rng.chance(switch) {
+                    self.bad = !self.bad;
+                }
+                let loss = match self.bad {
+                    true => loss_bad,
+                    false => loss_good
+                };
+                self.rng.chance(loss)
+            }
+        }
+    }
+
+    /// The delay of the next packet
+    fn delay(&mut self) -> Duration {
+        let latency = seconds(self.config.latency);
+        let jitter = seconds(self.config.jitter);
+        if jitter == 0.0 {
+            return self.config.latency;
+        }
+        let deviation = match self.config.distribution {
+            Distribution::Uniform => self.rng.next_f64() * 2.0 - 1.0,
+            Distribution::Normal => {
+                // Box-Muller
+                let u = 1.0 - self.rng.next_f64();
+                let v = self.rng.next_f64();
+                (-2.0 * u.ln()).sqrt() * (2.0 * ::std::f64::consts::PI * v).cos()
+            },
+            Distribution::Pareto => {
+                // Shape 3, scaled to a mean of zero
+                let u = 1.0 - self.rng.next_f64();
+                2.0 / 3.0 / u.powf(1.0 / 3.0) - 1.0
+            }
+        };
+        duration((latency + jitter * deviation).max(0.0))
+    }
+
+    fn inject(&self, duplicate: Option<Vec<u8>>) {
+        if let (Some(data), Some(injector)) = (duplicate, self.injector.as_ref()) {
+            let _ = injector.send(&data);
+        }
+    }
+}
+
+impl PacketHandler for Netem {
+    fn handle(&mut self, hq: &QueueHandle, message: Result<&Message, &Error>) -> i32 {
+        let message = match message {
+            Ok(m) => m,
+            Err(_) => return 0
+        };
+        if self.lose() {
+            let _ = Verdict::set_message_verdict(hq, message, Verdict::Drop);
+            return 0;
+        }
+        let duplicate = match self.injector.is_some() && self.rng.chance(self.config.duplicate) {
+            true => message.decode().ok().filter(|p| p.is_complete()).map(|p| p.data().to_vec()),
+            false => None
+        };
+        let delay = match self.rng.chance(self.config.reorder) {
+            true => Duration::from_secs(0),
+            false => self.delay()
+        };
+        if delay == Duration::from_secs(0) {
+            let _ = Verdict::set_message_verdict(hq, message, Verdict::Accept);
+            self.inject(duplicate);
+            return 0;
+        }
+        let held = Held { deferred: Deferred::new(hq, message.header.id()), duplicate: duplicate };
+        self.held.insert(Instant::now() + delay, held);
+        0
+    }
+
+    fn poll(&mut self, _: &QueueHandle, now: Instant) -> Option<Instant> {
+        for held in self.held.expire(now) {
+            let _ = held.deferred.set(Verdict::Accept);
+            self.inject(held.duplicate);
+        }
+        self.held.next_expiry()
+    }
+}
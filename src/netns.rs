@@ -0,0 +1,313 @@
+//! Network namespace test harness
+//!
+//! Built with the `netns` feature. A `Namespace` is a throwaway pair of network namespaces
+//! joined by a veth pair: packets sent from the peer namespace arrive in the other,
+//! where `queue` installs nftables rules queueing them and `serve` runs a `Handle` to decide them.
+//! Nothing is added to the host's namespace, and both are deleted when the `Namespace` is dropped.
+//!
+//! Needs root, or `CAP_NET_ADMIN` and `CAP_SYS_ADMIN`, along with the `ip` and `nft` commands
+//! and the `nfnetlink_queue` module.
+//!
+//! ```text
+//! let ns = Namespace::new().unwrap();
+//! ns.queue(0, "udp dport 9000").unwrap();
+//! let _server = ns.serve(0, || |_: &Message| Verdict::Drop).unwrap();
+//! assert_eq!(ns.send_udp(9000, b"ping").unwrap(), None);
+//! ```
+
+use std::error::Error as Base;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use libc::c_int;
+use handle::{Handle, ProtocolFamily};
+use queue::{CopyMode, PacketHandler, Queue};
+
+// Missing from libc
+const CLONE_NEWNET: c_int = 0x40000000;
+
+extern "C" {
+    fn setns(fd: c_int, nstype: c_int) -> c_int;
+}
+
+/// The address of the namespace packets are queued in
+const ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 200, 0, 1);
+/// The address of the peer namespace packets are sent from
+const PEER_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 200, 0, 2);
+
+/// The nftables table and chain queueing rules are added to
+const TABLE: &'static str = "libnfqueue";
+const CHAIN: &'static str = "input";
+
+/// Namespaces created by this process, so each has a name of its own
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Reasons a namespace could not be set up or used
+#[derive(Debug)]
+pub enum NetnsError {
+    /// A namespace could not be entered, or a socket used
+    Io(io::Error),
+    /// A command failed, with its arguments and what it wrote to stderr
+    Command(String, String),
+    /// The handle or queue could not be opened
+    Queue(String),
+}
+
+impl fmt::Display for NetnsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NetnsError::Io(ref e) => write!(f, "{}: {}", self.reason(), e),
+            NetnsError::Command(ref command, ref stderr) => write!(f, "{}: `{}`: {}", self.reason(), command, stderr.trim()),
+            NetnsError::Queue(ref e) => write!(f, "{}: {}", self.reason(), e),
+        }
+    }
+}
+
+impl Base for NetnsError {
+    fn description(&self) -> &str {
+        self.reason()
+    }
+}
+
+impl NetnsError {
+    fn reason(&self) -> &'static str {
+        match *self {
+            NetnsError::Io(_) => "Failed to use namespace",
+            NetnsError::Command(..) => "Command failed",
+            NetnsError::Queue(_) => "Failed to open queue",
+        }
+    }
+}
+
+impl From<io::Error> for NetnsError {
+    fn from(e: io::Error) -> NetnsError {
+        NetnsError::Io(e)
+    }
+}
+
+/// A namespace to queue packets in, and a peer to send them from
+pub struct Namespace {
+    name: String,
+    peer: String,
+}
+
+impl Drop for Namespace {
+    fn drop(&mut self) {
+        let _ = run("ip", &["netns", "del", &self.name]);
+        let _ = run("ip", &["netns", "del", &self.peer]);
+    }
+}
+
+impl Namespace {
+    /// Create the namespaces, with `10.200.0.1/24` and `10.200.0.2/24` on either end of their veth pair
+    ///
+    /// The queueing namespace starts with an empty nftables `input` chain, so accepts everything.
+    pub fn new() -> Result<Namespace, NetnsError> {
+        let name = format!("libnfqueue-{}-{}", process::id(), CREATED.fetch_add(1, Ordering::SeqCst));
+        let ns = Namespace { peer: format!("{}-peer", name), name: name };
+        run("ip", &["netns", "add", &ns.name])?;
+        run("ip", &["netns", "add", &ns.peer])?;
+        run("ip", &["link", "add", "nfq0", "netns", &ns.name, "type", "veth", "peer", "name", "nfq1", "netns", &ns.peer])?;
+        for &(namespace, device, address) in [(&ns.name, "nfq0", ADDRESS), (&ns.peer, "nfq1", PEER_ADDRESS)].iter() {
+            run("ip", &["-n", namespace, "addr", "add", &format!("{}/24", address), "dev", device])?;
+            run("ip", &["-n", namespace, "link", "set", device, "up"])?;
+            run("ip", &["-n", namespace, "link", "set", "lo", "up"])?;
+        }
+        ns.nft(&["add", "table", "inet", TABLE])?;
+        ns.nft(&["add", "chain", "inet", TABLE, CHAIN, "{ type filter hook input priority 0 ; }"])?;
+        Ok(ns)
+    }
+
+    /// The name of the namespace packets are queued in
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The name of the namespace packets are sent from
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    /// The address packets are sent to
+    pub fn address(&self) -> Ipv4Addr {
+        ADDRESS
+    }
+
+    /// The address packets are sent from
+    pub fn peer_address(&self) -> Ipv4Addr {
+        PEER_ADDRESS
+    }
+
+    /// Queue packets arriving in the namespace that match an nftables expression to `queue`
+    ///
+    /// e.g. `ns.queue(0, "udp dport 9000")`. Packets queued while nothing listens on `queue` are dropped.
+    pub fn queue(&self, queue: u16, matches: &str) -> Result<(), NetnsError> {
+        let number = queue.to_string();
+        let mut args = vec!["add", "rule", "inet", TABLE, CHAIN];
+        args.extend(matches.split_whitespace());
+        args.extend(&["queue", "num", &number]);
+        self.nft(&args)
+    }
+
+    /// Run `nft` in the namespace packets are queued in
+    pub fn nft(&self, args: &[&str]) -> Result<(), NetnsError> {
+        let mut command = vec!["netns", "exec", &self.name, "nft"];
+        command.extend(args);
+        run("ip", &command)
+    }
+
+    /// A command that runs `program` in the peer namespace, to send other traffic, such as `ping`
+    pub fn peer_command(&self, program: &str) -> Command {
+        let mut command = Command::new("ip");
+        command.args(&["netns", "exec", &self.peer, program]);
+        command
+    }
+
+    /// Move the current thread into the namespace packets are queued in, until the guard is dropped
+    ///
+    /// Sockets, and `Handle`s, opened meanwhile stay in the namespace after it is left.
+    pub fn enter(&self) -> Result<Entered, NetnsError> {
+        enter(&self.name)
+    }
+
+    /// Move the current thread into the peer namespace, until the guard is dropped
+    pub fn enter_peer(&self) -> Result<Entered, NetnsError> {
+        enter(&self.peer)
+    }
+
+    /// Decide the packets sent to `queue` in a thread of their own, until the `Server` is dropped
+    ///
+    /// `handler` is called in the thread, so the handler need not be `Send`.
+    /// Returns once the queue is open, with the whole of each packet copied.
+    pub fn serve<F, H>(&self, queue: u16, handler: F) -> Result<Server, NetnsError>
+            where F: FnOnce() -> H + Send + 'static, H: PacketHandler + 'static {
+        let name = self.name.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let (ready, opened) = mpsc::channel();
+
+        let worker = thread::spawn(move || {
+            match open(&name, queue, handler()) {
+                Ok((mut handle, queue)) => {
+                    let _ = ready.send(Ok(()));
+                    while !stopped.load(Ordering::SeqCst) {
+                        if !handle.step() {
+                            thread::sleep(Duration::from_millis(1));
+                        }
+                    }
+                    // The queue is destroyed through the handle, so goes first
+                    drop(queue);
+                },
+                Err(e) => { let _ = ready.send(Err(e)); }
+            }
+        });
+
+        match opened.recv() {
+            Ok(Ok(())) => Ok(Server { stop: stop, thread: Some(worker) }),
+            Ok(Err(e)) => {
+                let _ = worker.join();
+                Err(e)
+            },
+            Err(_) => {
+                let _ = worker.join();
+                Err(NetnsError::Queue("handler panicked".to_string()))
+            }
+        }
+    }
+
+    /// Send a UDP datagram from the peer namespace to `port`, returning what arrived, if anything
+    ///
+    /// Waits a second for the datagram, so a dropped one returns `None`,
+    /// and one rewritten by the handler returns its new payload.
+    pub fn send_udp(&self, port: u16, payload: &[u8]) -> Result<Option<Vec<u8>>, NetnsError> {
+        let receiver = {
+            let _entered = self.enter()?;
+            UdpSocket::bind((ADDRESS, port))?
+        };
+        receiver.set_read_timeout(Some(Duration::from_secs(1)))?;
+        {
+            let _entered = self.enter_peer()?;
+            let sender = UdpSocket::bind((PEER_ADDRESS, 0))?;
+            sender.send_to(payload, (ADDRESS, port))?;
+        }
+
+        let mut buffer = vec![0; 0xffff];
+        match receiver.recv_from(&mut buffer) {
+            Ok((len, _)) => {
+                buffer.truncate(len);
+                Ok(Some(buffer))
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(NetnsError::Io(e))
+        }
+    }
+}
+
+/// A thread deciding queued packets, see `Namespace::serve`
+///
+/// Dropping it stops the thread, and panics if the handler did.
+pub struct Server {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() && !thread::panicking() {
+                panic!("Packet handler panicked");
+            }
+        }
+    }
+}
+
+/// A thread moved into a namespace, returned to its own when dropped
+pub struct Entered {
+    original: File,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        if unsafe { setns(self.original.as_raw_fd(), CLONE_NEWNET) } != 0 {
+            panic!("Failed to leave network namespace");
+        }
+    }
+}
+
+/// Move the current thread into the namespace named `name`
+fn enter(name: &str) -> Result<Entered, NetnsError> {
+    let original = File::open("/proc/thread-self/ns/net")?;
+    let namespace = File::open(format!("/var/run/netns/{}", name))?;
+    match unsafe { setns(namespace.as_raw_fd(), CLONE_NEWNET) } {
+        0 => Ok(Entered { original: original }),
+        _ => Err(NetnsError::Io(io::Error::last_os_error()))
+    }
+}
+
+/// Open a handle and queue in the namespace named `name`
+fn open<H: PacketHandler>(name: &str, number: u16, handler: H) -> Result<(Handle, Box<Queue<H>>), NetnsError> {
+    let _entered = enter(name)?;
+    let mut handle = Handle::new().map_err(|e| NetnsError::Queue(e.to_string()))?;
+    handle.bind(ProtocolFamily::INET).map_err(|e| NetnsError::Queue(e.to_string()))?;
+    let mut queue = handle.queue(number, handler).map_err(|e| NetnsError::Queue(e.to_string()))?;
+    queue.set_mode(CopyMode::Packet(0xffff)).map_err(|e| NetnsError::Queue(e.to_string()))?;
+    Ok((handle, queue))
+}
+
+/// Run a command, failing if it does
+fn run(program: &str, args: &[&str]) -> Result<(), NetnsError> {
+    let output = Command::new(program).args(args).output()?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(NetnsError::Command(format!("{} {}", program, args.join(" ")),
+                                         String::from_utf8_lossy(&output.stderr).into_owned()))
+    }
+}
@@ -0,0 +1,52 @@
+//! Verdicts set after the callback has returned
+use libc::*;
+use std::ptr::null;
+use error::*;
+use message::PacketBuffer;
+use super::verdict::Verdict;
+use super::QueueHandle;
+
+/// A packet whose verdict will be set later
+///
+/// The kernel holds a queued packet until its verdict is set,
+/// so a handler may keep a `Deferred` past the callback, e.g. until more packets have been seen.
+/// Held packets count towards the queue's max-length.
+/// Setting the verdict fails once the `Queue` it came from is dropped.
+pub struct Deferred {
+    qh: QueueHandle,
+    id: u32,
+}
+
+impl Deferred {
+    /// Defer the verdict of the packet `packet_id` of the queue `qh`
+    pub fn new(qh: &QueueHandle, packet_id: u32) -> Deferred {
+        Deferred { qh: qh.clone(), id: packet_id }
+    }
+
+    /// The id of the held packet
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Set the verdict of the held packet
+    pub fn set(self, verdict: Verdict) -> Result<c_int, Error> {
+        Verdict::set_verdict(&self.qh, self.id, verdict, 0, null())
+    }
+
+    /// Set the verdict of the held packet, replacing it with a rewritten one
+    pub fn set_mangled(self, verdict: Verdict, buffer: &mut PacketBuffer) -> Result<c_int, Error> {
+        Verdict::set_mangled_verdict(&self.qh, self.id, verdict, buffer)
+    }
+
+    /// Set the verdict of the held packet, and its mark
+    pub fn set_marked(self, verdict: Verdict, mark: u32) -> Result<c_int, Error> {
+        Verdict::set_marked_verdict(&self.qh, self.id, verdict, mark, 0, null())
+    }
+
+    /// Set the verdict of the held packet, along with a mark and a rewritten packet if given
+    ///
+    /// This passes on what a handler set on the packet's message, see `Message::take_mark` and `Message::take_mangled`.
+    pub fn set_rewritten(self, verdict: Verdict, mark: Option<u32>, buffer: Option<&mut PacketBuffer>) -> Result<c_int, Error> {
+        Verdict::set_rewritten_verdict(&self.qh, self.id, verdict, mark, buffer)
+    }
+}
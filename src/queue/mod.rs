@@ -3,25 +3,33 @@
 //! The queue handle and callback,
 //! analagous to <http://netfilter.org/projects/libnetfilter_queue/doxygen/group__Queue.html>
 mod verdict;
+mod deferred;
 
 use libc::*;
+use std::cell::{Cell, RefCell};
 use std::mem;
-use std::ptr::null;
+use std::ptr::null_mut;
+use std::rc::Rc;
+use std::time::Instant;
 
 use error::*;
 use util::*;
 use message::{Message, Payload};
+use mock::{Mock, Call};
 pub use self::verdict::Verdict;
+pub use self::deferred::Deferred;
 use lock::NFQ_LOCK as LOCK;
 
 use ffi::*;
-pub use ffi::nfq_q_handle as QueueHandle;
 
 const NFQNL_COPY_NONE: uint8_t = 0;
 const NFQNL_COPY_META: uint8_t = 1;
 const NFQNL_COPY_PACKET: uint8_t = 2;
 
+const NFQA_CFG_F_UID_GID: uint32_t = 1 << 3;
+
 /// The amount of data to be copied to userspace for each packet queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CopyMode {
     /// None
     None,
@@ -31,9 +39,40 @@ pub enum CopyMode {
     Packet(u16)
 }
 
+/// Where the verdicts set on a queue's packets go
+#[derive(Clone)]
+enum Backend {
+    /// The kernel, through libnetfilter_queue, while the queue exists
+    ///
+    /// Shared with every clone of the handle, and null before the queue is created and once it is destroyed.
+    Netfilter(Rc<Cell<*mut nfq_q_handle>>),
+    /// A `Mock`, with the queue number
+    Memory(Mock, u16)
+}
+
+/// The queue a packet came from, on which its verdict is set
+///
+/// Handlers are passed the handle of their queue, and may clone it to set verdicts later, see `Deferred`.
+/// Verdicts set through a handle once its queue is dropped fail.
+#[derive(Clone)]
+pub struct QueueHandle {
+    backend: Backend
+}
+
+impl QueueHandle {
+    #[doc(hidden)]
+    /// A handle whose verdicts are recorded by `mock` as set on `queue_number`
+    pub fn in_memory(mock: &Mock, queue_number: u16) -> QueueHandle {
+        QueueHandle { backend: Backend::Memory(mock.clone(), queue_number) }
+    }
 
+    /// A handle for a queue not yet created in the kernel
+    fn netfilter() -> QueueHandle {
+        QueueHandle { backend: Backend::Netfilter(Rc::new(Cell::new(null_mut()))) }
+    }
+}
 
-extern fn queue_callback<F: PacketHandler>(qh: *mut QueueHandle,
+extern fn queue_callback<F: PacketHandler>(_: *mut nfq_q_handle,
                                            nfmsg: *mut nfgenmsg,
                                            nfad: *mut nfq_data,
                                            cdata: *mut c_void) -> c_int {
@@ -41,20 +80,64 @@ extern fn queue_callback<F: PacketHandler>(qh: *mut QueueHandle,
     let queue: &mut Queue<F> = unsafe { as_mut(&queue_ptr).unwrap() };
     let message = Message::new(nfmsg, nfad);
 
-    queue.callback.handle(qh, message.as_ref()) as c_int
+    queue.callback.handle(&queue.handle, message.as_ref()) as c_int
+}
+
+fn queue_deliver<F: PacketHandler>(queue: *mut c_void, message: &Message) -> i32 {
+    let queue_ptr: *mut Queue<F> = queue as *mut Queue<F>;
+    let queue: &mut Queue<F> = unsafe { as_mut(&queue_ptr).unwrap() };
+    queue.callback.handle(&queue.handle, Ok(message))
+}
+
+fn queue_poll<F: PacketHandler>(queue: *mut c_void, now: Instant) -> Option<Instant> {
+    let queue_ptr: *mut Queue<F> = queue as *mut Queue<F>;
+    let queue: &mut Queue<F> = unsafe { as_mut(&queue_ptr).unwrap() };
+    queue.callback.poll(&queue.handle, now)
+}
+
+#[doc(hidden)]
+pub struct Poller {
+    queue: *mut c_void,
+    poll: fn(*mut c_void, Instant) -> Option<Instant>,
+}
+
+#[doc(hidden)]
+/// The queues of a `Handle`, polled for timers by its loop
+pub type Pollers = Rc<RefCell<Vec<Poller>>>;
+
+#[doc(hidden)]
+/// Poll every queue, returning the earliest time any needs to be polled again
+pub fn poll_all(pollers: &Pollers, now: Instant) -> Option<Instant> {
+    pollers.borrow().iter().filter_map(|p| (p.poll)(p.queue, now)).min()
 }
 
 /// A handle to an NFQueue queue
 ///
 /// This is used to set queue-specific settings, such as copy-mode and max-length.
 pub struct Queue<F: PacketHandler> {
-    ptr: *mut QueueHandle,
-    callback: F
+    handle: QueueHandle,
+    callback: F,
+    pollers: Pollers
 }
 
 impl<F: PacketHandler> Drop for Queue<F> {
     fn drop(&mut self) {
-        let ret = unsafe { nfq_destroy_queue(self.ptr) };
+        let queue_ptr = self as *mut Queue<F> as *mut c_void;
+        self.pollers.borrow_mut().retain(|p| p.queue != queue_ptr);
+
+        let ptr = match self.handle.backend {
+            // Clones of the handle fail from now on
+            Backend::Netfilter(ref ptr) => ptr.replace(null_mut()),
+            Backend::Memory(ref mock, number) => {
+                mock.unbind_queue(number);
+                return;
+            }
+        };
+        if ptr.is_null() {
+            // Never created
+            return;
+        }
+        let ret = unsafe { nfq_destroy_queue(ptr) };
         if ret != 0 {
             panic!("Failed to destroy nfq queue");
         }
@@ -65,13 +148,14 @@ impl<F: PacketHandler> Queue<F> {
     #[doc(hidden)]
     pub fn new(handle: *mut nfq_handle,
                queue_number: uint16_t,
-               packet_handler: F) -> Result<Box<Queue<F>>, Error> {
+               packet_handler: F,
+               pollers: Pollers) -> Result<Box<Queue<F>>, Error> {
         let _lock = LOCK.lock().unwrap();
 
-        let nfq_ptr: *const QueueHandle = null();
         let mut queue: Box<Queue<F>> = Box::new(Queue {
-            ptr: nfq_ptr as *mut QueueHandle, // set after nfq_create_queue
+            handle: QueueHandle::netfilter(), // set after nfq_create_queue
             callback: packet_handler,
+            pollers: pollers
         });
         let queue_ptr: *mut Queue<F> = &mut *queue;
 
@@ -84,9 +168,31 @@ impl<F: PacketHandler> Queue<F> {
 
         if ptr.is_null() {
             return Err(error(Reason::CreateQueue, "Failed to create queue", None));
-        } else {
-            queue.ptr = ptr;
+        } else if let Backend::Netfilter(ref handle) = queue.handle.backend {
+            handle.set(ptr);
         }
+        queue.pollers.borrow_mut().push(Poller { queue: queue_ptr as *mut c_void, poll: queue_poll::<F> });
+
+        Ok(queue)
+    }
+
+    #[doc(hidden)]
+    pub fn in_memory(mock: &Mock,
+                     queue_number: u16,
+                     packet_handler: F,
+                     pollers: Pollers) -> Result<Box<Queue<F>>, Error> {
+        let mut queue: Box<Queue<F>> = Box::new(Queue {
+            handle: QueueHandle::netfilter(), // set once bound
+            callback: packet_handler,
+            pollers: pollers
+        });
+        let queue_ptr: *mut Queue<F> = &mut *queue;
+
+        if !mock.bind_queue(queue_number, queue_ptr as *mut c_void, queue_deliver::<F>) {
+            return Err(error(Reason::CreateQueue, "Failed to create queue", None));
+        }
+        queue.handle = QueueHandle::in_memory(mock, queue_number);
+        queue.pollers.borrow_mut().push(Poller { queue: queue_ptr as *mut c_void, poll: queue_poll::<F> });
 
         Ok(queue)
     }
@@ -103,7 +209,14 @@ impl<F: PacketHandler> Queue<F> {
             _ => 0
         } as uint32_t;
 
-        let res = unsafe { nfq_set_mode(self.ptr, copy_mode, range) };
+        let ptr = match self.handle.backend {
+            Backend::Netfilter(ref ptr) => ptr.get(),
+            Backend::Memory(ref mock, number) => {
+                mock.call(Call::SetMode(number, mode));
+                return Ok(());
+            }
+        };
+        let res = unsafe { nfq_set_mode(ptr, copy_mode, range) };
         if res != 0 {
             Err(error(Reason::SetQueueMode, "Failed to set queue mode", Some(res)))
         } else {
@@ -124,13 +237,43 @@ impl<F: PacketHandler> Queue<F> {
     ///
     /// Once `length` packets are enqueued, packets will be dropped until enqueued packets are processed.
     pub fn set_max_length(&mut self, length: u32) -> Result<(), Error> {
-        let res = unsafe { nfq_set_queue_maxlen(self.ptr, length) };
+        let ptr = match self.handle.backend {
+            Backend::Netfilter(ref ptr) => ptr.get(),
+            Backend::Memory(ref mock, number) => {
+                mock.call(Call::SetMaxLength(number, length));
+                return Ok(());
+            }
+        };
+        let res = unsafe { nfq_set_queue_maxlen(ptr, length) };
         if res != 0 {
             Err(error(Reason::SetQueueMaxlen, "Failed to set queue maxlen", Some(res)))
         } else {
             Ok(())
         }
     }
+
+    /// Set whether the uid and gid of the socket that sent a packet are queued with it
+    ///
+    /// Needed for `message.uid()`, and only known for packets sent from this host.
+    pub fn set_uid_gid(&mut self, enabled: bool) -> Result<(), Error> {
+        let flags = match enabled {
+            true => NFQA_CFG_F_UID_GID,
+            false => 0
+        };
+        let ptr = match self.handle.backend {
+            Backend::Netfilter(ref ptr) => ptr.get(),
+            Backend::Memory(ref mock, number) => {
+                mock.call(Call::SetUidGid(number, enabled));
+                return Ok(());
+            }
+        };
+        let res = unsafe { nfq_set_queue_flags(ptr, NFQA_CFG_F_UID_GID, flags) };
+        if res != 0 {
+            Err(error(Reason::SetQueueFlags, "Failed to set queue flags", Some(res)))
+        } else {
+            Ok(())
+        }
+    }
 }
 
 /// Invoked to handle packets from the queue
@@ -138,7 +281,17 @@ pub trait PacketHandler {
     /// Handle a packet from the queue
     ///
     /// `Verdict`s must be set using the `set_verdict` fn.
-    fn handle(&mut self, hq: *mut QueueHandle, message: Result<&Message, &Error>) -> i32;
+    fn handle(&mut self, hq: &QueueHandle, message: Result<&Message, &Error>) -> i32;
+
+    /// Handle timers, such as releasing `Deferred` verdicts
+    ///
+    /// Called by the `Handle`'s loop after every packet, and whenever the time last returned has passed.
+    /// Returns when the handler next needs to be polled, if ever.
+    /// This should return quickly when nothing is due.
+    fn poll(&mut self, hq: &QueueHandle, now: Instant) -> Option<Instant> {
+        let _ = (hq, now);
+        None
+    }
 }
 
 /// An abstraction over `PacketHandler` for simple handling that needs only a `Verdict`
@@ -146,15 +299,17 @@ pub trait VerdictHandler {
     /// Handle a packet from the queue
     ///
     /// Only properly formed `Message`s will be passed to this fn.
+    /// A packet rewritten through `message.mangle()` is returned to the kernel along with the verdict.
     fn decide(&mut self, message: &Message) -> Verdict;
 }
 
-#[allow(non_snake_case)]
 impl<V> PacketHandler for V where V: VerdictHandler {
-    fn handle(&mut self, hq: *mut QueueHandle, message: Result<&Message, &Error>) -> i32 {
-        let NULL: *const c_uchar = null();
+    fn handle(&mut self, hq: &QueueHandle, message: Result<&Message, &Error>) -> i32 {
         match message {
-            Ok(m) => { let _ = Verdict::set_verdict(hq, m.header.id(), self.decide(m), 0, NULL); },
+            Ok(m) => {
+                let verdict = self.decide(m);
+                let _ = Verdict::set_message_verdict(hq, m, verdict);
+            },
             Err(_) => ()
         }
         0
@@ -1,10 +1,15 @@
 //! Verdict and packet handling for NFQueue packets.
 use libc::*;
+use std::ptr::null;
+use std::slice;
 use error::*;
+use message::{Message, PacketBuffer};
+use mock::Decision;
+use super::{Backend, QueueHandle};
 use ffi::*;
-use ffi::nfq_q_handle as QueueHandle;
 
 /// Packet verdict used to notify netfilter of a packet's destiny
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Verdict {
     /// Drop the packet and release it's memory
     Drop,
@@ -41,12 +46,88 @@ impl Verdict {
     ///
     /// The `packet_id` must be used to identify a packet, fetched from `packet.header.id()`.
     /// For simpler cases, pass `data_len = 0` and `buffer = std::ptr::null()`.
-    pub fn set_verdict(qh: *mut QueueHandle, packet_id: u32, verdict: Verdict, data_len: u32, buffer: *const c_uchar) -> Result<c_int, Error> {
+    pub fn set_verdict(qh: &QueueHandle, packet_id: u32, verdict: Verdict, data_len: u32, buffer: *const c_uchar) -> Result<c_int, Error> {
+        let ptr = match netfilter(qh, packet_id, verdict, None, data_len, buffer)? {
+            Some(ptr) => ptr,
+            None => return Ok(0)
+        };
 	let c_verdict = verdict.as_u32() as uint32_t;
 
-        match unsafe { nfq_set_verdict(qh, packet_id as uint32_t, c_verdict as uint32_t, data_len as uint32_t, buffer) } {
+        match unsafe { nfq_set_verdict(ptr, packet_id as uint32_t, c_verdict as uint32_t, data_len as uint32_t, buffer) } {
             -1 => Err(error(Reason::SetVerdict, "Failed to set verdict", None)),
             r @ _ => Ok(r)
         }
     }
+
+    /// Set the verdict for a packet, and its mark
+    ///
+    /// The mark can be matched by later rules, or saved to the connection with `CONNMARK --save-mark`.
+    pub fn set_marked_verdict(qh: &QueueHandle, packet_id: u32, verdict: Verdict, mark: u32, data_len: u32, buffer: *const c_uchar) -> Result<c_int, Error> {
+        let ptr = match netfilter(qh, packet_id, verdict, Some(mark), data_len, buffer)? {
+            Some(ptr) => ptr,
+            None => return Ok(0)
+        };
+        let c_verdict = verdict.as_u32() as uint32_t;
+
+        match unsafe { nfq_set_verdict2(ptr, packet_id as uint32_t, c_verdict, mark as uint32_t, data_len as uint32_t, buffer) } {
+            -1 => Err(error(Reason::SetVerdict, "Failed to set verdict", None)),
+            r @ _ => Ok(r)
+        }
+    }
+
+    /// Set the verdict for a packet, replacing it with a rewritten one
+    ///
+    /// Stale checksums in `buffer` are recomputed before it is handed to the kernel.
+    pub fn set_mangled_verdict(qh: &QueueHandle, packet_id: u32, verdict: Verdict, buffer: &mut PacketBuffer) -> Result<c_int, Error> {
+        let data = buffer.finish();
+        Verdict::set_verdict(qh, packet_id, verdict, data.len() as u32, data.as_ptr())
+    }
+
+    /// Set the verdict for a message
+    ///
+    /// If the packet was rewritten through `message.mangle()`, the rewritten packet replaces it,
+    /// and a mark given to `message.set_mark()` is set along with the verdict.
+    pub fn set_message_verdict(qh: &QueueHandle, message: &Message, verdict: Verdict) -> Result<c_int, Error> {
+        let mut buffer = message.take_mangled();
+        Verdict::set_rewritten_verdict(qh, message.header.id(), verdict, message.take_mark(), buffer.as_mut())
+    }
+
+    /// Set the verdict for a packet, along with a mark and a rewritten packet if given
+    ///
+    /// This is `set_message_verdict` for handlers that take the rewritten packet and mark from a message
+    /// themselves, to look at them once the verdict is set.
+    pub fn set_rewritten_verdict(qh: &QueueHandle, packet_id: u32, verdict: Verdict, mark: Option<u32>,
+                                 buffer: Option<&mut PacketBuffer>) -> Result<c_int, Error> {
+        match (buffer, mark) {
+            (Some(buffer), Some(mark)) => {
+                let data = buffer.finish();
+                Verdict::set_marked_verdict(qh, packet_id, verdict, mark, data.len() as u32, data.as_ptr())
+            },
+            (Some(buffer), None) => Verdict::set_mangled_verdict(qh, packet_id, verdict, buffer),
+            (None, Some(mark)) => Verdict::set_marked_verdict(qh, packet_id, verdict, mark, 0, null()),
+            (None, None) => Verdict::set_verdict(qh, packet_id, verdict, 0, null())
+        }
+    }
+}
+
+/// The queue to set a verdict on through libnetfilter_queue, or `None` if it was recorded by a `Mock` instead
+fn netfilter(qh: &QueueHandle, packet_id: u32, verdict: Verdict, mark: Option<u32>,
+             data_len: u32, buffer: *const c_uchar) -> Result<Option<*mut nfq_q_handle>, Error> {
+    match qh.backend {
+        Backend::Netfilter(ref ptr) if ptr.get().is_null() => Err(error(Reason::SetVerdict, "Queue was destroyed", None)),
+        Backend::Netfilter(ref ptr) => Ok(Some(ptr.get())),
+        Backend::Memory(ref mock, number) => {
+            let payload = payload(data_len, buffer).map(|p| p.to_vec());
+            mock.record(number, Decision { id: packet_id, verdict: verdict, mark: mark, payload: payload });
+            Ok(None)
+        }
+    }
+}
+
+/// The packet passed with a verdict, if any
+fn payload<'a>(data_len: u32, buffer: *const c_uchar) -> Option<&'a [u8]> {
+    match buffer.is_null() {
+        true => None,
+        false => Some(unsafe { slice::from_raw_parts(buffer, data_len as usize) })
+    }
 }
@@ -0,0 +1,411 @@
+//! QUIC Initial packet decryption
+//!
+//! A QUIC client opens a connection with Initial packets whose CRYPTO frames carry the TLS ClientHello.
+//! They are encrypted, but with keys derived from the destination connection id in their header
+//! ([RFC 9001](https://tools.ietf.org/html/rfc9001) section 5.2, and RFC 9369 for QUIC version 2),
+//! so the SNI and ALPN of HTTP/3 can be read on the wire like those of TLS over TCP.
+//!
+//! A ClientHello with large key shares spans the Initials of several UDP datagrams;
+//! `ClientHelloAssembler` collects their CRYPTO frames until it is complete.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error as Base;
+use std::fmt;
+use std::time::{Duration, Instant};
+use util::*;
+use crypto::{aes128_gcm_open, hkdf_expand, hkdf_extract, Aes128};
+use tls::{ClientHello, TlsError};
+
+/// QUIC version 1, RFC 9000
+pub const VERSION_1: u32 = 0x0000_0001;
+/// QUIC version 2, RFC 9369
+pub const VERSION_2: u32 = 0x6b33_43cf;
+
+const SALT_V1: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17,
+    0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+];
+const SALT_V2: [u8; 20] = [
+    0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93,
+    0x81, 0xbe, 0x6e, 0x26, 0x9d, 0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+];
+
+/// The longest connection id of QUIC versions 1 and 2
+const MAX_CID: usize = 20;
+/// The most CRYPTO data buffered for a ClientHello
+const MAX_CRYPTO: u64 = 64 * 1024;
+
+const FRAME_PADDING: u64 = 0x00;
+const FRAME_PING: u64 = 0x01;
+const FRAME_ACK: u64 = 0x02;
+const FRAME_ACK_ECN: u64 = 0x03;
+const FRAME_CRYPTO: u64 = 0x06;
+const FRAME_CONNECTION_CLOSE: u64 = 0x1c;
+
+/// Reasons a QUIC Initial could not be read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuicError {
+    /// The datagram does not start with a QUIC Initial packet
+    NotInitial,
+    /// The version is neither 1 nor 2
+    Version(u32),
+    /// The packet is cut short
+    Truncated,
+    /// The packet failed to decrypt, as server Initials and corrupted packets do
+    Decrypt,
+    /// A frame not allowed in Initial packets, or a malformed one
+    Frame,
+    /// The ClientHello could not be parsed, or more of it is needed
+    Tls(TlsError),
+}
+
+impl fmt::Display for QuicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QuicError::Version(v) => write!(f, "Unsupported QUIC version {:#010x}", v),
+            QuicError::Tls(e) => write!(f, "{}", e),
+            _ => write!(f, "{}", self.reason())
+        }
+    }
+}
+
+impl Base for QuicError {
+    fn description(&self) -> &str {
+        self.reason()
+    }
+}
+
+impl QuicError {
+    fn reason(&self) -> &'static str {
+        match *self {
+            QuicError::NotInitial => "Not a QUIC Initial packet",
+            QuicError::Version(_) => "Unsupported QUIC version",
+            QuicError::Truncated => "Truncated QUIC packet",
+            QuicError::Decrypt => "Failed to decrypt QUIC Initial",
+            QuicError::Frame => "Malformed QUIC frame",
+            QuicError::Tls(_) => "Failed to parse ClientHello",
+        }
+    }
+}
+
+/// A decrypted client Initial packet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Initial {
+    /// The QUIC version
+    pub version: u32,
+    /// The destination connection id, from which the keys were derived
+    pub dcid: Vec<u8>,
+    /// The source connection id
+    pub scid: Vec<u8>,
+    /// The address validation token
+    pub token: Vec<u8>,
+    /// The packet number, as truncated on the wire
+    pub packet_number: u64,
+    /// The CRYPTO frames, as their offset into the handshake stream and data
+    pub crypto: Vec<(u64, Vec<u8>)>,
+}
+
+impl Initial {
+    /// Decrypt the client Initial packets of a UDP datagram
+    ///
+    /// Coalesced packets of other types are skipped.
+    /// Fails unless the datagram starts with an Initial that decrypts with the client's keys.
+    pub fn parse_datagram(datagram: &[u8]) -> Result<Vec<Initial>, QuicError> {
+        let mut initials = Vec::new();
+        let mut rest = datagram;
+        // Padding may follow the last packet
+        while !rest.is_empty() && rest[0] & 0x80 != 0 {
+            let (initial, len) = match Initial::parse(rest) {
+                Ok(parsed) => parsed,
+                Err(e) if initials.is_empty() => return Err(e),
+                Err(_) => break
+            };
+            match initial {
+                Some(initial) => initials.push(initial),
+                None if initials.is_empty() => return Err(QuicError::NotInitial),
+                None => ()
+            }
+            rest = &rest[len..];
+        }
+        match initials.is_empty() {
+            true => Err(QuicError::NotInitial),
+            false => Ok(initials)
+        }
+    }
+
+    /// Decrypt the long header packet at the start of `data`, returning it if it is an Initial, and its length
+    fn parse(data: &[u8]) -> Result<(Option<Initial>, usize), QuicError> {
+        let mut reader = Reader::new(data);
+        let first = match reader.u8() {
+            Some(first) if first & 0x80 != 0 => first,
+            Some(_) => return Err(QuicError::NotInitial),
+            None => return Err(QuicError::Truncated)
+        };
+        let version = reader.u32().ok_or(QuicError::Truncated)?;
+        let dcid = reader.vec8().ok_or(QuicError::Truncated)?;
+        let scid = reader.vec8().ok_or(QuicError::Truncated)?;
+        if dcid.len() > MAX_CID || scid.len() > MAX_CID {
+            return Err(QuicError::NotInitial);
+        }
+        // The long packet types were permuted by version 2
+        let (initial, retry) = match version {
+            VERSION_1 => (0, 3),
+            VERSION_2 => (1, 0),
+            _ => return Err(QuicError::Version(version))
+        };
+        let kind = (first >> 4) & 0x03;
+        if kind == retry {
+            return Ok((None, data.len()));
+        }
+        let token = match kind == initial {
+            true => {
+                let len = varint(&mut reader).ok_or(QuicError::Truncated)?;
+                reader.bytes(len as usize).ok_or(QuicError::Truncated)?
+            },
+            false => &[]
+        };
+        let length = varint(&mut reader).ok_or(QuicError::Truncated)? as usize;
+        let pn_offset = reader.position();
+        if data.len() - pn_offset < length || length < 20 {
+            return Err(QuicError::Truncated);
+        }
+        if kind != initial {
+            return Ok((None, pn_offset + length));
+        }
+
+        let keys = Keys::client_initial(version, dcid);
+        let mut mask = [0u8; 16];
+        mask.copy_from_slice(&data[pn_offset + 4..pn_offset + 20]);
+        Aes128::new(&keys.hp).encrypt_block(&mut mask);
+        let first = first ^ (mask[0] & 0x0f);
+        let pn_len = (first & 0x03) as usize + 1;
+        let mut header = data[..pn_offset + pn_len].to_vec();
+        header[0] = first;
+        let mut packet_number = 0u64;
+        for i in 0..pn_len {
+            header[pn_offset + i] ^= mask[1 + i];
+            packet_number = packet_number << 8 | header[pn_offset + i] as u64;
+        }
+        let mut nonce = keys.iv;
+        for i in 0..8 {
+            nonce[4 + i] ^= (packet_number >> (56 - 8 * i)) as u8;
+        }
+        let payload = match aes128_gcm_open(&keys.key, &nonce, &header, &data[pn_offset + pn_len..pn_offset + length]) {
+            Some(payload) => payload,
+            None => return Err(QuicError::Decrypt)
+        };
+
+        let initial = Initial {
+            version: version,
+            dcid: dcid.to_vec(),
+            scid: scid.to_vec(),
+            token: token.to_vec(),
+            packet_number: packet_number,
+            crypto: crypto_frames(&payload)?,
+        };
+        Ok((Some(initial), pn_offset + length))
+    }
+}
+
+/// Read the ClientHello from a single UDP datagram
+///
+/// Fails with `QuicError::Tls(TlsError::Incomplete)` if the ClientHello continues in later datagrams.
+pub fn client_hello(datagram: &[u8]) -> Result<ClientHello, QuicError> {
+    let mut crypto = Crypto::default();
+    for initial in Initial::parse_datagram(datagram)? {
+        crypto.add(initial.crypto);
+    }
+    crypto.client_hello()
+}
+
+/// Collects the CRYPTO frames of client Initials across datagrams until their ClientHello is complete
+///
+/// Connections are told apart by their destination connection id.
+pub struct ClientHelloAssembler {
+    connections: HashMap<Vec<u8>, (Instant, Crypto)>,
+    timeout: Duration,
+    max_connections: usize,
+}
+
+impl ClientHelloAssembler {
+    /// Collect ClientHellos of up to 1024 connections at once, for up to 10 seconds each
+    pub fn new() -> ClientHelloAssembler {
+        ClientHelloAssembler::with_limits(Duration::from_secs(10), 1024)
+    }
+
+    /// Collect ClientHellos of up to `max_connections` at once, for up to `timeout` each
+    pub fn with_limits(timeout: Duration, max_connections: usize) -> ClientHelloAssembler {
+        ClientHelloAssembler { connections: HashMap::new(), timeout: timeout, max_connections: max_connections }
+    }
+
+    /// Add a UDP datagram, returning the ClientHello once complete
+    ///
+    /// Fails with `QuicError::Tls(TlsError::Incomplete)` while more datagrams are needed.
+    pub fn add(&mut self, datagram: &[u8], now: Instant) -> Result<ClientHello, QuicError> {
+        let initials = Initial::parse_datagram(datagram)?;
+        let dcid = initials[0].dcid.clone();
+        if !self.connections.contains_key(&dcid) {
+            let timeout = self.timeout;
+            self.connections.retain(|_, &mut (started, _)| now < started + timeout);
+            if self.connections.len() >= self.max_connections {
+                let oldest = self.connections.iter().min_by_key(|&(_, &(started, _))| started).map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    self.connections.remove(&oldest);
+                }
+            }
+        }
+        let result = {
+            let &mut (_, ref mut crypto) = self.connections.entry(dcid.clone()).or_insert_with(|| (now, Crypto::default()));
+            for initial in initials {
+                crypto.add(initial.crypto);
+            }
+            crypto.client_hello()
+        };
+        if result != Err(QuicError::Tls(TlsError::Incomplete)) {
+            self.connections.remove(&dcid);
+        }
+        result
+    }
+
+    /// The number of connections with an incomplete ClientHello
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Whether no ClientHellos are incomplete
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+}
+
+impl Default for ClientHelloAssembler {
+    fn default() -> ClientHelloAssembler {
+        ClientHelloAssembler::new()
+    }
+}
+
+/// The start of the handshake stream, from CRYPTO frames in any order
+#[derive(Default)]
+struct Crypto {
+    data: Vec<u8>,
+    pending: BTreeMap<u64, Vec<u8>>,
+    overflowed: bool,
+}
+
+impl Crypto {
+    fn add(&mut self, frames: Vec<(u64, Vec<u8>)>) {
+        for (offset, data) in frames {
+            if offset + data.len() as u64 > MAX_CRYPTO {
+                self.overflowed = true;
+                continue;
+            }
+            self.pending.insert(offset, data);
+        }
+        while let Some(offset) = self.pending.keys().next().cloned() {
+            if offset > self.data.len() as u64 {
+                break;
+            }
+            let data = self.pending.remove(&offset).unwrap();
+            let seen = self.data.len() - offset as usize;
+            if data.len() > seen {
+                self.data.extend_from_slice(&data[seen..]);
+            }
+        }
+    }
+
+    fn client_hello(&self) -> Result<ClientHello, QuicError> {
+        match ClientHello::parse_handshake(&self.data) {
+            Err(TlsError::Incomplete) if self.overflowed => Err(QuicError::Tls(TlsError::Malformed)),
+            Err(e) => Err(QuicError::Tls(e)),
+            Ok(hello) => Ok(hello)
+        }
+    }
+}
+
+#[doc(hidden)]
+/// The client's Initial packet protection keys
+pub struct Keys {
+    pub key: [u8; 16],
+    pub iv: [u8; 12],
+    pub hp: [u8; 16],
+}
+
+impl Keys {
+    #[doc(hidden)]
+    /// Derive the keys from the destination connection id of the client's first Initial
+    pub fn client_initial(version: u32, dcid: &[u8]) -> Keys {
+        let (salt, prefix) = match version {
+            VERSION_2 => (&SALT_V2, "quicv2 "),
+            _ => (&SALT_V1, "quic "),
+        };
+        let initial = hkdf_extract(salt, dcid);
+        let client = expand_label(&initial, "client in", 32);
+        let mut keys = Keys { key: [0; 16], iv: [0; 12], hp: [0; 16] };
+        keys.key.copy_from_slice(&expand_label(&client, &format!("{}key", prefix), 16));
+        keys.iv.copy_from_slice(&expand_label(&client, &format!("{}iv", prefix), 12));
+        keys.hp.copy_from_slice(&expand_label(&client, &format!("{}hp", prefix), 16));
+        keys
+    }
+}
+
+/// HKDF-Expand-Label of TLS 1.3, with an empty context
+fn expand_label(secret: &[u8], label: &str, len: usize) -> Vec<u8> {
+    let mut info = vec![(len >> 8) as u8, len as u8, (6 + label.len()) as u8];
+    info.extend_from_slice(b"tls13 ");
+    info.extend_from_slice(label.as_bytes());
+    info.push(0);
+    hkdf_expand(secret, &info, len)
+}
+
+/// Read a variable-length integer
+fn varint(reader: &mut Reader) -> Option<u64> {
+    let first = reader.u8()?;
+    let len = 1 << (first >> 6);
+    let mut value = (first & 0x3f) as u64;
+    for _ in 1..len {
+        value = value << 8 | reader.u8()? as u64;
+    }
+    Some(value)
+}
+
+/// The CRYPTO frames of a decrypted Initial payload
+fn crypto_frames(payload: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, QuicError> {
+    let mut frames = Vec::new();
+    let mut reader = Reader::new(payload);
+    while !reader.is_empty() {
+        let parsed = match varint(&mut reader) {
+            Some(FRAME_PADDING) | Some(FRAME_PING) => Some(()),
+            Some(kind @ FRAME_ACK) | Some(kind @ FRAME_ACK_ECN) => skip_ack(&mut reader, kind == FRAME_ACK_ECN),
+            Some(FRAME_CRYPTO) => match (varint(&mut reader), varint(&mut reader)) {
+                (Some(offset), Some(len)) => reader.bytes(len as usize).map(|data| frames.push((offset, data.to_vec()))),
+                _ => None
+            },
+            Some(FRAME_CONNECTION_CLOSE) => match (varint(&mut reader), varint(&mut reader), varint(&mut reader)) {
+                (Some(_), Some(_), Some(len)) => reader.bytes(len as usize).map(|_| ()),
+                _ => None
+            },
+            _ => None
+        };
+        if parsed.is_none() {
+            return Err(QuicError::Frame);
+        }
+    }
+    Ok(frames)
+}
+
+fn skip_ack(reader: &mut Reader, ecn: bool) -> Option<()> {
+    varint(reader)?;
+    varint(reader)?;
+    let ranges = varint(reader)?;
+    varint(reader)?;
+    for _ in 0..ranges {
+        varint(reader)?;
+        varint(reader)?;
+    }
+    if ecn {
+        for _ in 0..3 {
+            varint(reader)?;
+        }
+    }
+    Some(())
+}
@@ -0,0 +1,427 @@
+//! Rate limiting
+//!
+//! `RateLimiter` polices the packets another handler accepts.
+//! Packets are grouped by an `Extractor`, such as their source address, and each group is held to a `Rate`.
+//! Limits are hierarchical: a packet must conform to every limit added, e.g. per address and per subnet,
+//! and only counts against them if it passes.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+use flow::FlowKey;
+use message::{Message, Packet, PacketBuffer};
+use queue::{Deferred, PacketHandler, QueueHandle, Verdict, VerdictHandler};
+use error::Error;
+use util::{duration, seconds};
+
+/// What a rate counts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Measure {
+    /// Packets
+    Packets,
+    /// Bytes of whole IP datagrams
+    Bytes,
+}
+
+/// How fast packets may pass
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rate {
+    /// Up to `burst` at once, refilled at `per_second`
+    TokenBucket {
+        /// The sustained rate
+        per_second: u64,
+        /// The most that may pass at once after a quiet period
+        burst: u64,
+    },
+    /// Up to `limit` in any `window`
+    ///
+    /// The count over the window is estimated from fixed windows,
+    /// weighting the previous one by how much of it the sliding window still covers.
+    SlidingWindow {
+        /// The most that may pass per window
+        limit: u64,
+        /// The length of the window
+        window: Duration,
+    },
+}
+
+/// What becomes of packets exceeding a limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Drop them
+    Drop,
+    /// Accept them with this mark, e.g. for a lower priority qdisc class
+    Mark(u32),
+    /// Hold them until they conform, for up to this long, dropping those that would wait longer
+    ///
+    /// Held packets count towards the queue's max-length.
+    Delay(Duration),
+}
+
+/// Groups packets to limit together
+pub trait Extractor {
+    /// What packets are grouped by
+    type Key: Hash + Eq + Clone;
+
+    /// The group of a packet, or `None` for packets not to be limited
+    fn key(&self, message: &Message, packet: &Packet) -> Option<Self::Key>;
+}
+
+impl<F, K> Extractor for F where F: Fn(&Message, &Packet) -> Option<K>, K: Hash + Eq + Clone {
+    type Key = K;
+
+    fn key(&self, message: &Message, packet: &Packet) -> Option<K> {
+        self(message, packet)
+    }
+}
+
+/// Groups packets by source address
+#[derive(Clone, Copy, Debug)]
+pub struct BySourceIp;
+
+impl Extractor for BySourceIp {
+    type Key = IpAddr;
+
+    fn key(&self, _: &Message, packet: &Packet) -> Option<IpAddr> {
+        Some(packet.network.saddr())
+    }
+}
+
+/// Groups packets by the subnet of their source address, of the given prefix lengths
+#[derive(Clone, Copy, Debug)]
+pub struct BySourceSubnet {
+    /// The prefix length of IPv4 subnets
+    pub v4: u8,
+    /// The prefix length of IPv6 subnets
+    pub v6: u8,
+}
+
+impl Extractor for BySourceSubnet {
+    type Key = IpAddr;
+
+    fn key(&self, _: &Message, packet: &Packet) -> Option<IpAddr> {
+        Some(match packet.network.saddr() {
+            IpAddr::V4(addr) => {
+                let mask = match self.v4 {
+                    0 => 0,
+                    len => !0u32 << (32 - len.min(32) as u32)
+                };
+                IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+            },
+            IpAddr::V6(addr) => {
+                let mask = match self.v6 {
+                    0 => 0,
+                    len => !0u128 << (128 - len.min(128) as u32)
+                };
+                IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+            }
+        })
+    }
+}
+
+/// Groups TCP and UDP packets by protocol and destination port
+#[derive(Clone, Copy, Debug)]
+pub struct ByDestinationPort;
+
+impl Extractor for ByDestinationPort {
+    type Key = (u8, u16);
+
+    fn key(&self, _: &Message, packet: &Packet) -> Option<(u8, u16)> {
+        packet.transport.and_then(|t| t.dst_port()).map(|port| (packet.protocol, port))
+    }
+}
+
+/// Groups packets by flow, in both directions
+#[derive(Clone, Copy, Debug)]
+pub struct ByFlow;
+
+impl Extractor for ByFlow {
+    type Key = FlowKey;
+
+    fn key(&self, _: &Message, packet: &Packet) -> Option<FlowKey> {
+        FlowKey::of(packet)
+    }
+}
+
+/// Groups locally sent packets by the uid of their socket, see `Message::uid`
+#[derive(Clone, Copy, Debug)]
+pub struct ByUid;
+
+impl Extractor for ByUid {
+    type Key = u32;
+
+    fn key(&self, message: &Message, _: &Packet) -> Option<u32> {
+        message.uid()
+    }
+}
+
+/// Groups packets by their mark
+#[derive(Clone, Copy, Debug)]
+pub struct ByMark;
+
+impl Extractor for ByMark {
+    type Key = u32;
+
+    fn key(&self, message: &Message, _: &Packet) -> Option<u32> {
+        Some(message.mark())
+    }
+}
+
+/// The state of a `Rate` for one group
+#[derive(Clone, Copy, Debug)]
+enum Meter {
+    Bucket { tokens: f64, last: Instant },
+    Window { start: Instant, current: u64, previous: u64 },
+}
+
+impl Meter {
+    fn new(rate: &Rate, now: Instant) -> Meter {
+        match *rate {
+            Rate::TokenBucket { burst, .. } => Meter::Bucket { tokens: burst as f64, last: now },
+            Rate::SlidingWindow { .. } => Meter::Window { start: now, current: 0, previous: 0 },
+        }
+    }
+
+    /// Bring the meter up to `now`
+    fn refresh(&mut self, rate: &Rate, now: Instant) {
+        match (self, *rate) {
+            (&mut Meter::Bucket { ref mut tokens, ref mut last }, Rate::TokenBucket { per_second, burst }) => {
+                if now > *last {
+                    let refill = seconds(now - *last) * per_second as f64;
+                    *tokens = (*tokens + refill).min(burst as f64);
+                    *last = now;
+                }
+            },
+            (&mut Meter::Window { ref mut start, ref mut current, ref mut previous }, Rate::SlidingWindow { window, .. }) => {
+                if now >= *start + window {
+                    let windows = (seconds(now - *start) / seconds(window)) as u32;
+                    *previous = match windows {
+                        1 => *current,
+                        _ => 0
+                    };
+                    *current = 0;
+                    *start += window * windows;
+                }
+            },
+            _ => ()
+        }
+    }
+
+    /// How long until `cost` conforms, zero if it does now
+    fn wait(&mut self, rate: &Rate, cost: u64, now: Instant) -> Duration {
+        self.refresh(rate, now);
+        match (*self, *rate) {
+            (Meter::Bucket { tokens, .. }, Rate::TokenBucket { per_second, burst }) => {
+                // Packets larger than the burst pass when the bucket is full
+                let needed = cost.min(burst) as f64;
+                match tokens >= needed {
+                    true => Duration::from_secs(0),
+                    false => duration((needed - tokens) / per_second.max(1) as f64)
+                }
+            },
+            (Meter::Window { start, current, previous }, Rate::SlidingWindow { limit, window }) => {
+                let covered = 1.0 - seconds(now - start) / seconds(window);
+                if previous as f64 * covered + (current + cost) as f64 <= limit as f64 || current == 0 && previous == 0 {
+                    Duration::from_secs(0)
+                } else if current + cost > limit || previous == 0 {
+                    start + window - now
+                } else {
+                    // When the previous window is covered little enough
+                    let uncovered = 1.0 - (limit - current - cost) as f64 / previous as f64;
+                    (start + duration(seconds(window) * uncovered)).saturating_duration_since(now)
+                }
+            },
+            _ => Duration::from_secs(0)
+        }
+    }
+
+    /// Count `cost` against the meter
+    fn take(&mut self, cost: u64) {
+        match *self {
+            Meter::Bucket { ref mut tokens, .. } => *tokens -= cost as f64,
+            Meter::Window { ref mut current, .. } => *current += cost,
+        }
+    }
+
+}
+
+/// One limit of a `RateLimiter`, with its extractor's key type erased
+trait Level {
+    /// How long until the packet conforms, `None` if it is not limited
+    fn wait(&mut self, message: &Message, packet: &Packet, now: Instant) -> Option<Duration>;
+
+    /// Count the packet last passed to `wait`
+    fn take(&mut self);
+}
+
+struct Limit<E: Extractor> {
+    extractor: E,
+    measure: Measure,
+    rate: Rate,
+    /// Meters by group, with when they were last used
+    meters: HashMap<E::Key, (Meter, u64)>,
+    /// Groups by when their meter was last used
+    lru: BTreeMap<u64, E::Key>,
+    next_tick: u64,
+    max_keys: usize,
+    /// The group and cost of the packet last passed to `wait`
+    last: Option<(E::Key, u64)>,
+}
+
+impl<E: Extractor> Limit<E> {
+    /// Make room for a new group by forgetting the one used least recently
+    fn make_room(&mut self) {
+        let oldest = self.lru.iter().next().map(|(&tick, key)| (tick, key.clone()));
+        if let Some((tick, key)) = oldest {
+            self.lru.remove(&tick);
+            self.meters.remove(&key);
+        }
+    }
+}
+
+impl<E: Extractor> Level for Limit<E> {
+    fn wait(&mut self, message: &Message, packet: &Packet, now: Instant) -> Option<Duration> {
+        self.last = None;
+        let key = match self.extractor.key(message, packet) {
+            Some(key) => key,
+            None => return None
+        };
+        let cost = match self.measure {
+            Measure::Packets => 1,
+            Measure::Bytes => packet.datagram_len() as u64,
+        };
+        if !self.meters.contains_key(&key) && self.meters.len() >= self.max_keys {
+            self.make_room();
+        }
+        let rate = self.rate;
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        let entry = self.meters.entry(key.clone()).or_insert_with(|| (Meter::new(&rate, now), tick));
+        self.lru.remove(&entry.1);
+        entry.1 = tick;
+        self.lru.insert(tick, key.clone());
+        let wait = entry.0.wait(&rate, cost, now);
+        self.last = Some((key, cost));
+        Some(wait)
+    }
+
+    fn take(&mut self) {
+        if let Some((key, cost)) = self.last.take() {
+            if let Some(&mut (ref mut meter, _)) = self.meters.get_mut(&key) {
+                meter.take(cost);
+            }
+        }
+    }
+}
+
+/// A `PacketHandler` that limits the rate of packets a `VerdictHandler` accepts
+pub struct RateLimiter<V> {
+    handler: V,
+    levels: Vec<Box<dyn Level>>,
+    action: Action,
+    max_keys: usize,
+    delayed: BTreeMap<(Instant, u32), (Deferred, Option<u32>, Option<PacketBuffer>)>,
+}
+
+impl<V: VerdictHandler> RateLimiter<V> {
+    /// Limit the packets `handler` accepts, applying `action` to those exceeding a limit
+    ///
+    /// Packets pass until limits are added with `add_limit`.
+    pub fn new(handler: V, action: Action) -> RateLimiter<V> {
+        RateLimiter { handler: handler, levels: Vec::new(), action: action, max_keys: 65536, delayed: BTreeMap::new() }
+    }
+
+    /// Hold each group of `extractor` to `rate`, counted in `measure`
+    pub fn add_limit<E: Extractor + 'static>(&mut self, extractor: E, measure: Measure, rate: Rate) {
+        self.levels.push(Box::new(Limit {
+            extractor: extractor,
+            measure: measure,
+            rate: rate,
+            meters: HashMap::new(),
+            lru: BTreeMap::new(),
+            next_tick: 0,
+            max_keys: self.max_keys,
+            last: None,
+        }));
+    }
+
+    /// Set the number of groups tracked per limit added after this, 65536 by default
+    ///
+    /// The group seen least recently is forgotten to make room for a new one.
+    pub fn set_max_keys(&mut self, max_keys: usize) {
+        self.max_keys = max_keys;
+    }
+
+    /// The wrapped handler
+    pub fn handler(&mut self) -> &mut V {
+        &mut self.handler
+    }
+
+    /// The number of packets held by `Action::Delay`
+    pub fn delayed(&self) -> usize {
+        self.delayed.len()
+    }
+
+    /// Police an accepted packet, returning its verdict unless it is held
+    fn police(&mut self, hq: &QueueHandle, message: &Message, packet: &Packet, now: Instant) -> Option<Verdict> {
+        let mut wait = Duration::from_secs(0);
+        for level in self.levels.iter_mut() {
+            if let Some(w) = level.wait(message, packet, now) {
+                wait = wait.max(w);
+            }
+        }
+        let conforms = wait == Duration::from_secs(0);
+        match self.action {
+            _ if conforms => (),
+            Action::Drop => return Some(Verdict::Drop),
+            Action::Mark(mark) => {
+                message.set_mark(mark);
+                return Some(Verdict::Accept);
+            },
+            Action::Delay(max) if wait > max => return Some(Verdict::Drop),
+            Action::Delay(_) => ()
+        }
+        for level in self.levels.iter_mut() {
+            level.take();
+        }
+        if conforms {
+            return Some(Verdict::Accept);
+        }
+        let id = message.header.id();
+        self.delayed.insert((now + wait, id), (Deferred::new(hq, id), message.take_mark(), message.take_mangled()));
+        None
+    }
+}
+
+impl<V: VerdictHandler> PacketHandler for RateLimiter<V> {
+    fn handle(&mut self, hq: &QueueHandle, message: Result<&Message, &Error>) -> i32 {
+        let message = match message {
+            Ok(m) => m,
+            Err(_) => return 0
+        };
+        let verdict = match self.handler.decide(message) {
+            Verdict::Accept => match message.decode() {
+                Ok(ref packet) => self.police(hq, message, packet, Instant::now()),
+                Err(_) => Some(Verdict::Accept)
+            },
+            verdict => Some(verdict)
+        };
+        if let Some(verdict) = verdict {
+            let _ = Verdict::set_message_verdict(hq, message, verdict);
+        }
+        0
+    }
+
+    fn poll(&mut self, _: &QueueHandle, now: Instant) -> Option<Instant> {
+        loop {
+            let due = match self.delayed.keys().next() {
+                Some(&(at, id)) if at <= now => (at, id),
+                Some(&(at, _)) => return Some(at),
+                None => return None
+            };
+            let (deferred, mark, mut buffer) = self.delayed.remove(&due).unwrap();
+            let _ = deferred.set_rewritten(Verdict::Accept, mark, buffer.as_mut());
+        }
+    }
+}
@@ -0,0 +1,408 @@
+//! IP fragment reassembly
+//!
+//! With `CopyMode::Packet`, handlers see IP fragments one at a time:
+//! only the first carries the transport header, and none carries the whole payload.
+//! `Reassembler` holds fragments with `Deferred` verdicts until their datagram is complete,
+//! decides on the reassembled datagram, and applies that verdict to every fragment.
+//! The copy range must cover whole fragments for them to be reassembled.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use util::*;
+use message::{checksum, Header, Message, Network, Packet};
+use message::{IPV6_AUTH, IPV6_DEST_OPTS, IPV6_FRAGMENT, IPV6_HOP_BY_HOP, IPV6_ROUTING};
+use queue::{Deferred, PacketHandler, QueueHandle, Verdict, VerdictHandler};
+use error::Error;
+
+/// Bounds on the fragments held for reassembly
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// How long the fragments of a datagram are held, from its first fragment arriving
+    pub timeout: Duration,
+    /// The total size of fragment data held
+    pub max_bytes: usize,
+    /// The number of datagrams being reassembled at once
+    pub max_datagrams: usize,
+}
+
+impl Default for Limits {
+    /// The defaults of the Linux `ipfrag_time` and `ipfrag_high_thresh` settings
+    fn default() -> Limits {
+        Limits {
+            timeout: Duration::from_secs(30),
+            max_bytes: 4 * 1024 * 1024,
+            max_datagrams: 1024,
+        }
+    }
+}
+
+/// The outcome of adding a fragment to a `Defragmenter`
+pub enum Reassembly<T> {
+    /// More fragments are needed
+    Pending,
+    /// The datagram is complete
+    Complete {
+        /// The reassembled datagram
+        packet: Vec<u8>,
+        /// Whatever was held with each of its fragments
+        held: Vec<T>,
+    },
+    /// The datagram was discarded, due to overlapping or inconsistent fragments
+    Discarded(Vec<T>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Key {
+    saddr: IpAddr,
+    daddr: IpAddr,
+    /// The protocol, which only identifies IPv4 datagrams
+    protocol: u8,
+    id: u32,
+}
+
+struct Datagram<T> {
+    first_seen: Instant,
+    /// The unfragmentable part of the first fragment
+    header: Option<Vec<u8>>,
+    /// IPv6 only: where the header's last next-header field is, and what it should become
+    next_header: Option<(usize, u8)>,
+    pieces: BTreeMap<u16, Vec<u8>>,
+    /// The length of the reassembled payload, known once the last fragment has arrived
+    total: Option<usize>,
+    held: Vec<T>,
+    bytes: usize,
+}
+
+/// Buffers fragments until their datagrams are complete
+///
+/// `T` is held with each fragment and handed back with its datagram, e.g. a `Deferred` verdict.
+pub struct Defragmenter<T> {
+    datagrams: HashMap<Key, Datagram<T>>,
+    limits: Limits,
+    bytes: usize,
+    expired: Vec<T>,
+}
+
+impl<T> Defragmenter<T> {
+    /// Reassemble within the given `limits`
+    pub fn new(limits: Limits) -> Defragmenter<T> {
+        Defragmenter { datagrams: HashMap::new(), limits: limits, bytes: 0, expired: Vec::new() }
+    }
+
+    /// Add a fragment
+    ///
+    /// `packet` must be a fragment that was copied in full.
+    /// When limits are exceeded, the oldest datagrams are evicted, and their held values returned by `expire`.
+    pub fn insert(&mut self, packet: &Packet, held: T, now: Instant) -> Reassembly<T> {
+        let fragment = match packet.fragment {
+            Some(f) if packet.is_complete() => f,
+            _ => return Reassembly::Discarded(vec![held])
+        };
+        let key = Key {
+            saddr: packet.network.saddr(),
+            daddr: packet.network.daddr(),
+            protocol: match packet.network { Network::Ipv4(ref ip) => ip.protocol(), Network::Ipv6(_) => 0 },
+            id: fragment.id,
+        };
+        let (header, next_header, data) = match split(packet) {
+            Some(parts) => parts,
+            None => return Reassembly::Discarded(vec![held])
+        };
+
+        if !self.datagrams.contains_key(&key) {
+            while self.datagrams.len() >= self.limits.max_datagrams && self.evict_oldest() {}
+        }
+        let discard = {
+            let datagram = self.datagrams.entry(key).or_insert_with(|| Datagram {
+                first_seen: now,
+                header: None,
+                next_header: None,
+                pieces: BTreeMap::new(),
+                total: None,
+                held: Vec::new(),
+                bytes: 0,
+            });
+            datagram.held.push(held);
+            if fragment.offset == 0 {
+                datagram.header = Some(header.to_vec());
+                datagram.next_header = next_header;
+            }
+            match datagram.add(fragment.offset, fragment.more, data, header.len()) {
+                Ok(added) => {
+                    self.bytes += added;
+                    false
+                },
+                Err(()) => true
+            }
+        };
+
+        if discard {
+            let datagram = self.remove(&key);
+            return Reassembly::Discarded(datagram.held);
+        }
+        if self.datagrams[&key].is_complete() {
+            let datagram = self.remove(&key);
+            return Reassembly::Complete { packet: datagram.reassemble(), held: datagram.held };
+        }
+        while self.bytes > self.limits.max_bytes && self.evict_oldest() {}
+        Reassembly::Pending
+    }
+
+    /// Discard datagrams that timed out, returning what was held with their fragments
+    ///
+    /// Values held with datagrams evicted by `insert` are returned too.
+    pub fn expire(&mut self, now: Instant) -> Vec<T> {
+        let timeout = self.limits.timeout;
+        let expired: Vec<Key> = self.datagrams.iter()
+            .filter(|&(_, d)| now >= d.first_seen + timeout)
+            .map(|(k, _)| *k)
+            .collect();
+        for key in expired {
+            let datagram = self.remove(&key);
+            self.expired.extend(datagram.held);
+        }
+        ::std::mem::replace(&mut self.expired, Vec::new())
+    }
+
+    /// When the next datagram times out
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.datagrams.values().map(|d| d.first_seen + self.limits.timeout).min()
+    }
+
+    /// The number of datagrams being reassembled
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    /// Whether no datagrams are being reassembled
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    /// The total size of fragment data held
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn remove(&mut self, key: &Key) -> Datagram<T> {
+        let datagram = self.datagrams.remove(key).unwrap();
+        self.bytes -= datagram.bytes;
+        datagram
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        let oldest = self.datagrams.iter().min_by_key(|&(_, d)| d.first_seen).map(|(k, _)| *k);
+        match oldest {
+            Some(key) => {
+                let datagram = self.remove(&key);
+                self.expired.extend(datagram.held);
+                true
+            },
+            None => false
+        }
+    }
+}
+
+impl<T> Datagram<T> {
+    /// Add the data of a fragment, returning how many bytes are now held for it
+    ///
+    /// Fails if the fragment overlaps another or contradicts the datagram's length.
+    fn add(&mut self, offset: u16, more: bool, data: &[u8], header_len: usize) -> Result<usize, ()> {
+        let end = offset as usize + data.len();
+        if (more && data.len() % 8 != 0) || end + header_len > 0xffff {
+            return Err(());
+        }
+        match (more, self.total) {
+            (false, Some(total)) if total != end => return Err(()),
+            (false, None) if self.pieces.keys().next_back().map(|&o| o as usize + self.pieces[&o].len() > end).unwrap_or(false) =>
+                return Err(()),
+            (false, None) => self.total = Some(end),
+            (true, Some(total)) if end > total => return Err(()),
+            _ => ()
+        }
+
+        // An exact duplicate is a retransmission, any other overlap is an error (RFC 5722)
+        if let Some(existing) = self.pieces.get(&offset) {
+            return match existing.len() == data.len() {
+                true => Ok(0),
+                false => Err(())
+            };
+        }
+        if let Some((&before, piece)) = self.pieces.range(..offset).next_back() {
+            if before as usize + piece.len() > offset as usize {
+                return Err(());
+            }
+        }
+        if let Some((&after, _)) = self.pieces.range(offset..).next() {
+            if end > after as usize {
+                return Err(());
+            }
+        }
+        self.pieces.insert(offset, data.to_vec());
+        self.bytes += data.len();
+        Ok(data.len())
+    }
+
+    fn is_complete(&self) -> bool {
+        let total = match (self.total, self.header.is_some()) {
+            (Some(total), true) => total,
+            _ => return false
+        };
+        let mut end = 0;
+        for (&offset, data) in self.pieces.iter() {
+            if offset as usize != end {
+                return false;
+            }
+            end += data.len();
+        }
+        end == total
+    }
+
+    fn reassemble(&self) -> Vec<u8> {
+        let header = self.header.as_ref().unwrap();
+        let total = self.total.unwrap();
+        let mut packet = Vec::with_capacity(header.len() + total);
+        packet.extend_from_slice(header);
+        for data in self.pieces.values() {
+            packet.extend_from_slice(data);
+        }
+        match self.next_header {
+            // IPv6: drop the fragment header by pointing its predecessor past it
+            Some((at, next)) => {
+                packet[at] = next;
+                let payload_len = (packet.len() - 40) as u16;
+                write16(&mut packet, 4, payload_len);
+            },
+            None => {
+                let len = packet.len() as u16;
+                write16(&mut packet, 2, len);
+                // Keep don't fragment, clear more fragments and the offset
+                packet[6] &= 0x40;
+                packet[7] = 0;
+                checksum::fill_network(&mut packet);
+            }
+        }
+        packet
+    }
+}
+
+/// Split a fragment into its unfragmentable part, the IPv6 next-header fix up, and its fragment data
+fn split<'a>(packet: &Packet<'a>) -> Option<(&'a [u8], Option<(usize, u8)>, &'a [u8])> {
+    let data = packet.data();
+    match packet.network {
+        Network::Ipv4(ref ip) => Some((&data[..ip.header_len()], None, &data[ip.header_len()..])),
+        Network::Ipv6(_) => {
+            let mut field = 6;
+            let mut offset = 40;
+            loop {
+                let len = match data[field] {
+                    IPV6_FRAGMENT if data.len() >= offset + 8 =>
+                        return Some((&data[..offset], Some((field, data[offset])), &data[offset + 8..])),
+                    IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DEST_OPTS if data.len() >= offset + 2 =>
+                        (data[offset + 1] as usize + 1) * 8,
+                    IPV6_AUTH if data.len() >= offset + 2 => (data[offset + 1] as usize + 2) * 4,
+                    _ => return None
+                };
+                field = offset;
+                offset += len;
+            }
+        }
+    }
+}
+
+/// A `PacketHandler` that reassembles fragments before a `VerdictHandler` decides on them
+///
+/// Unfragmented packets are passed straight to the handler.
+/// A mark the handler sets on a reassembled datagram is set on each of its fragments,
+/// but rewrites of reassembled datagrams through `message.mangle()` are not returned to the kernel.
+pub struct Reassembler<V> {
+    handler: V,
+    defragmenter: Defragmenter<Deferred>,
+    timeout_verdict: Verdict,
+}
+
+impl<V: VerdictHandler> Reassembler<V> {
+    /// Reassemble fragments for `handler` within the default `Limits`
+    pub fn new(handler: V) -> Reassembler<V> {
+        Reassembler::with_limits(handler, Limits::default())
+    }
+
+    /// Reassemble fragments for `handler` within `limits`
+    pub fn with_limits(handler: V, limits: Limits) -> Reassembler<V> {
+        Reassembler {
+            handler: handler,
+            defragmenter: Defragmenter::new(limits),
+            timeout_verdict: Verdict::Drop,
+        }
+    }
+
+    /// Set the verdict for fragments of datagrams that time out, are evicted or are discarded
+    ///
+    /// Defaults to `Verdict::Drop`, as the kernel does.
+    pub fn set_timeout_verdict(&mut self, verdict: Verdict) {
+        self.timeout_verdict = verdict;
+    }
+
+    /// The wrapped handler
+    pub fn handler(&mut self) -> &mut V {
+        &mut self.handler
+    }
+
+    /// The fragments being held
+    pub fn defragmenter(&self) -> &Defragmenter<Deferred> {
+        &self.defragmenter
+    }
+
+    fn release(&self, held: Vec<Deferred>, verdict: Verdict, mark: Option<u32>) {
+        for deferred in held {
+            let _ = match mark {
+                Some(mark) => deferred.set_marked(verdict, mark),
+                None => deferred.set(verdict)
+            };
+        }
+    }
+}
+
+impl<V: VerdictHandler> PacketHandler for Reassembler<V> {
+    fn handle(&mut self, hq: &QueueHandle, message: Result<&Message, &Error>) -> i32 {
+        let message = match message {
+            Ok(m) => m,
+            Err(_) => return 0
+        };
+        let now = Instant::now();
+        let reassembly = match message.decode() {
+            Ok(ref packet) if packet.fragment.is_some() && packet.is_complete() =>
+                self.defragmenter.insert(packet, Deferred::new(hq, message.header.id()), now),
+            _ => {
+                let verdict = self.handler.decide(message);
+                let _ = Verdict::set_message_verdict(hq, message, verdict);
+                return 0;
+            }
+        };
+
+        match reassembly {
+            Reassembly::Pending => (),
+            Reassembly::Complete { packet, held } => {
+                let header = Header {
+                    packet_id: message.header.packet_id,
+                    hw_protocol: message.header.hw_protocol,
+                    hook: message.header.hook,
+                };
+                let reassembled = Message::from_data(&header, &packet);
+                let verdict = self.handler.decide(&reassembled);
+                self.release(held, verdict, reassembled.take_mark());
+            },
+            Reassembly::Discarded(held) => self.release(held, self.timeout_verdict, None),
+        }
+        let expired = self.defragmenter.expire(now);
+        self.release(expired, self.timeout_verdict, None);
+        0
+    }
+
+    fn poll(&mut self, _: &QueueHandle, now: Instant) -> Option<Instant> {
+        let expired = self.defragmenter.expire(now);
+        self.release(expired, self.timeout_verdict, None);
+        self.defragmenter.next_expiry()
+    }
+}
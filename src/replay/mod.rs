@@ -0,0 +1,340 @@
+//! Offline replay
+//!
+//! `Replay` runs the packets of a pcap or pcapng file through a `PacketHandler`, or a `VerdictHandler`,
+//! without a queue, root or iptables, and reports the verdict, mark and rewritten packet of each,
+//! so policy can be tested in CI. Verdicts are collected in place of being set in the kernel,
+//! including those set later through `Deferred`; a packet whose verdict is never set is reported as undecided.
+//!
+//! Each packet becomes a `Message` with an id counting up from one, the hook and `hw_protocol` of an IP packet,
+//! and the mark and interfaces recorded by `capture::Writer`, whose verdicts are compared to the new ones.
+//! Interfaces are numbered from the capture alone, never from the host replaying it,
+//! so a capture replays the same on any machine; see `Trace::interfaces`.
+//! Frames of raw IP, Ethernet, Linux cooked and loopback captures are read; other frames are skipped.
+mod read;
+
+use std::collections::BTreeMap;
+use std::error::Error as Base;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use message::{Header, Message, Metadata};
+use mock::Mock;
+use queue::{PacketHandler, QueueHandle, Verdict};
+
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+
+/// Reasons a capture could not be read
+#[derive(Debug)]
+pub enum ReplayError {
+    /// The file could not be read
+    Io(io::Error),
+    /// The file is not pcap or pcapng, or is cut short
+    Format,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReplayError::Io(ref e) => write!(f, "{}: {}", self.reason(), e),
+            ReplayError::Format => write!(f, "{}", self.reason()),
+        }
+    }
+}
+
+impl Base for ReplayError {
+    fn description(&self) -> &str {
+        self.reason()
+    }
+}
+
+impl ReplayError {
+    fn reason(&self) -> &'static str {
+        match *self {
+            ReplayError::Io(_) => "Failed to read capture",
+            ReplayError::Format => "Malformed capture",
+        }
+    }
+}
+
+/// A captured IP packet
+#[derive(Clone, Debug)]
+pub struct Captured {
+    /// The packet, from its IP header
+    pub data: Vec<u8>,
+    /// The timestamp, and the mark and interface indices if they were recorded
+    pub metadata: Metadata,
+    /// The name of the interface the packet arrived on, if recorded
+    pub indev: Option<String>,
+    /// The name of the interface the packet was to leave by, if recorded
+    pub outdev: Option<String>,
+    /// The packet's comment, if any
+    pub comment: Option<String>,
+    /// The verdict recorded by `capture::Writer`, if any
+    pub expected: Option<Verdict>,
+}
+
+/// The IP packets of a capture
+#[derive(Clone, Debug, Default)]
+pub struct Trace {
+    /// The packets, in the order captured
+    pub packets: Vec<Captured>,
+    /// The number of frames skipped for not holding IP packets
+    pub skipped: usize,
+    /// The index of each interface named in the capture, as given in the packets' metadata
+    ///
+    /// Names like `if3`, written by `capture::Writer` for interfaces it could not name, keep the index they give.
+    /// Other interfaces are numbered from one, in the order the capture describes them,
+    /// then in the order packets name them. Use `set_interface` to give one another index.
+    pub interfaces: BTreeMap<String, u32>,
+}
+
+impl Trace {
+    /// Parse a pcap or pcapng file
+    pub fn parse(data: &[u8]) -> Result<Trace, ReplayError> {
+        read::parse(data).map(|(packets, described, skipped)| {
+            let mut trace = Trace { packets: packets, skipped: skipped, interfaces: BTreeMap::new() };
+            trace.number_interfaces(described);
+            trace
+        })
+    }
+
+    /// Read and parse a pcap or pcapng file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Trace, ReplayError> {
+        match fs::read(path) {
+            Ok(data) => Trace::parse(&data),
+            Err(e) => Err(ReplayError::Io(e))
+        }
+    }
+
+    /// Give the interface `name` the index `index` in the metadata of the packets naming it
+    ///
+    /// Such as the index a handler matching on interfaces expects.
+    pub fn set_interface(&mut self, name: &str, index: u32) {
+        self.interfaces.insert(name.to_string(), index);
+        self.resolve_interfaces();
+    }
+
+    /// Number the interfaces described, then those only packets name, as `interfaces` documents
+    fn number_interfaces(&mut self, described: Vec<String>) {
+        let mut names = described;
+        for packet in self.packets.iter() {
+            for name in packet.indev.iter().chain(packet.outdev.iter()) {
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+        }
+        for name in names.iter() {
+            if let Some(index) = numbered(name) {
+                self.interfaces.insert(name.clone(), index);
+            }
+        }
+        let mut next = 1;
+        for name in names {
+            if self.interfaces.contains_key(&name) {
+                continue;
+            }
+            while self.interfaces.values().any(|&index| index == next) {
+                next += 1;
+            }
+            self.interfaces.insert(name, next);
+        }
+        self.resolve_interfaces();
+    }
+
+    /// Set the interface indices of each packet from its interface names
+    fn resolve_interfaces(&mut self) {
+        let interfaces = &self.interfaces;
+        for packet in self.packets.iter_mut() {
+            packet.metadata.indev = packet.indev.as_ref().and_then(|name| interfaces.get(name).cloned());
+            packet.metadata.outdev = packet.outdev.as_ref().and_then(|name| interfaces.get(name).cloned());
+        }
+    }
+}
+
+/// What became of a replayed packet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Outcome {
+    /// The index of the packet in the trace
+    pub index: usize,
+    /// The id the packet was given
+    pub id: u32,
+    /// The verdict, if one was set
+    pub verdict: Option<Verdict>,
+    /// The mark set with the verdict, if any
+    pub mark: Option<u32>,
+    /// The packet that replaced it, if it was rewritten
+    pub payload: Option<Vec<u8>>,
+    /// The verdict recorded in the capture, if any
+    pub expected: Option<Verdict>,
+}
+
+impl Outcome {
+    /// Whether a verdict was recorded and this one differs
+    pub fn is_mismatch(&self) -> bool {
+        self.expected.is_some() && self.expected != self.verdict
+    }
+}
+
+/// The outcome of a replay
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    /// The outcome of each packet, in the order replayed
+    pub outcomes: Vec<Outcome>,
+    /// The number of frames skipped for not holding IP packets
+    pub skipped: usize,
+}
+
+impl Report {
+    /// The number of packets given `verdict`
+    pub fn count(&self, verdict: Verdict) -> usize {
+        self.outcomes.iter().filter(|o| o.verdict == Some(verdict)).count()
+    }
+
+    /// The number of packets never given a verdict
+    pub fn undecided(&self) -> usize {
+        self.outcomes.iter().filter(|o| o.verdict.is_none()).count()
+    }
+
+    /// The number of packets rewritten
+    pub fn rewritten(&self) -> usize {
+        self.outcomes.iter().filter(|o| o.payload.is_some()).count()
+    }
+
+    /// The packets whose verdict differs from that recorded
+    pub fn mismatches(&self) -> Vec<&Outcome> {
+        self.outcomes.iter().filter(|o| o.is_mismatch()).collect()
+    }
+}
+
+impl fmt::Display for Report {
+    /// A summary, then a line for each mismatched verdict
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut counts: Vec<(Verdict, usize)> = Vec::new();
+        for verdict in self.outcomes.iter().filter_map(|o| o.verdict) {
+            match counts.iter_mut().find(|c| c.0 == verdict) {
+                Some(count) => count.1 += 1,
+                None => counts.push((verdict, 1))
+            }
+        }
+        write!(f, "{} packets:", self.outcomes.len())?;
+        for (verdict, count) in counts {
+            write!(f, " {} {:?},", count, verdict)?;
+        }
+        write!(f, " {} undecided, {} rewritten, {} mismatched, {} skipped",
+               self.undecided(), self.rewritten(), self.mismatches().len(), self.skipped)?;
+        for outcome in self.mismatches() {
+            write!(f, "\npacket {} (ID: {}): expected {:?}, got ", outcome.index, outcome.id, outcome.expected.unwrap())?;
+            match outcome.verdict {
+                Some(verdict) => write!(f, "{:?}", verdict)?,
+                None => write!(f, "no verdict")?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs captured packets through handlers
+#[derive(Clone, Copy, Debug)]
+pub struct Replay {
+    hook: u8,
+    drain: Duration,
+}
+
+impl Default for Replay {
+    fn default() -> Replay {
+        Replay::new()
+    }
+}
+
+impl Replay {
+    /// Replay packets as if queued at `NF_INET_PRE_ROUTING`, waiting up to a second for deferred verdicts
+    pub fn new() -> Replay {
+        Replay { hook: 0, drain: Duration::from_secs(1) }
+    }
+
+    /// Set the netfilter hook the packets seem to be queued at
+    pub fn set_hook(&mut self, hook: u8) {
+        self.hook = hook;
+    }
+
+    /// Set how long to keep polling the handler for verdicts it deferred, after the last packet
+    pub fn set_drain(&mut self, drain: Duration) {
+        self.drain = drain;
+    }
+
+    /// Run the packets of a trace through `handler`
+    ///
+    /// Packets are handled as fast as the handler takes them, not at the pace they were captured,
+    /// and the handler is polled after each, as by `Handle::start`.
+    pub fn run<H: PacketHandler>(&self, handler: &mut H, trace: &Trace) -> Report {
+        let mock = Mock::new();
+        let hq = &QueueHandle::in_memory(&mock, 0);
+        let mut report = Report { outcomes: Vec::with_capacity(trace.packets.len()), skipped: trace.skipped };
+        for (index, packet) in trace.packets.iter().enumerate() {
+            let id = index as u32 + 1;
+            let hw_protocol = match packet.data.first().map(|b| b >> 4) {
+                Some(6) => ETH_P_IPV6,
+                _ => ETH_P_IP
+            };
+            let header = Header { packet_id: id.to_be(), hw_protocol: hw_protocol.to_be(), hook: self.hook };
+            let message = Message::with_metadata(&header, &packet.data, packet.metadata);
+            report.outcomes.push(Outcome {
+                index: index,
+                id: id,
+                verdict: None,
+                mark: None,
+                payload: None,
+                expected: packet.expected,
+            });
+            handler.handle(hq, Ok(&message));
+            handler.poll(hq, Instant::now());
+            collect(&mut report, &mock);
+        }
+
+        let deadline = Instant::now() + self.drain;
+        while report.undecided() > 0 {
+            let now = Instant::now();
+            match handler.poll(hq, now) {
+                Some(at) if at <= deadline => {
+                    if at > now {
+                        thread::sleep(at - now);
+                    }
+                },
+                _ => break
+            }
+            collect(&mut report, &mock);
+        }
+        collect(&mut report, &mock);
+        report
+    }
+}
+
+/// Record the verdicts set since last collected
+fn collect(report: &mut Report, mock: &Mock) {
+    for decision in mock.verdicts(0) {
+        // Ids count up from one
+        let index = (decision.id as usize).wrapping_sub(1);
+        if let Some(outcome) = report.outcomes.get_mut(index) {
+            outcome.verdict = Some(decision.verdict);
+            outcome.mark = decision.mark;
+            outcome.payload = decision.payload;
+        }
+    }
+    mock.clear();
+}
+
+/// The index given by a name like `if3`, written by `capture::Writer` for an interface it could not name
+fn numbered(name: &str) -> Option<u32> {
+    match name.starts_with("if") {
+        true => name[2..].parse().ok().and_then(|index| match index {
+            0 => None,
+            index => Some(index)
+        }),
+        false => None
+    }
+}
@@ -0,0 +1,320 @@
+//! Reading pcap and pcapng files
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use capture::{Annotation, LINKTYPE_RAW};
+use message::Metadata;
+use super::{Captured, ReplayError};
+
+const PCAP_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_NANOS: u32 = 0xa1b2_3c4d;
+
+const BLOCK_SECTION: u32 = 0x0a0d_0d0a;
+const BLOCK_INTERFACE: u32 = 1;
+const BLOCK_PACKET: u32 = 2;
+const BLOCK_SIMPLE_PACKET: u32 = 3;
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const IF_TSOFFSET: u16 = 14;
+const EPB_FLAGS: u16 = 2;
+
+const FLAG_INBOUND: u32 = 1;
+const FLAG_OUTBOUND: u32 = 2;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_LOOP: u32 = 108;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+/// Reads integers of either byte order
+#[derive(Clone, Copy)]
+struct Order { big: bool }
+
+impl Order {
+    fn u16(&self, data: &[u8], at: usize) -> Option<u16> {
+        let b = data.get(at..at + 2)?;
+        Some(match self.big {
+            true => (b[0] as u16) << 8 | b[1] as u16,
+            false => (b[1] as u16) << 8 | b[0] as u16
+        })
+    }
+
+    fn u32(&self, data: &[u8], at: usize) -> Option<u32> {
+        let (a, b) = (self.u16(data, at)? as u32, self.u16(data, at + 2)? as u32);
+        Some(match self.big {
+            true => a << 16 | b,
+            false => b << 16 | a
+        })
+    }
+
+    fn u64(&self, data: &[u8], at: usize) -> Option<u64> {
+        let (a, b) = (self.u32(data, at)? as u64, self.u32(data, at + 4)? as u64);
+        Some(match self.big {
+            true => a << 32 | b,
+            false => b << 32 | a
+        })
+    }
+}
+
+/// Parse a pcap or pcapng file, returning the IP packets, the names of the interfaces described in order,
+/// and the number of other packets skipped
+pub fn parse(data: &[u8]) -> Result<(Vec<Captured>, Vec<String>, usize), ReplayError> {
+    let little = Order { big: false };
+    match little.u32(data, 0) {
+        Some(BLOCK_SECTION) => pcapng(data),
+        Some(magic) if magic == PCAP_MICROS || magic == PCAP_NANOS => pcap(data, little, magic == PCAP_NANOS),
+        Some(magic) if magic.swap_bytes() == PCAP_MICROS || magic.swap_bytes() == PCAP_NANOS => {
+            pcap(data, Order { big: true }, magic.swap_bytes() == PCAP_NANOS)
+        },
+        _ => Err(ReplayError::Format)
+    }
+}
+
+fn pcap(data: &[u8], order: Order, nanos: bool) -> Result<(Vec<Captured>, Vec<String>, usize), ReplayError> {
+    let link = match order.u32(data, 20) {
+        Some(link) => link & 0x0fff_ffff,
+        None => return Err(ReplayError::Format)
+    };
+    let (mut packets, mut skipped) = (Vec::new(), 0);
+    let mut at = 24;
+    while at < data.len() {
+        let (secs, frac, len) = match (order.u32(data, at), order.u32(data, at + 4), order.u32(data, at + 8)) {
+            (Some(secs), Some(frac), Some(len)) => (secs, frac, len as usize),
+            _ => return Err(ReplayError::Format)
+        };
+        let frame = match data.get(at + 16..at + 16 + len) {
+            Some(frame) => frame,
+            None => return Err(ReplayError::Format)
+        };
+        at += 16 + len;
+        let nanos = match nanos {
+            true => frac,
+            false => frac.saturating_mul(1000)
+        };
+        let timestamp = UNIX_EPOCH + Duration::new(secs as u64, nanos.min(999_999_999));
+        match decapsulate(link, frame) {
+            Some(ip) => packets.push(captured(ip, timestamp, None, None, None)),
+            None => skipped += 1
+        }
+    }
+    Ok((packets, Vec::new(), skipped))
+}
+
+/// An interface described in a pcapng section
+struct Interface {
+    link: u32,
+    name: Option<String>,
+    /// Timestamp units per second, as a power of ten or, if `binary`, of two
+    resolution: u8,
+    binary: bool,
+    offset: i64,
+}
+
+impl Interface {
+    /// The time `units` after the epoch, if it can be represented
+    fn timestamp(&self, units: u64) -> Option<SystemTime> {
+        let nanos = match (self.binary, self.resolution) {
+            (false, r) if r <= 9 => (units as u128) * 10u128.pow(9 - r as u32),
+            (false, r) => (units as u128) / 10u128.checked_pow(r as u32 - 9).unwrap_or(u128::max_value()),
+            (true, r) => ((units as u128) * 1_000_000_000) >> r.min(127),
+        };
+        let since_epoch = Duration::new((nanos / 1_000_000_000) as u64, (nanos % 1_000_000_000) as u32);
+        let timestamp = UNIX_EPOCH.checked_add(since_epoch)?;
+        match self.offset {
+            o if o >= 0 => timestamp.checked_add(Duration::from_secs(o as u64)),
+            o => Some(timestamp.checked_sub(Duration::from_secs(o.unsigned_abs())).unwrap_or(UNIX_EPOCH))
+        }
+    }
+}
+
+fn pcapng(data: &[u8]) -> Result<(Vec<Captured>, Vec<String>, usize), ReplayError> {
+    let (mut packets, mut skipped) = (Vec::new(), 0);
+    let mut described: Vec<String> = Vec::new();
+    let little = Order { big: false };
+    let mut order = little;
+    let mut interfaces: Vec<Interface> = Vec::new();
+    let mut at = 0;
+    while at < data.len() {
+        if order.u32(data, at) == Some(BLOCK_SECTION) {
+            order = match little.u32(data, at + 8) {
+                Some(BYTE_ORDER_MAGIC) => little,
+                Some(magic) if magic.swap_bytes() == BYTE_ORDER_MAGIC => Order { big: true },
+                _ => return Err(ReplayError::Format)
+            };
+            interfaces.clear();
+        }
+        let (kind, len) = match (order.u32(data, at), order.u32(data, at + 4)) {
+            (Some(kind), Some(len)) if len >= 12 && len % 4 == 0 => (kind, len as usize),
+            _ => return Err(ReplayError::Format)
+        };
+        // The block ends with its length again
+        let body = match data.get(at + 8..at + len) {
+            Some(block) => &block[..len - 12],
+            None => return Err(ReplayError::Format)
+        };
+        at += len;
+        match kind {
+            BLOCK_INTERFACE => {
+                let link = match order.u16(body, 0) {
+                    Some(link) => link as u32,
+                    None => return Err(ReplayError::Format)
+                };
+                let mut interface = Interface { link: link, name: None, resolution: 6, binary: false, offset: 0 };
+                for (code, value) in options(order, body.get(8..).unwrap_or(&[])) {
+                    match code {
+                        IF_NAME => interface.name = Some(String::from_utf8_lossy(value).trim_end_matches('\0').to_string()),
+                        IF_TSRESOL if !value.is_empty() => {
+                            interface.binary = value[0] & 0x80 != 0;
+                            interface.resolution = value[0] & 0x7f;
+                        },
+                        IF_TSOFFSET => interface.offset = order.u64(value, 0).unwrap_or(0) as i64,
+                        _ => ()
+                    }
+                }
+                if let Some(ref name) = interface.name {
+                    if !described.contains(name) {
+                        described.push(name.clone());
+                    }
+                }
+                interfaces.push(interface);
+            },
+            BLOCK_ENHANCED_PACKET | BLOCK_PACKET => {
+                // The obsolete packet block has a 16 bit interface id and a drop count where the enhanced one has its id
+                let id = match kind {
+                    BLOCK_PACKET => order.u16(body, 0).map(|id| id as u32),
+                    _ => order.u32(body, 0)
+                };
+                let (id, high, low, len) = match (id, order.u32(body, 4), order.u32(body, 8), order.u32(body, 12)) {
+                    (Some(id), Some(high), Some(low), Some(len)) => (id as usize, high, low, len as usize),
+                    _ => return Err(ReplayError::Format)
+                };
+                let (frame, rest) = match (body.get(20..20 + len), body.get(20 + (len + 3) / 4 * 4..)) {
+                    (Some(frame), rest) => (frame, rest.unwrap_or(&[])),
+                    _ => return Err(ReplayError::Format)
+                };
+                let interface = match interfaces.get(id) {
+                    Some(interface) => interface,
+                    None => return Err(ReplayError::Format)
+                };
+                let (mut comment, mut flags) = (None, 0);
+                for (code, value) in options(order, rest) {
+                    match code {
+                        OPT_COMMENT => comment = Some(String::from_utf8_lossy(value).into_owned()),
+                        EPB_FLAGS if kind == BLOCK_ENHANCED_PACKET => flags = order.u32(value, 0).unwrap_or(0),
+                        _ => ()
+                    }
+                }
+                let (indev, outdev) = match flags & 0x3 {
+                    FLAG_INBOUND => (interface.name.clone(), None),
+                    FLAG_OUTBOUND => (None, interface.name.clone()),
+                    _ => (None, None)
+                };
+                let timestamp = match interface.timestamp((high as u64) << 32 | low as u64) {
+                    Some(timestamp) => timestamp,
+                    None => return Err(ReplayError::Format)
+                };
+                match decapsulate(interface.link, frame) {
+                    Some(ip) => packets.push(captured(ip, timestamp, indev, outdev, comment)),
+                    None => skipped += 1
+                }
+            },
+            BLOCK_SIMPLE_PACKET => {
+                let len = order.u32(body, 0).unwrap_or(0) as usize;
+                let frame = &body[4.min(body.len())..];
+                let frame = &frame[..len.min(frame.len())];
+                let link = match interfaces.first() {
+                    Some(interface) => interface.link,
+                    None => return Err(ReplayError::Format)
+                };
+                match decapsulate(link, frame) {
+                    Some(ip) => packets.push(captured(ip, UNIX_EPOCH, None, None, None)),
+                    None => skipped += 1
+                }
+            },
+            _ => ()
+        }
+    }
+    Ok((packets, described, skipped))
+}
+
+/// The options of a block, as code and value
+fn options(order: Order, mut data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut options = Vec::new();
+    while let (Some(code), Some(len)) = (order.u16(data, 0), order.u16(data, 2)) {
+        let len = len as usize;
+        if code == OPT_END {
+            break;
+        }
+        match data.get(4..4 + len) {
+            Some(value) => options.push((code, value)),
+            None => break
+        }
+        data = data.get(4 + (len + 3) / 4 * 4..).unwrap_or(&[]);
+    }
+    options
+}
+
+/// A packet, with what its comment recorded if it was written by `capture::Writer`
+///
+/// Interfaces named in the comment are used over those of the pcapng block.
+fn captured(data: &[u8], timestamp: SystemTime, mut indev: Option<String>, mut outdev: Option<String>,
+            comment: Option<String>) -> Captured {
+    let mut metadata = Metadata { timestamp: Some(timestamp), ..Metadata::default() };
+    let annotation = comment.as_ref().and_then(|c| Annotation::parse(c));
+    let expected = match annotation {
+        Some(annotation) => {
+            metadata.mark = annotation.mark;
+            if annotation.indev.is_some() || annotation.outdev.is_some() {
+                indev = annotation.indev;
+                outdev = annotation.outdev;
+            }
+            annotation.verdict
+        },
+        None => None
+    };
+    Captured { data: data.to_vec(), metadata: metadata, indev: indev, outdev: outdev, comment: comment, expected: expected }
+}
+
+/// The IP packet of a frame, if it holds one
+fn decapsulate(link: u32, frame: &[u8]) -> Option<&[u8]> {
+    let big = Order { big: true };
+    let ip = match link {
+        l if l == LINKTYPE_RAW as u32 || l == LINKTYPE_IPV4 || l == LINKTYPE_IPV6 => frame,
+        LINKTYPE_NULL | LINKTYPE_LOOP => frame.get(4..)?,
+        LINKTYPE_LINUX_SLL => ethertype(big.u16(frame, 14)?, frame.get(16..)?)?,
+        LINKTYPE_LINUX_SLL2 => ethertype(big.u16(frame, 0)?, frame.get(20..)?)?,
+        LINKTYPE_ETHERNET => {
+            let mut at = 12;
+            let mut kind = big.u16(frame, at)?;
+            while kind == ETHERTYPE_VLAN || kind == ETHERTYPE_QINQ {
+                at += 4;
+                kind = big.u16(frame, at)?;
+            }
+            ethertype(kind, frame.get(at + 2..)?)?
+        },
+        _ => return None
+    };
+    match ip.first().map(|b| b >> 4) {
+        Some(4) | Some(6) => Some(ip),
+        _ => None
+    }
+}
+
+fn ethertype(kind: u16, payload: &[u8]) -> Option<&[u8]> {
+    match kind {
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => Some(payload),
+        _ => None
+    }
+}
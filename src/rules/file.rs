@@ -0,0 +1,669 @@
+//! Rule files
+//!
+//! Rules are written in a subset of TOML: settings at the top, then a `[[rule]]` table per rule,
+//! tried in the order written unless given priorities.
+//!
+//! ```toml
+//! # The verdict on packets matching no rule
+//! default = "accept"
+//!
+//! [[rule]]
+//! protocol = "udp"
+//! destination_port = 53
+//! verdict = "accept"
+//! set_mark = 0x10
+//!
+//! [[rule]]
+//! priority = 10
+//! source = ["10.0.0.0/8", "fd00::/8"]
+//! not_destination = "10.0.0.0/8"
+//! destination_port = [22, "6000-6063"]
+//! tcp_flags = "syn,!ack"
+//! verdict = "drop"
+//! ```
+//!
+//! The settings are `default`, the verdict on packets matching no rule, `accept` unless given,
+//! and `default_mark`, a mark to set along with it.
+//!
+//! Each rule has a `verdict` of `accept`, `drop`, `repeat`, `stop` or `queue N`,
+//! and may have a `set_mark` and a `priority`. Its matches, any of which is negated by a `not_` prefix, are:
+//!
+//! * `protocol`: `tcp`, `udp`, `icmp`, `icmpv6` or a number
+//! * `source` and `destination`: networks like `10.0.0.0/8`, or addresses
+//! * `source_port` and `destination_port`: ports, or ranges like `"6000-6063"`
+//! * `in_interface` and `out_interface`: interface names
+//! * `mark`: a mark, or a masked mark like `"0x10/0xf0"`
+//! * `uid`: the uid of the local socket that sent the packet
+//! * `tcp_flags`: flags set, or clear if prefixed with `!`, of `fin`, `syn`, `rst`, `psh`, `ack`, `urg`, `ece` and `cwr`
+//! * `sni`: a server name, or the names under a domain like `*.example.com`
+//! * `filter`: a pcap filter expression like `"tcp[tcpflags] & tcp-rst != 0"`, see `bpf`
+//!
+//! Networks and ports may be given alone or in an array, which may span lines.
+//!
+//! `RuleFile` decides packets by the rules of a file, reloading it on `SIGHUP` or once changed.
+//! New rules replace the old only once the whole file is loaded and compiled, between packets,
+//! so each packet is decided entirely by either; if the file is invalid the old rules are kept.
+
+use libc::{c_int, sighandler_t, SIGHUP};
+use std::collections::HashSet;
+use std::error::Error as Base;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use bpf::Filter;
+use cidr::Cidr;
+use error::Error;
+use message::{Message, TCP_ACK, TCP_CWR, TCP_ECE, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN, TCP_URG};
+use queue::{PacketHandler, QueueHandle, Verdict, VerdictHandler};
+use rules::{Action, Engine, Match, Rule, RuleError, Ruleset};
+
+/// How often a `RuleFile` is polled without packets arriving, so that a `SIGHUP` interrupts the wait
+const IDLE_POLL: Duration = Duration::from_secs(60);
+
+/// The number of `SIGHUP`s received
+static HANGUPS: AtomicUsize = AtomicUsize::new(0);
+static INSTALL: Once = Once::new();
+
+extern "C" {
+    fn signal(signum: c_int, handler: sighandler_t) -> sighandler_t;
+}
+
+/// Reasons a rule file could not be loaded
+#[derive(Debug)]
+pub enum FileError {
+    /// The file could not be read
+    Io(io::Error),
+    /// The file is malformed
+    Syntax {
+        /// The line, from one
+        line: usize,
+        /// What is wrong
+        message: String,
+    },
+    /// A rule is invalid
+    Rule {
+        /// The line, from one
+        line: usize,
+        /// What is wrong
+        error: RuleError,
+    },
+    /// The rules could not be compiled
+    Compile(RuleError),
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FileError::Io(ref e) => write!(f, "{}: {}", self.reason(), e),
+            FileError::Syntax { line, ref message } => write!(f, "line {}: {}", line, message),
+            FileError::Rule { line, ref error } => write!(f, "line {}: {}", line, error),
+            FileError::Compile(ref e) => write!(f, "{}: {}", self.reason(), e),
+        }
+    }
+}
+
+impl Base for FileError {
+    fn description(&self) -> &str {
+        self.reason()
+    }
+}
+
+impl FileError {
+    fn reason(&self) -> &'static str {
+        match *self {
+            FileError::Io(_) => "Failed to read rule file",
+            FileError::Syntax { .. } => "Malformed rule file",
+            FileError::Rule { .. } => "Invalid rule",
+            FileError::Compile(_) => "Failed to compile rules",
+        }
+    }
+}
+
+/// Parse the text of a rule file
+pub fn parse(text: &str) -> Result<Ruleset, FileError> {
+    let mut parser = Parser { text: text.as_bytes(), pos: 0, line: 1 };
+    let mut tables = parser.tables()?.into_iter();
+    let settings = tables.next().unwrap();
+    unique(&settings)?;
+    let mut default = Action::new(Verdict::Accept);
+    for entry in &settings.entries {
+        match &entry.key[..] {
+            "default" => default.verdict = verdict(entry)?,
+            "default_mark" => default.mark = Some(integer(entry, &entry.value, 0, 0xffff_ffff)? as u32),
+            key => return invalid(entry, format!("Unknown setting {}", key))
+        }
+    }
+    let mut ruleset = Ruleset::new(default);
+    for table in tables {
+        ruleset.add(rule(&table)?);
+    }
+    Ok(ruleset)
+}
+
+/// Read and parse a rule file
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Ruleset, FileError> {
+    match fs::read_to_string(path) {
+        Ok(text) => parse(&text),
+        Err(e) => Err(FileError::Io(e))
+    }
+}
+
+/// A `PacketHandler` deciding packets by the rules of a file, reloaded on `SIGHUP` or once changed
+pub struct RuleFile {
+    path: PathBuf,
+    engine: Engine,
+    /// The `SIGHUP`s seen
+    hangups: usize,
+    /// How often to check whether the file changed
+    watch: Option<Duration>,
+    /// When the file loaded was modified
+    modified: Option<SystemTime>,
+    next_check: Instant,
+    error: Option<FileError>,
+}
+
+impl RuleFile {
+    /// Decide packets by the rules of the file at `path`, reloading it on `SIGHUP`
+    ///
+    /// This sets the process's handler of `SIGHUP`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<RuleFile, FileError> {
+        INSTALL.call_once(|| unsafe {
+            signal(SIGHUP, hangup as extern "C" fn(c_int) as sighandler_t);
+        });
+        let path = path.as_ref().to_path_buf();
+        let modified = modified(&path);
+        let engine = compile(&path)?;
+        Ok(RuleFile {
+            path: path,
+            engine: engine,
+            hangups: HANGUPS.load(Ordering::SeqCst),
+            watch: None,
+            modified: modified,
+            next_check: Instant::now() + IDLE_POLL,
+            error: None,
+        })
+    }
+
+    /// Also reload the file once its modification time changes, checking every `interval`
+    pub fn set_watch(&mut self, interval: Duration) {
+        self.watch = Some(interval);
+        self.next_check = Instant::now() + interval;
+    }
+
+    /// Reload the file now, keeping the current rules if it is invalid
+    pub fn reload(&mut self) -> Result<(), FileError> {
+        // Taken first, so that a change while loading is noticed
+        self.modified = modified(&self.path);
+        self.engine = compile(&self.path)?;
+        Ok(())
+    }
+
+    /// The path of the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The rules in use
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    /// Why the last automatic reload failed, if it did
+    pub fn last_error(&self) -> Option<&FileError> {
+        self.error.as_ref()
+    }
+
+    /// Reload the file if a `SIGHUP` was received since last checked
+    fn check_hangup(&mut self) {
+        let hangups = HANGUPS.load(Ordering::SeqCst);
+        if hangups != self.hangups {
+            self.hangups = hangups;
+            self.error = self.reload().err();
+        }
+    }
+
+    /// Reload the file if it changed, when due to check
+    fn check_modified(&mut self, now: Instant) {
+        if now < self.next_check {
+            return;
+        }
+        self.next_check = now + self.watch.unwrap_or(IDLE_POLL);
+        if self.watch.is_some() && modified(&self.path) != self.modified {
+            self.error = self.reload().err();
+        }
+    }
+}
+
+impl PacketHandler for RuleFile {
+    fn handle(&mut self, hq: &QueueHandle, message: Result<&Message, &Error>) -> i32 {
+        self.check_hangup();
+        if let Ok(message) = message {
+            let verdict = self.engine.decide(message);
+            let _ = Verdict::set_message_verdict(hq, message, verdict);
+        }
+        0
+    }
+
+    fn poll(&mut self, _: &QueueHandle, now: Instant) -> Option<Instant> {
+        // Polling at all sets a receive timeout, which makes a `SIGHUP` interrupt the `Handle`'s wait
+        self.check_hangup();
+        self.check_modified(now);
+        Some(self.next_check)
+    }
+}
+
+extern "C" fn hangup(_: c_int) {
+    HANGUPS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn compile(path: &Path) -> Result<Engine, FileError> {
+    load(path)?.compile().map_err(FileError::Compile)
+}
+
+/// A value in a rule file
+#[derive(Debug)]
+enum Value {
+    Str(String),
+    Int(i64),
+    Array(Vec<Value>),
+}
+
+/// A `key = value` line
+struct Entry {
+    line: usize,
+    key: String,
+    value: Value,
+}
+
+/// The settings at the top of a file, or a `[[rule]]`
+struct Table {
+    line: usize,
+    entries: Vec<Entry>,
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// The settings, then the rules
+    fn tables(&mut self) -> Result<Vec<Table>, FileError> {
+        let mut tables = vec![Table { line: 1, entries: Vec::new() }];
+        loop {
+            self.skip_lines();
+            match self.peek() {
+                None => return Ok(tables),
+                Some(b'[') => {
+                    let line = self.line;
+                    if !self.text[self.pos..].starts_with(b"[[") {
+                        return self.error("Only [[rule]] tables are supported");
+                    }
+                    self.pos += 2;
+                    self.skip_space();
+                    let name = self.key()?;
+                    self.skip_space();
+                    if !self.text[self.pos..].starts_with(b"]]") {
+                        return self.error("Expected ]]");
+                    }
+                    self.pos += 2;
+                    if name != "rule" {
+                        return self.error(&format!("Unknown table [[{}]]", name));
+                    }
+                    tables.push(Table { line: line, entries: Vec::new() });
+                },
+                Some(_) => {
+                    let line = self.line;
+                    let key = self.key()?;
+                    self.skip_space();
+                    if self.peek() != Some(b'=') {
+                        return self.error("Expected =");
+                    }
+                    self.pos += 1;
+                    self.skip_space();
+                    let value = self.value()?;
+                    tables.last_mut().unwrap().entries.push(Entry { line: line, key: key, value: value });
+                }
+            }
+            self.skip_space();
+            match self.peek() {
+                None | Some(b'\n') | Some(b'\r') => (),
+                Some(_) => return self.error("Expected the end of the line")
+            }
+        }
+    }
+
+    fn key(&mut self) -> Result<String, FileError> {
+        let start = self.pos;
+        while let Some(b) = self.peek() {
+            match b.is_ascii_alphanumeric() || b == b'_' || b == b'-' {
+                true => self.pos += 1,
+                false => break
+            }
+        }
+        match self.pos > start {
+            true => Ok(String::from_utf8_lossy(&self.text[start..self.pos]).into_owned()),
+            false => self.error("Expected a key")
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, FileError> {
+        match self.peek() {
+            Some(b'"') => self.string().map(Value::Str),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                loop {
+                    self.skip_lines();
+                    if self.peek() == Some(b']') {
+                        self.pos += 1;
+                        return Ok(Value::Array(values));
+                    }
+                    values.push(self.value()?);
+                    self.skip_lines();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(Value::Array(values));
+                        },
+                        _ => return self.error("Expected , or ] in array")
+                    }
+                }
+            },
+            Some(_) => {
+                let start = self.pos;
+                while let Some(b) = self.peek() {
+                    match b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || b == b'+' {
+                        true => self.pos += 1,
+                        false => break
+                    }
+                }
+                let word = String::from_utf8_lossy(&self.text[start..self.pos]).into_owned();
+                match &word[..] {
+                    "" => self.error("Expected a value"),
+                    _ => match number(&word) {
+                        Some(n) => Ok(Value::Int(n)),
+                        None => self.error(&format!("Malformed value {}", word))
+                    }
+                }
+            },
+            None => self.error("Expected a value")
+        }
+    }
+
+    fn string(&mut self) -> Result<String, FileError> {
+        self.pos += 1;
+        let mut s = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some(b'\n') => return self.error("Unterminated string"),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.pos += 1;
+                    s.push(match self.peek() {
+                        Some(b'n') => b'\n',
+                        Some(b't') => b'\t',
+                        Some(b'r') => b'\r',
+                        Some(b'"') => b'"',
+                        Some(b'\\') => b'\\',
+                        _ => return self.error("Unknown escape in string")
+                    });
+                },
+                Some(b) => s.push(b)
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        Ok(String::from_utf8_lossy(&s).into_owned())
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).cloned()
+    }
+
+    /// Skip spaces and any comment to the end of the line
+    fn skip_space(&mut self) {
+        while let Some(b' ') | Some(b'\t') = self.peek() {
+            self.pos += 1;
+        }
+        if self.peek() == Some(b'#') {
+            while let Some(b) = self.peek() {
+                if b == b'\n' {
+                    break;
+                }
+                self.pos += 1;
+            }
+        }
+    }
+
+    /// Skip spaces, comments and line ends
+    fn skip_lines(&mut self) {
+        loop {
+            self.skip_space();
+            match self.peek() {
+                Some(b'\n') => {
+                    self.pos += 1;
+                    self.line += 1;
+                },
+                Some(b'\r') => self.pos += 1,
+                _ => return
+            }
+        }
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, FileError> {
+        Err(FileError::Syntax { line: self.line, message: message.to_string() })
+    }
+}
+
+/// Check no key is given twice
+fn unique(table: &Table) -> Result<(), FileError> {
+    let mut keys = HashSet::new();
+    for entry in &table.entries {
+        if !keys.insert(&entry.key[..]) {
+            return invalid(entry, format!("Duplicate key {}", entry.key));
+        }
+    }
+    Ok(())
+}
+
+fn rule(table: &Table) -> Result<Rule, FileError> {
+    unique(table)?;
+    let mut action = None;
+    let mut mark = None;
+    let mut priority = 0;
+    let mut matches = Vec::new();
+    for entry in &table.entries {
+        match &entry.key[..] {
+            "verdict" => action = Some(verdict(entry)?),
+            "set_mark" => mark = Some(integer(entry, &entry.value, 0, 0xffff_ffff)? as u32),
+            "priority" => priority = integer(entry, &entry.value, i32::min_value() as i64, i32::max_value() as i64)? as i32,
+            key => {
+                let m = match key.starts_with("not_") {
+                    true => Match::Not(Box::new(condition(entry, &key[4..])?)),
+                    false => condition(entry, key)?
+                };
+                if let Err(e) = m.validate() {
+                    return Err(FileError::Rule { line: entry.line, error: e });
+                }
+                matches.push(m);
+            }
+        }
+    }
+    let verdict = match action {
+        Some(verdict) => verdict,
+        None => return Err(FileError::Syntax { line: table.line, message: "Rule has no verdict".to_string() })
+    };
+    let mut rule = Rule::new(matches, Action { verdict: verdict, mark: mark });
+    rule.priority = priority;
+    Ok(rule)
+}
+
+/// The match of `entry`, its key stripped of any `not_`
+fn condition(entry: &Entry, key: &str) -> Result<Match, FileError> {
+    Ok(match key {
+        "protocol" => Match::Protocol(match entry.value {
+            Value::Str(ref name) => match &name.to_lowercase()[..] {
+                "icmp" => 1,
+                "tcp" => 6,
+                "udp" => 17,
+                "icmpv6" => 58,
+                _ => return invalid(entry, format!("Unknown protocol {}", name))
+            },
+            ref value => integer(entry, value, 0, 0xff)? as u8
+        }),
+        "source" => Match::Source(networks(entry)?),
+        "destination" => Match::Destination(networks(entry)?),
+        "source_port" => Match::SourcePort(ports(entry)?),
+        "destination_port" => Match::DestinationPort(ports(entry)?),
+        "in_interface" => Match::InInterface(string(entry, &entry.value)?.to_string()),
+        "out_interface" => Match::OutInterface(string(entry, &entry.value)?.to_string()),
+        "mark" => match entry.value {
+            Value::Str(ref s) => {
+                let (value, mask) = match s.find('/') {
+                    Some(slash) => (number(&s[..slash]), number(&s[slash + 1..])),
+                    None => (number(s), Some(0xffff_ffff))
+                };
+                match (value, mask) {
+                    (Some(value), Some(mask)) if value >= 0 && value <= 0xffff_ffff && mask >= 0 && mask <= 0xffff_ffff => {
+                        Match::Mark { value: value as u32, mask: mask as u32 }
+                    },
+                    _ => return invalid(entry, format!("Malformed mark {}", s))
+                }
+            },
+            ref value => Match::Mark { value: integer(entry, value, 0, 0xffff_ffff)? as u32, mask: !0 }
+        },
+        "uid" => Match::Uid(integer(entry, &entry.value, 0, 0xffff_ffff)? as u32),
+        "tcp_flags" => {
+            let (mut flags, mut mask) = (0, 0);
+            for name in string(entry, &entry.value)?.split(',').map(|f| f.trim()) {
+                let (set, name) = match name.starts_with('!') {
+                    true => (false, &name[1..]),
+                    false => (true, name)
+                };
+                let flag = match &name.to_lowercase()[..] {
+                    "fin" => TCP_FIN,
+                    "syn" => TCP_SYN,
+                    "rst" => TCP_RST,
+                    "psh" => TCP_PSH,
+                    "ack" => TCP_ACK,
+                    "urg" => TCP_URG,
+                    "ece" => TCP_ECE,
+                    "cwr" => TCP_CWR,
+                    _ => return invalid(entry, format!("Unknown TCP flag {}", name))
+                };
+                mask |= flag;
+                if set {
+                    flags |= flag;
+                }
+            }
+            Match::TcpFlags { flags: flags, mask: mask }
+        },
+        "sni" => Match::Sni(string(entry, &entry.value)?.to_string()),
+        "filter" => match Filter::new(string(entry, &entry.value)?) {
+            Ok(filter) => Match::Filter(filter),
+            Err(e) => return invalid(entry, format!("{}", e))
+        },
+        _ => return invalid(entry, format!("Unknown key {}", entry.key))
+    })
+}
+
+fn verdict(entry: &Entry) -> Result<Verdict, FileError> {
+    let s = string(entry, &entry.value)?;
+    let words: Vec<_> = s.split_whitespace().collect();
+    Ok(match (words.first().map(|w| w.to_lowercase()), words.len()) {
+        (Some(ref w), 1) if w == "accept" => Verdict::Accept,
+        (Some(ref w), 1) if w == "drop" => Verdict::Drop,
+        (Some(ref w), 1) if w == "repeat" => Verdict::Repeat,
+        (Some(ref w), 1) if w == "stop" => Verdict::Stop,
+        (Some(ref w), 2) if w == "queue" => match number(words[1]) {
+            Some(n) if n >= 0 && n <= 0xffff => Verdict::Queue(n as u16),
+            _ => return invalid(entry, format!("Malformed queue number {}", words[1]))
+        },
+        _ => return invalid(entry, format!("Unknown verdict {}", s))
+    })
+}
+
+fn networks(entry: &Entry) -> Result<Vec<Cidr>, FileError> {
+    list(&entry.value).into_iter().map(|value| {
+        let s = string(entry, value)?;
+        match s.parse() {
+            Ok(network) => Ok(network),
+            Err(e) => invalid(entry, format!("{} in {}", e, s))
+        }
+    }).collect()
+}
+
+fn ports(entry: &Entry) -> Result<Vec<(u16, u16)>, FileError> {
+    list(&entry.value).into_iter().map(|value| match *value {
+        Value::Str(ref s) => {
+            let range = match s.find('-') {
+                Some(dash) => (number(&s[..dash]), number(&s[dash + 1..])),
+                None => (number(s), number(s))
+            };
+            match range {
+                (Some(start), Some(end)) if start >= 0 && end <= 0xffff && start <= end => Ok((start as u16, end as u16)),
+                _ => invalid(entry, format!("Malformed port range {}", s))
+            }
+        },
+        ref value => integer(entry, value, 0, 0xffff).map(|p| (p as u16, p as u16))
+    }).collect()
+}
+
+/// The values of an array, or a lone value
+fn list(value: &Value) -> Vec<&Value> {
+    match *value {
+        Value::Array(ref values) => values.iter().collect(),
+        ref value => vec![value]
+    }
+}
+
+fn string<'v>(entry: &Entry, value: &'v Value) -> Result<&'v str, FileError> {
+    match *value {
+        Value::Str(ref s) => Ok(s),
+        _ => invalid(entry, format!("Expected a string for {}", entry.key))
+    }
+}
+
+fn integer(entry: &Entry, value: &Value, min: i64, max: i64) -> Result<i64, FileError> {
+    match *value {
+        Value::Int(n) if n >= min && n <= max => Ok(n),
+        Value::Int(n) => invalid(entry, format!("{} is out of range for {}", n, entry.key)),
+        _ => invalid(entry, format!("Expected an integer for {}", entry.key))
+    }
+}
+
+/// Parse a decimal or `0x` prefixed hexadecimal integer, with any `_` separators
+fn number(s: &str) -> Option<i64> {
+    let s = s.trim().replace('_', "");
+    let (negative, digits) = match s.chars().next() {
+        Some('-') => (true, &s[1..]),
+        Some('+') => (false, &s[1..]),
+        _ => (false, &s[..])
+    };
+    let n = match digits.starts_with("0x") || digits.starts_with("0X") {
+        true => i64::from_str_radix(&digits[2..], 16).ok(),
+        false => match digits.chars().all(|c| c.is_ascii_digit()) {
+            true => digits.parse().ok(),
+            false => None
+        }
+    };
+    n.map(|n| match negative {
+        true => -n,
+        false => n
+    })
+}
+
+fn invalid<T>(entry: &Entry, message: String) -> Result<T, FileError> {
+    Err(FileError::Syntax { line: entry.line, message: message })
+}
@@ -0,0 +1,533 @@
+//! Rule engine
+//!
+//! A `Ruleset` is a list of `Rule`s, each a set of `Match`es on the packet and its metadata,
+//! with the `Action` to take when they all hold. Compiling it gives an `Engine`, a `VerdictHandler`
+//! taking the action of the first rule to match by descending priority, then in the order added,
+//! or the default action if none do.
+//!
+//! The engine does not try the rules one by one. The values of the protocol, ports and addresses
+//! are split into intervals over which the same rules match, each with a bit vector of those rules,
+//! so the rules a packet may match are the AND of one vector per field, each found by binary search.
+//! Only those rules are checked against their other matches, in order, until one holds.
+//!
+//! Rules can also be written in a file, see `file`.
+pub mod file;
+
+use libc::if_nametoindex;
+use std::error::Error as Base;
+use std::ffi::CString;
+use std::fmt;
+use std::net::IpAddr;
+use bpf::Filter;
+use cidr::Cidr;
+use message::{Message, Packet, Transport};
+use queue::{Verdict, VerdictHandler};
+use quic;
+use tls::ClientHello;
+use util::ip_bits;
+
+/// The port of packets without ports, past every real port
+const NO_PORT: u128 = 0x10000;
+
+/// A condition on a packet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Match {
+    /// The transport protocol is this, e.g. 6 for TCP
+    Protocol(u8),
+    /// The source address is in any of these networks
+    Source(Vec<Cidr>),
+    /// The destination address is in any of these networks
+    Destination(Vec<Cidr>),
+    /// The TCP or UDP source port is in any of these inclusive ranges
+    SourcePort(Vec<(u16, u16)>),
+    /// The TCP or UDP destination port is in any of these inclusive ranges
+    DestinationPort(Vec<(u16, u16)>),
+    /// The packet arrived on the interface of this name
+    ///
+    /// Interface names are resolved when the rules are compiled.
+    InInterface(String),
+    /// The packet will leave by the interface of this name
+    OutInterface(String),
+    /// The packet's mark, masked, is `value`
+    Mark {
+        /// The expected value
+        value: u32,
+        /// The bits of the mark compared
+        mask: u32,
+    },
+    /// The packet was sent by a local socket of this uid, see `Message::uid`
+    Uid(u32),
+    /// The TCP flags, masked, are `flags`
+    TcpFlags {
+        /// The expected flags, e.g. `TCP_SYN`
+        flags: u8,
+        /// The flags compared, e.g. `TCP_SYN | TCP_ACK`
+        mask: u8,
+    },
+    /// The server name of a TLS or QUIC ClientHello in the packet is this name,
+    /// or, for a pattern like `*.example.com`, any name under it
+    ///
+    /// Names are compared without case. The ClientHello must be whole within the packet.
+    Sni(String),
+    /// The packet matches a BPF filter, such as a pcap filter expression
+    Filter(Filter),
+    /// The match does not hold
+    Not(Box<Match>),
+}
+
+impl Match {
+    /// Check the match can be compiled, resolving interface names
+    pub fn validate(&self) -> Result<(), RuleError> {
+        Test::compile(self).map(|_| ())
+    }
+}
+
+/// What to do with a packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Action {
+    /// The verdict
+    pub verdict: Verdict,
+    /// The mark to set along with the verdict, if any
+    pub mark: Option<u32>,
+}
+
+impl Action {
+    /// Give `verdict`
+    pub fn new(verdict: Verdict) -> Action {
+        Action { verdict: verdict, mark: None }
+    }
+
+    /// Give `verdict` and set the packet's mark to `mark`
+    pub fn marked(verdict: Verdict, mark: u32) -> Action {
+        Action { verdict: verdict, mark: Some(mark) }
+    }
+}
+
+/// Matches, and the action to take on packets matching them all
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    /// The matches, all of which must hold
+    pub matches: Vec<Match>,
+    /// The action to take
+    pub action: Action,
+    /// Rules of higher priority are tried first, rules of equal priority in the order added
+    pub priority: i32,
+}
+
+impl Rule {
+    /// A rule of priority zero
+    pub fn new(matches: Vec<Match>, action: Action) -> Rule {
+        Rule { matches: matches, action: action, priority: 0 }
+    }
+}
+
+/// Reasons a ruleset could not be compiled
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuleError {
+    /// No interface has this name
+    UnknownInterface(String),
+    /// The start of a port range is past its end
+    PortRange(u16, u16),
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RuleError::UnknownInterface(ref name) => write!(f, "{} {}", self.reason(), name),
+            RuleError::PortRange(start, end) => write!(f, "{} {}-{}", self.reason(), start, end),
+        }
+    }
+}
+
+impl Base for RuleError {
+    fn description(&self) -> &str {
+        self.reason()
+    }
+}
+
+impl RuleError {
+    fn reason(&self) -> &'static str {
+        match *self {
+            RuleError::UnknownInterface(_) => "Unknown interface",
+            RuleError::PortRange(_, _) => "Empty port range",
+        }
+    }
+}
+
+/// Rules to compile into an `Engine`
+#[derive(Clone, Debug)]
+pub struct Ruleset {
+    rules: Vec<Rule>,
+    default: Action,
+}
+
+impl Ruleset {
+    /// An empty ruleset, taking `default` action on packets matching no rule
+    pub fn new(default: Action) -> Ruleset {
+        Ruleset { rules: Vec::new(), default: default }
+    }
+
+    /// Add a rule, tried after those of the same priority already added
+    pub fn add(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    /// The rules, in the order added
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// The action on packets matching no rule
+    pub fn default_action(&self) -> Action {
+        self.default
+    }
+
+    /// Compile the rules into an `Engine`
+    pub fn compile(&self) -> Result<Engine, RuleError> {
+        let mut rules = self.rules.clone();
+        // Stable, so rules of equal priority keep their order
+        rules.sort_by(|a, b| b.priority.cmp(&a.priority));
+
+        let n = rules.len();
+        let mut protocols = Vec::with_capacity(n);
+        let mut src_ports = Vec::with_capacity(n);
+        let mut dst_ports = Vec::with_capacity(n);
+        let mut src_v4 = Vec::with_capacity(n);
+        let mut src_v6 = Vec::with_capacity(n);
+        let mut dst_v4 = Vec::with_capacity(n);
+        let mut dst_v6 = Vec::with_capacity(n);
+        let mut tests = Vec::with_capacity(n);
+        let mut unindexed = bits(n);
+        for (i, rule) in rules.iter().enumerate() {
+            let mut protocol = Vec::new();
+            let (mut src_port, mut dst_port) = (Vec::new(), Vec::new());
+            let (mut s4, mut s6, mut d4, mut d6) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+            let mut residual = Vec::new();
+            for m in &rule.matches {
+                match *m {
+                    Match::Protocol(p) => protocol.push(vec![(p as u128, p as u128)]),
+                    Match::SourcePort(ref ranges) => src_port.push(port_intervals(ranges)?),
+                    Match::DestinationPort(ref ranges) => dst_port.push(port_intervals(ranges)?),
+                    Match::Source(ref networks) => {
+                        s4.push(address_intervals(networks, true));
+                        s6.push(address_intervals(networks, false));
+                    },
+                    Match::Destination(ref networks) => {
+                        d4.push(address_intervals(networks, true));
+                        d6.push(address_intervals(networks, false));
+                    },
+                    _ => residual.push(Test::compile(m)?)
+                }
+            }
+            if protocol.is_empty() && src_port.is_empty() && dst_port.is_empty() && s4.is_empty() && d4.is_empty() {
+                set(&mut unindexed, i);
+            }
+            protocols.push(protocol);
+            src_ports.push(src_port);
+            dst_ports.push(dst_port);
+            src_v4.push(s4);
+            src_v6.push(s6);
+            dst_v4.push(d4);
+            dst_v6.push(d6);
+            tests.push(residual);
+        }
+
+        let protocol = Index::build(n, 0xff, &protocols);
+        Ok(Engine {
+            protocol: (0..256).map(|p| protocol.get(p).clone()).collect(),
+            src_port: Index::build(n, NO_PORT, &src_ports),
+            dst_port: Index::build(n, NO_PORT, &dst_ports),
+            src_v4: Index::build(n, 0xffff_ffff, &src_v4),
+            src_v6: Index::build(n, !0, &src_v6),
+            dst_v4: Index::build(n, 0xffff_ffff, &dst_v4),
+            dst_v6: Index::build(n, !0, &dst_v6),
+            unindexed: unindexed,
+            tests: tests,
+            rules: rules,
+            default: self.default,
+        })
+    }
+}
+
+/// A compiled `Ruleset`
+pub struct Engine {
+    /// Rules by transport protocol
+    protocol: Vec<Vec<u64>>,
+    src_port: Index,
+    dst_port: Index,
+    src_v4: Index,
+    src_v6: Index,
+    dst_v4: Index,
+    dst_v6: Index,
+    /// Rules without protocol, port or address matches, the only ones packets that fail to decode may match
+    unindexed: Vec<u64>,
+    /// The matches of each rule that are not indexed
+    tests: Vec<Vec<Test>>,
+    /// The rules in the order they are tried
+    rules: Vec<Rule>,
+    default: Action,
+}
+
+impl Engine {
+    /// The rules in the order they are tried
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Change the action on packets matching no rule
+    pub fn set_default(&mut self, default: Action) {
+        self.default = default;
+    }
+
+    /// The first rule `message` matches
+    pub fn lookup(&self, message: &Message) -> Option<&Rule> {
+        self.find(message).map(|i| &self.rules[i])
+    }
+
+    /// The action to take on `message`
+    pub fn action(&self, message: &Message) -> Action {
+        match self.find(message) {
+            Some(i) => self.rules[i].action,
+            None => self.default
+        }
+    }
+
+    fn find(&self, message: &Message) -> Option<usize> {
+        let packet = message.decode().ok();
+        let candidates = match packet {
+            Some(ref packet) => self.candidates(packet),
+            None => self.unindexed.clone()
+        };
+        let mut sni = None;
+        for (w, &word) in candidates.iter().enumerate() {
+            let mut word = word;
+            while word != 0 {
+                let i = w * 64 + word.trailing_zeros() as usize;
+                word &= word - 1;
+                if self.tests[i].iter().all(|t| t.holds(message, packet.as_ref(), &mut sni)) {
+                    return Some(i);
+                }
+            }
+        }
+        None
+    }
+
+    /// The rules whose indexed matches `packet` satisfies
+    fn candidates(&self, packet: &Packet) -> Vec<u64> {
+        let port = |port: Option<u16>| port.map(|p| p as u128).unwrap_or(NO_PORT);
+        let mut candidates = self.protocol[packet.protocol as usize].clone();
+        and(&mut candidates, self.src_port.get(port(packet.transport.and_then(|t| t.src_port()))));
+        and(&mut candidates, self.dst_port.get(port(packet.transport.and_then(|t| t.dst_port()))));
+        let (saddr, daddr) = (packet.network.saddr(), packet.network.daddr());
+        let (src, dst) = match saddr {
+            IpAddr::V4(_) => (&self.src_v4, &self.dst_v4),
+            IpAddr::V6(_) => (&self.src_v6, &self.dst_v6),
+        };
+        and(&mut candidates, src.get(ip_bits(&saddr)));
+        and(&mut candidates, dst.get(ip_bits(&daddr)));
+        candidates
+    }
+}
+
+impl VerdictHandler for Engine {
+    fn decide(&mut self, message: &Message) -> Verdict {
+        let action = self.action(message);
+        if let Some(mark) = action.mark {
+            message.set_mark(mark);
+        }
+        action.verdict
+    }
+}
+
+/// The rules matching each interval of a field's values
+struct Index {
+    /// The first value of each interval, from zero
+    starts: Vec<u128>,
+    /// The rules matching each interval
+    sets: Vec<Vec<u64>>,
+}
+
+impl Index {
+    /// Index `n` rules over values up to `max`
+    ///
+    /// Each rule has a list of constraints, all of which must hold; each constraint a list of
+    /// inclusive intervals, any of which the value must be in.
+    fn build(n: usize, max: u128, constraints: &[Vec<Vec<(u128, u128)>>]) -> Index {
+        let mut bounds = vec![0];
+        for &(start, end) in constraints.iter().flat_map(|c| c.iter().flat_map(|c| c.iter())) {
+            bounds.push(start);
+            if end < max {
+                bounds.push(end + 1);
+            }
+        }
+        bounds.sort();
+        bounds.dedup();
+
+        let mut index = Index { starts: Vec::new(), sets: Vec::new() };
+        for start in bounds {
+            let mut rules = bits(n);
+            for (i, c) in constraints.iter().enumerate() {
+                if c.iter().all(|c| c.iter().any(|&(s, e)| s <= start && start <= e)) {
+                    set(&mut rules, i);
+                }
+            }
+            // Neighbouring intervals matching the same rules are merged
+            if index.sets.last() != Some(&rules) {
+                index.starts.push(start);
+                index.sets.push(rules);
+            }
+        }
+        index
+    }
+
+    /// The rules matching `value`
+    fn get(&self, value: u128) -> &Vec<u64> {
+        match self.starts.binary_search(&value) {
+            Ok(i) => &self.sets[i],
+            Err(i) => &self.sets[i - 1]
+        }
+    }
+}
+
+/// A match checked against each candidate packet
+#[derive(Debug)]
+enum Test {
+    Protocol(u8),
+    Source(Vec<Cidr>),
+    Destination(Vec<Cidr>),
+    SourcePort(Vec<(u16, u16)>),
+    DestinationPort(Vec<(u16, u16)>),
+    InInterface(u32),
+    OutInterface(u32),
+    Mark { value: u32, mask: u32 },
+    Uid(u32),
+    TcpFlags { flags: u8, mask: u8 },
+    Sni(String),
+    Filter(Filter),
+    Not(Box<Test>),
+}
+
+impl Test {
+    fn compile(m: &Match) -> Result<Test, RuleError> {
+        Ok(match *m {
+            Match::Protocol(p) => Test::Protocol(p),
+            Match::Source(ref networks) => Test::Source(networks.clone()),
+            Match::Destination(ref networks) => Test::Destination(networks.clone()),
+            Match::SourcePort(ref ranges) => {
+                port_intervals(ranges)?;
+                Test::SourcePort(ranges.clone())
+            },
+            Match::DestinationPort(ref ranges) => {
+                port_intervals(ranges)?;
+                Test::DestinationPort(ranges.clone())
+            },
+            Match::InInterface(ref name) => Test::InInterface(interface(name)?),
+            Match::OutInterface(ref name) => Test::OutInterface(interface(name)?),
+            Match::Mark { value, mask } => Test::Mark { value: value, mask: mask },
+            Match::Uid(uid) => Test::Uid(uid),
+            Match::TcpFlags { flags, mask } => Test::TcpFlags { flags: flags, mask: mask },
+            Match::Sni(ref pattern) => Test::Sni(pattern.trim_end_matches('.').to_lowercase()),
+            Match::Filter(ref filter) => Test::Filter(filter.clone()),
+            Match::Not(ref m) => Test::Not(Box::new(Test::compile(m)?)),
+        })
+    }
+
+    /// Whether the test holds, parsing the server name into `sni` once needed
+    fn holds(&self, message: &Message, packet: Option<&Packet>, sni: &mut Option<Option<String>>) -> bool {
+        let in_ranges = |port: Option<u16>, ranges: &[(u16, u16)]| {
+            port.map(|p| ranges.iter().any(|&(s, e)| s <= p && p <= e)).unwrap_or(false)
+        };
+        match *self {
+            Test::InInterface(index) => message.indev() == Some(index),
+            Test::OutInterface(index) => message.outdev() == Some(index),
+            Test::Mark { value, mask } => message.mark() & mask == value,
+            Test::Uid(uid) => message.uid() == Some(uid),
+            Test::Filter(ref filter) => filter.matches(message),
+            Test::Not(ref test) => !test.holds(message, packet, sni),
+            _ => {
+                let packet = match packet {
+                    Some(packet) => packet,
+                    None => return false
+                };
+                match *self {
+                    Test::Protocol(p) => packet.protocol == p,
+                    Test::Source(ref networks) => networks.iter().any(|n| n.contains(&packet.network.saddr())),
+                    Test::Destination(ref networks) => networks.iter().any(|n| n.contains(&packet.network.daddr())),
+                    Test::SourcePort(ref ranges) => in_ranges(packet.transport.and_then(|t| t.src_port()), ranges),
+                    Test::DestinationPort(ref ranges) => in_ranges(packet.transport.and_then(|t| t.dst_port()), ranges),
+                    Test::TcpFlags { flags, mask } => match packet.transport {
+                        Some(Transport::Tcp(ref tcp)) => tcp.flags() & mask == flags,
+                        _ => false
+                    },
+                    Test::Sni(ref pattern) => {
+                        if sni.is_none() {
+                            *sni = Some(server_name(packet));
+                        }
+                        match *sni {
+                            Some(Some(ref name)) => sni_matches(pattern, name),
+                            _ => false
+                        }
+                    },
+                    _ => unreachable!()
+                }
+            }
+        }
+    }
+}
+
+/// The server name of a ClientHello in a TCP segment or QUIC Initial, in lower case
+fn server_name(packet: &Packet) -> Option<String> {
+    let hello = match packet.transport {
+        Some(Transport::Tcp(_)) => ClientHello::parse(packet.payload).ok(),
+        Some(Transport::Udp(_)) => quic::client_hello(packet.payload).ok(),
+        _ => None
+    };
+    hello.and_then(|h| h.server_name().map(|n| n.trim_end_matches('.').to_lowercase()))
+}
+
+fn sni_matches(pattern: &str, name: &str) -> bool {
+    match pattern.starts_with("*.") {
+        true => name.len() > pattern.len() - 1 && name.ends_with(&pattern[1..]),
+        false => pattern == name
+    }
+}
+
+fn interface(name: &str) -> Result<u32, RuleError> {
+    let index = match CString::new(name) {
+        Ok(c) => unsafe { if_nametoindex(c.as_ptr()) },
+        Err(_) => 0
+    };
+    match index {
+        0 => Err(RuleError::UnknownInterface(name.to_string())),
+        index => Ok(index as u32)
+    }
+}
+
+fn port_intervals(ranges: &[(u16, u16)]) -> Result<Vec<(u128, u128)>, RuleError> {
+    ranges.iter().map(|&(start, end)| match start <= end {
+        true => Ok((start as u128, end as u128)),
+        false => Err(RuleError::PortRange(start, end))
+    }).collect()
+}
+
+/// The intervals of the IPv4 or IPv6 networks
+fn address_intervals(networks: &[Cidr], v4: bool) -> Vec<(u128, u128)> {
+    networks.iter()
+        .filter(|n| n.is_ipv4() == v4)
+        .map(|n| (ip_bits(&n.addr()), ip_bits(&n.last())))
+        .collect()
+}
+
+fn bits(n: usize) -> Vec<u64> {
+    vec![0; (n + 63) / 64]
+}
+
+fn set(bits: &mut [u64], i: usize) {
+    bits[i / 64] |= 1 << (i % 64);
+}
+
+fn and(bits: &mut [u64], other: &[u64]) {
+    for (a, b) in bits.iter_mut().zip(other) {
+        *a &= *b;
+    }
+}
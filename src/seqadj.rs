@@ -0,0 +1,208 @@
+//! TCP sequence number translation
+//!
+//! Changing the length of a TCP payload shifts the sequence numbers of everything the sender transmits afterwards,
+//! and the acknowledgements the receiver sends back.
+//! `SeqAdjust` records such changes per connection and translates later segments in both directions,
+//! like the `seqadj` extension of nf_conntrack.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use util::*;
+use message::{PacketBuffer, MangleError, Packet, Transport, Tcp, TCPOPT_SACK, TCP_ACK};
+
+/// One direction of a TCP connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Direction {
+    /// The sender's address
+    pub saddr: IpAddr,
+    /// The sender's port
+    pub sport: u16,
+    /// The receiver's address
+    pub daddr: IpAddr,
+    /// The receiver's port
+    pub dport: u16,
+}
+
+impl Direction {
+    /// The direction of a decoded TCP segment
+    pub fn of(packet: &Packet) -> Option<Direction> {
+        match packet.transport {
+            Some(Transport::Tcp(ref tcp)) => Some(Direction {
+                saddr: packet.network.saddr(),
+                sport: tcp.src_port(),
+                daddr: packet.network.daddr(),
+                dport: tcp.dst_port(),
+            }),
+            _ => None
+        }
+    }
+
+    /// The opposite direction of the same connection
+    pub fn reverse(&self) -> Direction {
+        Direction { saddr: self.daddr, sport: self.dport, daddr: self.saddr, dport: self.sport }
+    }
+}
+
+/// The offsets introduced in one direction
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Offsets {
+    /// The original sequence number of the last segment whose length was changed
+    correction_pos: u32,
+    /// The offset of sequence numbers up to `correction_pos`
+    offset_before: i32,
+    /// The offset of sequence numbers after `correction_pos`
+    offset_after: i32,
+}
+
+impl Offsets {
+    /// The offset of an original sequence number
+    fn at(&self, seq: u32) -> i32 {
+        match after(seq, self.correction_pos) {
+            true => self.offset_after,
+            false => self.offset_before
+        }
+    }
+
+    /// The offset of a translated sequence number
+    fn at_translated(&self, seq: u32) -> i32 {
+        match after(seq.wrapping_sub(self.offset_before as u32), self.correction_pos) {
+            true => self.offset_after,
+            false => self.offset_before
+        }
+    }
+}
+
+/// Tracks sequence number offsets of TCP connections whose payload lengths were changed
+///
+/// Every segment of a tracked connection, in both directions, must be passed through `adjust`,
+/// after any length change of that segment was recorded.
+/// Connections are tracked until `remove` is called, e.g. once they are closed.
+#[derive(Default)]
+pub struct SeqAdjust {
+    directions: HashMap<Direction, Offsets>,
+}
+
+impl SeqAdjust {
+    /// Track no connections
+    pub fn new() -> SeqAdjust {
+        SeqAdjust { directions: HashMap::new() }
+    }
+
+    /// Whether either direction of the segment's connection has recorded offsets
+    pub fn is_tracked(&self, packet: &Packet) -> bool {
+        match Direction::of(packet) {
+            Some(d) => self.directions.contains_key(&d) || self.directions.contains_key(&d.reverse()),
+            None => false
+        }
+    }
+
+    /// Replace `remove` bytes of a segment's payload at `offset` with `insert`, recording the change in length
+    ///
+    /// The segment must not have been passed through `adjust` yet.
+    pub fn splice_payload(&mut self, buffer: &mut PacketBuffer, offset: usize, remove: usize, insert: &[u8])
+                          -> Result<(), MangleError> {
+        let (direction, seq) = segment(buffer)?;
+        buffer.splice_payload(offset, remove, insert)?;
+        self.record(direction, seq, insert.len() as i32 - remove as i32);
+        Ok(())
+    }
+
+    /// Record that the payload of the segment starting at original sequence number `seq` changed length by `delta`
+    pub fn record(&mut self, direction: Direction, seq: u32, delta: i32) {
+        if delta == 0 {
+            return;
+        }
+        let offsets = self.directions.entry(direction).or_insert_with(Offsets::default);
+        // Retransmits of segments before the last change must not move the correction point back
+        if offsets.offset_before == offsets.offset_after || after(seq, offsets.correction_pos) {
+            offsets.correction_pos = seq;
+            offsets.offset_before = offsets.offset_after;
+            offsets.offset_after = offsets.offset_after.wrapping_add(delta);
+        }
+    }
+
+    /// Translate the sequence, acknowledgement and SACK numbers of a segment
+    ///
+    /// Returns whether the segment was changed.
+    pub fn adjust(&self, buffer: &mut PacketBuffer) -> Result<bool, MangleError> {
+        let (direction, seq) = segment(buffer)?;
+        let this_way = self.directions.get(&direction).cloned();
+        let other_way = self.directions.get(&direction.reverse()).cloned();
+        let (ack, has_ack, sack) = {
+            let packet = buffer.decode().map_err(MangleError::Decode)?;
+            let tcp = tcp_of(&packet).unwrap();
+            (tcp.ack(), tcp.has_flags(TCP_ACK), tcp.option(TCPOPT_SACK).map(|o| o.data.to_vec()))
+        };
+        let mut changed = false;
+
+        if let Some(offsets) = this_way {
+            let offset = offsets.at(seq);
+            if offset != 0 {
+                buffer.set_seq(seq.wrapping_add(offset as u32))?;
+                changed = true;
+            }
+        }
+        if let (Some(offsets), true) = (other_way, has_ack) {
+            let offset = offsets.at_translated(ack);
+            if offset != 0 {
+                buffer.set_ack(ack.wrapping_sub(offset as u32))?;
+                changed = true;
+            }
+            if let Some(mut blocks) = sack {
+                let mut moved = false;
+                for edge in blocks.chunks_mut(4).filter(|e| e.len() == 4) {
+                    let value = be32(edge, 0);
+                    let offset = offsets.at_translated(value);
+                    if offset != 0 {
+                        write32(edge, 0, value.wrapping_sub(offset as u32));
+                        moved = true;
+                    }
+                }
+                // Blocks entirely before the first change are left alone, saving a checksum update
+                if moved {
+                    buffer.set_tcp_option(TCPOPT_SACK, &blocks)?;
+                    changed = true;
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Stop tracking both directions of a connection
+    pub fn remove(&mut self, direction: Direction) {
+        self.directions.remove(&direction);
+        self.directions.remove(&direction.reverse());
+    }
+
+    /// The number of tracked connection directions
+    pub fn len(&self) -> usize {
+        self.directions.len()
+    }
+
+    /// Whether no connections are tracked
+    pub fn is_empty(&self) -> bool {
+        self.directions.is_empty()
+    }
+}
+
+/// The direction and original sequence number of a TCP segment
+fn segment(buffer: &PacketBuffer) -> Result<(Direction, u32), MangleError> {
+    let packet = buffer.decode().map_err(MangleError::Decode)?;
+    match (Direction::of(&packet), tcp_of(&packet)) {
+        (Some(direction), Some(tcp)) => Ok((direction, tcp.seq())),
+        _ => Err(MangleError::Field)
+    }
+}
+
+fn tcp_of<'a>(packet: &Packet<'a>) -> Option<Tcp<'a>> {
+    match packet.transport {
+        Some(Transport::Tcp(tcp)) => Some(tcp),
+        _ => None
+    }
+}
+
+/// Whether sequence number `a` comes after `b`, accounting for wrap around
+#[inline]
+fn after(a: u32, b: u32) -> bool {
+    (b.wrapping_sub(a) as i32) < 0
+}
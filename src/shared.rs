@@ -0,0 +1,47 @@
+//! Values shared between threads and replaced atomically
+//!
+//! `Shared` holds a value, such as a `cidr::PrefixSet`, that a queue's handler reads for every packet
+//! while another thread builds its replacement. Readers take a reference counted snapshot,
+//! so replacing the value neither waits for them nor changes what they see mid-packet.
+
+use std::sync::{Arc, RwLock};
+
+/// A value that can be read and replaced from any thread
+pub struct Shared<T> {
+    current: Arc<RwLock<Arc<T>>>,
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Shared<T> {
+        Shared { current: self.current.clone() }
+    }
+}
+
+impl<T> Shared<T> {
+    /// Share `value`
+    pub fn new(value: T) -> Shared<T> {
+        Shared { current: Arc::new(RwLock::new(Arc::new(value))) }
+    }
+
+    /// The current value
+    ///
+    /// The lock is only held to copy the reference, so this is cheap enough for every packet.
+    pub fn get(&self) -> Arc<T> {
+        match self.current.read() {
+            Ok(current) => current.clone(),
+            Err(poisoned) => poisoned.into_inner().clone()
+        }
+    }
+
+    /// Replace the value, returning the old one
+    ///
+    /// Readers holding the old value keep it until they drop it.
+    pub fn set(&self, value: T) -> Arc<T> {
+        let value = Arc::new(value);
+        let mut current = match self.current.write() {
+            Ok(current) => current,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        ::std::mem::replace(&mut *current, value)
+    }
+}
@@ -0,0 +1,485 @@
+//! TCP stream reassembly
+//!
+//! Each `Message` carries a single TCP segment, while application protocols like HTTP and TLS
+//! may spread a request over several.
+//! `StreamReassembler` orders the segments of each connection into a byte stream per direction,
+//! and holds them with `Deferred` verdicts until a `StreamHandler` has seen enough to decide.
+//! The copy range of `CopyMode::Packet` must cover whole segments for them to be inspected.
+
+use std::cmp::max;
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::time::{Duration, Instant};
+use message::{Message, Packet, PacketBuffer, Transport, TCP_FIN, TCP_RST, TCP_SYN, TCP_ACK};
+use queue::{Deferred, PacketHandler, QueueHandle, Verdict};
+use seqadj::Direction;
+use error::Error;
+
+/// One side of a TCP connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    /// The side that opened the connection, or sent the first segment seen
+    Client,
+    /// The other side
+    Server,
+}
+
+impl Side {
+    /// The opposite side
+    pub fn other(&self) -> Side {
+        match *self {
+            Side::Client => Side::Server,
+            Side::Server => Side::Client,
+        }
+    }
+
+    fn index(&self) -> usize {
+        match *self {
+            Side::Client => 0,
+            Side::Server => 1,
+        }
+    }
+}
+
+/// The decision of a `StreamHandler` on a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamVerdict {
+    /// Keep holding segments until more bytes arrive
+    Continue,
+    /// Accept the held segments and the rest of the connection
+    Accept,
+    /// Drop the held segments and the rest of the connection
+    Drop,
+    /// Reset the connection and drop the rest of it
+    ///
+    /// The first held segment from each side is rewritten into a RST,
+    /// which its receiver accepts as nothing after it was delivered.
+    Reset,
+}
+
+/// Decides on TCP connections from their byte streams
+pub trait StreamHandler {
+    /// Decide on a connection after new bytes arrived from `side`, or `side` finished sending
+    ///
+    /// While this returns `StreamVerdict::Continue`, segments carrying data are held in both directions,
+    /// so a handler waiting on a reply that needs the request delivered first only decides at the timeout.
+    fn data(&mut self, stream: &Stream, side: Side) -> StreamVerdict;
+
+    /// Decide on a packet that is not inspected as part of a stream
+    ///
+    /// These are packets other than TCP, IP fragments, and segments that were not copied in full.
+    fn other(&mut self, message: &Message) -> Verdict {
+        let _ = message;
+        Verdict::Accept
+    }
+}
+
+impl<F> StreamHandler for F where F: FnMut(&Stream, Side) -> StreamVerdict {
+    fn data(&mut self, stream: &Stream, side: Side) -> StreamVerdict {
+        self(stream, side)
+    }
+}
+
+/// Bounds on the connections tracked and the segments held
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// How long segments are held before the timeout verdict applies to their connection
+    pub hold_timeout: Duration,
+    /// How long a connection is remembered without segments
+    pub idle_timeout: Duration,
+    /// The bytes buffered per connection before the overflow verdict applies
+    pub max_flow_bytes: usize,
+    /// The number of connections tracked at once
+    pub max_flows: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            hold_timeout: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(300),
+            max_flow_bytes: 64 * 1024,
+            max_flows: 65536,
+        }
+    }
+}
+
+/// The bytes sent by one side
+#[derive(Default)]
+struct Half {
+    /// The sequence number of the first byte of the stream
+    base: Option<u32>,
+    /// The bytes received in order
+    data: Vec<u8>,
+    /// Bytes received ahead of a gap, by offset into the stream
+    pending: BTreeMap<u32, Vec<u8>>,
+    /// The length of the stream, once its FIN was seen
+    fin: Option<u32>,
+}
+
+impl Half {
+    /// The offset into the stream of a sequence number
+    ///
+    /// Negative for sequence numbers before the start of the stream, such as that of the SYN.
+    fn offset(&mut self, seq: u32, syn: bool) -> i64 {
+        let start = match syn {
+            true => seq.wrapping_add(1),
+            false => seq,
+        };
+        let base = *self.base.get_or_insert(start);
+        seq.wrapping_sub(base) as i32 as i64
+    }
+
+    /// Add a segment, returning whether the stream grew or finished
+    ///
+    /// Bytes already in the stream are never replaced by those of a retransmission.
+    fn add(&mut self, seq: u32, syn: bool, fin: bool, payload: &[u8]) -> bool {
+        let before = (self.data.len(), self.is_finished());
+        let start = self.offset(seq, syn) + syn as i64;
+        let end = start + payload.len() as i64;
+        if fin && end >= 0 && self.fin.is_none() {
+            self.fin = Some(end as u32);
+        }
+        let from = max(start, self.data.len() as i64);
+        if end > from {
+            let piece = &payload[(from - start) as usize..];
+            let shorter = self.pending.get(&(from as u32)).map(|p| p.len() < piece.len()).unwrap_or(true);
+            if shorter {
+                self.pending.insert(from as u32, piece.to_vec());
+            }
+            while let Some(offset) = self.pending.keys().next().cloned() {
+                if offset as usize > self.data.len() {
+                    break;
+                }
+                let piece = self.pending.remove(&offset).unwrap();
+                let seen = self.data.len() - offset as usize;
+                if piece.len() > seen {
+                    self.data.extend_from_slice(&piece[seen..]);
+                }
+            }
+        }
+        (self.data.len(), self.is_finished()) != before
+    }
+
+    fn is_finished(&self) -> bool {
+        self.fin.map(|f| self.data.len() >= f as usize).unwrap_or(false)
+    }
+
+    fn buffered(&self) -> usize {
+        self.data.len() + self.pending.values().map(|p| p.len()).sum::<usize>()
+    }
+}
+
+/// The byte streams of a TCP connection
+pub struct Stream {
+    direction: Direction,
+    halves: [Half; 2],
+}
+
+impl Stream {
+    /// The direction from the client to the server
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// The bytes `side` sent, as far as they arrived in order
+    pub fn data(&self, side: Side) -> &[u8] {
+        &self.halves[side.index()].data
+    }
+
+    /// Whether `side` finished sending, and all its bytes arrived
+    pub fn is_finished(&self, side: Side) -> bool {
+        self.halves[side.index()].is_finished()
+    }
+}
+
+struct Held {
+    deferred: Deferred,
+    /// A copy of the segment, kept for the first segment from each side to rewrite into a RST
+    packet: Option<Vec<u8>>,
+}
+
+struct Flow {
+    stream: Stream,
+    held: Vec<Held>,
+    /// For each side, the index into `held` and stream offset of its first held segment
+    first: [Option<(usize, i64)>; 2],
+    /// The verdict for the rest of the connection, once decided
+    decided: Option<Verdict>,
+    /// When the oldest held segment arrived
+    held_since: Option<Instant>,
+    last_seen: Instant,
+}
+
+impl Flow {
+    fn new(direction: Direction, now: Instant) -> Flow {
+        Flow {
+            stream: Stream { direction: direction, halves: [Half::default(), Half::default()] },
+            held: Vec::new(),
+            first: [None, None],
+            decided: None,
+            held_since: None,
+            last_seen: now,
+        }
+    }
+
+    fn hold(&mut self, side: Side, offset: i64, deferred: Deferred, packet: &[u8], now: Instant) {
+        let index = self.held.len();
+        let first = match self.first[side.index()] {
+            Some((_, o)) if o <= offset => false,
+            Some((i, _)) => {
+                self.held[i].packet = None;
+                true
+            },
+            None => true
+        };
+        self.held.push(Held { deferred: deferred, packet: match first { true => Some(packet.to_vec()), false => None } });
+        if first {
+            self.first[side.index()] = Some((index, offset));
+        }
+        if self.held_since.is_none() {
+            self.held_since = Some(now);
+        }
+    }
+
+    fn buffered(&self) -> usize {
+        self.stream.halves[0].buffered() + self.stream.halves[1].buffered()
+    }
+
+    /// When the held segments time out, or else the connection is forgotten
+    fn expiry(&self, limits: &Limits) -> Instant {
+        match self.held_since {
+            Some(since) => since + limits.hold_timeout,
+            None => self.last_seen + limits.idle_timeout
+        }
+    }
+
+    /// Set `verdict` for the held segments and the rest of the connection
+    fn release(&mut self, verdict: Verdict) {
+        for held in self.decide(verdict) {
+            let _ = held.deferred.set(verdict);
+        }
+    }
+
+    /// Rewrite the first held segment from each side into a RST, and drop the rest of the connection
+    fn reset(&mut self) {
+        for held in self.decide(Verdict::Drop) {
+            let buffer = held.packet.and_then(|p| PacketBuffer::new(p).ok());
+            match buffer {
+                Some(mut buffer) => match buffer.make_reset() {
+                    Ok(()) => { let _ = held.deferred.set_mangled(Verdict::Accept, &mut buffer); },
+                    Err(_) => { let _ = held.deferred.set(Verdict::Drop); }
+                },
+                None => { let _ = held.deferred.set(Verdict::Drop); }
+            }
+        }
+    }
+
+    /// Record the decision and free the streams, returning the held segments
+    fn decide(&mut self, verdict: Verdict) -> Vec<Held> {
+        self.decided = Some(verdict);
+        self.held_since = None;
+        self.first = [None, None];
+        self.stream.halves = [Half::default(), Half::default()];
+        mem::replace(&mut self.held, Vec::new())
+    }
+}
+
+/// A `PacketHandler` that reassembles TCP streams for a `StreamHandler` to decide on
+///
+/// Segments that carry data are held until the handler decides on their connection;
+/// the handshake and pure acknowledgements pass through.
+/// Once decided, the rest of a connection gets the same verdict without inspection.
+/// Segments are passed on unmarked, as a `StreamVerdict` carries no mark;
+/// only packets decided by `StreamHandler::other` keep the mark it sets on their message.
+pub struct StreamReassembler<S> {
+    handler: S,
+    flows: HashMap<Direction, Flow>,
+    limits: Limits,
+    overflow_verdict: Verdict,
+    timeout_verdict: Verdict,
+    next_expiry: Option<Instant>,
+}
+
+impl<S: StreamHandler> StreamReassembler<S> {
+    /// Reassemble streams for `handler` within the default `Limits`
+    pub fn new(handler: S) -> StreamReassembler<S> {
+        StreamReassembler::with_limits(handler, Limits::default())
+    }
+
+    /// Reassemble streams for `handler` within `limits`
+    pub fn with_limits(handler: S, limits: Limits) -> StreamReassembler<S> {
+        StreamReassembler {
+            handler: handler,
+            flows: HashMap::new(),
+            limits: limits,
+            overflow_verdict: Verdict::Accept,
+            timeout_verdict: Verdict::Accept,
+            next_expiry: None,
+        }
+    }
+
+    /// Set the verdict for connections that buffer more than `Limits::max_flow_bytes` undecided
+    ///
+    /// Defaults to `Verdict::Accept`.
+    pub fn set_overflow_verdict(&mut self, verdict: Verdict) {
+        self.overflow_verdict = verdict;
+    }
+
+    /// Set the verdict for connections that hold segments for longer than `Limits::hold_timeout`,
+    /// or are evicted undecided
+    ///
+    /// Defaults to `Verdict::Accept`.
+    pub fn set_timeout_verdict(&mut self, verdict: Verdict) {
+        self.timeout_verdict = verdict;
+    }
+
+    /// The wrapped handler
+    pub fn handler(&mut self) -> &mut S {
+        &mut self.handler
+    }
+
+    /// The number of connections tracked
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// Whether no connections are tracked
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    /// Handle a TCP segment, returning its verdict unless it is held
+    fn segment(&mut self, hq: &QueueHandle, message: &Message, packet: &Packet, now: Instant) -> Option<Verdict> {
+        let tcp = match packet.transport {
+            Some(Transport::Tcp(tcp)) if packet.fragment.is_none() && packet.is_complete() => tcp,
+            _ => return Some(self.handler.other(message))
+        };
+        let direction = Direction::of(packet).unwrap();
+        let (key, side) = if self.flows.contains_key(&direction) {
+            (direction, Side::Client)
+        } else if self.flows.contains_key(&direction.reverse()) || tcp.has_flags(TCP_SYN | TCP_ACK) {
+            (direction.reverse(), Side::Server)
+        } else {
+            (direction, Side::Client)
+        };
+
+        if !self.flows.contains_key(&key) {
+            if tcp.has_flags(TCP_RST) {
+                return Some(Verdict::Accept);
+            }
+            while self.flows.len() >= self.limits.max_flows && self.evict_oldest() {}
+            self.flows.insert(key, Flow::new(key, now));
+        }
+        if tcp.has_flags(TCP_RST) {
+            let mut flow = self.flows.remove(&key).unwrap();
+            let verdict = flow.decided.unwrap_or(Verdict::Accept);
+            flow.release(verdict);
+            return Some(verdict);
+        }
+
+        let (verdict, expiry) = {
+            let flow = self.flows.get_mut(&key).unwrap();
+            flow.last_seen = now;
+            let verdict = match flow.decided {
+                Some(verdict) => Some(verdict),
+                None => {
+                    let (syn, fin) = (tcp.has_flags(TCP_SYN), tcp.has_flags(TCP_FIN));
+                    let half = &mut flow.stream.halves[side.index()];
+                    if packet.payload.is_empty() && !fin {
+                        half.offset(tcp.seq(), syn);
+                        Some(Verdict::Accept)
+                    } else {
+                        let offset = half.offset(tcp.seq(), syn);
+                        let grew = half.add(tcp.seq(), syn, fin, packet.payload);
+                        flow.hold(side, offset, Deferred::new(hq, message.header.id()), packet.data(), now);
+
+                        if flow.buffered() > self.limits.max_flow_bytes {
+                            flow.release(self.overflow_verdict);
+                        } else if grew {
+                            match self.handler.data(&flow.stream, side) {
+                                StreamVerdict::Continue => (),
+                                StreamVerdict::Accept => flow.release(Verdict::Accept),
+                                StreamVerdict::Drop => flow.release(Verdict::Drop),
+                                StreamVerdict::Reset => flow.reset(),
+                            }
+                        }
+                        None
+                    }
+                }
+            };
+            (verdict, flow.expiry(&self.limits))
+        };
+        // Every flow touched is expired in time, including those only ever seen handshaking or already decided
+        self.schedule(expiry);
+        verdict
+    }
+
+    fn schedule(&mut self, at: Instant) {
+        self.next_expiry = Some(match self.next_expiry {
+            Some(next) if next < at => next,
+            _ => at
+        });
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        let oldest = self.flows.iter().min_by_key(|&(_, f)| f.last_seen).map(|(k, _)| *k);
+        match oldest {
+            Some(key) => {
+                let mut flow = self.flows.remove(&key).unwrap();
+                flow.release(self.timeout_verdict);
+                true
+            },
+            None => false
+        }
+    }
+}
+
+impl<S: StreamHandler> PacketHandler for StreamReassembler<S> {
+    fn handle(&mut self, hq: &QueueHandle, message: Result<&Message, &Error>) -> i32 {
+        let message = match message {
+            Ok(m) => m,
+            Err(_) => return 0
+        };
+        let verdict = match message.decode() {
+            Ok(ref packet) => self.segment(hq, message, packet, Instant::now()),
+            Err(_) => Some(self.handler.other(message))
+        };
+        if let Some(verdict) = verdict {
+            let _ = Verdict::set_message_verdict(hq, message, verdict);
+        }
+        0
+    }
+
+    fn poll(&mut self, _: &QueueHandle, now: Instant) -> Option<Instant> {
+        match self.next_expiry {
+            Some(next) if now >= next => (),
+            next => return next
+        }
+        let limits = self.limits;
+        let timeout_verdict = self.timeout_verdict;
+        let mut idle = Vec::new();
+        let mut next = None;
+        for (key, flow) in self.flows.iter_mut() {
+            if now >= flow.expiry(&limits) {
+                match flow.held_since {
+                    Some(_) => flow.release(timeout_verdict),
+                    None => {
+                        idle.push(*key);
+                        continue;
+                    }
+                }
+            }
+            let expiry = flow.expiry(&limits);
+            next = Some(match next {
+                Some(n) if n < expiry => n,
+                _ => expiry
+            });
+        }
+        for key in idle {
+            self.flows.remove(&key);
+        }
+        self.next_expiry = next;
+        next
+    }
+}
@@ -0,0 +1,195 @@
+use std::net::Ipv6Addr;
+use bpf::{run, Filter, FilterError, Insn};
+
+fn ip4(protocol: u8, src: [u8; 4], dst: [u8; 4], fragment: u16, transport: &[u8]) -> Vec<u8> {
+    let len = 20 + transport.len();
+    let mut packet = vec![0x45, 0, (len >> 8) as u8, len as u8, 0, 0, (fragment >> 8) as u8, fragment as u8, 64, protocol, 0, 0];
+    packet.extend_from_slice(&src);
+    packet.extend_from_slice(&dst);
+    packet.extend_from_slice(transport);
+    packet
+}
+
+/// An IPv4 packet with four bytes of options
+fn ip4_options(protocol: u8, transport: &[u8]) -> Vec<u8> {
+    let len = 24 + transport.len();
+    let mut packet = vec![0x46, 0, (len >> 8) as u8, len as u8, 0, 0, 0, 0, 64, protocol, 0, 0,
+                          1, 1, 1, 1, 2, 2, 2, 2, 1, 1, 1, 0];
+    packet.extend_from_slice(transport);
+    packet
+}
+
+fn ip6(next: u8, src: &str, dst: &str, transport: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x60, 0, 0, 0, 0, transport.len() as u8, next, 64];
+    packet.extend_from_slice(&src.parse::<Ipv6Addr>().ok().unwrap().octets());
+    packet.extend_from_slice(&dst.parse::<Ipv6Addr>().ok().unwrap().octets());
+    packet.extend_from_slice(transport);
+    packet
+}
+
+fn tcp(sport: u16, dport: u16, flags: u8) -> Vec<u8> {
+    vec![(sport >> 8) as u8, sport as u8, (dport >> 8) as u8, dport as u8, 0, 0, 0, 1, 0, 0, 0, 0, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]
+}
+
+fn udp(sport: u16, dport: u16) -> Vec<u8> {
+    vec![(sport >> 8) as u8, sport as u8, (dport >> 8) as u8, dport as u8, 0, 8, 0, 0]
+}
+
+fn matches(expression: &str, packet: &[u8]) -> bool {
+    let filter = Filter::new(expression).unwrap_or_else(|e| panic!("{}: {}", expression, e));
+    assert_eq!(Filter::from_program(filter.program().to_vec()).ok().unwrap(), filter);
+    filter.matches_data(packet)
+}
+
+#[test]
+fn expressions() {
+    let syn = ip4(6, [1, 2, 3, 4], [10, 0, 0, 5], 0, &tcp(5555, 443, 0x02));
+    let syn_ack = ip4(6, [10, 9, 3, 4], [1, 0, 0, 5], 0, &tcp(5555, 443, 0x12));
+    let dns = ip4(17, [1, 2, 3, 4], [8, 8, 8, 8], 0, &udp(999, 53));
+    let fragment = ip4(17, [1, 2, 3, 4], [8, 8, 8, 8], 0x0010, &udp(999, 53));
+    let options = ip4_options(6, &tcp(80, 1234, 0x10));
+    let syn6 = ip6(6, "2001:db8::1", "fd00::2", &tcp(1000, 443, 0x02));
+    let dns6 = ip6(17, "2001:db8::1", "fd00::2", &udp(53, 1000));
+    let echo = ip4(1, [1, 1, 1, 1], [2, 2, 2, 2], 0, &[8, 0, 0, 0, 0, 1, 0, 1]);
+
+    assert!(matches("", &syn));
+    assert!(!matches("tcp port 443 and not net 10.0.0.0/8", &syn));
+    assert!(matches("tcp port 443 and not src net 10.0.0.0/8", &syn));
+    assert!(matches("tcp port 443", &syn6) && !matches("udp port 443", &syn6));
+    assert!(matches("udp src port 53", &dns6) && !matches("udp dst port 53", &dns6));
+    assert!(matches("port domain", &dns) && !matches("port 53", &fragment));
+    assert!(matches("ip", &dns) && !matches("ip6", &dns) && matches("ip6", &syn6));
+    assert!(matches("src port 80 and dst port 1234", &options) && !matches("src port 1234", &options));
+    assert!(matches("tcp[tcpflags] & tcp-ack != 0", &options));
+    assert!(matches("tcp[13] = 2", &syn) && matches("tcp[13] = 2", &syn6));
+    assert!(matches("tcp[tcpflags] & (tcp-syn|tcp-ack) == tcp-syn", &syn));
+    assert!(!matches("tcp[tcpflags] & (tcp-syn|tcp-ack) == tcp-syn", &syn_ack));
+    assert!(matches("tcp[1 + 1:2] = 443", &syn) && matches("tcp[1+1:2] == 443", &syn6));
+    assert!(matches("tcp[2:2] = tcp[0:2] - 5112", &syn));
+    assert!(matches("ip[9] = 17", &dns) && !matches("ip[9] = 17", &dns6) && matches("ip6[6] = 17", &dns6));
+    assert!(matches("len = 40", &syn) && !matches("len < 40", &syn) && matches("len <= 40", &syn));
+    assert!(matches("less 40", &syn) && !matches("less 39", &syn) && matches("greater 40", &syn) && !matches("greater 41", &syn));
+    assert!(matches("host 8.8.8.8", &dns) && matches("dst host 8.8.8.8", &dns) && !matches("src host 8.8.8.8", &dns));
+    assert!(matches("net 8.8.0.0 mask 255.255.0.0", &dns) && !matches("net 8.9.0.0 mask 255.255.0.0", &dns));
+    assert!(matches("src net 2001:db8::/32", &syn6) && !matches("src net 2001:db9::/32", &syn6));
+    assert!(matches("host fd00::2", &syn6) && !matches("host fd00::3", &syn6));
+    assert!(matches("portrange 400-500", &syn) && !matches("portrange 444-500", &syn));
+    assert!(matches("ip proto udp", &dns) && matches("ip6 proto tcp", &syn6) && !matches("ip proto tcp", &syn6));
+    assert!(matches("icmp[icmptype] = icmp-echo", &echo));
+    assert!(matches("icmp6 and icmp6[icmp6type] == icmp6-echo", &ip6(58, "::1", "::2", &[128, 0, 0, 0, 0, 1, 0, 1])));
+    assert!(matches("not (udp or tcp port 80)", &syn) && !matches("!(udp || tcp)", &syn));
+    // Loads past the end of the packet reject it
+    assert!(!matches("tcp[100] = 0", &syn));
+
+    for bad in ["tcp port", "port 70000", "host 10.0.0.0/8", "ip6 host 1.2.3.4", "foo", "tcp[0:3] = 1", "(tcp",
+                "tcp port 80 80", "icmp port 1", "net 1.2.3.4 mask 255.0.255.0", "ip[0] = ip6[0]"].iter() {
+        assert!(Filter::new(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn long_jumps() {
+    // Enough tests that jumps to the end need unconditional jumps to reach
+    let hosts: Vec<String> = (0..200).map(|i| format!("host 10.0.{}.{}", i / 256, i % 256)).collect();
+    let filter = Filter::new(&format!("({}) and tcp", hosts.join(" or "))).ok().unwrap();
+    assert!(filter.program().len() > 256);
+    assert!(filter.program().iter().any(|insn| insn.code == 0x05 && insn.k > 0));
+    assert!(filter.matches_data(&ip4(6, [10, 0, 0, 199], [1, 1, 1, 1], 0, &tcp(1, 2, 0))));
+    assert!(!filter.matches_data(&ip4(6, [10, 0, 1, 199], [1, 1, 1, 1], 0, &tcp(1, 2, 0))));
+    assert!(!filter.matches_data(&ip4(17, [10, 0, 0, 0], [1, 1, 1, 1], 0, &udp(1, 2))));
+
+    let deep = (0..20).fold("tcp[0]".to_string(), |e, _| format!("tcp[0] + ({})", e));
+    assert_eq!(Filter::new(&format!("{} = 1", deep)), Err(FilterError::TooComplex));
+}
+
+#[test]
+fn programs() {
+    assert_eq!(Filter::from_program(vec![]), Err(FilterError::Program));
+    // Jumping past the end, and using scratch memory past its 16 words
+    assert_eq!(Filter::from_program(vec![Insn { code: 0x15, jt: 5, jf: 0, k: 0 }, Insn { code: 0x06, jt: 0, jf: 0, k: 0 }]),
+               Err(FilterError::Program));
+    assert_eq!(Filter::from_program(vec![Insn { code: 0x02, jt: 0, jf: 0, k: 16 }, Insn { code: 0x06, jt: 0, jf: 0, k: 0 }]),
+               Err(FilterError::Program));
+}
+
+/// Parse a listing in the form `tcpdump -d` prints
+fn listing(text: &str) -> Vec<Insn> {
+    text.lines().enumerate().map(|(pc, line)| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let number = |s: &str| match s.starts_with("0x") {
+            true => u32::from_str_radix(&s[2..], 16).ok().unwrap(),
+            false => s.parse().ok().unwrap()
+        };
+        // Jump targets are absolute, BPF's are relative to the next instruction
+        let target = |s: &str| (s.parse::<usize>().ok().unwrap() - pc - 1) as u8;
+        let operand = fields.get(2).cloned().unwrap_or("");
+        let (code, k) = match (fields[1], operand) {
+            ("ld", "#pktlen") => (0x80, 0),
+            ("ldxb", "4*([0]&0xf)") => (0xb1, 0),
+            ("ldb", "[x") => (0x50, number(fields[4].trim_matches(']'))),
+            ("ldh", "[x") => (0x48, number(fields[4].trim_matches(']'))),
+            ("ld", _) => (0x20, number(operand.trim_matches(|c| c == '[' || c == ']'))),
+            ("ldh", _) => (0x28, number(operand.trim_matches(|c| c == '[' || c == ']'))),
+            ("ldb", _) => (0x30, number(operand.trim_matches(|c| c == '[' || c == ']'))),
+            ("and", _) => (0x54, number(&operand[1..])),
+            ("jeq", _) => (0x15, number(&operand[1..])),
+            ("jgt", _) => (0x25, number(&operand[1..])),
+            ("jge", _) => (0x35, number(&operand[1..])),
+            ("jset", _) => (0x45, number(&operand[1..])),
+            ("ret", _) => (0x06, number(&operand[1..])),
+            (op, _) => panic!("{}", op)
+        };
+        match code & 0x07 {
+            0x05 => Insn { code: code, jt: target(fields[4]), jf: target(fields[6]), k: k },
+            _ => Insn { code: code, jt: 0, jf: 0, k: k }
+        }
+    }).collect()
+}
+
+#[test]
+fn tcpdump() {
+    let ports = [53, 80, 443, 1000];
+    let mut packets = Vec::new();
+    for &(src, dst) in [([1, 2, 3, 4], [8, 8, 8, 8]), ([10, 9, 3, 4], [10, 0, 0, 5]), ([8, 8, 8, 8], [11, 0, 0, 1])].iter() {
+        for &sport in ports.iter() {
+            for &dport in ports.iter() {
+                for &flags in [0x02, 0x12, 0x10].iter() {
+                    packets.push(ip4(6, src, dst, 0, &tcp(sport, dport, flags)));
+                }
+                packets.push(ip4(17, src, dst, 0, &udp(sport, dport)));
+                packets.push(ip4(17, src, dst, 0x2010, &udp(sport, dport)));
+            }
+        }
+        packets.push(ip4(1, src, dst, 0, &[8, 0, 0, 0, 0, 1, 0, 1]));
+        packets.push(ip4(1, src, dst, 0, &[0, 0, 0, 0, 0, 1, 0, 1]));
+    }
+    for &port in ports.iter() {
+        packets.push(ip4_options(6, &tcp(port, 1234, 0x02)));
+        packets.push(ip4_options(17, &udp(1234, port)));
+        packets.push(ip6(6, "2001:db8::1", "fd00::2", &tcp(1000, port, 0x02)));
+        packets.push(ip6(17, "2001:db8::1", "fd00::2", &udp(port, 53)));
+    }
+    packets.push(ip6(58, "::1", "::2", &[128, 0, 0, 0, 0, 1, 0, 1]));
+    packets.push(vec![0x45, 0, 0]);
+
+    // Compiled programs may differ from libpcap's, but must filter the same packets
+    let text = include_str!("fixtures/tcpdump.txt");
+    let mut filters = 0;
+    for block in text.split("\n\n").filter(|block| !block.starts_with('#')) {
+        let mut lines = block.trim().splitn(2, '\n');
+        let expression = lines.next().unwrap();
+        let reference = listing(lines.next().unwrap());
+        assert!(Filter::from_program(reference.clone()).is_ok(), "{}", expression);
+        let filter = Filter::new(expression).ok().unwrap();
+        let mut matched = 0;
+        for packet in &packets {
+            let expected = run(&reference, packet) != 0;
+            assert_eq!(filter.matches_data(packet), expected, "{} on {:?}", expression, packet);
+            matched += expected as usize;
+        }
+        assert!(matched > 0 && matched < packets.len(), "{}", expression);
+        filters += 1;
+    }
+    assert_eq!(filters, 10);
+    assert_eq!(Filter::new("ip").ok().unwrap().program(), &listing(text.split("\n\n").nth(1).unwrap().splitn(2, '\n').nth(1).unwrap())[..]);
+}
@@ -0,0 +1,272 @@
+use message::{Packet, PacketBuffer, MangleError, DecodeError, Network, Transport, Tcp, TcpOptions};
+use message::{TCPOPT_MSS, TCPOPT_NOP, TCPOPT_TIMESTAMP, TCPOPT_WINDOW_SCALE};
+use message::checksum;
+use util;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// A TCP SYN from 10.0.0.1:1234 to 10.0.0.2:80, with no options
+fn syn() -> Vec<u8> {
+    vec![0x45, 0, 0, 40, 0, 1, 0, 0, 64, 6, 0, 0,
+         10, 0, 0, 1, 10, 0, 0, 2,
+         0x04, 0xd2, 0, 80, 0, 0, 0, 1, 0, 0, 0, 0,
+         0x50, 0x02, 0xff, 0xff, 0, 0, 0, 0]
+}
+
+#[test]
+fn insert_tcp_option() {
+    let mut buffer = PacketBuffer::new(syn()).ok().unwrap();
+    buffer.insert_tcp_option(2, &[0x05, 0xb4]).ok().unwrap();
+    let packet = Packet::new(buffer.finish()).ok().unwrap();
+    assert_eq!(packet.datagram_len(), 44);
+    assert_eq!(packet.verify_transport_checksum(), Some(true));
+}
+
+#[test]
+fn tcp_options_past_segment() {
+    let mut buffer = PacketBuffer::new(syn()).ok().unwrap();
+    // A data offset of 60 bytes, in a 20 byte segment
+    buffer.data_mut()[32] = 0xf0;
+    match buffer.insert_tcp_option(2, &[0x05, 0xb4]) {
+        Err(MangleError::Decode(DecodeError::Truncated)) => (),
+        other => panic!("{:?}", other)
+    }
+}
+
+/// A UDP datagram from 10.0.0.1:1234 to 10.0.0.2:53 followed by link layer padding
+fn padded_udp() -> Vec<u8> {
+    let mut packet = super::udp_packet(64);
+    checksum::fill_network(&mut packet);
+    // Enable the UDP checksum, so `fill_transport` computes it
+    packet[26] = 0xff;
+    checksum::fill_transport(&mut packet);
+    packet.extend_from_slice(&[0; 6]);
+    packet
+}
+
+#[test]
+fn splice_padding() {
+    let mut buffer = PacketBuffer::new(padded_udp()).ok().unwrap();
+    buffer.splice_payload(4, 0, b" pong").ok().unwrap();
+    let data = buffer.into_vec();
+    let packet = Packet::new(&data).ok().unwrap();
+    assert_eq!(packet.datagram_len(), 37);
+    assert_eq!(packet.payload, b"ping pong");
+    assert_eq!(util::be16(&data, 24), 17);
+    assert_eq!(packet.verify_network_checksum(), Some(true));
+    assert_eq!(packet.verify_transport_checksum(), Some(true));
+}
+
+#[test]
+fn splice_fragment() {
+    let mut data = padded_udp();
+    // The first fragment of a larger datagram
+    data[6] = 0x20;
+    let mut buffer = PacketBuffer::new(data).ok().unwrap();
+    assert_eq!(buffer.splice_payload(0, 4, b"pong"), Ok(()));
+    assert_eq!(buffer.set_payload(b"pong pong"), Err(MangleError::Fragment));
+    assert_eq!(buffer.splice_payload(0, 1, b""), Err(MangleError::Fragment));
+}
+
+/// Assert both checksums of the buffer are correct without recomputing them
+fn assert_checksums(buffer: &PacketBuffer) {
+    assert!(!buffer.is_stale());
+    let packet = buffer.decode().ok().unwrap();
+    assert!(packet.verify_network_checksum() != Some(false));
+    assert_eq!(packet.verify_transport_checksum(), Some(true));
+}
+
+#[test]
+fn rewrite_ipv4() {
+    let mut data = syn();
+    checksum::fill_network(&mut data);
+    checksum::fill_transport(&mut data);
+    let mut buffer = PacketBuffer::new(data).ok().unwrap();
+    buffer.set_saddr(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))).ok().unwrap();
+    assert_checksums(&buffer);
+    buffer.set_daddr(IpAddr::V4(Ipv4Addr::new(255, 255, 255, 254))).ok().unwrap();
+    assert_checksums(&buffer);
+    buffer.set_src_port(1).ok().unwrap();
+    assert_checksums(&buffer);
+    buffer.set_dst_port(65535).ok().unwrap();
+    assert_checksums(&buffer);
+    buffer.set_ttl(1).ok().unwrap();
+    assert_checksums(&buffer);
+    buffer.set_dscp(46).ok().unwrap();
+    assert_checksums(&buffer);
+    buffer.set_ecn(3).ok().unwrap();
+    assert_checksums(&buffer);
+
+    let packet = buffer.decode().ok().unwrap();
+    match packet.network {
+        Network::Ipv4(ip) => {
+            assert_eq!(ip.saddr(), Ipv4Addr::new(192, 0, 2, 1));
+            assert_eq!(ip.daddr(), Ipv4Addr::new(255, 255, 255, 254));
+            assert_eq!((ip.ttl(), ip.dscp(), ip.ecn()), (1, 46, 3));
+        },
+        other => panic!("{:?}", other)
+    }
+    let transport = packet.transport.unwrap();
+    assert_eq!((transport.src_port(), transport.dst_port()), (Some(1), Some(65535)));
+    assert_eq!(buffer.set_saddr(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))),
+               Err(MangleError::Family));
+}
+
+#[test]
+fn rewrite_ipv6() {
+    let mut data = vec![0x60, 0, 0, 0, 0, 12, 17, 64];
+    data.extend_from_slice(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    data.extend_from_slice(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+    data.extend_from_slice(&[0x12, 0x34, 0, 53, 0, 12, 0, 0, 1, 2, 3, 4]);
+    checksum::fill_transport(&mut data);
+    let mut buffer = PacketBuffer::new(data).ok().unwrap();
+    buffer.set_daddr(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 9))).ok().unwrap();
+    assert_checksums(&buffer);
+    buffer.set_dst_port(5353).ok().unwrap();
+    assert_checksums(&buffer);
+    buffer.set_dscp(10).ok().unwrap();
+    buffer.set_ecn(1).ok().unwrap();
+    buffer.set_ttl(2).ok().unwrap();
+    assert_checksums(&buffer);
+    match buffer.decode().ok().unwrap().network {
+        Network::Ipv6(ip) => {
+            assert_eq!((ip.dscp(), ip.ecn(), ip.hop_limit()), (10, 1, 2));
+            assert_eq!(ip.flow_label(), 0);
+        },
+        other => panic!("{:?}", other)
+    }
+}
+
+#[test]
+fn rewrite_udp_disabled() {
+    // A zero UDP checksum over IPv4 stays disabled
+    let mut data = super::udp_packet(64);
+    checksum::fill_network(&mut data);
+    let mut buffer = PacketBuffer::new(data).ok().unwrap();
+    buffer.set_saddr(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 9))).ok().unwrap();
+    buffer.set_src_port(4321).ok().unwrap();
+    assert_eq!(util::be16(buffer.data(), 26), 0);
+    assert_checksums(&buffer);
+}
+
+#[test]
+fn rewrite_icmp() {
+    // An echo request, whose checksum covers no pseudo-header
+    let mut data = vec![0x45, 0, 0, 28, 0, 1, 0, 0, 64, 1, 0, 0,
+                        10, 0, 0, 1, 10, 0, 0, 2,
+                        8, 0, 0, 0, 0, 1, 0, 1];
+    checksum::fill_network(&mut data);
+    checksum::fill_transport(&mut data);
+    let mut buffer = PacketBuffer::new(data).ok().unwrap();
+    let icmp = util::be16(buffer.data(), 22);
+    buffer.set_daddr(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3))).ok().unwrap();
+    assert_checksums(&buffer);
+    assert_eq!(util::be16(buffer.data(), 22), icmp);
+    assert_eq!(buffer.set_dst_port(80), Err(MangleError::Field));
+}
+
+/// A captured TCP SYN with MSS 1460, SACK permitted, timestamps, and window scale 7 options
+fn syn_with_options() -> Vec<u8> {
+    let mut data = vec![0x45, 0x00, 0x00, 0x3c, 0x1c, 0x46, 0x40, 0x00, 0x40, 0x06, 0xb1, 0xe6,
+                        0xac, 0x10, 0x0a, 0x63, 0xac, 0x10, 0x0a, 0x0c,
+                        0xe5, 0x2c, 0x00, 0x15, 0x8b, 0xd3, 0x4f, 0x4b, 0x00, 0x00, 0x00, 0x00,
+                        0xa0, 0x02, 0x16, 0xd0, 0x00, 0x00, 0x00, 0x00,
+                        0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a, 0x00, 0x4d, 0x7d, 0x3f,
+                        0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x07];
+    checksum::fill_transport(&mut data);
+    data
+}
+
+fn tcp(buffer: &PacketBuffer) -> Tcp<'_> {
+    match buffer.decode().ok().unwrap().transport {
+        Some(Transport::Tcp(tcp)) => tcp,
+        other => panic!("{:?}", other)
+    }
+}
+
+fn assert_finished(buffer: &mut PacketBuffer) {
+    let data = buffer.finish().to_vec();
+    let packet = Packet::new(&data).ok().unwrap();
+    assert!(packet.is_complete());
+    assert_eq!(packet.verify_network_checksum(), Some(true));
+    assert_eq!(packet.verify_transport_checksum(), Some(true));
+}
+
+#[test]
+fn tcp_options() {
+    let options: Vec<(u8, usize)> = TcpOptions::new(&syn_with_options()[40..]).map(|o| (o.kind, o.offset)).collect();
+    assert_eq!(options, vec![(2, 0), (4, 4), (8, 6), (1, 16), (3, 17)]);
+    // Iteration stops at a length running past the options, or too short to cover itself
+    assert_eq!(TcpOptions::new(&[1, 2, 4, 5]).count(), 1);
+    assert_eq!(TcpOptions::new(&[1, 8, 1, 0]).count(), 1);
+    assert_eq!(TcpOptions::new(&[0, 1, 1, 1]).count(), 0);
+
+    let buffer = PacketBuffer::new(syn_with_options()).ok().unwrap();
+    assert_eq!(tcp(&buffer).mss(), Some(1460));
+    assert_eq!(tcp(&buffer).window_scale(), Some(7));
+    assert_eq!(tcp(&buffer).timestamps(), Some((0x004d7d3f, 0)));
+}
+
+#[test]
+fn clamp_mss() {
+    let mut buffer = PacketBuffer::new(syn_with_options()).ok().unwrap();
+    assert_eq!(buffer.clamp_mss(1460), Ok(false));
+    assert!(!buffer.is_stale());
+    assert_eq!(buffer.clamp_mss(1400), Ok(true));
+    assert_checksums(&buffer);
+    assert_eq!(tcp(&buffer).mss(), Some(1400));
+
+    // Only SYNs are clamped
+    let mut buffer = PacketBuffer::new(syn_with_options()).ok().unwrap();
+    buffer.set_tcp_flags(0x10).ok().unwrap();
+    assert_eq!(buffer.clamp_mss(1400), Ok(false));
+}
+
+#[test]
+fn remove_tcp_option() {
+    let mut buffer = PacketBuffer::new(syn_with_options()).ok().unwrap();
+    assert_eq!(buffer.remove_tcp_option(TCPOPT_TIMESTAMP), Ok(true));
+    assert_eq!(buffer.remove_tcp_option(TCPOPT_TIMESTAMP), Ok(false));
+    assert_checksums(&buffer);
+    assert_eq!(tcp(&buffer).header_len(), 40);
+    assert_eq!(tcp(&buffer).timestamps(), None);
+    assert_eq!(tcp(&buffer).options()[6..16], [TCPOPT_NOP; 10]);
+}
+
+#[test]
+fn set_tcp_option() {
+    let mut buffer = PacketBuffer::new(syn_with_options()).ok().unwrap();
+    // The same size is overwritten in place
+    buffer.set_tcp_option(TCPOPT_WINDOW_SCALE, &[14]).ok().unwrap();
+    assert_checksums(&buffer);
+    assert_eq!(tcp(&buffer).window_scale(), Some(14));
+
+    // Another size rebuilds the options
+    buffer.set_tcp_option(TCPOPT_MSS, &[0x05, 0xb4, 0]).ok().unwrap();
+    assert_finished(&mut buffer);
+    assert_eq!(tcp(&buffer).option(TCPOPT_MSS).unwrap().data, &[0x05, 0xb4, 0]);
+    assert_eq!(tcp(&buffer).window_scale(), Some(14));
+
+    // A new kind is appended into the padding, growing the header by whole words
+    let len = buffer.data().len();
+    buffer.set_tcp_option(30, &[1, 2, 3]).ok().unwrap();
+    assert_finished(&mut buffer);
+    assert_eq!(buffer.data().len(), len + 4);
+    assert_eq!(tcp(&buffer).option(30).unwrap().data, &[1, 2, 3]);
+    assert_eq!(buffer.insert_tcp_option(31, &[0; 30]), Err(MangleError::Space));
+}
+
+#[test]
+fn reflect() {
+    let mut data = super::udp_packet(3);
+    checksum::fill_network(&mut data);
+    checksum::fill_transport(&mut data);
+    let mut buffer = PacketBuffer::new(data).ok().unwrap();
+    buffer.reflect().ok().unwrap();
+    assert_checksums(&buffer);
+    let packet = buffer.decode().ok().unwrap();
+    assert_eq!(packet.network.saddr(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+    assert_eq!(packet.network.daddr(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+    assert_eq!(packet.network.ttl(), 64);
+    let transport = packet.transport.unwrap();
+    assert_eq!((transport.src_port(), transport.dst_port()), (Some(53), Some(1234)));
+}
@@ -0,0 +1,131 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+use message::{Header, Message, Packet};
+use queue::{Verdict, VerdictHandler};
+use flow::FlowKey;
+use cache::VerdictCache;
+
+/// A UDP datagram from port `sport` of 10.0.0.1 to port 53 of 10.0.0.3
+fn udp(sport: u16) -> Vec<u8> {
+    vec![0x45, 0, 0, 28, 0, 0, 0x40, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 3,
+         (sport >> 8) as u8, sport as u8, 0, 53, 0, 8, 0, 0]
+}
+
+fn key(sport: u16) -> FlowKey {
+    FlowKey::of(&Packet::new(&udp(sport)).ok().unwrap()).unwrap()
+}
+
+/// A cache over a handler accepting port 1 and dropping the rest, counting its calls
+fn cache(calls: &Rc<Cell<usize>>) -> VerdictCache<Box<dyn FnMut(&Message) -> Verdict>> {
+    let calls = calls.clone();
+    VerdictCache::new(Box::new(move |message: &Message| {
+        calls.set(calls.get() + 1);
+        match message.decode().ok().and_then(|packet| packet.transport).and_then(|t| t.src_port()) {
+            Some(1) => Verdict::Accept,
+            _ => Verdict::Drop
+        }
+    }))
+}
+
+/// Decide on a packet, returning the verdict and the mark set
+fn decide<V: VerdictHandler>(cache: &mut V, data: &[u8]) -> (Verdict, Option<u32>) {
+    let header = Header { packet_id: 0, hw_protocol: 0, hook: 0 };
+    let message = Message::from_data(&header, data);
+    let verdict = cache.decide(&message);
+    (verdict, message.take_mark())
+}
+
+#[test]
+fn remembers() {
+    let calls = Rc::new(Cell::new(0));
+    let mut cache = cache(&calls);
+    cache.set_mark(0x10);
+    assert_eq!(decide(&mut cache, &udp(1)), (Verdict::Accept, Some(0x10)));
+    assert_eq!(decide(&mut cache, &udp(1)), (Verdict::Accept, Some(0x10)));
+    assert_eq!(decide(&mut cache, &udp(2)), (Verdict::Drop, None));
+    assert_eq!(decide(&mut cache, &udp(2)), (Verdict::Drop, None));
+    assert_eq!(calls.get(), 2);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.cached(&key(1)), Some(Verdict::Accept));
+    assert_eq!(cache.cached(&key(2)), Some(Verdict::Drop));
+    assert_eq!(cache.cached(&key(3)), None);
+
+    // Packets without a flow always reach the handler
+    assert_eq!(decide(&mut cache, &[0x45, 0, 0, 20]), (Verdict::Drop, None));
+    assert_eq!(calls.get(), 3);
+}
+
+#[test]
+fn invalidate() {
+    let calls = Rc::new(Cell::new(0));
+    let mut cache = cache(&calls);
+    decide(&mut cache, &udp(1));
+    decide(&mut cache, &udp(2));
+    assert_eq!(cache.invalidate(&key(1)), Some(Verdict::Accept));
+    assert_eq!(cache.invalidate(&key(1)), None);
+    assert_eq!(cache.cached(&key(1)), None);
+    // The flow is still tracked, and decided again on its next packet
+    assert_eq!(cache.len(), 2);
+    decide(&mut cache, &udp(1));
+    decide(&mut cache, &udp(2));
+    assert_eq!(calls.get(), 3);
+
+    cache.clear();
+    assert_eq!(cache.cached(&key(1)), None);
+    assert_eq!(cache.cached(&key(2)), None);
+    decide(&mut cache, &udp(1));
+    decide(&mut cache, &udp(2));
+    assert_eq!(calls.get(), 5);
+}
+
+#[test]
+fn ttl() {
+    let calls = Rc::new(Cell::new(0));
+    let mut cache = cache(&calls);
+    cache.set_ttl(Duration::from_millis(5));
+    decide(&mut cache, &udp(1));
+    decide(&mut cache, &udp(1));
+    assert_eq!(calls.get(), 1);
+    thread::sleep(Duration::from_millis(10));
+    // Expired, the handler decides again and the verdict is remembered afresh
+    assert_eq!(decide(&mut cache, &udp(1)).0, Verdict::Accept);
+    decide(&mut cache, &udp(1));
+    assert_eq!(calls.get(), 2);
+}
+
+fn accepted_only(_: &Message, verdict: Verdict) -> bool {
+    verdict == Verdict::Accept
+}
+
+#[test]
+fn policy() {
+    let calls = Rc::new(Cell::new(0));
+    let mut cache = cache(&calls);
+    cache.set_policy(accepted_only);
+    decide(&mut cache, &udp(1));
+    decide(&mut cache, &udp(1));
+    decide(&mut cache, &udp(2));
+    decide(&mut cache, &udp(2));
+    assert_eq!(calls.get(), 3);
+    assert_eq!(cache.cached(&key(2)), None);
+}
+
+#[test]
+fn handler_marks() {
+    let calls = Rc::new(Cell::new(0));
+    let counted = calls.clone();
+    let mut cache = VerdictCache::new(move |message: &Message| {
+        counted.set(counted.get() + 1);
+        message.set_mark(7);
+        Verdict::Accept
+    });
+    assert_eq!(decide(&mut cache, &udp(1)), (Verdict::Accept, Some(7)));
+    assert_eq!(decide(&mut cache, &udp(1)), (Verdict::Accept, Some(7)));
+    assert_eq!(calls.get(), 1);
+
+    // The cache's own mark replaces the handler's
+    cache.set_mark(1);
+    assert_eq!(decide(&mut cache, &udp(1)), (Verdict::Accept, Some(1)));
+}
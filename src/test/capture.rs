@@ -0,0 +1,87 @@
+use std::env;
+use std::fs;
+use std::process;
+use message::Message;
+use queue::Verdict;
+use handle::Handle;
+use mock::Mock;
+use capture::{Capture, CaptureFile, Rotation};
+use replay::Trace;
+use super::udp_packet;
+
+/// Drop the packet of TTL 1, rewrite the TTL of 2 and 4, and mark those of 3 and 4
+fn handler(message: &Message) -> Verdict {
+    let ttl = message.data().ok().unwrap()[8];
+    if ttl % 2 == 0 {
+        message.mangle().ok().unwrap().set_ttl(9).ok().unwrap();
+    }
+    if ttl >= 3 {
+        message.set_mark(0x42);
+    }
+    match ttl {
+        1 => Verdict::Drop,
+        _ => Verdict::Accept
+    }
+}
+
+#[test]
+fn round_trip() {
+    let dir = env::temp_dir().join(format!("capture-{}", process::id()));
+    fs::create_dir_all(&dir).ok().unwrap();
+    let path = dir.join("round_trip.pcapng");
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let queue = handle.queue(0, Capture::new(handler, CaptureFile::create(&path).ok().unwrap())).ok().unwrap();
+    let ids: Vec<u32> = (1..5).map(|ttl| mock.inject(0, &udp_packet(ttl))).collect();
+    handle.start(4096);
+    drop(queue);
+
+    // The verdicts are given as the handler decided
+    let decisions: Vec<_> = ids.iter().map(|&id| mock.verdict(id).unwrap()).collect();
+    let verdicts: Vec<Verdict> = decisions.iter().map(|d| d.verdict).collect();
+    assert_eq!(verdicts, vec![Verdict::Drop, Verdict::Accept, Verdict::Accept, Verdict::Accept]);
+    let marks: Vec<Option<u32>> = decisions.iter().map(|d| d.mark).collect();
+    assert_eq!(marks, vec![None, None, Some(0x42), Some(0x42)]);
+    let rewritten = decisions[1].payload.clone().unwrap();
+    assert_eq!(rewritten[8], 9);
+
+    // And recorded as they were passed on
+    let trace = Trace::load(&path).ok().unwrap();
+    assert_eq!(trace.packets.len(), 4);
+    let expected: Vec<Option<Verdict>> = trace.packets.iter().map(|p| p.expected).collect();
+    assert_eq!(expected, verdicts.into_iter().map(Some).collect::<Vec<_>>());
+    let ttls: Vec<u8> = trace.packets.iter().map(|p| p.data[8]).collect();
+    assert_eq!(ttls, vec![1, 9, 3, 9]);
+    assert_eq!(trace.packets[1].data, rewritten);
+    let marks: Vec<u32> = trace.packets.iter().map(|p| p.metadata.mark).collect();
+    assert_eq!(marks, vec![0, 0, 0x42, 0x42]);
+    fs::remove_dir_all(&dir).ok().unwrap();
+}
+
+#[test]
+fn rotation() {
+    let dir = env::temp_dir().join(format!("capture-rotation-{}", process::id()));
+    fs::create_dir_all(&dir).ok().unwrap();
+    let path = dir.join("q.pcapng");
+    let mut file = CaptureFile::create(&path).ok().unwrap();
+    file.set_rotation(Rotation { max_size: Some(400), max_age: None, keep: 2 });
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let queue = handle.queue(0, Capture::new(handler, file)).ok().unwrap();
+    for _ in 0..20 {
+        mock.inject(0, &udp_packet(3));
+    }
+    handle.start(4096);
+    drop(queue);
+
+    // Each file is whole, the oldest are removed
+    let mut names: Vec<String> = fs::read_dir(&dir).ok().unwrap().map(|e| e.ok().unwrap().file_name().into_string().ok().unwrap()).collect();
+    names.sort();
+    assert_eq!(names, vec!["q.pcapng", "q.pcapng.1", "q.pcapng.2"]);
+    for name in &names {
+        let trace = Trace::load(dir.join(name)).ok().unwrap();
+        assert!(!trace.packets.is_empty());
+        assert!(trace.packets.iter().all(|p| p.expected == Some(Verdict::Accept) && p.metadata.mark == 0x42));
+    }
+    fs::remove_dir_all(&dir).ok().unwrap();
+}
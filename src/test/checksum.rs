@@ -0,0 +1,119 @@
+use message::{Packet, PacketBuffer};
+use message::checksum::*;
+use util::{be16, be32, write16, Rng};
+
+/// A captured TCP SYN from 172.16.10.99:58668 to 172.16.10.12:21, with a correct IPv4 header checksum
+fn syn() -> Vec<u8> {
+    vec![0x45, 0x00, 0x00, 0x3c, 0x1c, 0x46, 0x40, 0x00, 0x40, 0x06, 0xb1, 0xe6,
+         0xac, 0x10, 0x0a, 0x63, 0xac, 0x10, 0x0a, 0x0c,
+         0xe5, 0x2c, 0x00, 0x15, 0x8b, 0xd3, 0x4f, 0x4b, 0x00, 0x00, 0x00, 0x00,
+         0xa0, 0x02, 0x16, 0xd0, 0x00, 0x00, 0x00, 0x00,
+         0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a, 0x00, 0x4d, 0x7d, 0x3f,
+         0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x07]
+}
+
+/// A UDP datagram from fe80::1:4660 to fe80::2:53, with no checksum
+fn udp6() -> Vec<u8> {
+    let mut packet = vec![0x60, 0, 0, 0, 0, 12, 17, 64];
+    packet.extend_from_slice(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    packet.extend_from_slice(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+    packet.extend_from_slice(&[0x12, 0x34, 0, 53, 0, 12, 0, 0, 1, 2, 3, 4]);
+    packet
+}
+
+#[test]
+fn verify() {
+    let mut data = syn();
+    assert_eq!(Packet::new(&data).ok().unwrap().verify_network_checksum(), Some(true));
+    assert_eq!(Packet::new(&data).ok().unwrap().verify_transport_checksum(), Some(false));
+    fill_transport(&mut data);
+    assert_eq!(Packet::new(&data).ok().unwrap().verify_transport_checksum(), Some(true));
+    data[30] ^= 0x10;
+    assert_eq!(Packet::new(&data).ok().unwrap().verify_transport_checksum(), Some(false));
+    data[8] = 1;
+    assert_eq!(Packet::new(&data).ok().unwrap().verify_network_checksum(), Some(false));
+    fill_network(&mut data);
+    assert_eq!(Packet::new(&data).ok().unwrap().verify_network_checksum(), Some(true));
+}
+
+#[test]
+fn pseudo_header_ipv6() {
+    let mut data = udp6();
+    // A zero UDP checksum is not allowed over IPv6
+    assert_eq!(Packet::new(&data).ok().unwrap().verify_transport_checksum(), Some(false));
+    fill_transport(&mut data);
+    assert!(be16(&data, 46) != 0);
+    assert_eq!(Packet::new(&data).ok().unwrap().verify_transport_checksum(), Some(true));
+}
+
+#[test]
+fn udp_disabled() {
+    let mut data = vec![0x45, 0, 0, 32, 0, 1, 0, 0, 64, 17, 0, 0,
+                        10, 0, 0, 1, 10, 0, 0, 2,
+                        0x04, 0xd2, 0, 53, 0, 12, 0, 0];
+    data.extend_from_slice(b"ping");
+    fill_transport(&mut data);
+    assert_eq!(be16(&data, 26), 0);
+    assert_eq!(Packet::new(&data).ok().unwrap().verify_transport_checksum(), Some(true));
+}
+
+#[test]
+fn update_matches_recompute() {
+    let mut rng = Rng::new(27);
+    for _ in 0..1000 {
+        let mut data: Vec<u8> = (0..40).map(|_| rng.next_u64() as u8).collect();
+        write16(&mut data, 10, 0);
+        let value = checksum(&data);
+        write16(&mut data, 10, value);
+
+        let at = (rng.next_u64() % 20) as usize * 2;
+        if at == 10 {
+            continue;
+        }
+        let old = be16(&data, at);
+        let new = rng.next_u64() as u16;
+        write16(&mut data, at, new);
+        let updated = update(value, old, new);
+
+        write16(&mut data, 10, 0);
+        assert_eq!(updated, checksum(&data));
+        write16(&mut data, 10, updated);
+        assert_eq!(checksum(&data), 0);
+    }
+}
+
+#[test]
+fn update_bytes_matches_recompute() {
+    let mut rng = Rng::new(1624);
+    for _ in 0..1000 {
+        let mut data: Vec<u8> = (0..60).map(|_| rng.next_u64() as u8).collect();
+        write16(&mut data, 0, 0);
+        let value = checksum(&data);
+
+        let at = 2 + (rng.next_u64() % 20) as usize * 2;
+        let len = 1 + (rng.next_u64() % 16) as usize;
+        let old = data[at..at + len].to_vec();
+        let new: Vec<u8> = (0..len).map(|_| rng.next_u64() as u8).collect();
+        data[at..at + len].copy_from_slice(&new);
+
+        assert_eq!(update_bytes(value, &old, &new), checksum(&data));
+        if len == 4 {
+            assert_eq!(update_u32(value, be32(&old, 0), be32(&new, 0)), checksum(&data));
+        }
+    }
+}
+
+#[test]
+fn finish_stale() {
+    let mut data = syn();
+    fill_transport(&mut data);
+    let mut buffer = PacketBuffer::new(data).ok().unwrap();
+    assert!(!buffer.is_stale());
+    buffer.data_mut()[8] = 3;
+    buffer.data_mut()[25] = 0;
+    assert!(buffer.is_stale());
+    let packet = buffer.into_vec();
+    let packet = Packet::new(&packet).ok().unwrap();
+    assert_eq!(packet.verify_network_checksum(), Some(true));
+    assert_eq!(packet.verify_transport_checksum(), Some(true));
+}
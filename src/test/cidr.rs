@@ -0,0 +1,118 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::thread;
+use cidr::{Cidr, CidrError, PrefixMap, PrefixSet};
+use shared::Shared;
+use util::Rng;
+
+fn cidr(network: &str) -> Cidr {
+    network.parse().ok().unwrap()
+}
+
+fn addr(addr: &str) -> IpAddr {
+    addr.parse().ok().unwrap()
+}
+
+#[test]
+fn networks() {
+    let network = cidr("10.1.2.3/8");
+    assert_eq!(network.to_string(), "10.0.0.0/8");
+    assert_eq!(network.last().to_string(), "10.255.255.255");
+    assert!(network.contains(&addr("10.9.9.9")));
+    assert!(!network.contains(&addr("11.0.0.0")));
+    assert!(!network.contains(&addr("::a00:1")));
+    assert_eq!(cidr("2001:db8::1/32").to_string(), "2001:db8::/32");
+    assert_eq!(cidr("0.0.0.0/0").last().to_string(), "255.255.255.255");
+    assert_eq!(cidr("1.2.3.4").prefix(), 32);
+    assert_eq!("1.2.3.4/33".parse::<Cidr>(), Err(CidrError::Prefix));
+    assert_eq!("x/3".parse::<Cidr>(), Err(CidrError::Address));
+}
+
+/// An address with few distinct high bits, so networks overlap
+fn random_addr(rng: &mut Rng, v4: bool) -> IpAddr {
+    match v4 {
+        true => IpAddr::V4(Ipv4Addr::from(rng.next_u64() as u32 & 0xc0ff_00ff | 0x0a00_0000)),
+        false => {
+            let bits = (rng.next_u64() as u128) << 64 | rng.next_u64() as u128;
+            IpAddr::V6(Ipv6Addr::from(bits & !(0xffff << 100) | 0x2001 << 112))
+        }
+    }
+}
+
+#[test]
+fn longest_match() {
+    let mut rng = Rng::new(44);
+    for _ in 0..20 {
+        let mut map = PrefixMap::new();
+        let mut list: Vec<(Cidr, u32)> = Vec::new();
+        for i in 0..300 {
+            let v4 = rng.next_u64() % 2 == 0;
+            let prefix = (rng.next_u64() % if v4 { 33 } else { 129 }) as u8;
+            let network = Cidr::new(random_addr(&mut rng, v4), prefix).ok().unwrap();
+            let position = list.iter().position(|&(n, _)| n == network);
+            assert_eq!(map.insert(network, i), position.map(|j| list[j].1));
+            match position {
+                Some(j) => list[j].1 = i,
+                None => list.push((network, i))
+            }
+            if rng.next_u64() % 4 == 0 {
+                let (network, value) = list.remove((rng.next_u64() % list.len() as u64) as usize);
+                assert_eq!(map.remove(&network), Some(value));
+                assert_eq!(map.remove(&network), None);
+            }
+        }
+        assert_eq!(map.len(), list.len());
+        assert_eq!(map.entries().len(), list.len());
+        for &(network, ref value) in &list {
+            assert_eq!(map.get(&network), Some(value));
+        }
+
+        // The same as trying every network
+        for _ in 0..2000 {
+            let v4 = rng.next_u64() % 3 != 0;
+            let addr = random_addr(&mut rng, v4);
+            let best = list.iter().filter(|&&(n, _)| n.contains(&addr)).max_by_key(|&&(n, _)| n.prefix());
+            assert_eq!(map.longest_match(&addr).map(|(n, &v)| (n, v)), best.cloned(), "{}", addr);
+        }
+
+        for &(network, _) in &list {
+            assert!(map.remove(&network).is_some());
+        }
+        assert!(map.is_empty());
+        assert!(map.entries().is_empty());
+    }
+}
+
+#[test]
+fn sets() {
+    let set = PrefixSet::parse("# DROP list\n1.10.16.0/20 ; SBL256894\n\n  2001:db8::/32\n10.0.0.0/8\n10.1.0.0/16 # more specific\n0.0.0.0/0\n")
+        .ok().unwrap();
+    assert_eq!(set.len(), 5);
+    assert_eq!(set.longest_match(&addr("10.1.2.3")), Some(cidr("10.1.0.0/16")));
+    assert_eq!(set.longest_match(&addr("10.2.2.3")), Some(cidr("10.0.0.0/8")));
+    assert_eq!(set.longest_match(&addr("11.2.2.3")), Some(cidr("0.0.0.0/0")));
+    assert!(set.contains(&addr("2001:db8::5")));
+    assert!(!set.contains(&addr("2001:db9::5")));
+    assert_eq!(set.networks()[0], cidr("0.0.0.0/0"));
+    assert_eq!(PrefixSet::parse("1.2.3.4\n\nnope\n").err().unwrap().to_string(), "line 3: Malformed address");
+
+    let mut set = PrefixSet::new();
+    assert!(set.insert(cidr("::/0")));
+    assert!(!set.insert(cidr("::/0")));
+    assert!(set.contains(&addr("::1")));
+    assert!(!set.contains(&addr("1.1.1.1")));
+    assert!(set.remove(&cidr("::/0")));
+    assert!(set.is_empty());
+}
+
+#[test]
+fn shared() {
+    let shared = Shared::new(PrefixSet::parse("10.0.0.0/8").ok().unwrap());
+    let reader = shared.clone();
+    let snapshot = reader.get();
+    assert_eq!(shared.set(PrefixSet::parse("192.168.0.0/16").ok().unwrap()).len(), 1);
+    // Readers keep the set they have until they get it again
+    assert!(snapshot.contains(&addr("10.0.0.1")));
+    assert!(!reader.get().contains(&addr("10.0.0.1")));
+    let other = thread::spawn(move || reader.get().contains(&addr("192.168.1.1")));
+    assert!(other.join().ok().unwrap());
+}
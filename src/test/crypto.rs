@@ -0,0 +1,117 @@
+use super::unhex;
+use crypto::{aes128_gcm_open, hkdf_expand, hkdf_extract, md5, sha256, Aes128};
+use util::hex;
+
+#[test]
+fn md5_rfc1321() {
+    // The test suite of RFC 1321, appendix A.5
+    let vectors: [(&[u8], &str); 7] = [
+        (b"", "d41d8cd98f00b204e9800998ecf8427e"),
+        (b"a", "0cc175b9c0f1b6a831c399e269772661"),
+        (b"abc", "900150983cd24fb0d6963f7d28e17f72"),
+        (b"message digest", "f96b697d7cb7938d525a2f31aaf161d0"),
+        (b"abcdefghijklmnopqrstuvwxyz", "c3fcd3d76192e4007dfb496cca67e13b"),
+        (b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789", "d174ab98d277d9f5a5611c2c9f419d9f"),
+        (b"12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+         "57edf4a22be3c955ac49da2e2107b67a"),
+    ];
+    for &(message, digest) in vectors.iter() {
+        assert_eq!(hex(&md5(message)), digest);
+    }
+}
+
+#[test]
+fn sha256_fips180() {
+    // The examples of FIPS 180-4, with messages of one and two blocks and one of many
+    let vectors: [(&[u8], &str); 4] = [
+        (b"", "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+        (b"abc", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+        (b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+         "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"),
+        (b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu",
+         "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1"),
+    ];
+    for &(message, digest) in vectors.iter() {
+        assert_eq!(hex(&sha256(message)), digest);
+    }
+    assert_eq!(hex(&sha256(&vec![b'a'; 1000000])), "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+}
+
+#[test]
+fn hkdf_rfc5869() {
+    // Test cases 1 to 3 of RFC 5869, appendix A
+    let vectors = [
+        ("0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b", "000102030405060708090a0b0c", "f0f1f2f3f4f5f6f7f8f9",
+         "077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5",
+         "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865"),
+        ("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\
+          202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f\
+          404142434445464748494a4b4c4d4e4f",
+         "606162636465666768696a6b6c6d6e6f707172737475767778797a7b7c7d7e7f\
+          808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f\
+          a0a1a2a3a4a5a6a7a8a9aaabacadaeaf",
+         "b0b1b2b3b4b5b6b7b8b9babbbcbdbebfc0c1c2c3c4c5c6c7c8c9cacbcccdcecf\
+          d0d1d2d3d4d5d6d7d8d9dadbdcdddedfe0e1e2e3e4e5e6e7e8e9eaebecedeeef\
+          f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff",
+         "06a6b88c5853361a06104c9ceb35b45cef760014904671014a193f40c15fc244",
+         "b11e398dc80327a1c8e7f78c596a49344f012eda2d4efad8a050cc4c19afa97c\
+          59045a99cac7827271cb41c65e590e09da3275600c2f09b8367793a9aca3db71\
+          cc30c58179ec3e87c14c01d5c1f3434f1d87"),
+        ("0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b", "", "",
+         "19ef24a32c717b167f33a91d6f648bdf96596776afdb6377ac434c1c293ccb04",
+         "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d9d201395faa4b61a96c8"),
+    ];
+    for &(ikm, salt, info, prk, okm) in vectors.iter() {
+        let extracted = hkdf_extract(&unhex(salt), &unhex(ikm));
+        assert_eq!(hex(&extracted), prk);
+        let okm = unhex(okm);
+        assert_eq!(hkdf_expand(&extracted, &unhex(info), okm.len()), okm);
+    }
+}
+
+#[test]
+fn aes128_fips197() {
+    // Appendix B and appendix C.1 of FIPS-197
+    let vectors = [
+        ("2b7e151628aed2a6abf7158809cf4f3c", "3243f6a8885a308d313198a2e0370734", "3925841d02dc09fbdc118597196a0b32"),
+        ("000102030405060708090a0b0c0d0e0f", "00112233445566778899aabbccddeeff", "69c4e0d86a7b0430d8cdb78070b4c55a"),
+    ];
+    for &(key, plaintext, ciphertext) in vectors.iter() {
+        let (mut k, mut block) = ([0u8; 16], [0u8; 16]);
+        k.copy_from_slice(&unhex(key));
+        block.copy_from_slice(&unhex(plaintext));
+        Aes128::new(&k).encrypt_block(&mut block);
+        assert_eq!(hex(&block), ciphertext);
+    }
+}
+
+#[test]
+fn gcm_sp800_38d() {
+    // Test cases 1, 2 and 4 of the GCM specification, as validated for SP 800-38D
+    let vectors = [
+        ("00000000000000000000000000000000", "000000000000000000000000", "", "", "58e2fccefa7e3061367f1d57a4e7455a"),
+        ("00000000000000000000000000000000", "000000000000000000000000", "", "00000000000000000000000000000000",
+         "0388dace60b6a392f328c2b971b2fe78ab6e47d42cec13bdf53a67b21257bddf"),
+        ("feffe9928665731c6d6a8f9467308308", "cafebabefacedbaddecaf888", "feedfacedeadbeeffeedfacedeadbeefabaddad2",
+         "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+          1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+         "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e\
+          21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091\
+          5bc94fbc3221a5db94fae95ae7121a47"),
+    ];
+    for &(key, nonce, aad, plaintext, sealed) in vectors.iter() {
+        let (mut k, mut n) = ([0u8; 16], [0u8; 12]);
+        k.copy_from_slice(&unhex(key));
+        n.copy_from_slice(&unhex(nonce));
+        let mut sealed = unhex(sealed);
+        assert_eq!(aes128_gcm_open(&k, &n, &unhex(aad), &sealed), Some(unhex(plaintext)));
+
+        // Any change to the ciphertext or tag fails authentication
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert_eq!(aes128_gcm_open(&k, &n, &unhex(aad), &sealed), None);
+        sealed[last] ^= 1;
+        sealed[0] ^= 0x80;
+        assert_eq!(aes128_gcm_open(&k, &n, &unhex(aad), &sealed), None);
+    }
+}
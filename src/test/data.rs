@@ -1,65 +1,65 @@
-use super::super::verdict::{Verdict, VerdictHandler};
-use super::super::message::Message;
-use super::super::queue::CopyMode;
-use super::super::handle::{Handle, ProtocolFamily};
+use queue::{CopyMode, Verdict, VerdictHandler, PacketHandler, QueueHandle};
+use message::Message;
+use handle::{Handle, ProtocolFamily};
+use error::Error;
+use mock::Mock;
+use super::udp_packet;
 
-struct Data(u32);
-struct Callback;
-struct Decider;
+struct Callback(u32);
+struct Decider(u32);
 
-impl PacketHandler<Data> for Callback {
-    fn callback(&self, hq: *mut QueueHandle, message: Result<&Message, &Error>, data: &mut Data) -> i32 {
-        match data {
-            Data(42) => -1,
+impl PacketHandler for Callback {
+    fn handle(&mut self, _: &QueueHandle, _: Result<&Message, &Error>) -> i32 {
+        match self.0 {
+            42 => { self.0 += 1; -1 },
             _ => panic!()
         }
     }
 }
 
-impl VerdictHandler<Data> for Decider {
-    fn decide(&self, message: &Message, data: &mut Data) -> Verdict {
-        match data {
-            Data(42) => panic!(),
-            Verdict::Accept
-        }
+impl VerdictHandler for Decider {
+    fn decide(&mut self, message: &Message) -> Verdict {
+        self.0 += 1;
+        message.set_mark(self.0);
+        Verdict::Accept
     }
 }
 
 #[test]
 fn bind() {
-    let data = Data(42);
-    let mut handle = Handle::new().ok().unwrap();
-    let _ = handle.queue_builder(data)
-        .copy_mode(CopyMode::None)
-        .decider_and_finalize(Decider)
-        .ok().unwrap();
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut queue = handle.queue(0, Decider(42)).ok().unwrap();
+    queue.set_mode(CopyMode::None).ok().unwrap();
 
     handle.bind(ProtocolFamily::INET).ok().unwrap();
 }
 
 #[test]
-#[should_panic]
 fn decider() {
-    let data = Data(42);
-    let mut handle = Handle::new().ok().unwrap();
-    let _ = handle.queue_builder(data)
-        .copy_mode(CopyMode::None)
-        .decider_and_finalize(Decider)
-        .ok().unwrap();
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut queue = handle.queue(0, Decider(42)).ok().unwrap();
+    queue.set_mode(CopyMode::None).ok().unwrap();
 
     handle.bind(ProtocolFamily::INET).ok().unwrap();
+    mock.inject(0, &udp_packet(64));
+    mock.inject(0, &udp_packet(64));
     handle.start(4096);
+    let marks: Vec<Option<u32>> = mock.verdicts(0).iter().map(|v| v.mark).collect();
+    assert_eq!(marks, vec![Some(43), Some(44)]);
 }
 
 #[test]
+#[should_panic]
 fn callback() {
-    let data = Data(42);
-    let mut handle = Handle::new().ok().unwrap();
-    let _ = handle.queue_builder(data)
-        .copy_mode(CopyMode::None)
-        .callback_and_finalize(Callback)
-        .ok().unwrap();
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut queue = handle.queue(0, Callback(42)).ok().unwrap();
+    queue.set_mode(CopyMode::None).ok().unwrap();
 
     handle.bind(ProtocolFamily::INET).ok().unwrap();
+    mock.inject(0, &udp_packet(64));
+    mock.inject(0, &udp_packet(64));
     handle.start(4096);
 }
@@ -0,0 +1,113 @@
+use message::{Packet, Transport, Extent, Extents, Fragment, DecodeError, TCP_SYN};
+
+/// A TCP SYN from 10.0.0.1:12345 to 10.0.0.2:80 with an MSS option and a five byte payload
+fn tcp_packet() -> Vec<u8> {
+    let mut packet = vec![0x45, 0, 0, 49, 0x12, 0x34, 0x40, 0, 64, 6, 0, 0,
+                          10, 0, 0, 1, 10, 0, 0, 2,
+                          0x30, 0x39, 0, 80, 0, 0, 0, 1, 0, 0, 0, 0,
+                          0x60, 0x02, 0xff, 0xff, 0, 0, 0, 0, 2, 4, 0x05, 0xb4];
+    packet.extend_from_slice(b"hello");
+    packet
+}
+
+#[test]
+fn full() {
+    let data = tcp_packet();
+    let packet = Packet::new(&data).ok().unwrap();
+    assert_eq!(packet.extents, Extents { network: Extent::Full, transport: Extent::Full, payload: Extent::Full });
+    assert_eq!(packet.transport_offset(), 20);
+    assert_eq!(packet.payload_offset(), 44);
+    assert_eq!(packet.payload, b"hello");
+    assert!(packet.is_complete());
+    match packet.transport {
+        Some(Transport::Tcp(tcp)) => {
+            assert_eq!(tcp.src_port(), 12345);
+            assert_eq!(tcp.options(), &[2, 4, 0x05, 0xb4]);
+            assert!(tcp.has_flags(TCP_SYN));
+        },
+        other => panic!("{:?}", other)
+    }
+}
+
+#[test]
+fn truncated() {
+    let data = tcp_packet();
+    for len in 0..data.len() {
+        let packet = match Packet::new(&data[..len]) {
+            Ok(packet) => packet,
+            Err(DecodeError::Truncated) if len < 20 => continue,
+            Err(e) => panic!("{}: {:?}", len, e)
+        };
+        assert!(!packet.is_complete());
+        let (transport, payload) = match len {
+            20 => (Extent::Missing, Extent::Missing),
+            _ if len < 44 => (Extent::Partial, Extent::Missing),
+            44 => (Extent::Full, Extent::Missing),
+            _ => (Extent::Full, Extent::Partial),
+        };
+        assert_eq!(packet.extents, Extents { network: Extent::Full, transport: transport, payload: payload });
+        assert_eq!(packet.payload, &data[44.min(len)..len]);
+    }
+}
+
+#[test]
+fn padding() {
+    // Link layer padding past the total length is not part of the datagram
+    let mut data = tcp_packet();
+    data.extend_from_slice(&[0; 6]);
+    let packet = Packet::new(&data).ok().unwrap();
+    assert_eq!(packet.datagram_len(), 49);
+    assert_eq!(packet.data().len(), 49);
+    assert_eq!(packet.payload, b"hello");
+}
+
+#[test]
+fn malformed() {
+    let mut data = tcp_packet();
+    data[0] = 0x44;
+    assert_eq!(Packet::new(&data).err(), Some(DecodeError::Malformed));
+    data[0] = 0x55;
+    assert_eq!(Packet::new(&data).err(), Some(DecodeError::Version(5)));
+    let mut data = tcp_packet();
+    data[3] = 10;
+    assert_eq!(Packet::new(&data).err(), Some(DecodeError::Malformed));
+}
+
+#[test]
+fn ipv4_fragment() {
+    let mut data = tcp_packet();
+    // A later fragment, at offset 1480, with more to follow
+    data[6] = 0x20;
+    data[7] = 185;
+    let packet = Packet::new(&data).ok().unwrap();
+    assert_eq!(packet.fragment, Some(Fragment { id: 0x1234, offset: 1480, more: true }));
+    assert!(packet.transport.is_none());
+    assert_eq!(packet.extents.transport, Extent::Missing);
+    assert_eq!(packet.payload.len(), 29);
+}
+
+#[test]
+fn ipv6_fragment() {
+    let mut data = vec![0x60, 0, 0, 0, 0, 16, 44, 64];
+    data.extend_from_slice(&[0x20, 1, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    data.extend_from_slice(&[0x20, 1, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+    // The first fragment, with more to follow
+    data.extend_from_slice(&[17, 0, 0, 1, 0, 0, 0, 7]);
+    data.extend_from_slice(&[0, 53, 0, 53, 0, 20, 0, 0]);
+    let packet = Packet::new(&data).ok().unwrap();
+    assert_eq!(packet.protocol, 17);
+    assert_eq!(packet.fragment, Some(Fragment { id: 7, offset: 0, more: true }));
+    assert_eq!(packet.transport.unwrap().dst_port(), Some(53));
+    assert_eq!(packet.transport_offset(), 48);
+}
+
+#[test]
+fn ipv6_extension_truncated() {
+    // A hop-by-hop header claiming more than was copied
+    let mut data = vec![0x60, 0, 0, 0, 0, 16, 0, 64];
+    data.extend_from_slice(&[0; 32]);
+    data.extend_from_slice(&[17, 3, 0, 0, 0, 0, 0, 0]);
+    let packet = Packet::new(&data).ok().unwrap();
+    assert_eq!(packet.extents.network, Extent::Partial);
+    assert!(packet.transport.is_none());
+}
@@ -0,0 +1,167 @@
+use std::net::IpAddr;
+use message::{checksum, Packet, PacketBuffer};
+use message::dns::{encode_name, Dns, DnsError, Response, RCODE_NXDOMAIN, TYPE_A, TYPE_AAAA, TYPE_CNAME};
+
+/// A query for the A records of `name`, with an EDNS0 record offering `udp_size` if any
+fn query(name: &str, qtype: u16, udp_size: Option<u16>) -> Vec<u8> {
+    let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    query.extend(encode_name(name).ok().unwrap());
+    query.extend_from_slice(&[(qtype >> 8) as u8, qtype as u8, 0, 1]);
+    if let Some(size) = udp_size {
+        query[11] = 1;
+        query.extend_from_slice(&[0, 0, 41, (size >> 8) as u8, size as u8, 0, 0, 0x80, 0, 0, 0]);
+    }
+    query
+}
+
+#[test]
+fn parse_query() {
+    let data = query("ads.Example.com", TYPE_A, Some(1232));
+    let dns = Dns::new(&data).ok().unwrap();
+    assert_eq!(dns.id(), 0x1234);
+    assert!(!dns.is_response());
+    assert!(dns.recursion_desired());
+    assert_eq!(dns.questions.len(), 1);
+    assert_eq!(dns.questions[0].name, "ads.Example.com");
+    assert_eq!(dns.questions[0].qtype, TYPE_A);
+    assert!(dns.additionals.is_empty());
+    let edns = dns.edns.unwrap();
+    assert_eq!(edns.udp_size, 1232);
+    assert!(edns.dnssec_ok);
+
+    // Every cut short message fails rather than panics
+    for len in 0..data.len() {
+        assert_eq!(Dns::new(&data[..len]).err(), Some(DnsError::Truncated));
+    }
+}
+
+#[test]
+fn names() {
+    assert_eq!(encode_name("a\\.b.c.").ok().unwrap(), vec![3, b'a', b'.', b'b', 1, b'c', 0]);
+    assert_eq!(encode_name("\\007x").ok().unwrap(), vec![2, 7, b'x', 0]);
+    assert_eq!(encode_name("a..b"), Err(DnsError::Name));
+    assert_eq!(encode_name(&"x".repeat(64)), Err(DnsError::Name));
+
+    // Escapes are written as they are read
+    let data = query("a\\.b.\\007x", TYPE_A, None);
+    assert_eq!(Dns::new(&data).ok().unwrap().questions[0].name, "a\\.b.\\007x");
+}
+
+#[test]
+fn answers() {
+    let data = query("ads.example.com", TYPE_A, Some(1232));
+    let dns = Dns::new(&data).ok().unwrap();
+    let v4: IpAddr = "0.0.0.0".parse().unwrap();
+    let v6: IpAddr = "::".parse().unwrap();
+
+    let wire = Response::sinkhole(&dns, &[v4, v6], 60).build().ok().unwrap();
+    let response = Dns::new(&wire).ok().unwrap();
+    assert!(response.is_response());
+    assert!(response.recursion_desired());
+    assert_eq!(response.id(), 0x1234);
+    assert_eq!(response.questions, dns.questions);
+    assert_eq!(response.answers.len(), 1);
+    assert_eq!(response.answers[0].name, "ads.example.com");
+    assert_eq!(response.answers[0].address(), Some(v4));
+    assert_eq!(response.answers[0].ttl, 60);
+    assert_eq!(response.edns.unwrap().udp_size, 1232);
+    // The answer's name points back at the question's
+    assert_eq!(&wire[33..35], &[0xc0, 12]);
+
+    let data = query("ads.example.com", TYPE_AAAA, None);
+    let dns = Dns::new(&data).ok().unwrap();
+    let wire = Response::sinkhole(&dns, &[v4, v6], 60).build().ok().unwrap();
+    let response = Dns::new(&wire).ok().unwrap();
+    assert_eq!(response.answers[0].address(), Some(v6));
+    assert!(response.edns.is_none());
+
+    let wire = Response::nxdomain(&dns).build().ok().unwrap();
+    let response = Dns::new(&wire).ok().unwrap();
+    assert_eq!(response.rcode(), RCODE_NXDOMAIN as u16);
+    assert!(response.answers.is_empty());
+}
+
+#[test]
+fn compressed_target() {
+    let data = query("www.Example.com", TYPE_A, None);
+    let dns = Dns::new(&data).ok().unwrap();
+    let mut response = Response::answering(&dns);
+    // `cdn` followed by a pointer to `Example.com` in the question
+    response.add_answer("www.example.com", TYPE_CNAME, 5, &[3, b'c', b'd', b'n', 0xc0, 16]);
+    let wire = response.build().ok().unwrap();
+    let parsed = Dns::new(&wire).ok().unwrap();
+    assert_eq!(parsed.answers[0].name, "www.Example.com");
+    assert_eq!(parsed.target(&parsed.answers[0]), Some("cdn.Example.com".to_string()));
+    assert_eq!(parsed.name_at(wire.len()), Err(DnsError::Truncated));
+}
+
+#[test]
+fn compression_loops() {
+    let header = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+
+    // A pointer to itself
+    let mut data = header.to_vec();
+    data.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+    assert_eq!(Dns::new(&data).err(), Some(DnsError::Name));
+
+    // A pointer forwards
+    let mut data = header.to_vec();
+    data.extend_from_slice(&[0xc0, 14, 0, 0, 1, 0, 1]);
+    assert_eq!(Dns::new(&data).err(), Some(DnsError::Name));
+
+    // A label followed by a pointer back to it
+    let mut data = header.to_vec();
+    data.extend_from_slice(&[1, b'a', 0xc0, 12, 0, 1, 0, 1]);
+    assert_eq!(Dns::new(&data).err(), Some(DnsError::Name));
+
+    // Two pointers to each other
+    let mut data = header.to_vec();
+    data.extend_from_slice(&[0xc0, 14, 0xc0, 12, 0, 1, 0, 1]);
+    assert_eq!(Dns::new(&data).err(), Some(DnsError::Name));
+
+    // Reserved label types
+    let mut data = header.to_vec();
+    data.extend_from_slice(&[0x40, 0, 0, 1, 0, 1]);
+    assert_eq!(Dns::new(&data).err(), Some(DnsError::Name));
+}
+
+#[test]
+fn udp_limit() {
+    let data = query("example.com", TYPE_A, None);
+    let dns = Dns::new(&data).ok().unwrap();
+    let mut response = Response::answering(&dns);
+    for i in 0..40 {
+        response.add_address("example.com", IpAddr::from([10, 0, 0, i]), 60);
+    }
+    // Without EDNS0 the response fits 512 bytes, leaving answers out with the truncated bit set
+    let wire = response.build().ok().unwrap();
+    assert!(wire.len() <= 512);
+    let parsed = Dns::new(&wire).ok().unwrap();
+    assert!(parsed.truncated());
+    assert_eq!(parsed.answers.len(), (512 - 29) / 16);
+}
+
+#[test]
+fn in_place() {
+    let payload = query("ads.example.com", TYPE_A, None);
+    let len = 28 + payload.len();
+    let mut data = vec![0x45, 0, (len >> 8) as u8, len as u8, 0, 0, 0x40, 0, 64, 17, 0, 0,
+                        10, 0, 0, 1, 10, 0, 0, 53,
+                        0xc0, 0x00, 0, 53, 0, (8 + payload.len()) as u8, 0, 0];
+    data.extend_from_slice(&payload);
+    checksum::fill_network(&mut data);
+    checksum::fill_transport(&mut data);
+
+    let answer = Response::nxdomain(&Dns::new(&payload).ok().unwrap()).build().ok().unwrap();
+    let mut buffer = PacketBuffer::new(data).ok().unwrap();
+    buffer.reflect().ok().unwrap();
+    buffer.set_payload(&answer).ok().unwrap();
+    let out = buffer.into_vec();
+    let packet = Packet::new(&out).ok().unwrap();
+    assert_eq!(packet.network.saddr().to_string(), "10.0.0.53");
+    assert_eq!(packet.transport.unwrap().dst_port(), Some(0xc000));
+    assert_eq!(packet.verify_network_checksum(), Some(true));
+    assert_eq!(packet.verify_transport_checksum(), Some(true));
+    assert!(packet.is_complete());
+    assert_eq!(Dns::new(packet.payload).ok().unwrap().rcode(), RCODE_NXDOMAIN as u16);
+}
@@ -0,0 +1,170 @@
+use message::Packet;
+use domain::{requested_name, to_ascii, to_unicode, DomainError, DomainMap, DomainSet, Pattern, PublicSuffixList};
+
+fn pattern(pattern: &str) -> Pattern {
+    pattern.parse().ok().unwrap()
+}
+
+/// The samples of RFC 3492, section 7.1, but for the last, which is not a host name
+const PUNYCODE: [(&'static str, &'static str); 18] = [
+    ("ليهمابتكلموشعربي؟", "egbpdaj6bu4bxfgehfvwxn"),
+    ("他们为什么不说中文", "ihqwcrb4cv8a8dqg056pqjye"),
+    ("他們爲什麽不說中文", "ihqwctvzc91f659drss3x8bo0yb"),
+    ("Pročprostěnemluvíčesky", "Proprostnemluvesky-uyb24dma41a"),
+    ("למההםפשוטלאמדבריםעברית", "4dbcagdahymbxekheh6e0a7fei0b"),
+    ("यहलोगहिन्दीक्योंनहींबोलसकतेहैं", "i1baa7eci9glrd9b2ae1bj0hfcgg6iyaf8o0a1dig0cd"),
+    ("なぜみんな日本語を話してくれないのか", "n8jok5ay5dzabd5bym9f0cm5685rrjetr6pdxa"),
+    ("세계의모든사람들이한국어를이해한다면얼마나좋을까", "989aomsvi5e83db1d2a355cv1e0vak1dwrv93d5xbh15a0dt30a5jpsd879ccm6fea98c"),
+    ("почемужеонинеговорятпорусски", "b1abfaaepdrnnbgefbaDotcwatmq2g4l"),
+    ("PorquénopuedensimplementehablarenEspañol", "PorqunopuedensimplementehablarenEspaol-fmd56a"),
+    ("TạisaohọkhôngthểchỉnóitiếngViệt", "TisaohkhngthchnitingVit-kjcr8268qyxafd2f1b9g"),
+    ("3年B組金八先生", "3B-ww4c5e180e575a65lsy2b"),
+    ("安室奈美恵-with-SUPER-MONKEYS", "-with-SUPER-MONKEYS-pc58ag80a8qai00g7n9n"),
+    ("Hello-Another-Way-それぞれの場所", "Hello-Another-Way--fc4qua05auwb3674vfr0b"),
+    ("ひとつ屋根の下2", "2-u9tlzr9756bt3uc0v"),
+    ("MajiでKoiする5秒前", "MajiKoi5-783gue6qz075azm5e"),
+    ("パフィーdeルンバ", "de-jg4avhby1noc0d"),
+    ("そのスピードで", "d9juau41awczczp"),
+];
+
+#[test]
+fn punycode() {
+    for &(unicode, punycode) in PUNYCODE.iter() {
+        // Case is kept when decoding, but names are lower cased before encoding
+        assert_eq!(to_unicode(&format!("xn--{}", punycode)), unicode);
+        let ascii = format!("xn--{}", punycode.to_lowercase());
+        match ascii.len() {
+            0..=63 => assert_eq!(to_ascii(unicode), Ok(ascii)),
+            _ => assert_eq!(to_ascii(unicode), Err(DomainError::TooLong))
+        }
+    }
+    assert_eq!(to_unicode("xn--r8jz45g.xn--zckzah.com"), "例え.テスト.com");
+    assert_eq!(to_unicode("xn--!!"), "xn--!!");
+}
+
+#[test]
+fn idna() {
+    assert_eq!(to_ascii("Example.COM."), Ok("example.com".to_string()));
+    assert_eq!(to_ascii("BÜCHER。example"), Ok("xn--bcher-kva.example".to_string()));
+    assert_eq!(to_ascii("münchen-ost"), Ok("xn--mnchen-ost-9db".to_string()));
+    assert_eq!(to_ascii(""), Err(DomainError::Empty));
+    assert_eq!(to_ascii("a..b"), Err(DomainError::Label));
+    assert_eq!(to_ascii("a b"), Err(DomainError::Label));
+    assert_eq!(to_ascii(&"a".repeat(64)), Err(DomainError::TooLong));
+
+    // The part of UTS 46 applied
+    assert_eq!(to_ascii("ＥＸＡＭＰＬＥ．ｃｏｍ"), Ok("example.com".to_string()));
+    assert_eq!(to_ascii("bü\u{ad}cher.example"), Ok("xn--bcher-kva.example".to_string()));
+    assert_eq!(to_ascii("例え\u{200b}.テスト"), Ok("xn--r8jz45g.xn--zckzah".to_string()));
+    // Names not in NFC, or with spaces, are rejected rather than encoded differently
+    assert_eq!(to_ascii("bu\u{308}cher.example"), Err(DomainError::Label));
+    assert_eq!(to_ascii("例え\u{3000}テスト"), Err(DomainError::Label));
+    assert_eq!(to_ascii("bücher\u{7f}"), Err(DomainError::Label));
+}
+
+#[test]
+fn patterns() {
+    assert_eq!("a.*.b".parse::<Pattern>(), Err(DomainError::Wildcard));
+    assert_eq!(pattern("*.Example.com").to_string(), "*.example.com");
+    assert_eq!(pattern(".example.com"), Pattern::Suffix("example.com".to_string()));
+    assert!(pattern("*.a.b").matches("x.a.b"));
+    assert!(!pattern("*.a.b").matches("a.b"));
+    assert!(!pattern("*.a.b").matches("xa.b"));
+    assert!(pattern(".a.b").matches("a.b"));
+    assert!(pattern(".a.b").matches("x.a.b"));
+}
+
+#[test]
+fn map() {
+    let mut map = DomainMap::new();
+    map.insert(pattern("example.com"), 1);
+    map.insert(pattern("*.example.com"), 2);
+    map.insert(pattern(".ads.example.com"), 3);
+    map.insert(pattern("x.ads.example.com"), 4);
+    map.insert(pattern(".org"), 5);
+    assert_eq!(map.insert(pattern(".org"), 6), Some(5));
+    assert_eq!(map.len(), 5);
+    assert_eq!(map.lookup("EXAMPLE.com."), Some((pattern("example.com"), &1)));
+    assert_eq!(map.lookup("www.example.com"), Some((pattern("*.example.com"), &2)));
+    assert_eq!(map.lookup("ads.example.com"), Some((pattern(".ads.example.com"), &3)));
+    assert_eq!(map.lookup("a.b.ads.example.com"), Some((pattern(".ads.example.com"), &3)));
+    assert_eq!(map.lookup("x.ads.example.com"), Some((pattern("x.ads.example.com"), &4)));
+    assert_eq!(map.lookup("wikipedia.org"), Some((pattern(".org"), &6)));
+    assert_eq!(map.lookup("org"), Some((pattern(".org"), &6)));
+    assert_eq!(map.lookup("example.net"), None);
+    assert_eq!(map.lookup("com"), None);
+    assert_eq!(map.lookup("notexample.com"), None);
+    assert_eq!(map.get(&pattern("*.example.com")), Some(&2));
+    assert_eq!(map.get(&pattern(".example.com")), None);
+    let names: Vec<String> = map.entries().iter().map(|e| e.0.to_string()).collect();
+    assert_eq!(names, vec!["example.com", "*.example.com", ".ads.example.com", "x.ads.example.com", ".org"]);
+
+    assert_eq!(map.remove(&pattern("*.example.com")), Some(2));
+    assert_eq!(map.lookup("www.example.com"), None);
+    for name in ["example.com", ".ads.example.com", "x.ads.example.com", ".org"].iter() {
+        assert!(map.remove(&pattern(name)).is_some());
+    }
+    assert!(map.is_empty());
+    assert!(map.entries().is_empty());
+}
+
+#[test]
+fn lists() {
+    let set = DomainSet::parse("# hosts\n127.0.0.1 localhost\n0.0.0.0 ads.example.com tracker.example.net # two\n\n*.doubleclick.net\n.bücher.de\n")
+        .ok().unwrap();
+    assert_eq!(set.len(), 5);
+    assert!(set.contains("ads.example.com"));
+    assert!(!set.contains("x.ads.example.com"));
+    assert!(set.contains("www.xn--bcher-kva.de"));
+    assert!(set.contains("Bücher.de"));
+    assert_eq!(set.lookup("a.doubleclick.net"), Some(pattern("*.doubleclick.net")));
+    assert_eq!(DomainSet::parse("ok.com\nbad name!\n").err().unwrap().to_string(), "line 2: Malformed label");
+}
+
+#[test]
+fn suffixes() {
+    let empty = PublicSuffixList::new();
+    assert_eq!(empty.registrable_domain("www.example.com"), Some("example.com".to_string()));
+    assert!(empty.is_public_suffix("com"));
+    assert_eq!(empty.registrable_domain("com"), None);
+
+    let list = PublicSuffixList::parse("// comment\ncom\nuk\nco.uk\n*.ck\n!www.ck\n// ===BEGIN PRIVATE DOMAINS===\ngithub.io\n公司.cn\n")
+        .ok().unwrap();
+    assert_eq!(list.public_suffix("www.example.co.uk"), Some("co.uk".to_string()));
+    assert_eq!(list.registrable_domain("www.example.co.uk"), Some("example.co.uk".to_string()));
+    assert_eq!(list.registrable_domain("co.uk"), None);
+    assert_eq!(list.registrable_domain("a.b.c.ck"), Some("b.c.ck".to_string()));
+    assert_eq!(list.registrable_domain("www.ck"), Some("www.ck".to_string()));
+    assert_eq!(list.registrable_domain("x.www.ck"), Some("www.ck".to_string()));
+    assert_eq!(list.registrable_domain("me.github.io"), Some("me.github.io".to_string()));
+    assert_eq!(list.registrable_domain("example.公司.cn"), Some("example.xn--55qx5d.cn".to_string()));
+    assert_eq!(list.registrable_domain("example.unknowntld"), Some("example.unknowntld".to_string()));
+    assert_eq!(list.check(&pattern("*.co.uk")), Err(DomainError::PublicSuffix));
+    assert_eq!(list.check(&pattern(".github.io")), Err(DomainError::PublicSuffix));
+    assert_eq!(list.check(&pattern(".example.co.uk")), Ok(()));
+    assert_eq!(list.check(&pattern("co.uk")), Ok(()));
+    assert!(PublicSuffixList::parse("a.*.b").is_err());
+}
+
+fn ip(protocol: u8, transport: &[u8]) -> Vec<u8> {
+    let len = 20 + transport.len();
+    let mut packet = vec![0x45, 0, (len >> 8) as u8, len as u8, 0, 0, 0x40, 0, 64, protocol, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2];
+    packet.extend_from_slice(transport);
+    packet
+}
+
+#[test]
+fn requested() {
+    let mut query = vec![0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 3, b'W', b'w', b'W', 7];
+    query.extend_from_slice(b"example");
+    query.extend_from_slice(&[3, b'c', b'o', b'm', 0, 0, 1, 0, 1]);
+    let mut udp = vec![0x30, 0x39, 0, 53, 0, (8 + query.len()) as u8, 0, 0];
+    udp.extend(query);
+    let packet = ip(17, &udp);
+    assert_eq!(requested_name(&Packet::new(&packet).ok().unwrap()), Some("www.example.com".to_string()));
+
+    let mut tcp = vec![0x30, 0x39, 0, 80, 0, 0, 0, 1, 0, 0, 0, 0, 0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0];
+    tcp.extend_from_slice("GET / HTTP/1.1\r\nHost: Bücher.example:8080\r\n\r\n".as_bytes());
+    let packet = ip(6, &tcp);
+    assert_eq!(requested_name(&Packet::new(&packet).ok().unwrap()), Some("xn--bcher-kva.example".to_string()));
+}
@@ -0,0 +1,195 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use message::{Message, Packet, TCP_ACK, TCP_FIN, TCP_RST, TCP_SYN};
+use queue::Verdict;
+use handle::Handle;
+use mock::Mock;
+use stream::Side;
+use flow::{Flow, FlowHandler, FlowKey, FlowTable, FlowTracker, Limits, TcpState};
+
+/// A TCP segment between 10.0.0.1 and port 80 of 10.0.0.2, sent by the server when `sport` is 80
+fn tcp(sport: u16, dport: u16, flags: u8) -> Vec<u8> {
+    let (saddr, daddr) = match sport {
+        80 => ([10, 0, 0, 2], [10, 0, 0, 1]),
+        _ => ([10, 0, 0, 1], [10, 0, 0, 2])
+    };
+    let mut packet = vec![0x45, 0, 0, 40, 0, 0, 0x40, 0, 64, 6, 0, 0];
+    packet.extend_from_slice(&saddr);
+    packet.extend_from_slice(&daddr);
+    packet.extend_from_slice(&[(sport >> 8) as u8, sport as u8, (dport >> 8) as u8, dport as u8,
+                               0, 0, 0, 1, 0, 0, 0, 1, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    packet
+}
+
+/// A UDP datagram between 10.0.0.1 and 10.0.0.3, sent by 10.0.0.3 when `reply`
+fn udp(sport: u16, dport: u16, reply: bool) -> Vec<u8> {
+    let (saddr, daddr) = match reply {
+        true => ([10, 0, 0, 3], [10, 0, 0, 1]),
+        false => ([10, 0, 0, 1], [10, 0, 0, 3])
+    };
+    let mut packet = vec![0x45, 0, 0, 28, 0, 0, 0x40, 0, 64, 17, 0, 0];
+    packet.extend_from_slice(&saddr);
+    packet.extend_from_slice(&daddr);
+    packet.extend_from_slice(&[(sport >> 8) as u8, sport as u8, (dport >> 8) as u8, dport as u8, 0, 8, 0, 0]);
+    packet
+}
+
+/// Track a packet, counting packets in the flow's state, which starts at seven
+fn track(table: &mut FlowTable<u32>, data: &[u8], now: Instant) -> (Side, Option<TcpState>, u32) {
+    let packet = Packet::new(data).ok().unwrap();
+    let (side, flow) = table.track(&packet, now, |_| 7).unwrap();
+    flow.data += 1;
+    (side, flow.tcp_state(), flow.data)
+}
+
+fn key(data: &[u8]) -> FlowKey {
+    FlowKey::of(&Packet::new(data).ok().unwrap()).unwrap()
+}
+
+#[test]
+fn tcp_states() {
+    let mut table = FlowTable::new();
+    let now = Instant::now();
+    assert_eq!(track(&mut table, &tcp(1000, 80, TCP_SYN), now), (Side::Client, Some(TcpState::SynSent), 8));
+    assert_eq!(track(&mut table, &tcp(80, 1000, TCP_SYN | TCP_ACK), now), (Side::Server, Some(TcpState::SynReceived), 9));
+    assert_eq!(track(&mut table, &tcp(1000, 80, TCP_ACK), now).1, Some(TcpState::Established));
+    // The server closes first
+    assert_eq!(track(&mut table, &tcp(80, 1000, TCP_FIN | TCP_ACK), now).1, Some(TcpState::FinWait));
+    assert_eq!(track(&mut table, &tcp(1000, 80, TCP_ACK), now).1, Some(TcpState::CloseWait));
+    assert_eq!(track(&mut table, &tcp(1000, 80, TCP_FIN | TCP_ACK), now).1, Some(TcpState::LastAck));
+    // Only the closer's acknowledgement finishes the connection
+    assert_eq!(track(&mut table, &tcp(1000, 80, TCP_ACK), now).1, Some(TcpState::LastAck));
+    assert_eq!(track(&mut table, &tcp(80, 1000, TCP_ACK), now).1, Some(TcpState::TimeWait));
+
+    assert_eq!(table.len(), 1);
+    {
+        let flow = table.get(&key(&tcp(1000, 80, 0))).unwrap();
+        assert_eq!(flow.client(), "10.0.0.1:1000".parse().unwrap());
+        assert_eq!(flow.server(), "10.0.0.2:80".parse().unwrap());
+        assert_eq!((flow.packets(Side::Client), flow.packets(Side::Server)), (5, 3));
+        assert_eq!((flow.bytes(Side::Client), flow.bytes(Side::Server)), (200, 120));
+        assert!(flow.is_replied());
+        assert_eq!(flow.expiry(), now + Duration::from_secs(120));
+    }
+
+    // A SYN after closing starts the connection over, ending the old one
+    assert_eq!(track(&mut table, &tcp(1000, 80, TCP_SYN), now), (Side::Client, Some(TcpState::SynSent), 8));
+    let ended = table.expire(now);
+    assert_eq!(ended.len(), 1);
+    assert_eq!(ended[0].data, 15);
+    assert_eq!(table.len(), 1);
+}
+
+#[test]
+fn tcp_midstream() {
+    let mut table = FlowTable::new();
+    let now = Instant::now();
+    // A SYN-ACK seen first makes its destination the client
+    assert_eq!(track(&mut table, &tcp(80, 2000, TCP_SYN | TCP_ACK), now), (Side::Server, Some(TcpState::SynReceived), 8));
+    assert_eq!(track(&mut table, &tcp(2000, 80, TCP_ACK), now), (Side::Client, Some(TcpState::Established), 9));
+    // As is a connection first seen established
+    assert_eq!(track(&mut table, &tcp(3000, 80, TCP_ACK), now), (Side::Client, Some(TcpState::Established), 8));
+    assert_eq!(table.get(&key(&tcp(3000, 80, 0))).unwrap().expiry(), now + Duration::from_secs(5 * 24 * 60 * 60));
+    // A RST closes either
+    assert_eq!(track(&mut table, &tcp(80, 3000, TCP_RST), now).1, Some(TcpState::Closed));
+    assert_eq!(track(&mut table, &tcp(4000, 80, TCP_RST), now).1, Some(TcpState::Closed));
+    assert_eq!(table.next_expiry(), Some(now + Duration::from_secs(10)));
+    assert_eq!(table.expire(now + Duration::from_secs(10)).len(), 2);
+    assert_eq!(table.len(), 1);
+}
+
+#[test]
+fn lru() {
+    let mut table = FlowTable::with_limits(Limits { max_flows: 2, ..Limits::default() });
+    let now = Instant::now();
+    track(&mut table, &udp(1, 53, false), now);
+    track(&mut table, &udp(2, 53, false), now);
+    // Seeing the first flow again leaves the second least recently seen
+    track(&mut table, &udp(1, 53, false), now);
+    track(&mut table, &udp(3, 53, false), now);
+    assert_eq!(table.len(), 2);
+    let ended = table.expire(now);
+    assert_eq!(ended.len(), 1);
+    assert_eq!(ended[0].client().port(), 2);
+    assert!(table.get(&key(&udp(1, 53, false))).is_some());
+    assert!(table.get(&key(&udp(3, 53, false))).is_some());
+
+    // Removing a flow makes room without evicting another
+    table.remove(&key(&udp(3, 53, false)));
+    track(&mut table, &udp(4, 53, false), now);
+    assert_eq!(table.len(), 2);
+    assert!(table.expire(now).is_empty());
+}
+
+#[test]
+fn expiry() {
+    let mut table = FlowTable::new();
+    let now = Instant::now();
+    track(&mut table, &udp(1, 53, false), now);
+    track(&mut table, &udp(2, 53, false), now + Duration::from_secs(10));
+    // A reply keeps the flow for longer, and either direction has the same key
+    assert_eq!(track(&mut table, &udp(53, 2, true), now + Duration::from_secs(10)).0, Side::Server);
+    assert_eq!(key(&udp(53, 2, true)), key(&udp(2, 53, false)));
+    assert!(table.get(&key(&udp(2, 53, false))).unwrap().is_replied());
+
+    assert_eq!(table.next_expiry(), Some(now + Duration::from_secs(30)));
+    assert!(table.expire(now + Duration::from_secs(29)).is_empty());
+    let ended = table.expire(now + Duration::from_secs(30));
+    assert_eq!(ended.len(), 1);
+    assert_eq!(ended[0].client().port(), 1);
+    assert_eq!(table.next_expiry(), Some(now + Duration::from_secs(130)));
+
+    // Each packet pushes the expiry back
+    track(&mut table, &udp(2, 53, false), now + Duration::from_secs(100));
+    assert!(table.expire(now + Duration::from_secs(130)).is_empty());
+    assert_eq!(table.expire(now + Duration::from_secs(220)).len(), 1);
+    assert!(table.is_empty());
+    assert_eq!(table.next_expiry(), None);
+}
+
+/// Drops the third packet of a flow onwards, recording the packet counts of flows that ended
+struct Counter {
+    ended: Rc<RefCell<Vec<u64>>>,
+}
+
+impl FlowHandler for Counter {
+    type State = u64;
+
+    fn start(&mut self, _: &FlowKey, _: &Message) -> u64 {
+        0
+    }
+
+    fn decide(&mut self, flow: &mut Flow<u64>, _: Side, _: &Message) -> Verdict {
+        flow.data += 1;
+        match flow.data > 2 {
+            true => Verdict::Drop,
+            false => Verdict::Accept
+        }
+    }
+
+    fn end(&mut self, flow: Flow<u64>) {
+        self.ended.borrow_mut().push(flow.data);
+    }
+}
+
+#[test]
+fn tracker() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let ended = Rc::new(RefCell::new(Vec::new()));
+    let counter = Counter { ended: ended.clone() };
+    let _queue = handle.queue(0, FlowTracker::with_limits(counter, Limits { max_flows: 1, ..Limits::default() })).ok().unwrap();
+
+    let ids: Vec<u32> = [udp(1, 53, false), udp(1, 53, false), udp(1, 53, false), udp(2, 53, false)].iter()
+        .map(|p| mock.inject(0, p))
+        .collect();
+    let other = mock.inject(0, &[0x45, 0, 0, 20]);
+    handle.start(4096);
+    let verdicts: Vec<Verdict> = ids.iter().map(|&id| mock.verdict(id).unwrap().verdict).collect();
+    assert_eq!(verdicts, vec![Verdict::Accept, Verdict::Accept, Verdict::Drop, Verdict::Accept]);
+    // Undecodable packets go to `other`, which accepts them
+    assert_eq!(mock.verdict(other).unwrap().verdict, Verdict::Accept);
+    // The first flow was evicted for the second
+    assert_eq!(*ended.borrow(), vec![3]);
+}
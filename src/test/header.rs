@@ -1,61 +1,85 @@
-use super::super::verdict::{Verdict, VerdictHandler};
-use super::super::message::{Message, IPHeader};
-use super::super::handle::{Handle, ProtocolFamily};
+use std::net::Ipv4Addr;
+use std::ptr::null;
+use std::slice;
+use queue::{Verdict, VerdictHandler, PacketHandler, QueueHandle};
+use message::{Message, Header, IPHeader};
+use handle::{Handle, ProtocolFamily};
+use error::Error;
+use mock::Mock;
+use super::udp_packet;
 
-struct Void;
 struct Callback;
 struct Decider;
 
-impl PacketHandler<Void> for Callback {
-    fn callback(&self, hq: *mut QueueHandle, message: Result<&Message, &Error>, _: &mut Void) -> i32 {
-        unsafe { Message.header.ip_header().ok().unwrap(); }
-        -1
+impl PacketHandler for Callback {
+    fn handle(&mut self, hq: &QueueHandle, message: Result<&Message, &Error>) -> i32 {
+        let message = message.ok().unwrap();
+        let header = unsafe { message.ip_header().ok().unwrap() };
+        assert_eq!(header.daddr(), Ipv4Addr::new(10, 0, 0, 2));
+        Verdict::set_verdict(hq, message.header.id(), Verdict::Accept, 0, null()).ok().unwrap()
     }
 }
 
-impl VerdictHandler<Void> for Decider {
-    fn decide(&self, message: &Message, _: &mut Void) -> Verdict {
-        unsafe { Message.header.ip_header().ok().unwrap(); }
+impl VerdictHandler for Decider {
+    fn decide(&mut self, message: &Message) -> Verdict {
+        let header = unsafe { message.ip_header().ok().unwrap() };
+        assert_eq!(header.saddr(), Ipv4Addr::new(10, 0, 0, 1));
         panic!();
-        Verdict::Accept
     }
 }
 
 #[test]
 fn bind() {
-    let void = Void;
-    let mut handle = Handle::new().ok().unwrap();
-    let _ = handle.queue_builder(void)
-        .copy_mode_sized_to_payload::<IPHeader>()
-        .decider_and_finalize(Decider)
-        .ok().unwrap();
-
-    let _ = handle.bind(ProtocolFamily::INET).ok().unwrap();
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut queue = handle.queue(0, Decider).ok().unwrap();
+    queue.set_mode_sized::<IPHeader>().ok().unwrap();
+
+    handle.bind(ProtocolFamily::INET).ok().unwrap();
 }
 
 #[test]
 #[should_panic]
 fn decide() {
-    let void = Void;
-    let mut handle = Handle::new().ok().unwrap();
-    let _ = handle.queue_builder(void)
-        .copy_mode_sized_to_payload::<IPHeader>()
-        .decider_and_finalize(Decider)
-        .ok().unwrap();
-
-    let _ = handle.bind(ProtocolFamily::INET).ok().unwrap();
-    handle.start_sized_to_payload::<IPHeader>();
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut queue = handle.queue(0, Decider).ok().unwrap();
+    queue.set_mode_sized::<IPHeader>().ok().unwrap();
+
+    handle.bind(ProtocolFamily::INET).ok().unwrap();
+    mock.inject(0, &udp_packet(64));
+    handle.start_sized::<IPHeader>();
 }
 
 #[test]
 fn callback() {
-    let void = Void;
-    let mut handle = Handle::new().ok().unwrap();
-    let _ = handle.queue_builder(void)
-        .copy_mode_sized_to_payload::<IPHeader>()
-        .callback_and_finalize(Callback)
-        .ok().unwrap();
-
-    let _ = handle.bind(ProtocolFamily::INET6).ok().unwrap();
-    handle.start_sized_to_payload::<IPHeader>();
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut queue = handle.queue(0, Callback).ok().unwrap();
+    queue.set_mode_sized::<IPHeader>().ok().unwrap();
+
+    handle.bind(ProtocolFamily::INET6).ok().unwrap();
+    let id = mock.inject(0, &udp_packet(64));
+    handle.start_sized::<IPHeader>();
+    assert_eq!(mock.verdict(id).unwrap().verdict, Verdict::Accept);
+}
+
+#[test]
+fn unaligned() {
+    let packet = udp_packet(64);
+    // Backed by words, so the bytes start aligned for `IPHeader`
+    let mut words = vec![0u32; packet.len() / 4 + 2];
+    let bytes = unsafe { slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, words.len() * 4) };
+    bytes[1..packet.len() + 1].copy_from_slice(&packet);
+    let header = Header { packet_id: 0, hw_protocol: 0, hook: 0 };
+
+    {
+        let message = Message::from_data(&header, &bytes[1..packet.len() + 1]);
+        assert!(unsafe { message.ip_header() }.is_err());
+        assert_eq!(message.decode().ok().unwrap().network.ttl(), 64);
+    }
+
+    bytes[..packet.len()].copy_from_slice(&packet);
+    let message = Message::from_data(&header, &bytes[..packet.len()]);
+    assert_eq!(unsafe { message.ip_header() }.ok().unwrap().daddr(), Ipv4Addr::new(10, 0, 0, 2));
 }
@@ -0,0 +1,87 @@
+use http::{HttpError, Request};
+
+#[test]
+fn origin_form() {
+    let data = b"GET /a/b?x=1 HTTP/1.1\r\nHost: Example.com:8080\r\nUser-Agent:  curl/8.0 \r\nAccept: */*\r\n\r\nbody";
+    let request = Request::parse(data).ok().unwrap();
+    assert_eq!(request.method, "GET");
+    assert_eq!(request.target, "/a/b?x=1");
+    assert_eq!(request.path(), "/a/b");
+    assert_eq!(request.query(), Some("x=1"));
+    assert_eq!(request.version, 1);
+    assert_eq!(request.host(), Some("Example.com"));
+    assert_eq!(request.user_agent(), Some("curl/8.0"));
+    assert_eq!(request.field("accept"), Some(&b"*/*"[..]));
+    assert_eq!(request.fields.len(), 3);
+    assert!(request.is_complete());
+    assert_eq!(request.header_len(), Some(data.len() - 4));
+}
+
+#[test]
+fn incomplete() {
+    let data = b"GET /a HTTP/1.0\nHost: example.com\nAccept: */*\n\n";
+    // Cut within the request line
+    for len in 0..16 {
+        assert_eq!(Request::parse(&data[..len]), Err(HttpError::Incomplete));
+    }
+    // Cut within the header section, leaving the fields that arrived whole
+    let partial = Request::parse(&data[..30]).ok().unwrap();
+    assert!(!partial.is_complete());
+    assert_eq!(partial.header_len(), None);
+    assert!(partial.fields.is_empty());
+    assert_eq!(partial.host(), None);
+    let partial = Request::parse(&data[..data.len() - 1]).ok().unwrap();
+    assert!(!partial.is_complete());
+    assert_eq!(partial.host(), Some("example.com"));
+    assert_eq!(Request::parse(data).ok().unwrap().header_len(), Some(data.len()));
+}
+
+#[test]
+fn not_http() {
+    assert_eq!(Request::parse(b"\x16\x03\x01\x02\x00"), Err(HttpError::NotHttp));
+    assert_eq!(Request::parse(b"GET / HTTP/2.0\r\n"), Err(HttpError::NotHttp));
+    assert_eq!(Request::parse(b"GET  / HTTP/1.1\r\n"), Err(HttpError::NotHttp));
+    assert_eq!(Request::parse(b"GET /\x7f HTTP/1.1\r\n"), Err(HttpError::NotHttp));
+    assert_eq!(Request::parse(b"SSH-2.0-OpenSSH_9.6\r\n"), Err(HttpError::NotHttp));
+    assert_eq!(Request::parse(b"GET / HTTP/1.0\nBad Name: x\n"), Err(HttpError::Malformed));
+    assert_eq!(Request::parse(b"GET / HTTP/1.0\nHost : x\n"), Err(HttpError::Malformed));
+    assert_eq!(Request::parse(b"GET / HTTP/1.0\n: x\n"), Err(HttpError::Malformed));
+}
+
+#[test]
+fn absolute_form() {
+    // The target's authority wins over the Host field
+    let request = Request::parse(b"GET http://user@Example.org:8080/p/q?r HTTP/1.1\r\nHost: other\r\n\r\n").ok().unwrap();
+    assert_eq!(request.host(), Some("Example.org"));
+    assert_eq!(request.path(), "/p/q");
+    assert_eq!(request.query(), Some("r"));
+
+    let request = Request::parse(b"GET http://example.org HTTP/1.1\r\n\r\n").ok().unwrap();
+    assert_eq!(request.host(), Some("example.org"));
+    assert_eq!(request.path(), "/");
+    let request = Request::parse(b"GET http://example.org?r HTTP/1.1\r\n\r\n").ok().unwrap();
+    assert_eq!(request.host(), Some("example.org"));
+    assert_eq!(request.path(), "/");
+    assert_eq!(request.query(), Some("r"));
+
+    let request = Request::parse(b"CONNECT example.org:443 HTTP/1.1\n\n").ok().unwrap();
+    assert_eq!(request.host(), Some("example.org"));
+    assert!(request.is_complete());
+}
+
+#[test]
+fn ipv6_host() {
+    let request = Request::parse(b"GET / HTTP/1.1\r\nHost: [2001:db8::1]:8080\r\n\r\n").ok().unwrap();
+    assert_eq!(request.host(), Some("[2001:db8::1]"));
+    let request = Request::parse(b"GET / HTTP/1.1\r\nHost: [2001:db8::1]\r\n\r\n").ok().unwrap();
+    assert_eq!(request.host(), Some("[2001:db8::1]"));
+    let request = Request::parse(b"GET http://[::1]:80/p HTTP/1.1\r\nHost: other\r\n\r\n").ok().unwrap();
+    assert_eq!(request.host(), Some("[::1]"));
+    assert_eq!(request.path(), "/p");
+    let request = Request::parse(b"CONNECT [::1]:443 HTTP/1.1\r\n\r\n").ok().unwrap();
+    assert_eq!(request.host(), Some("[::1]"));
+
+    // An empty host is no host
+    let request = Request::parse(b"GET / HTTP/1.1\r\nHost: :80\r\n\r\n").ok().unwrap();
+    assert_eq!(request.host(), None);
+}
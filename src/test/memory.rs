@@ -0,0 +1,140 @@
+use std::mem;
+use std::time::{Duration, Instant};
+use queue::{CopyMode, Deferred, Verdict, PacketHandler, QueueHandle};
+use message::{Message, Metadata};
+use handle::Handle;
+use error::Error;
+use mock::{Mock, Call};
+use super::udp_packet;
+
+#[test]
+fn rewrite() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut queue = handle.queue(0, |message: &Message| {
+        message.mangle().ok().unwrap().set_ttl(1).ok().unwrap();
+        Verdict::Accept
+    }).ok().unwrap();
+    queue.set_mode(CopyMode::Packet(0xffff)).ok().unwrap();
+
+    let id = mock.inject(0, &udp_packet(64));
+    assert!(handle.step());
+    assert!(!handle.step());
+    let decision = mock.verdict(id).unwrap();
+    assert_eq!(decision.verdict, Verdict::Accept);
+    assert_eq!(decision.payload.unwrap()[8], 1);
+}
+
+#[test]
+fn metadata() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let _queue = handle.queue(3, |message: &Message| {
+        match message.indev() {
+            Some(2) => Verdict::Queue(4),
+            _ => Verdict::Drop
+        }
+    }).ok().unwrap();
+
+    let inside = mock.inject_with(3, &udp_packet(64), Metadata { indev: Some(2), ..Metadata::default() });
+    let outside = mock.inject(3, &udp_packet(64));
+    handle.start(4096);
+    assert_eq!(mock.verdict(inside).unwrap().verdict, Verdict::Queue(4));
+    assert_eq!(mock.verdict(outside).unwrap().verdict, Verdict::Drop);
+}
+
+#[test]
+fn copy_mode() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut queue = handle.queue(0, |message: &Message| {
+        match message.data().ok().unwrap().len() {
+            20 => Verdict::Accept,
+            _ => Verdict::Drop
+        }
+    }).ok().unwrap();
+    queue.set_mode(CopyMode::Packet(20)).ok().unwrap();
+    assert_eq!(mock.mode(0), Some(CopyMode::Packet(20)));
+
+    let id = mock.inject(0, &udp_packet(64));
+    handle.start(4096);
+    assert_eq!(mock.verdict(id).unwrap().verdict, Verdict::Accept);
+}
+
+/// Holds every packet until `release` is set
+struct Holder {
+    held: Vec<Deferred>,
+    release: Option<Instant>,
+}
+
+impl PacketHandler for Holder {
+    fn handle(&mut self, hq: &QueueHandle, message: Result<&Message, &Error>) -> i32 {
+        self.held.push(Deferred::new(hq, message.ok().unwrap().header.id()));
+        0
+    }
+
+    fn poll(&mut self, _: &QueueHandle, now: Instant) -> Option<Instant> {
+        match self.release {
+            Some(at) if at <= now => {
+                for deferred in mem::replace(&mut self.held, Vec::new()) {
+                    deferred.set(Verdict::Accept).ok().unwrap();
+                }
+                None
+            },
+            release => release
+        }
+    }
+}
+
+#[test]
+fn max_length() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut queue = handle.queue(0, Holder { held: Vec::new(), release: None }).ok().unwrap();
+    queue.set_max_length(2).ok().unwrap();
+    assert_eq!(mock.max_length(0), Some(2));
+
+    for _ in 0..3 {
+        mock.inject(0, &udp_packet(64));
+    }
+    handle.start(4096);
+    assert_eq!(mock.dropped(), 1);
+    assert!(mock.verdicts(0).is_empty());
+}
+
+#[test]
+fn deferred() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let release = Instant::now() + Duration::from_millis(10);
+    let _queue = handle.queue(0, Holder { held: Vec::new(), release: Some(release) }).ok().unwrap();
+
+    let id = mock.inject(0, &udp_packet(64));
+    handle.start(4096);
+    while Instant::now() < release {
+        assert!(mock.verdict(id).is_none());
+        handle.step();
+    }
+    handle.step();
+    assert_eq!(mock.verdict(id).unwrap().verdict, Verdict::Accept);
+}
+
+#[test]
+fn queues() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let queue = handle.queue(0, |_: &Message| Verdict::Accept).ok().unwrap();
+    assert!(handle.queue(0, |_: &Message| Verdict::Drop).is_err());
+
+    let id = mock.inject(0, &udp_packet(64));
+    mock.inject(1, &udp_packet(64));
+    handle.start(4096);
+    assert_eq!(mock.dropped(), 1);
+
+    drop(queue);
+    mock.inject(0, &udp_packet(64));
+    handle.start(4096);
+    assert_eq!(mock.dropped(), 2);
+    assert_eq!(mock.verdict(id).unwrap().verdict, Verdict::Accept);
+    assert_eq!(mock.calls(), vec![Call::CreateQueue(0), Call::CreateQueue(0)]);
+}
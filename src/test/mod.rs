@@ -1,3 +1,42 @@
 mod simple;
 mod header;
 mod data;
+mod memory;
+mod buffer;
+mod decode;
+mod checksum;
+mod seqadj;
+mod reassembly;
+mod stream;
+mod crypto;
+mod dns;
+mod http;
+mod flow;
+mod cache;
+mod ratelimit;
+mod timer;
+mod netem;
+mod rules;
+mod rulefile;
+mod bpf;
+mod cidr;
+mod domain;
+mod capture;
+mod replay;
+mod tls;
+mod quic;
+
+/// A UDP packet from 10.0.0.1:1234 to 10.0.0.2:53 with the given TTL
+fn udp_packet(ttl: u8) -> Vec<u8> {
+    let mut packet = vec![0x45, 0, 0, 32, 0, 1, 0, 0, ttl, 17, 0, 0,
+                          10, 0, 0, 1, 10, 0, 0, 2,
+                          0x04, 0xd2, 0, 53, 0, 12, 0, 0];
+    packet.extend_from_slice(b"ping");
+    packet
+}
+
+/// Bytes from hex digits, ignoring whitespace
+fn unhex(hex: &str) -> Vec<u8> {
+    let digits: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    digits.chunks(2).map(|d| u8::from_str_radix(::std::str::from_utf8(d).unwrap(), 16).unwrap()).collect()
+}
@@ -0,0 +1,68 @@
+use std::thread;
+use std::time::Duration;
+use queue::Verdict;
+use handle::Handle;
+use mock::Mock;
+use netem::{Config, Distribution, Loss, Netem};
+
+fn udp() -> Vec<u8> {
+    vec![0x45, 0, 0, 28, 0, 0, 0x40, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 200, 0, 1, 0, 53, 0, 8, 0, 0]
+}
+
+/// Queue `count` packets and handle them
+fn send(mock: &Mock, handle: &mut Handle, count: usize) -> Vec<u32> {
+    let ids = (0..count).map(|_| mock.inject(0, &udp())).collect();
+    handle.start(4096);
+    ids
+}
+
+fn verdicts(mock: &Mock, ids: &[u32]) -> Vec<Option<Verdict>> {
+    ids.iter().map(|&id| mock.verdict(id).map(|d| d.verdict)).collect()
+}
+
+#[test]
+fn latency() {
+    let mut config = Config::default();
+    config.latency = Duration::from_millis(20);
+    config.jitter = Duration::from_millis(5);
+    config.distribution = Distribution::Uniform;
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let _queue = handle.queue(0, Netem::with_seed(config, 1)).ok().unwrap();
+    let ids = send(&mock, &mut handle, 200);
+    assert!(verdicts(&mock, &ids).iter().all(|v| v.is_none()));
+
+    thread::sleep(Duration::from_millis(30));
+    handle.step();
+    assert!(verdicts(&mock, &ids).iter().all(|&v| v == Some(Verdict::Accept)));
+}
+
+#[test]
+fn bursty_loss() {
+    let mut config = Config::default();
+    config.loss = Loss::GilbertElliott { p: 0.01, r: 0.2, loss_bad: 1.0, loss_good: 0.0 };
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let _queue = handle.queue(0, Netem::with_seed(config, 2)).ok().unwrap();
+    let ids = send(&mock, &mut handle, 5000);
+    let given = verdicts(&mock, &ids);
+    let lost = given.iter().filter(|&&v| v == Some(Verdict::Drop)).count();
+    // The bad state lasts 0.01 / 0.21 of the time, five packets at a time
+    assert!(lost > 120 && lost < 360, "{}", lost);
+    let bursts = given.windows(2).filter(|w| w[0] == Some(Verdict::Accept) && w[1] == Some(Verdict::Drop)).count();
+    assert!(bursts * 3 < lost, "{} bursts of {}", bursts, lost);
+}
+
+#[test]
+fn reorder() {
+    let mut config = Config::default();
+    config.latency = Duration::from_millis(10);
+    config.reorder = 0.5;
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let _queue = handle.queue(0, Netem::with_seed(config, 3)).ok().unwrap();
+    let ids = send(&mock, &mut handle, 100);
+    // Packets sent at once overtake those held
+    let sent = verdicts(&mock, &ids).iter().filter(|v| v.is_some()).count();
+    assert!(sent > 30 && sent < 70, "{}", sent);
+}
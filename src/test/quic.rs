@@ -0,0 +1,87 @@
+use std::time::Instant;
+use super::unhex;
+use util::hex;
+use quic::{client_hello, ClientHelloAssembler, Initial, Keys, QuicError, VERSION_1, VERSION_2};
+use tls::TlsError;
+
+/// The client Initial of RFC 9001, appendix A.2
+const RFC9001: &'static str = include_str!("fixtures/rfc9001_a2.hex");
+/// The same ClientHello sent over QUIC version 2, RFC 9369 appendix A.2
+const RFC9369: &'static str = include_str!("fixtures/rfc9369_a2.hex");
+/// That ClientHello again, its first 120 bytes in one client Initial and the rest in another
+const SPLIT: [&'static str; 2] = [include_str!("fixtures/split_initial_1.hex"),
+                                  include_str!("fixtures/split_initial_2.hex")];
+
+const DCID: [u8; 8] = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+#[test]
+fn keys() {
+    // RFC 9001, appendix A.1
+    let keys = Keys::client_initial(VERSION_1, &DCID);
+    assert_eq!(hex(&keys.key), "1f369613dd76d5467730efcbe3b1a22d");
+    assert_eq!(hex(&keys.iv), "fa044b2f42a3fd3b46fb255c");
+    assert_eq!(hex(&keys.hp), "9f50449e04a0e810283a1e9933adedd2");
+
+    // RFC 9369, appendix A.1
+    let keys = Keys::client_initial(VERSION_2, &DCID);
+    assert_eq!(hex(&keys.key), "8b1a0bc121284290a29e0971b5cd045d");
+    assert_eq!(hex(&keys.iv), "91f73e2351d8fa91660e909f");
+    assert_eq!(hex(&keys.hp), "45b95e15235d6f45a6b19cbcb0294ba9");
+}
+
+#[test]
+fn sample_initial() {
+    for &(fixture, version) in [(RFC9001, VERSION_1), (RFC9369, VERSION_2)].iter() {
+        let datagram = unhex(fixture);
+        let initials = Initial::parse_datagram(&datagram).ok().unwrap();
+        assert_eq!(initials.len(), 1);
+        let initial = &initials[0];
+        assert_eq!(initial.version, version);
+        assert_eq!(initial.dcid, DCID.to_vec());
+        assert!(initial.scid.is_empty());
+        assert!(initial.token.is_empty());
+        assert_eq!(initial.packet_number, 2);
+        assert_eq!(initial.crypto.len(), 1);
+        assert_eq!(initial.crypto[0].0, 0);
+        assert_eq!(initial.crypto[0].1.len(), 241);
+
+        let hello = client_hello(&datagram).ok().unwrap();
+        assert_eq!(hello.server_name(), Some("example.com"));
+        assert_eq!(hello.alpn(), vec![&b"alpn"[..]]);
+        assert_eq!(hello.max_version(), 0x0304);
+    }
+}
+
+#[test]
+fn corrupted() {
+    let mut datagram = unhex(RFC9001);
+    datagram[100] ^= 1;
+    assert_eq!(client_hello(&datagram), Err(QuicError::Decrypt));
+    assert_eq!(client_hello(&datagram[..600]), Err(QuicError::Truncated));
+
+    let mut datagram = unhex(RFC9001);
+    datagram[1..5].copy_from_slice(&[0xff, 0, 0, 0x1d]);
+    assert_eq!(client_hello(&datagram), Err(QuicError::Version(0xff00_001d)));
+    assert_eq!(client_hello(b"\x40short header"), Err(QuicError::NotInitial));
+}
+
+#[test]
+fn split() {
+    let (first, second) = (unhex(SPLIT[0]), unhex(SPLIT[1]));
+    assert_eq!(client_hello(&first), Err(QuicError::Tls(TlsError::Incomplete)));
+
+    // Either order completes the ClientHello
+    let now = Instant::now();
+    let mut assembler = ClientHelloAssembler::new();
+    assert_eq!(assembler.add(&second, now), Err(QuicError::Tls(TlsError::Incomplete)));
+    assert_eq!(assembler.len(), 1);
+    let hello = assembler.add(&first, now).ok().unwrap();
+    assert_eq!(hello.server_name(), Some("example.com"));
+    assert!(assembler.is_empty());
+
+    // As do the two Initials coalesced into one datagram
+    let mut coalesced = first.clone();
+    coalesced.extend_from_slice(&second);
+    assert_eq!(Initial::parse_datagram(&coalesced).ok().unwrap().len(), 2);
+    assert_eq!(client_hello(&coalesced).ok().unwrap(), hello);
+}
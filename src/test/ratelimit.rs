@@ -0,0 +1,114 @@
+use std::thread;
+use std::time::Duration;
+use message::Message;
+use queue::Verdict;
+use handle::Handle;
+use mock::Mock;
+use ratelimit::{Action, ByDestinationPort, ByFlow, BySourceIp, BySourceSubnet, Measure, Rate, RateLimiter};
+
+/// A UDP datagram from 10.0.0.`source` to port 53 of 10.0.0.200
+fn udp(source: u8) -> Vec<u8> {
+    vec![0x45, 0, 0, 28, 0, 0, 0x40, 0, 64, 17, 0, 0, 10, 0, 0, source, 10, 0, 0, 200,
+         0, 1, 0, 53, 0, 8, 0, 0]
+}
+
+fn accept(_: &Message) -> Verdict {
+    Verdict::Accept
+}
+
+/// Queue each datagram from the given sources and the verdicts given straight away
+fn verdicts(mock: &Mock, handle: &mut Handle, sources: &[u8]) -> Vec<Option<Verdict>> {
+    let ids: Vec<u32> = sources.iter().map(|&source| mock.inject(0, &udp(source))).collect();
+    handle.start(4096);
+    ids.iter().map(|&id| mock.verdict(id).map(|d| d.verdict)).collect()
+}
+
+#[test]
+fn token_bucket() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut limiter = RateLimiter::new(accept, Action::Drop);
+    limiter.add_limit(BySourceSubnet { v4: 24, v6: 64 }, Measure::Packets, Rate::TokenBucket { per_second: 1, burst: 3 });
+    limiter.add_limit(BySourceIp, Measure::Packets, Rate::TokenBucket { per_second: 1, burst: 2 });
+    let _queue = handle.queue(0, limiter).ok().unwrap();
+
+    // Each address gets two packets, and their subnet three, the packets dropped not counting
+    let (a, d) = (Some(Verdict::Accept), Some(Verdict::Drop));
+    assert_eq!(verdicts(&mock, &mut handle, &[1, 1, 1, 2, 2]), vec![a, a, d, a, d]);
+}
+
+#[test]
+fn sliding_window() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut limiter = RateLimiter::new(accept, Action::Mark(7));
+    limiter.add_limit(ByDestinationPort, Measure::Bytes, Rate::SlidingWindow { limit: 60, window: Duration::from_secs(10) });
+    let _queue = handle.queue(0, limiter).ok().unwrap();
+
+    // Two datagrams of 28 bytes fit, the third is accepted marked
+    let ids: Vec<u32> = (0..3).map(|_| mock.inject(0, &udp(1))).collect();
+    handle.start(4096);
+    let marks: Vec<Option<u32>> = ids.iter().map(|&id| mock.verdict(id).unwrap().mark).collect();
+    assert_eq!(marks, vec![None, None, Some(7)]);
+    assert!(ids.iter().all(|&id| mock.verdict(id).unwrap().verdict == Verdict::Accept));
+}
+
+#[test]
+fn delay() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut limiter = RateLimiter::new(accept, Action::Delay(Duration::from_millis(150)));
+    limiter.add_limit(ByFlow, Measure::Packets, Rate::TokenBucket { per_second: 10, burst: 1 });
+    let _queue = handle.queue(0, limiter).ok().unwrap();
+
+    // The second packet waits a tenth of a second, the third would wait too long
+    let (a, d) = (Some(Verdict::Accept), Some(Verdict::Drop));
+    let ids: Vec<u32> = (0..3).map(|_| mock.inject(0, &udp(1))).collect();
+    handle.start(4096);
+    let given: Vec<Option<Verdict>> = ids.iter().map(|&id| mock.verdict(id).map(|d| d.verdict)).collect();
+    assert_eq!(given, vec![a, None, d]);
+    thread::sleep(Duration::from_millis(110));
+    handle.step();
+    assert_eq!(mock.verdict(ids[1]).map(|d| d.verdict), a);
+}
+
+#[test]
+fn delay_marked() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut limiter = RateLimiter::new(|message: &Message| {
+        message.set_mark(5);
+        message.mangle().ok().unwrap().set_ttl(9).ok().unwrap();
+        Verdict::Accept
+    }, Action::Delay(Duration::from_millis(150)));
+    limiter.add_limit(ByFlow, Measure::Packets, Rate::TokenBucket { per_second: 10, burst: 1 });
+    let _queue = handle.queue(0, limiter).ok().unwrap();
+
+    // The delayed packet keeps the mark and rewrite the handler gave it
+    let ids: Vec<u32> = (0..2).map(|_| mock.inject(0, &udp(1))).collect();
+    handle.start(4096);
+    assert!(mock.verdict(ids[1]).is_none());
+    thread::sleep(Duration::from_millis(110));
+    handle.step();
+    for &id in ids.iter() {
+        let decision = mock.verdict(id).unwrap();
+        assert_eq!(decision.verdict, Verdict::Accept);
+        assert_eq!(decision.mark, Some(5));
+        assert_eq!(decision.payload.unwrap()[8], 9);
+    }
+}
+
+#[test]
+fn least_recently_used() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut limiter = RateLimiter::new(accept, Action::Drop);
+    limiter.set_max_keys(2);
+    limiter.add_limit(BySourceIp, Measure::Packets, Rate::TokenBucket { per_second: 1, burst: 2 });
+    let _queue = handle.queue(0, limiter).ok().unwrap();
+
+    // The third address makes room by forgetting the second, not the busy first,
+    // whose limit still holds, and so on
+    let (a, d) = (Some(Verdict::Accept), Some(Verdict::Drop));
+    assert_eq!(verdicts(&mock, &mut handle, &[1, 2, 1, 3, 1, 2, 1]), vec![a, a, a, a, d, a, d]);
+}
@@ -0,0 +1,187 @@
+use std::time::{Duration, Instant};
+use message::{checksum, Message, Packet};
+use queue::Verdict;
+use handle::Handle;
+use mock::Mock;
+use reassembly::{Defragmenter, Limits, Reassembler, Reassembly};
+
+/// An IPv4 fragment of a UDP datagram from 10.0.0.1 to 10.0.0.2
+fn ipv4(id: u16, offset: u16, more: bool, data: &[u8]) -> Vec<u8> {
+    let flags = offset / 8 | if more { 0x2000 } else { 0 };
+    let len = 20 + data.len();
+    let mut packet = vec![0x45, 0, (len >> 8) as u8, len as u8, (id >> 8) as u8, id as u8,
+                          (flags >> 8) as u8, flags as u8, 64, 17, 0, 0,
+                          10, 0, 0, 1, 10, 0, 0, 2];
+    packet.extend_from_slice(data);
+    checksum::fill_network(&mut packet);
+    packet
+}
+
+/// An IPv6 fragment of a UDP datagram from fe80::1 to fe80::2
+fn ipv6(id: u32, offset: u16, more: bool, data: &[u8]) -> Vec<u8> {
+    let len = 8 + data.len();
+    let mut packet = vec![0x60, 0, 0, 0, (len >> 8) as u8, len as u8, 44, 64];
+    packet.extend_from_slice(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    packet.extend_from_slice(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+    let field = offset | if more { 1 } else { 0 };
+    packet.extend_from_slice(&[17, 0, (field >> 8) as u8, field as u8,
+                               (id >> 24) as u8, (id >> 16) as u8, (id >> 8) as u8, id as u8]);
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// A UDP datagram to port 53 of `len` bytes
+fn udp(len: usize) -> Vec<u8> {
+    let mut datagram = vec![0x12, 0x34, 0, 53, (len >> 8) as u8, len as u8, 0, 0];
+    datagram.extend((8..len).map(|i| i as u8));
+    datagram
+}
+
+fn insert(defragmenter: &mut Defragmenter<u32>, packet: &[u8], held: u32, now: Instant) -> Reassembly<u32> {
+    defragmenter.insert(&Packet::new(packet).ok().unwrap(), held, now)
+}
+
+fn assert_pending(reassembly: Reassembly<u32>) {
+    match reassembly {
+        Reassembly::Pending => (),
+        Reassembly::Complete { .. } => panic!("complete"),
+        Reassembly::Discarded(held) => panic!("discarded {:?}", held),
+    }
+}
+
+#[test]
+fn ipv4_out_of_order() {
+    let datagram = udp(40);
+    let now = Instant::now();
+    let mut defragmenter = Defragmenter::new(Limits::default());
+    assert_pending(insert(&mut defragmenter, &ipv4(7, 32, false, &datagram[32..]), 3, now));
+    assert_pending(insert(&mut defragmenter, &ipv4(7, 0, true, &datagram[..16]), 1, now));
+    assert_eq!(defragmenter.bytes(), 24);
+    match insert(&mut defragmenter, &ipv4(7, 16, true, &datagram[16..32]), 2, now) {
+        Reassembly::Complete { packet, held } => {
+            assert_eq!(held, vec![3, 1, 2]);
+            let decoded = Packet::new(&packet).ok().unwrap();
+            assert!(decoded.fragment.is_none());
+            assert_eq!(decoded.datagram_len(), 60);
+            assert_eq!(decoded.verify_network_checksum(), Some(true));
+            assert_eq!(&packet[20..], &datagram[..]);
+        },
+        _ => panic!()
+    }
+    assert!(defragmenter.is_empty());
+    assert_eq!(defragmenter.bytes(), 0);
+}
+
+#[test]
+fn ipv6_complete() {
+    let datagram = udp(24);
+    let now = Instant::now();
+    let mut defragmenter = Defragmenter::new(Limits::default());
+    assert_pending(insert(&mut defragmenter, &ipv6(9, 0, true, &datagram[..8]), 1, now));
+    match insert(&mut defragmenter, &ipv6(9, 8, false, &datagram[8..]), 2, now) {
+        Reassembly::Complete { packet, .. } => {
+            // The fragment header is gone
+            assert_eq!(packet.len(), 40 + 24);
+            assert_eq!(packet[6], 17);
+            let decoded = Packet::new(&packet).ok().unwrap();
+            assert!(decoded.fragment.is_none());
+            assert_eq!(decoded.transport.unwrap().dst_port(), Some(53));
+        },
+        _ => panic!()
+    }
+}
+
+#[test]
+fn overlap() {
+    let datagram = udp(40);
+    let now = Instant::now();
+    let mut defragmenter = Defragmenter::new(Limits::default());
+    assert_pending(insert(&mut defragmenter, &ipv4(1, 0, true, &datagram[..16]), 1, now));
+    match insert(&mut defragmenter, &ipv4(1, 8, true, &datagram[8..24]), 2, now) {
+        Reassembly::Discarded(held) => assert_eq!(held, vec![1, 2]),
+        _ => panic!()
+    }
+    assert!(defragmenter.is_empty());
+
+    // An exact duplicate is a retransmission
+    assert_pending(insert(&mut defragmenter, &ipv4(2, 0, true, &datagram[..16]), 3, now));
+    assert_pending(insert(&mut defragmenter, &ipv4(2, 0, true, &datagram[..16]), 4, now));
+    assert_eq!(defragmenter.bytes(), 16);
+
+    // As is a last fragment contradicting the length already known
+    assert_pending(insert(&mut defragmenter, &ipv4(3, 32, false, &datagram[32..]), 5, now));
+    match insert(&mut defragmenter, &ipv4(3, 16, false, &datagram[16..32]), 6, now) {
+        Reassembly::Discarded(held) => assert_eq!(held, vec![5, 6]),
+        _ => panic!()
+    }
+    // And a fragment that is not a multiple of eight bytes, but is not the last
+    match insert(&mut defragmenter, &ipv4(4, 0, true, &datagram[..12]), 7, now) {
+        Reassembly::Discarded(held) => assert_eq!(held, vec![7]),
+        _ => panic!()
+    }
+}
+
+#[test]
+fn expire() {
+    let datagram = udp(40);
+    let now = Instant::now();
+    let mut defragmenter = Defragmenter::new(Limits::default());
+    assert_pending(insert(&mut defragmenter, &ipv4(1, 0, true, &datagram[..16]), 1, now));
+    assert_pending(insert(&mut defragmenter, &ipv4(1, 16, true, &datagram[16..32]), 2, now + Duration::from_secs(5)));
+    assert_eq!(defragmenter.next_expiry(), Some(now + Duration::from_secs(30)));
+    assert!(defragmenter.expire(now + Duration::from_secs(29)).is_empty());
+    assert_eq!(defragmenter.expire(now + Duration::from_secs(30)), vec![1, 2]);
+    assert!(defragmenter.is_empty());
+    assert_eq!(defragmenter.next_expiry(), None);
+}
+
+#[test]
+fn limits() {
+    let datagram = udp(40);
+    let now = Instant::now();
+    let later = now + Duration::from_secs(1);
+
+    let mut defragmenter = Defragmenter::new(Limits { max_datagrams: 1, ..Limits::default() });
+    assert_pending(insert(&mut defragmenter, &ipv4(1, 0, true, &datagram[..16]), 1, now));
+    assert_pending(insert(&mut defragmenter, &ipv4(2, 0, true, &datagram[..16]), 2, later));
+    assert_eq!(defragmenter.len(), 1);
+    assert_eq!(defragmenter.expire(later), vec![1]);
+
+    // The oldest datagrams are evicted to stay within the bytes held
+    let mut defragmenter = Defragmenter::new(Limits { max_bytes: 24, ..Limits::default() });
+    assert_pending(insert(&mut defragmenter, &ipv4(1, 0, true, &datagram[..16]), 1, now));
+    assert_pending(insert(&mut defragmenter, &ipv4(2, 0, true, &datagram[..16]), 2, later));
+    assert_eq!(defragmenter.bytes(), 16);
+    assert_eq!(defragmenter.expire(later), vec![1]);
+}
+
+#[test]
+fn reassembler() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let _queue = handle.queue(0, Reassembler::new(|message: &Message| {
+        match message.decode().ok().unwrap().payload.len() {
+            32 => {
+                message.set_mark(3);
+                Verdict::Accept
+            },
+            _ => Verdict::Drop
+        }
+    })).ok().unwrap();
+
+    let datagram = udp(40);
+    let first = mock.inject(0, &ipv4(5, 0, true, &datagram[..16]));
+    handle.start(4096);
+    assert!(mock.verdict(first).is_none());
+    let last = mock.inject(0, &ipv4(5, 16, false, &datagram[16..]));
+    let whole = mock.inject(0, &ipv4(6, 0, false, &udp(12)));
+    handle.start(4096);
+    assert_eq!(mock.verdict(first).unwrap().verdict, Verdict::Accept);
+    assert_eq!(mock.verdict(last).unwrap().verdict, Verdict::Accept);
+    assert_eq!(mock.verdict(whole).unwrap().verdict, Verdict::Drop);
+
+    // The mark set on the reassembled datagram is set on every fragment
+    assert_eq!(mock.verdict(first).unwrap().mark, Some(3));
+    assert_eq!(mock.verdict(last).unwrap().mark, Some(3));
+    assert_eq!(mock.verdict(whole).unwrap().mark, None);
+}
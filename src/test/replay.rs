@@ -0,0 +1,110 @@
+use std::time::{Duration, UNIX_EPOCH};
+use replay::{ReplayError, Trace};
+use super::{udp_packet, unhex};
+
+/// A pcapng section with one raw IP interface of `if_tsresol` 0, and one packet timestamped
+/// 0xffffffff_ffffffff seconds after the epoch
+const TIMESTAMP_OVERFLOW: &'static str = include_str!("fixtures/timestamp_overflow.hex");
+
+/// Offset of the `if_tsresol` value in the fixture
+const RESOLUTION: usize = 48;
+
+fn is_format(result: Result<Trace, ReplayError>) -> bool {
+    match result {
+        Err(ReplayError::Format) => true,
+        _ => false
+    }
+}
+
+#[test]
+fn timestamp_overflow() {
+    let mut data = unhex(TIMESTAMP_OVERFLOW);
+    assert!(is_format(Trace::parse(&data)));
+
+    // The same count of nanoseconds can be represented
+    data[RESOLUTION] = 9;
+    let trace = Trace::parse(&data).ok().unwrap();
+    assert_eq!(trace.packets.len(), 1);
+    let nanos = 0xffffffff_ffffffffu64;
+    let expected = UNIX_EPOCH + Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32);
+    assert_eq!(trace.packets[0].metadata.timestamp, Some(expected));
+}
+
+#[test]
+fn truncated() {
+    let mut data = unhex(TIMESTAMP_OVERFLOW);
+    data[RESOLUTION] = 9;
+    for end in 0..data.len() {
+        match end {
+            // Cut between blocks
+            28 | 60 => assert_eq!(Trace::parse(&data[..end]).ok().unwrap().packets.len(), 0),
+            _ => assert!(is_format(Trace::parse(&data[..end])), "cut at {}", end)
+        }
+    }
+    assert!(is_format(Trace::parse(b"nope")));
+}
+
+/// A pcapng block of `kind`, its body padded to 32 bits
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let len = 12 + (body.len() + 3) / 4 * 4;
+    let mut block = Vec::new();
+    block.extend_from_slice(&u32le(kind));
+    block.extend_from_slice(&u32le(len as u32));
+    block.extend_from_slice(body);
+    block.resize(len - 4, 0);
+    block.extend_from_slice(&u32le(len as u32));
+    block
+}
+
+fn u32le(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+/// An option of `code`, its value padded to 32 bits
+fn option(code: u16, value: &[u8]) -> Vec<u8> {
+    let mut option = vec![code as u8, (code >> 8) as u8, value.len() as u8, (value.len() >> 8) as u8];
+    option.extend_from_slice(value);
+    option.resize(4 + (value.len() + 3) / 4 * 4, 0);
+    option
+}
+
+/// A section of raw IP interfaces named `names`, and a packet received on each in turn,
+/// then one sent on the first
+fn named(names: &[&str]) -> Vec<u8> {
+    let mut data = block(0x0a0d0d0a, &[0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    for name in names.iter() {
+        let mut body = vec![101, 0, 0, 0, 0xff, 0xff, 0, 0];
+        body.extend(option(2, name.as_bytes()));
+        body.extend(option(0, &[]));
+        data.extend(block(1, &body));
+    }
+    let packet = udp_packet(64);
+    let sent = (0..names.len()).map(|id| (id as u32, 1)).chain(Some((0, 2)));
+    for (id, flags) in sent {
+        let mut body = Vec::new();
+        for &value in [id, 0, 0, packet.len() as u32, packet.len() as u32].iter() {
+            body.extend_from_slice(&u32le(value));
+        }
+        body.extend_from_slice(&packet);
+        body.resize(20 + (packet.len() + 3) / 4 * 4, 0);
+        body.extend(option(2, &u32le(flags)));
+        body.extend(option(0, &[]));
+        data.extend(block(6, &body));
+    }
+    data
+}
+
+#[test]
+fn interfaces() {
+    // Numbered by the capture alone, whatever the host calls `lo`, keeping the index of `if2`
+    let mut trace = Trace::parse(&named(&["wan0", "if2", "lo"])).ok().unwrap();
+    let indices: Vec<(Option<u32>, Option<u32>)> = trace.packets.iter().map(|p| (p.metadata.indev, p.metadata.outdev)).collect();
+    assert_eq!(indices, vec![(Some(1), None), (Some(2), None), (Some(3), None), (None, Some(1))]);
+    assert_eq!(trace.packets[2].indev, Some("lo".to_string()));
+    assert_eq!(trace.interfaces.get("if2"), Some(&2));
+
+    trace.set_interface("wan0", 7);
+    assert_eq!(trace.packets[0].metadata.indev, Some(7));
+    assert_eq!(trace.packets[3].metadata.outdev, Some(7));
+    assert_eq!(trace.packets[2].metadata.indev, Some(3));
+}
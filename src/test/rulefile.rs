@@ -0,0 +1,110 @@
+use std::env;
+use std::fs;
+use std::process;
+use libc::{c_int, SIGHUP};
+use message::{TCP_ACK, TCP_SYN};
+use queue::Verdict;
+use handle::Handle;
+use mock::Mock;
+use rules::{Action, Match};
+use rules::file::{parse, RuleFile};
+
+extern "C" {
+    fn raise(signum: c_int) -> c_int;
+}
+
+const TEXT: &'static str = r#"
+# The verdict on packets matching no rule
+default = "drop"   # trailing comments too
+default_mark = 0x1
+
+[[rule]]
+protocol = "udp"
+destination_port = [53,
+   "1000-1_100", # in arrays as well
+]
+verdict = "accept"
+set_mark = 0x10
+
+[[ rule ]]
+priority = -3
+source = ["10.0.0.0/8", "fd00::/8"]
+not_destination = "192.168.0.0/16"
+tcp_flags = "syn,!ack"
+mark = "0x10/0xf0"
+verdict = "queue 4"
+"#;
+
+#[test]
+fn rules() {
+    let rules = parse(TEXT).ok().unwrap();
+    assert_eq!(rules.default_action(), Action::marked(Verdict::Drop, 1));
+    assert_eq!(rules.rules().len(), 2);
+    assert_eq!(rules.rules()[0].matches, vec![Match::Protocol(17), Match::DestinationPort(vec![(53, 53), (1000, 1100)])]);
+    assert_eq!(rules.rules()[0].action, Action::marked(Verdict::Accept, 0x10));
+    let rule = &rules.rules()[1];
+    assert_eq!(rule.priority, -3);
+    assert_eq!(rule.action, Action::new(Verdict::Queue(4)));
+    assert_eq!(rule.matches[1], Match::Not(Box::new(Match::Destination(vec!["192.168.0.0/16".parse().ok().unwrap()]))));
+    assert_eq!(rule.matches[2], Match::TcpFlags { flags: TCP_SYN, mask: TCP_SYN | TCP_ACK });
+    assert_eq!(rule.matches[3], Match::Mark { value: 0x10, mask: 0xf0 });
+}
+
+fn error(text: &str) -> String {
+    parse(text).err().unwrap().to_string()
+}
+
+#[test]
+fn syntax_errors() {
+    assert_eq!(error("[rules]"), "line 1: Only [[rule]] tables are supported");
+    assert_eq!(error("\n[[rules]]"), "line 2: Unknown table [[rules]]");
+    assert_eq!(error("[[rule]\nverdict = \"drop\""), "line 1: Expected ]]");
+    assert_eq!(error("[[rule]]\nverdict \"drop\""), "line 2: Expected =");
+    assert_eq!(error("[[rule]]\nverdict = \"drop\nx"), "line 2: Unterminated string");
+    assert_eq!(error("[[rule]]\nverdict = \"drop\" x"), "line 2: Expected the end of the line");
+    // Values spanning lines are reported where they go wrong
+    assert_eq!(error("[[rule]]\nsource = [\n\"1.1.1.1\",\n\n x"), "line 5: Malformed value x");
+}
+
+#[test]
+fn rule_errors() {
+    assert_eq!(error("default = \"nope\""), "line 1: Unknown verdict nope");
+    assert_eq!(error("\n\n[[rule]]\nverdict = \"drop\"\nport = 3\n"), "line 5: Unknown key port");
+    assert_eq!(error("[[rule]]\nsource_port = 70000\nverdict = \"drop\""), "line 2: 70000 is out of range for source_port");
+    assert_eq!(error("[[rule]]\n\nprotocol = 6"), "line 1: Rule has no verdict");
+    assert_eq!(error("[[rule]]\nverdict = \"drop\"\nverdict = \"drop\""), "line 3: Duplicate key verdict");
+    assert_eq!(error("[[rule]]\nverdict = \"drop\"\nin_interface = \"nosuch9\""), "line 3: Unknown interface nosuch9");
+    assert_eq!(error("[[rule]]\nverdict = \"drop\"\nsource = [\"1.2.3.4/40\"]"), "line 3: Malformed prefix length in 1.2.3.4/40");
+    assert_eq!(error("[[rule]]\nverdict = \"drop\"\ndestination_port = \"9-3\""), "line 3: Malformed port range 9-3");
+    assert!(error("[[rule]]\nnot_filter = \"udp\"\nverdict = \"drop\"\n[[rule]]\nfilter = \"tcp[\"\nverdict = \"drop\"")
+            .starts_with("line 5: Malformed filter expression"));
+}
+
+fn udp() -> Vec<u8> {
+    vec![0x45, 0, 0, 28, 0, 0, 0x40, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 3, 0, 9, 0, 53, 0, 8, 0, 0]
+}
+
+#[test]
+fn reload() {
+    let path = env::temp_dir().join(format!("rules-{}.toml", process::id()));
+    fs::write(&path, "[[rule]]\ndestination_port = 53\nverdict = \"drop\"\n").ok().unwrap();
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let _queue = handle.queue(0, RuleFile::open(&path).ok().unwrap()).ok().unwrap();
+    let mut verdict = || {
+        let id = mock.inject(0, &udp());
+        handle.start(4096);
+        mock.verdict(id).map(|d| d.verdict)
+    };
+    assert_eq!(verdict(), Some(Verdict::Drop));
+
+    fs::write(&path, "[[rule]]\ndestination_port = 53\nverdict = \"accept\"\n").ok().unwrap();
+    unsafe { raise(SIGHUP); }
+    assert_eq!(verdict(), Some(Verdict::Accept));
+
+    // An invalid file leaves the rules as they were
+    fs::write(&path, "[[rule]]\nbad").ok().unwrap();
+    unsafe { raise(SIGHUP); }
+    assert_eq!(verdict(), Some(Verdict::Accept));
+    fs::remove_file(&path).ok().unwrap();
+}
@@ -0,0 +1,211 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use cidr::Cidr;
+use message::{Header, Message, Packet, Transport, TCP_ACK, TCP_RST, TCP_SYN};
+use queue::{Verdict, VerdictHandler};
+use rules::{Action, Engine, Match, Rule, RuleError, Ruleset};
+use util::Rng;
+
+/// A packet of `protocol` between the addresses, with ports and TCP flags where it has them
+fn packet(protocol: u8, src: IpAddr, dst: IpAddr, sport: u16, dport: u16, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut transport = match protocol {
+        6 => vec![(sport >> 8) as u8, sport as u8, (dport >> 8) as u8, dport as u8,
+                  0, 0, 0, 1, 0, 0, 0, 0, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0],
+        17 => vec![(sport >> 8) as u8, sport as u8, (dport >> 8) as u8, dport as u8, 0, 8, 0, 0],
+        _ => vec![8, 0, 0, 0, 0, 1, 0, 1]
+    };
+    transport.extend_from_slice(payload);
+    let mut packet = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let len = 20 + transport.len();
+            let mut packet = vec![0x45, 0, (len >> 8) as u8, len as u8, 0, 0, 0x40, 0, 64, protocol, 0, 0];
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            packet
+        },
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let len = transport.len();
+            let mut packet = vec![0x60, 0, 0, 0, (len >> 8) as u8, len as u8, protocol, 64];
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            packet
+        },
+        _ => panic!()
+    };
+    packet.extend(transport);
+    packet
+}
+
+fn v4(a: [u8; 4]) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(a[0], a[1], a[2], a[3]))
+}
+
+fn udp(src: [u8; 4], dst: [u8; 4], dport: u16) -> Vec<u8> {
+    packet(17, v4(src), v4(dst), 999, dport, 0, b"")
+}
+
+fn tcp(dport: u16, flags: u8, payload: &[u8]) -> Vec<u8> {
+    packet(6, v4([1, 1, 1, 1]), v4([2, 2, 2, 2]), 999, dport, flags, payload)
+}
+
+/// A TLS record holding a ClientHello for `name`
+fn hello(name: &str) -> Vec<u8> {
+    let name = name.as_bytes();
+    let mut sni = vec![0, (name.len() + 3) as u8, 0, 0, name.len() as u8];
+    sni.extend_from_slice(name);
+    let mut extension = vec![0, 0, 0, sni.len() as u8];
+    extension.extend(sni);
+    let mut body = vec![3, 3];
+    body.extend_from_slice(&[0; 32]);
+    body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0, 0, extension.len() as u8]);
+    body.extend(extension);
+    let mut handshake = vec![1, 0, 0, body.len() as u8];
+    handshake.extend(body);
+    let mut record = vec![22, 3, 1, 0, handshake.len() as u8];
+    record.extend(handshake);
+    record
+}
+
+fn decide(engine: &mut Engine, data: &[u8]) -> (Verdict, Option<u32>) {
+    let header = Header { packet_id: 0, hw_protocol: 0, hook: 0 };
+    let message = Message::from_data(&header, data);
+    let verdict = engine.decide(&message);
+    (verdict, message.take_mark())
+}
+
+fn cidr(network: &str) -> Cidr {
+    network.parse().ok().unwrap()
+}
+
+#[test]
+fn engine() {
+    let mut rules = Ruleset::new(Action::new(Verdict::Accept));
+    rules.add(Rule::new(vec![Match::Protocol(17), Match::DestinationPort(vec![(53, 53)])], Action::marked(Verdict::Accept, 7)));
+    rules.add(Rule::new(vec![Match::Source(vec![cidr("10.0.0.0/8")]), Match::Not(Box::new(Match::Destination(vec![cidr("192.168.0.0/16")])))],
+                        Action::new(Verdict::Drop)));
+    let mut first = Rule::new(vec![Match::Source(vec![cidr("10.1.0.0/16")])], Action::new(Verdict::Repeat));
+    first.priority = 5;
+    rules.add(first);
+    rules.add(Rule::new(vec![Match::TcpFlags { flags: TCP_SYN, mask: TCP_SYN | TCP_ACK }, Match::DestinationPort(vec![(20, 25), (8000, 8999)])],
+                        Action::new(Verdict::Drop)));
+    rules.add(Rule::new(vec![Match::Sni("*.example.com".to_string())], Action::marked(Verdict::Drop, 9)));
+    rules.add(Rule::new(vec![Match::Mark { value: 0, mask: 0 }, Match::Protocol(1)], Action::new(Verdict::Queue(3))));
+    let mut engine = rules.compile().ok().unwrap();
+
+    assert_eq!(decide(&mut engine, &udp([1, 1, 1, 1], [8, 8, 8, 8], 53)), (Verdict::Accept, Some(7)));
+    assert_eq!(decide(&mut engine, &udp([1, 1, 1, 1], [8, 8, 8, 8], 54)), (Verdict::Accept, None));
+    assert_eq!(decide(&mut engine, &udp([10, 2, 0, 1], [8, 8, 8, 8], 54)), (Verdict::Drop, None));
+    assert_eq!(decide(&mut engine, &udp([10, 2, 0, 1], [192, 168, 1, 1], 54)), (Verdict::Accept, None));
+    // Priority comes before order
+    assert_eq!(decide(&mut engine, &udp([10, 1, 0, 1], [8, 8, 8, 8], 53)), (Verdict::Repeat, None));
+    assert_eq!(decide(&mut engine, &tcp(22, TCP_SYN, b"")), (Verdict::Drop, None));
+    assert_eq!(decide(&mut engine, &tcp(8080, TCP_SYN | TCP_ACK, b"")), (Verdict::Accept, None));
+    assert_eq!(decide(&mut engine, &tcp(8999, TCP_SYN, b"")), (Verdict::Drop, None));
+    assert_eq!(decide(&mut engine, &tcp(9000, TCP_SYN, b"")), (Verdict::Accept, None));
+    assert_eq!(decide(&mut engine, &tcp(443, TCP_ACK, &hello("WWW.Example.com"))), (Verdict::Drop, Some(9)));
+    assert_eq!(decide(&mut engine, &tcp(443, TCP_ACK, &hello("example.com"))), (Verdict::Accept, None));
+    assert_eq!(decide(&mut engine, &packet(1, v4([1, 1, 1, 1]), v4([2, 2, 2, 2]), 0, 0, 0, b"")), (Verdict::Queue(3), None));
+    // Packets that fail to decode only match rules without protocol, port or address matches
+    assert_eq!(decide(&mut engine, &[0x99, 1, 2]), (Verdict::Accept, None));
+}
+
+#[test]
+fn invalid() {
+    let mut rules = Ruleset::new(Action::new(Verdict::Accept));
+    rules.add(Rule::new(vec![Match::InInterface("nosuchif0".to_string())], Action::new(Verdict::Drop)));
+    assert_eq!(rules.compile().err(), Some(RuleError::UnknownInterface("nosuchif0".to_string())));
+    let mut rules = Ruleset::new(Action::new(Verdict::Accept));
+    rules.add(Rule::new(vec![Match::Not(Box::new(Match::SourcePort(vec![(5, 4)])))], Action::new(Verdict::Drop)));
+    assert_eq!(rules.compile().err(), Some(RuleError::PortRange(5, 4)));
+}
+
+/// Whether `packet` satisfies `m`, checked directly
+fn holds(m: &Match, packet: &Packet) -> bool {
+    let in_ranges = |port: Option<u16>, ranges: &[(u16, u16)]| {
+        port.map(|p| ranges.iter().any(|&(s, e)| s <= p && p <= e)).unwrap_or(false)
+    };
+    match *m {
+        Match::Protocol(p) => packet.protocol == p,
+        Match::Source(ref networks) => networks.iter().any(|n| n.contains(&packet.network.saddr())),
+        Match::Destination(ref networks) => networks.iter().any(|n| n.contains(&packet.network.daddr())),
+        Match::SourcePort(ref ranges) => in_ranges(packet.transport.and_then(|t| t.src_port()), ranges),
+        Match::DestinationPort(ref ranges) => in_ranges(packet.transport.and_then(|t| t.dst_port()), ranges),
+        Match::TcpFlags { flags, mask } => match packet.transport {
+            Some(Transport::Tcp(ref tcp)) => tcp.flags() & mask == flags,
+            _ => false
+        },
+        Match::Not(ref m) => !holds(m, packet),
+        _ => unreachable!()
+    }
+}
+
+/// An address from a handful, so that rules and packets often agree
+fn address(rng: &mut Rng, v6: bool) -> IpAddr {
+    let (a, b) = ((rng.next_u64() % 3) as u8, (rng.next_u64() % 4) as u8);
+    match v6 {
+        true => IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, a as u16, b as u16)),
+        false => v4([10, 0, a, b])
+    }
+}
+
+fn ports(rng: &mut Rng) -> Vec<(u16, u16)> {
+    (0..rng.next_u64() % 3 + 1).map(|_| {
+        let start = (rng.next_u64() % 20) as u16;
+        (start, start + (rng.next_u64() % 5) as u16)
+    }).collect()
+}
+
+fn random_match(rng: &mut Rng, depth: u32) -> Match {
+    match rng.next_u64() % 8 {
+        0 => Match::Protocol([1, 6, 17][(rng.next_u64() % 3) as usize]),
+        1 | 2 => {
+            let v6 = rng.next_u64() % 4 == 0;
+            let networks = (0..rng.next_u64() % 2 + 1).map(|_| {
+                let prefix = match v6 {
+                    true => [0, 112, 120, 126, 128][(rng.next_u64() % 5) as usize],
+                    false => [0, 16, 24, 30, 32][(rng.next_u64() % 5) as usize]
+                };
+                Cidr::new(address(rng, v6), prefix).ok().unwrap()
+            }).collect();
+            match rng.next_u64() % 2 {
+                0 => Match::Source(networks),
+                _ => Match::Destination(networks)
+            }
+        },
+        3 => Match::SourcePort(ports(rng)),
+        4 => Match::DestinationPort(ports(rng)),
+        5 => Match::TcpFlags { flags: TCP_SYN, mask: [TCP_SYN, TCP_SYN | TCP_ACK][(rng.next_u64() % 2) as usize] },
+        _ if depth == 0 => Match::Not(Box::new(random_match(rng, 1))),
+        _ => Match::Protocol(6)
+    }
+}
+
+#[test]
+fn linear() {
+    let mut rng = Rng::new(41);
+    for _ in 0..50 {
+        let mut rules = Ruleset::new(Action::new(Verdict::Accept));
+        for i in 0..rng.next_u64() % 30 {
+            let matches = (0..rng.next_u64() % 3 + 2).map(|_| random_match(&mut rng, 0)).collect();
+            let mut rule = Rule::new(matches, Action::marked(Verdict::Drop, i as u32));
+            rule.priority = (rng.next_u64() % 3) as i32;
+            rules.add(rule);
+        }
+        let engine = rules.compile().ok().unwrap();
+        // Tried by descending priority, then in the order added
+        let mut ordered = rules.rules().to_vec();
+        ordered.sort_by(|a, b| b.priority.cmp(&a.priority));
+
+        for _ in 0..200 {
+            let v6 = rng.next_u64() % 4 == 0;
+            let protocol = [1, 6, 17][(rng.next_u64() % 3) as usize];
+            let flags = [0, TCP_SYN, TCP_SYN | TCP_ACK, TCP_RST][(rng.next_u64() % 4) as usize];
+            let (sport, dport) = ((rng.next_u64() % 25) as u16, (rng.next_u64() % 25) as u16);
+            let data = packet(protocol, address(&mut rng, v6), address(&mut rng, v6), sport, dport, flags, b"");
+            let decoded = Packet::new(&data).ok().unwrap();
+            let header = Header { packet_id: 0, hw_protocol: 0, hook: 0 };
+            let message = Message::from_data(&header, &data);
+            let expected = ordered.iter().find(|rule| rule.matches.iter().all(|m| holds(m, &decoded)));
+            assert_eq!(engine.lookup(&message), expected);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use message::{Packet, PacketBuffer, Transport};
+use message::checksum::{fill_network, fill_transport};
+use seqadj::{SeqAdjust, Direction};
+
+const CLIENT: [u8; 4] = [10, 0, 0, 1];
+const SERVER: [u8; 4] = [10, 0, 0, 2];
+
+/// A TCP segment with correct checksums, and a SACK block if given
+fn segment(src: [u8; 4], sport: u16, dst: [u8; 4], dport: u16, seq: u32, ack: u32,
+           payload: &[u8], sack: Option<(u32, u32)>) -> PacketBuffer {
+    let options = if sack.is_some() { 12 } else { 0 };
+    let total = 40 + options + payload.len();
+    let mut data = vec![0x45, 0, (total >> 8) as u8, total as u8, 0, 0, 0x40, 0, 64, 6, 0, 0];
+    data.extend_from_slice(&src);
+    data.extend_from_slice(&dst);
+    data.extend_from_slice(&[(sport >> 8) as u8, sport as u8, (dport >> 8) as u8, dport as u8]);
+    data.extend_from_slice(&be32(seq));
+    data.extend_from_slice(&be32(ack));
+    data.extend_from_slice(&[(((20 + options) / 4) << 4) as u8, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+    if let Some((left, right)) = sack {
+        data.extend_from_slice(&[1, 1, 5, 10]);
+        data.extend_from_slice(&be32(left));
+        data.extend_from_slice(&be32(right));
+    }
+    data.extend_from_slice(payload);
+    fill_network(&mut data);
+    fill_transport(&mut data);
+    PacketBuffer::new(data).ok().unwrap()
+}
+
+fn be32(value: u32) -> [u8; 4] {
+    [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+/// The finished segment's sequence and acknowledgement numbers, SACK blocks and payload length
+fn numbers(buffer: &mut PacketBuffer) -> (u32, u32, Vec<(u32, u32)>, usize) {
+    let data = buffer.finish().to_vec();
+    let packet = Packet::new(&data).ok().unwrap();
+    assert_eq!(packet.verify_network_checksum(), Some(true));
+    assert_eq!(packet.verify_transport_checksum(), Some(true));
+    match packet.transport {
+        Some(Transport::Tcp(tcp)) => (tcp.seq(), tcp.ack(), tcp.sack_blocks(), packet.payload.len()),
+        other => panic!("{:?}", other)
+    }
+}
+
+#[test]
+fn translate() {
+    let mut adjust = SeqAdjust::new();
+    let mut request = segment(CLIENT, 1000, SERVER, 80, 1000, 500, b"GET / HTTP/1.1\r\n", None);
+    assert!(!adjust.is_tracked(&request.decode().ok().unwrap()));
+    adjust.splice_payload(&mut request, 16, 0, b"X-Injected: 1\r\n").ok().unwrap();
+    assert_eq!(adjust.adjust(&mut request), Ok(false));
+    assert_eq!(numbers(&mut request), (1000, 500, vec![], 31));
+
+    // Later segments from the client move up by the bytes inserted
+    let mut next = segment(CLIENT, 1000, SERVER, 80, 1016, 500, b"Host: x\r\n", None);
+    assert!(adjust.is_tracked(&next.decode().ok().unwrap()));
+    assert_eq!(adjust.adjust(&mut next), Ok(true));
+    assert_eq!(numbers(&mut next).0, 1031);
+
+    // Acknowledgements and SACK blocks from the server move back down
+    let mut reply = segment(SERVER, 80, CLIENT, 1000, 500, 1040, b"", Some((1031, 1040)));
+    assert_eq!(adjust.adjust(&mut reply), Ok(true));
+    let (_, ack, sack, _) = numbers(&mut reply);
+    assert_eq!((ack, sack), (1025, vec![(1016, 1025)]));
+
+    // Nothing before the change moves
+    let mut reply = segment(SERVER, 80, CLIENT, 1000, 500, 1000, b"", None);
+    assert_eq!(adjust.adjust(&mut reply), Ok(false));
+    let mut retransmit = segment(CLIENT, 1000, SERVER, 80, 1000, 500, b"GET / HTTP/1.1\r\n", None);
+    assert_eq!(adjust.adjust(&mut retransmit), Ok(false));
+    assert_eq!(numbers(&mut retransmit).0, 1000);
+}
+
+#[test]
+fn sack_unmoved() {
+    let mut adjust = SeqAdjust::new();
+    let mut request = segment(CLIENT, 1000, SERVER, 80, 1000, 500, b"GET / HTTP/1.1\r\n", None);
+    adjust.splice_payload(&mut request, 0, 4, b"").ok().unwrap();
+
+    // SACK blocks wholly before the change are not rewritten
+    let mut reply = segment(SERVER, 80, CLIENT, 1000, 500, 900, b"", Some((950, 1000)));
+    assert_eq!(adjust.adjust(&mut reply), Ok(false));
+    assert!(!reply.is_stale());
+    assert_eq!(numbers(&mut reply).2, vec![(950, 1000)]);
+}
+
+#[test]
+fn wrap_around() {
+    let mut adjust = SeqAdjust::new();
+    let seq = 0xffff_fff0;
+    let mut request = segment(CLIENT, 1000, SERVER, 80, seq, 1, b"0123456789abcdef", None);
+    adjust.splice_payload(&mut request, 0, 16, b"01234567").ok().unwrap();
+
+    let mut next = segment(CLIENT, 1000, SERVER, 80, 0, 1, b"x", None);
+    assert_eq!(adjust.adjust(&mut next), Ok(true));
+    assert_eq!(numbers(&mut next).0, 0xffff_fff8);
+
+    let mut reply = segment(SERVER, 80, CLIENT, 1000, 1, 0xffff_fff9, b"", None);
+    assert_eq!(adjust.adjust(&mut reply), Ok(true));
+    assert_eq!(numbers(&mut reply).1, 1);
+
+    let direction = Direction::of(&request.decode().ok().unwrap()).unwrap();
+    assert_eq!(adjust.len(), 1);
+    adjust.remove(direction.reverse());
+    assert!(adjust.is_empty());
+}
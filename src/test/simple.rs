@@ -1,55 +1,57 @@
-use super::super::verdict::{Verdict, VerdictHandler};
-use super::super::message::Message;
-use super::super::queue::CopyMode;
-use super::super::handle::{Handle, ProtocolFamily};
+use queue::{CopyMode, Verdict, VerdictHandler, PacketHandler, QueueHandle};
+use message::Message;
+use handle::{Handle, ProtocolFamily};
+use error::Error;
+use mock::{Mock, Call};
+use super::udp_packet;
 
-struct Void;
 struct Callback;
 struct Decider;
 
-impl PacketHandler<Void> for Callback {
-    fn callback(&self, hq: *mut QueueHandle, message: Result<&Message, &Error>, _: &mut Void) -> i32 { -1 }
+impl PacketHandler for Callback {
+    fn handle(&mut self, _: &QueueHandle, _: Result<&Message, &Error>) -> i32 { -1 }
 }
 
-impl VerdictHandler<Void> for Decider {
-    fn decide(&self, message: &Message, _: &mut Void) -> Verdict { panic!(); Verdict::Accept }
+impl VerdictHandler for Decider {
+    fn decide(&mut self, _: &Message) -> Verdict { panic!() }
 }
 
 #[test]
 fn bind() {
-    let void = Void;
-    let mut handle = Handle::new().ok().unwrap();
-    let _ = handle.queue_builder(void)
-        .copy_mode(CopyMode::None)
-        .decider_and_finalize(Decider)
-        .ok().unwrap();
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut queue = handle.queue(0, Decider).ok().unwrap();
+    queue.set_mode(CopyMode::None).ok().unwrap();
 
     handle.bind(ProtocolFamily::INET).ok().unwrap();
+    assert_eq!(mock.calls(), vec![Call::CreateQueue(0),
+                                  Call::SetMode(0, CopyMode::None),
+                                  Call::Bind(ProtocolFamily::INET)]);
 }
 
 #[test]
 #[should_panic]
 fn decider() {
-    let void = Void;
-    let mut handle = Handle::new().ok().unwrap();
-    let _ = handle.queue_builder(void)
-        .copy_mode(CopyMode::None)
-        .decider_and_finalize(Decider)
-        .ok().unwrap();
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut queue = handle.queue(0, Decider).ok().unwrap();
+    queue.set_mode(CopyMode::None).ok().unwrap();
 
     handle.bind(ProtocolFamily::INET).ok().unwrap();
+    mock.inject(0, &udp_packet(64));
     handle.start(4096);
 }
 
 #[test]
 fn callback() {
-    let void = Void;
-    let mut handle = Handle::new().ok().unwrap();
-    let _ = handle.queue_builder(void)
-        .copy_mode(CopyMode::None)
-        .callback_and_finalize(Callback)
-        .ok().unwrap();
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut queue = handle.queue(0, Callback).ok().unwrap();
+    queue.set_mode(CopyMode::None).ok().unwrap();
 
     handle.bind(ProtocolFamily::INET).ok().unwrap();
+    mock.inject(0, &udp_packet(64));
     handle.start(4096);
+    assert_eq!(mock.pending(), 0);
+    assert!(mock.verdicts(0).is_empty());
 }
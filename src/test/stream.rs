@@ -0,0 +1,183 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use message::{checksum, Header, Message, Packet, Transport, TCP_ACK, TCP_RST, TCP_SYN};
+use queue::{PacketHandler, QueueHandle, Verdict};
+use handle::Handle;
+use mock::Mock;
+use stream::{Limits, Side, Stream, StreamReassembler, StreamVerdict};
+
+/// A TCP segment between 10.0.0.1 and port 80 of 10.0.0.2, sent by the server when `sport` is 80
+fn tcp(sport: u16, dport: u16, seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let (saddr, daddr) = match sport {
+        80 => ([10, 0, 0, 2], [10, 0, 0, 1]),
+        _ => ([10, 0, 0, 1], [10, 0, 0, 2])
+    };
+    let len = 40 + payload.len();
+    let mut packet = vec![0x45, 0, (len >> 8) as u8, len as u8, 0, 0, 0x40, 0, 64, 6, 0, 0];
+    packet.extend_from_slice(&saddr);
+    packet.extend_from_slice(&daddr);
+    packet.extend_from_slice(&[(sport >> 8) as u8, sport as u8, (dport >> 8) as u8, dport as u8,
+                               (seq >> 24) as u8, (seq >> 16) as u8, (seq >> 8) as u8, seq as u8,
+                               0, 0, 0, 1, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    packet.extend_from_slice(payload);
+    checksum::fill_network(&mut packet);
+    checksum::fill_transport(&mut packet);
+    packet
+}
+
+/// Decide once the client's request ends, resetting requests for `/bad`
+fn request(stream: &Stream, side: Side) -> StreamVerdict {
+    let data = stream.data(Side::Client);
+    match side == Side::Client && data.ends_with(b"\r\n\r\n") {
+        true if data.starts_with(b"GET /bad") => StreamVerdict::Reset,
+        true => StreamVerdict::Accept,
+        false => StreamVerdict::Continue
+    }
+}
+
+fn verdict(mock: &Mock, id: u32) -> Option<Verdict> {
+    mock.verdict(id).map(|d| d.verdict)
+}
+
+#[test]
+fn out_of_order() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let _queue = handle.queue(0, StreamReassembler::new(request)).ok().unwrap();
+
+    let syn = mock.inject(0, &tcp(1000, 80, 100, TCP_SYN, b""));
+    let syn_ack = mock.inject(0, &tcp(80, 1000, 500, TCP_SYN | TCP_ACK, b""));
+    handle.start(4096);
+    assert_eq!(verdict(&mock, syn), Some(Verdict::Accept));
+    assert_eq!(verdict(&mock, syn_ack), Some(Verdict::Accept));
+
+    // The end of the request arrives first, then a segment overlapping its start
+    let last = mock.inject(0, &tcp(1000, 80, 109, TCP_ACK, b"HTTP/1.1\r\n\r\n"));
+    let first = mock.inject(0, &tcp(1000, 80, 101, TCP_ACK, b"GET /o"));
+    handle.start(4096);
+    assert_eq!(verdict(&mock, last), None);
+    assert_eq!(verdict(&mock, first), None);
+    let middle = mock.inject(0, &tcp(1000, 80, 105, TCP_ACK, b"/ok "));
+    handle.start(4096);
+    assert_eq!(verdict(&mock, last), Some(Verdict::Accept));
+    assert_eq!(verdict(&mock, first), Some(Verdict::Accept));
+    assert_eq!(verdict(&mock, middle), Some(Verdict::Accept));
+
+    // The rest of the connection follows without inspection
+    let response = mock.inject(0, &tcp(80, 1000, 501, TCP_ACK, b"HTTP/1.1 200 OK\r\n"));
+    handle.start(4096);
+    assert_eq!(verdict(&mock, response), Some(Verdict::Accept));
+}
+
+#[test]
+fn reset() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let _queue = handle.queue(0, StreamReassembler::new(request)).ok().unwrap();
+
+    mock.inject(0, &tcp(1000, 80, 100, TCP_SYN, b""));
+    let first = mock.inject(0, &tcp(1000, 80, 101, TCP_ACK, b"GET /bad "));
+    let last = mock.inject(0, &tcp(1000, 80, 110, TCP_ACK, b"HTTP/1.1\r\n\r\n"));
+    handle.start(4096);
+
+    // The first segment held becomes a reset, the others are dropped
+    let decision = mock.verdict(first).unwrap();
+    assert_eq!(decision.verdict, Verdict::Accept);
+    let payload = decision.payload.unwrap();
+    let rst = Packet::new(&payload).ok().unwrap();
+    match rst.transport {
+        Some(Transport::Tcp(tcp)) => {
+            assert!(tcp.has_flags(TCP_RST | TCP_ACK));
+            assert_eq!(tcp.seq(), 101);
+        },
+        _ => panic!()
+    }
+    assert!(rst.payload.is_empty());
+    assert_eq!(rst.verify_network_checksum(), Some(true));
+    assert_eq!(rst.verify_transport_checksum(), Some(true));
+    assert_eq!(verdict(&mock, last), Some(Verdict::Drop));
+
+    let response = mock.inject(0, &tcp(80, 1000, 501, TCP_ACK, b"x"));
+    handle.start(4096);
+    assert_eq!(verdict(&mock, response), Some(Verdict::Drop));
+}
+
+#[test]
+fn overflow() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut reassembler = StreamReassembler::with_limits(request, Limits { max_flow_bytes: 16, ..Limits::default() });
+    reassembler.set_overflow_verdict(Verdict::Drop);
+    let _queue = handle.queue(0, reassembler).ok().unwrap();
+
+    let first = mock.inject(0, &tcp(1000, 80, 1, TCP_ACK, b"GET / HTTP"));
+    handle.start(4096);
+    assert_eq!(verdict(&mock, first), None);
+    // Bytes held past a gap count too
+    let second = mock.inject(0, &tcp(1000, 80, 20, TCP_ACK, b"/1.1\r\n"));
+    let third = mock.inject(0, &tcp(1000, 80, 40, TCP_ACK, b"\r\n"));
+    handle.start(4096);
+    assert_eq!(verdict(&mock, first), Some(Verdict::Drop));
+    assert_eq!(verdict(&mock, second), Some(Verdict::Drop));
+    assert_eq!(verdict(&mock, third), Some(Verdict::Drop));
+}
+
+#[test]
+fn evict() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let mut reassembler = StreamReassembler::with_limits(request, Limits { max_flows: 2, ..Limits::default() });
+    reassembler.set_timeout_verdict(Verdict::Drop);
+    let _queue = handle.queue(0, reassembler).ok().unwrap();
+
+    let first = mock.inject(0, &tcp(1000, 80, 1, TCP_ACK, b"GET"));
+    let second = mock.inject(0, &tcp(1001, 80, 1, TCP_ACK, b"GET"));
+    handle.start(4096);
+    thread::sleep(Duration::from_millis(2));
+    let third = mock.inject(0, &tcp(1001, 80, 4, TCP_ACK, b" /"));
+    let fourth = mock.inject(0, &tcp(1002, 80, 1, TCP_ACK, b"GET"));
+    handle.start(4096);
+
+    // The connection seen least recently makes room, undecided
+    assert_eq!(verdict(&mock, first), Some(Verdict::Drop));
+    assert_eq!(verdict(&mock, second), None);
+    assert_eq!(verdict(&mock, third), None);
+    assert_eq!(verdict(&mock, fourth), None);
+}
+
+#[test]
+fn hold_timeout() {
+    let mock = Mock::new();
+    let mut handle = Handle::in_memory(&mock);
+    let limits = Limits { hold_timeout: Duration::from_millis(5), ..Limits::default() };
+    let mut reassembler = StreamReassembler::with_limits(request, limits);
+    reassembler.set_timeout_verdict(Verdict::Drop);
+    let _queue = handle.queue(0, reassembler).ok().unwrap();
+
+    let held = mock.inject(0, &tcp(1000, 80, 1, TCP_ACK, b"GET / HTTP/1.1\r\n"));
+    handle.start(4096);
+    assert_eq!(verdict(&mock, held), None);
+    thread::sleep(Duration::from_millis(10));
+    assert!(!handle.step());
+    assert_eq!(verdict(&mock, held), Some(Verdict::Drop));
+}
+
+#[test]
+fn idle_handshake() {
+    let mock = Mock::new();
+    let hq = QueueHandle::in_memory(&mock, 0);
+    let limits = Limits { idle_timeout: Duration::from_millis(5), ..Limits::default() };
+    let mut reassembler = StreamReassembler::with_limits(request, limits);
+
+    let syn = tcp(1000, 80, 100, TCP_SYN, b"");
+    let header = Header { packet_id: 1u32.to_be(), hw_protocol: 0x0800u16.to_be(), hook: 0 };
+    let start = Instant::now();
+    reassembler.handle(&hq, Ok(&Message::from_data(&header, &syn)));
+    assert_eq!(reassembler.len(), 1);
+
+    // No segment was ever held, yet the connection is forgotten once idle
+    let next = reassembler.poll(&hq, start).unwrap();
+    assert!(next >= start + limits.idle_timeout);
+    assert_eq!(reassembler.poll(&hq, next), None);
+    assert!(reassembler.is_empty());
+}
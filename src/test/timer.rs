@@ -0,0 +1,58 @@
+use std::time::{Duration, Instant};
+use timer::TimerWheel;
+
+fn ms(start: Instant, ms: u64) -> Instant {
+    start + Duration::from_millis(ms)
+}
+
+#[test]
+fn stepping() {
+    let start = Instant::now();
+    let mut wheel = TimerWheel::new(start, Duration::from_millis(1), 8);
+    // Three timers share a slot, a revolution apart
+    wheel.insert(ms(start, 3), 'a');
+    wheel.insert(ms(start, 11), 'b');
+    wheel.insert(ms(start, 19) + Duration::from_micros(500), 'c');
+    wheel.insert(ms(start, 5), 'd');
+    assert_eq!(wheel.len(), 4);
+
+    assert_eq!(wheel.next_expiry(), Some(ms(start, 4)));
+    assert_eq!(wheel.expire(ms(start, 3)), vec![]);
+    assert_eq!(wheel.expire(ms(start, 4)), vec!['a']);
+    assert_eq!(wheel.next_expiry(), Some(ms(start, 6)));
+    assert_eq!(wheel.expire(ms(start, 6)), vec!['d']);
+    assert_eq!(wheel.next_expiry(), Some(ms(start, 12)));
+    assert_eq!(wheel.expire(ms(start, 12)), vec!['b']);
+    assert_eq!(wheel.next_expiry(), Some(ms(start, 20)));
+    assert_eq!(wheel.expire(ms(start, 19)), vec![]);
+    assert_eq!(wheel.expire(ms(start, 20)), vec!['c']);
+    assert_eq!(wheel.len(), 0);
+    assert_eq!(wheel.next_expiry(), None);
+}
+
+#[test]
+fn revolutions() {
+    let start = Instant::now();
+    let mut wheel = TimerWheel::new(start, Duration::from_millis(1), 8);
+    for &(at, item) in [(30, 30), (2, 2), (10, 10), (2, 3), (18, 18), (100, 100)].iter() {
+        wheel.insert(ms(start, at), item);
+    }
+    // Jumping five revolutions expires everything due, by tick and then insertion
+    assert_eq!(wheel.expire(ms(start, 40)), vec![2, 3, 10, 18, 30]);
+    assert_eq!(wheel.len(), 1);
+    // The one left is further away than a revolution
+    assert_eq!(wheel.next_expiry(), Some(ms(start, 101)));
+    assert_eq!(wheel.expire(ms(start, 100)), vec![]);
+    assert_eq!(wheel.expire(ms(start, 101)), vec![100]);
+}
+
+#[test]
+fn past() {
+    let start = Instant::now();
+    let mut wheel = TimerWheel::new(start, Duration::from_millis(1), 8);
+    assert_eq!(wheel.expire(ms(start, 50)), Vec::<u8>::new());
+    // Timers already due are due with the current tick
+    wheel.insert(ms(start, 10), 1);
+    assert_eq!(wheel.next_expiry(), Some(ms(start, 51)));
+    assert_eq!(wheel.expire(ms(start, 51)), vec![1]);
+}
@@ -0,0 +1,124 @@
+use super::unhex;
+use tls::{ClientHello, Extension, TlsError, EXT_ALPN, EXT_SERVER_NAME, EXT_SIGNATURE_ALGORITHMS};
+
+/// The ClientHello of the simple 1-RTT handshake of RFC 8448, section 3
+const RFC8448: &'static str = "\
+    010000c00303cb34ecb1e78163ba1c38c6dacb196a6dffa21a8d9912ec18a2ef6283024dece7000006130113031302\
+    010000910000000b0009000006736572766572ff01000100000a00140012001d0017001800190100010101020103\
+    010400230000003300260024001d002099381de560e4bd43d23d8e435a7dbafeb3c06e51c13cae4d5413691e529a\
+    af2c002b0003020304000d0020001e040305030603020308040805080604010501060102010402050206020202\
+    002d00020101001c00024001";
+
+fn record(handshake: &[u8]) -> Vec<u8> {
+    let mut record = vec![22, 3, 1, (handshake.len() >> 8) as u8, handshake.len() as u8];
+    record.extend_from_slice(handshake);
+    record
+}
+
+fn ext(kind: u16, data: &[u8]) -> Vec<u8> {
+    let mut ext = vec![(kind >> 8) as u8, kind as u8, (data.len() >> 8) as u8, data.len() as u8];
+    ext.extend_from_slice(data);
+    ext
+}
+
+fn vec16(data: &[u8]) -> Vec<u8> {
+    let mut vec = vec![(data.len() >> 8) as u8, data.len() as u8];
+    vec.extend_from_slice(data);
+    vec
+}
+
+fn u16s(values: &[u16]) -> Vec<u8> {
+    values.iter().flat_map(|v| vec![(v >> 8) as u8, *v as u8]).collect()
+}
+
+/// A ClientHello with the ciphers, extensions and order of Chrome's, GREASE included,
+/// split over two records
+fn chrome() -> Vec<u8> {
+    let ciphers = [0x0a0a, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030,
+                   0xcca9, 0xcca8, 0xc013, 0xc014, 0x009c, 0x009d, 0x002f, 0x0035];
+    let mut body = vec![3, 3];
+    body.extend_from_slice(&[7; 32]);
+    body.push(0);
+    body.extend(vec16(&u16s(&ciphers)));
+    body.extend_from_slice(&[1, 0]);
+    let mut sni = vec![0];
+    sni.extend(vec16(b"example.com"));
+    let mut extensions = Vec::new();
+    extensions.extend(ext(0x1a1a, b""));
+    extensions.extend(ext(0, &vec16(&sni)));
+    extensions.extend(ext(0x0017, b""));
+    extensions.extend(ext(0xff01, &[0]));
+    extensions.extend(ext(10, &vec16(&u16s(&[0x2a2a, 0x001d, 0x0017, 0x0018]))));
+    extensions.extend(ext(11, &[1, 0]));
+    extensions.extend(ext(0x0023, b""));
+    extensions.extend(ext(16, &vec16(b"\x02h2\x08http/1.1")));
+    extensions.extend(ext(5, &[1, 0, 0, 0, 0]));
+    extensions.extend(ext(13, &vec16(&u16s(&[0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601]))));
+    extensions.extend(ext(0x0012, b""));
+    extensions.extend(ext(0x0033, &[0, 0]));
+    extensions.extend(ext(0x002d, &[1, 1]));
+    extensions.extend(ext(43, &[6, 0x3a, 0x3a, 3, 4, 3, 3]));
+    extensions.extend(ext(0x001b, &[2, 0, 2]));
+    extensions.extend(ext(0x4469, &[0, 0]));
+    extensions.extend(ext(0x0015, &[0; 10]));
+    body.extend(vec16(&extensions));
+    let mut handshake = vec![1, 0, (body.len() >> 8) as u8, body.len() as u8];
+    handshake.extend(body);
+
+    let (first, second) = handshake.split_at(50);
+    let mut stream = record(first);
+    stream.extend(record(second));
+    stream
+}
+
+#[test]
+fn rfc8448() {
+    let hello = ClientHello::parse(&record(&unhex(RFC8448))).ok().unwrap();
+    assert_eq!(hello.server_name(), Some("server"));
+    assert!(hello.alpn().is_empty());
+    assert_eq!(hello.max_version(), 0x0304);
+    assert_eq!(hello.ja3_string(), "771,4865-4867-4866,0-65281-10-35-51-43-13-45-28,29-23-24-25-256-257-258-259-260,");
+    assert_eq!(hello.ja3(), "da4dea34fe6d4ce5f0725df3f2682fa0");
+    assert_eq!(hello.ja4(), "t13d030900_55b375c5d22e_59cd3dafc54d");
+    assert_eq!(hello.ja4_quic(), "q13d030900_55b375c5d22e_59cd3dafc54d");
+}
+
+#[test]
+fn chrome_fingerprints() {
+    let stream = chrome();
+    let hello = ClientHello::parse(&stream).ok().unwrap();
+    assert_eq!(hello.server_name(), Some("example.com"));
+    assert_eq!(hello.alpn(), vec![&b"h2"[..], &b"http/1.1"[..]]);
+    // The published fingerprints of Chrome, whose GREASE values change with every connection
+    assert_eq!(hello.ja3(), "cd08e31494f9531f560d64c695473da9");
+    assert_eq!(hello.ja4(), "t13d1516h2_8daaf6152771_e5627efa2ab1");
+}
+
+#[test]
+fn incomplete() {
+    let stream = chrome();
+    for &cut in [0, 3, 20, 55, stream.len() - 1].iter() {
+        assert_eq!(ClientHello::parse(&stream[..cut]), Err(TlsError::Incomplete));
+    }
+    assert_eq!(ClientHello::parse(b"GET / HTTP/1.1\r\n"), Err(TlsError::NotClientHello));
+}
+
+#[test]
+fn ja4_empty_lists() {
+    let mut hello = ClientHello {
+        version: 0x0303,
+        random: vec![0; 32],
+        session_id: Vec::new(),
+        cipher_suites: Vec::new(),
+        compression_methods: vec![0],
+        extensions: vec![Extension { kind: EXT_SERVER_NAME, data: vec16(&[0, 0, 1, b'a']) },
+                         Extension { kind: EXT_ALPN, data: vec16(b"\x02h2") }],
+    };
+    // Nothing is left to hash once SNI and ALPN are taken out
+    assert_eq!(hello.ja4(), "t12d0002h2_000000000000_000000000000");
+
+    // The signature algorithms are hashed along with their own extension
+    hello.cipher_suites = vec![0x1301];
+    hello.extensions.push(Extension { kind: EXT_SIGNATURE_ALGORITHMS, data: vec16(&u16s(&[0x0403, 0x0804])) });
+    assert_eq!(hello.ja4(), "t12d0103h2_0f2cb44170f4_ef95ca21a004");
+}
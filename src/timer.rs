@@ -0,0 +1,110 @@
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// A hashed timer wheel
+///
+/// Timers are bucketed into slots by their tick, so inserting is constant time
+/// and expiring visits only the slots of the ticks that passed.
+/// Timers due in the same tick expire in the order they were inserted.
+pub struct TimerWheel<T> {
+    /// Timers by tick modulo the number of slots, each with its tick
+    slots: Vec<Vec<(u64, T)>>,
+    start: Instant,
+    /// The length of a tick in nanoseconds
+    resolution: u64,
+    /// The first tick not yet expired
+    current: u64,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    /// A wheel of `slots` slots each covering `resolution`, starting at `start`
+    pub fn new(start: Instant, resolution: Duration, slots: usize) -> TimerWheel<T> {
+        TimerWheel {
+            slots: (0..slots).map(|_| Vec::new()).collect(),
+            start: start,
+            resolution: resolution.as_secs() * 1_000_000_000 + resolution.subsec_nanos() as u64,
+            current: 0,
+            len: 0,
+        }
+    }
+
+    /// The tick containing `at`
+    fn tick(&self, at: Instant) -> u64 {
+        let since = at.saturating_duration_since(self.start);
+        let nanos = since.as_secs() as u128 * 1_000_000_000 + since.subsec_nanos() as u128;
+        (nanos / self.resolution as u128) as u64
+    }
+
+    /// The end of `tick`, when its timers are due
+    fn instant(&self, tick: u64) -> Instant {
+        self.start + Duration::from_nanos(self.resolution * (tick + 1))
+    }
+
+    /// Add a timer due at `at`, rounded up to the end of its tick
+    pub fn insert(&mut self, at: Instant, item: T) {
+        let tick = self.tick(at).max(self.current);
+        let slot = (tick % self.slots.len() as u64) as usize;
+        self.slots[slot].push((tick, item));
+        self.len += 1;
+    }
+
+    /// Remove the timers due by `now`, in the order they are due
+    pub fn expire(&mut self, now: Instant) -> Vec<T> {
+        let mut expired = Vec::new();
+        if self.len == 0 || now < self.instant(self.current) {
+            if self.len == 0 {
+                self.current = self.current.max(self.tick(now));
+            }
+            return expired;
+        }
+        // The last tick that has passed
+        let last = self.tick(now) - 1;
+        let slots = self.slots.len() as u64;
+        if last - self.current >= slots {
+            // Every slot is visited; sort the due timers by tick
+            let mut due = Vec::new();
+            for slot in self.slots.iter_mut() {
+                let (ready, waiting): (Vec<_>, Vec<_>) = mem::replace(slot, Vec::new()).into_iter().partition(|&(t, _)| t <= last);
+                *slot = waiting;
+                due.extend(ready);
+            }
+            // Stable, so insertion order is kept within a tick
+            due.sort_by_key(|&(t, _)| t);
+            expired.extend(due.into_iter().map(|(_, item)| item));
+        } else {
+            for tick in self.current..last + 1 {
+                let slot = (tick % slots) as usize;
+                if self.slots[slot].iter().any(|&(t, _)| t == tick) {
+                    let (ready, waiting): (Vec<_>, Vec<_>) = mem::replace(&mut self.slots[slot], Vec::new()).into_iter().partition(|&(t, _)| t == tick);
+                    self.slots[slot] = waiting;
+                    expired.extend(ready.into_iter().map(|(_, item)| item));
+                }
+            }
+        }
+        self.current = last + 1;
+        self.len -= expired.len();
+        expired
+    }
+
+    /// When the next timer is due
+    pub fn next_expiry(&self) -> Option<Instant> {
+        if self.len == 0 {
+            return None;
+        }
+        let slots = self.slots.len() as u64;
+        for tick in self.current..self.current + slots {
+            let slot = &self.slots[(tick % slots) as usize];
+            if slot.iter().any(|&(t, _)| t == tick) {
+                return Some(self.instant(tick));
+            }
+        }
+        // Every timer is more than a revolution away
+        self.slots.iter().flat_map(|s| s.iter().map(|&(t, _)| t)).min().map(|t| self.instant(t))
+    }
+
+    /// The number of timers
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
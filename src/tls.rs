@@ -0,0 +1,350 @@
+//! TLS ClientHello parsing and fingerprinting
+//!
+//! The ClientHello opens a TLS connection in the clear, naming the server (SNI) and application protocols (ALPN)
+//! the client wants, so it is where HTTPS traffic can be filtered by hostname.
+//! A ClientHello may be split over several records and TCP segments;
+//! parse it from the byte stream of a `stream::StreamReassembler`, which yields `TlsError::Incomplete`
+//! until enough of the stream has arrived.
+//!
+//! Fingerprints follow [JA3](https://github.com/salesforce/ja3)
+//! and [JA4](https://github.com/FoxIO-LLC/ja4/blob/main/technical_details/JA4.md).
+
+use std::error::Error as Base;
+use std::fmt;
+use util::*;
+use crypto::{md5, sha256};
+
+/// The record content type of handshake messages
+pub const CONTENT_HANDSHAKE: u8 = 22;
+/// The handshake type of a ClientHello
+pub const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+
+/// Server name indication
+pub const EXT_SERVER_NAME: u16 = 0;
+/// Supported groups, formerly elliptic curves
+pub const EXT_SUPPORTED_GROUPS: u16 = 10;
+/// Elliptic curve point formats
+pub const EXT_EC_POINT_FORMATS: u16 = 11;
+/// Signature algorithms
+pub const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+/// Application layer protocol negotiation
+pub const EXT_ALPN: u16 = 16;
+/// Supported versions
+pub const EXT_SUPPORTED_VERSIONS: u16 = 43;
+
+/// The largest ClientHello accepted, well beyond those of post-quantum key shares
+const MAX_CLIENT_HELLO: usize = 64 * 1024;
+
+/// Reasons a ClientHello could not be parsed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsError {
+    /// More of the stream is needed
+    Incomplete,
+    /// The stream does not start with a ClientHello
+    NotClientHello,
+    /// The ClientHello is malformed
+    Malformed,
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason())
+    }
+}
+
+impl Base for TlsError {
+    fn description(&self) -> &str {
+        self.reason()
+    }
+}
+
+impl TlsError {
+    fn reason(&self) -> &'static str {
+        match *self {
+            TlsError::Incomplete => "Incomplete ClientHello",
+            TlsError::NotClientHello => "Not a ClientHello",
+            TlsError::Malformed => "Malformed ClientHello",
+        }
+    }
+}
+
+/// A ClientHello extension
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Extension {
+    /// The extension type, see the `EXT_*` constants
+    pub kind: u16,
+    /// The extension data
+    pub data: Vec<u8>,
+}
+
+/// A parsed ClientHello
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientHello {
+    /// The legacy version field, 0x0303 for TLS 1.2 and 1.3
+    pub version: u16,
+    /// The client random
+    pub random: Vec<u8>,
+    /// The legacy session id
+    pub session_id: Vec<u8>,
+    /// The offered cipher suites, in order of preference
+    pub cipher_suites: Vec<u16>,
+    /// The offered compression methods
+    pub compression_methods: Vec<u8>,
+    /// The extensions, in the order sent
+    pub extensions: Vec<Extension>,
+}
+
+/// Whether `value` is a GREASE value of RFC 8701, sent to keep servers tolerant of unknown values
+pub fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+impl ClientHello {
+    /// Parse a ClientHello from the start of the client's byte stream
+    ///
+    /// Handshake records are reassembled until the whole ClientHello has arrived.
+    pub fn parse(stream: &[u8]) -> Result<ClientHello, TlsError> {
+        let mut records = Reader::new(stream);
+        let mut handshake = Vec::new();
+        loop {
+            if let Some(len) = message_len(&handshake) {
+                if len > MAX_CLIENT_HELLO {
+                    return Err(TlsError::Malformed);
+                }
+                if handshake.len() >= len {
+                    return ClientHello::parse_handshake(&handshake[..len]);
+                }
+            }
+            match records.u8() {
+                Some(CONTENT_HANDSHAKE) => (),
+                Some(_) => return Err(TlsError::NotClientHello),
+                None => return Err(TlsError::Incomplete)
+            }
+            // The record version varies between clients and is ignored, as in JA3
+            if records.u16().is_none() {
+                return Err(TlsError::Incomplete);
+            }
+            match records.vec16() {
+                Some(fragment) => handshake.extend_from_slice(fragment),
+                None => return Err(TlsError::Incomplete)
+            }
+            if handshake.first().map(|&t| t != HANDSHAKE_CLIENT_HELLO).unwrap_or(false) {
+                return Err(TlsError::NotClientHello);
+            }
+        }
+    }
+
+    /// Parse a ClientHello handshake message, without the record layer
+    ///
+    /// This is the form carried in the CRYPTO frames of QUIC.
+    pub fn parse_handshake(message: &[u8]) -> Result<ClientHello, TlsError> {
+        let len = match message_len(message) {
+            Some(_) if message[0] != HANDSHAKE_CLIENT_HELLO => return Err(TlsError::NotClientHello),
+            Some(len) if message.len() >= len => len,
+            Some(_) | None => return Err(TlsError::Incomplete)
+        };
+        parse_body(&message[4..len]).ok_or(TlsError::Malformed)
+    }
+
+    /// Find the first extension of `kind`
+    pub fn extension(&self, kind: u16) -> Option<&[u8]> {
+        self.extensions.iter().find(|e| e.kind == kind).map(|e| &e.data[..])
+    }
+
+    /// The host name of the server name indication
+    pub fn server_name(&self) -> Option<&str> {
+        let data = match self.extension(EXT_SERVER_NAME) {
+            Some(d) => d,
+            None => return None
+        };
+        let mut list = match Reader::new(data).vec16() {
+            Some(list) => Reader::new(list),
+            None => return None
+        };
+        while let (Some(kind), Some(name)) = (list.u8(), list.vec16()) {
+            if kind == 0 {
+                return ::std::str::from_utf8(name).ok();
+            }
+        }
+        None
+    }
+
+    /// The application protocols offered through ALPN, in order of preference
+    pub fn alpn(&self) -> Vec<&[u8]> {
+        let mut protocols = Vec::new();
+        if let Some(mut list) = self.extension(EXT_ALPN).and_then(|d| Reader::new(d).vec16()).map(Reader::new) {
+            while let Some(protocol) = list.vec8() {
+                protocols.push(protocol);
+            }
+        }
+        protocols
+    }
+
+    /// The versions offered through the supported versions extension, GREASE included
+    pub fn supported_versions(&self) -> Vec<u16> {
+        u16_list(self.extension(EXT_SUPPORTED_VERSIONS).and_then(|d| Reader::new(d).vec8()))
+    }
+
+    /// The groups offered through the supported groups extension, GREASE included
+    pub fn supported_groups(&self) -> Vec<u16> {
+        u16_list(self.extension(EXT_SUPPORTED_GROUPS).and_then(|d| Reader::new(d).vec16()))
+    }
+
+    /// The elliptic curve point formats offered
+    pub fn ec_point_formats(&self) -> Vec<u8> {
+        match self.extension(EXT_EC_POINT_FORMATS).and_then(|d| Reader::new(d).vec8()) {
+            Some(formats) => formats.to_vec(),
+            None => Vec::new()
+        }
+    }
+
+    /// The signature algorithms offered, in order of preference
+    pub fn signature_algorithms(&self) -> Vec<u16> {
+        u16_list(self.extension(EXT_SIGNATURE_ALGORITHMS).and_then(|d| Reader::new(d).vec16()))
+    }
+
+    /// The highest version offered, from the supported versions extension or else the legacy version field
+    pub fn max_version(&self) -> u16 {
+        self.supported_versions().into_iter().filter(|&v| !is_grease(v)).max().unwrap_or(self.version)
+    }
+
+    /// The JA3 string, before hashing
+    pub fn ja3_string(&self) -> String {
+        let extensions: Vec<u16> = self.extensions.iter().map(|e| e.kind).collect();
+        let formats: Vec<u16> = self.ec_point_formats().into_iter().map(|f| f as u16).collect();
+        format!("{},{},{},{},{}",
+                self.version,
+                join_decimal(&self.cipher_suites),
+                join_decimal(&extensions),
+                join_decimal(&self.supported_groups()),
+                join_decimal(&formats))
+    }
+
+    /// The JA3 fingerprint, the MD5 of the JA3 string in hex
+    pub fn ja3(&self) -> String {
+        hex(&md5(self.ja3_string().as_bytes()))
+    }
+
+    /// The JA4 fingerprint of a ClientHello sent over TCP
+    pub fn ja4(&self) -> String {
+        self.ja4_with('t')
+    }
+
+    /// The JA4 fingerprint of a ClientHello sent over QUIC
+    pub fn ja4_quic(&self) -> String {
+        self.ja4_with('q')
+    }
+
+    fn ja4_with(&self, protocol: char) -> String {
+        let version = match self.max_version() {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            0x0002 => "s2",
+            0xfeff => "d1",
+            0xfefd => "d2",
+            0xfefc => "d3",
+            _ => "00"
+        };
+        let sni = match self.extension(EXT_SERVER_NAME) {
+            Some(_) => 'd',
+            None => 'i'
+        };
+        let mut ciphers: Vec<u16> = self.cipher_suites.iter().cloned().filter(|&c| !is_grease(c)).collect();
+        let mut extensions: Vec<u16> = self.extensions.iter().map(|e| e.kind).filter(|&e| !is_grease(e)).collect();
+        let alpn = match self.alpn().first() {
+            Some(first) if !first.is_empty() => {
+                let (a, b) = (first[0] as char, first[first.len() - 1] as char);
+                match a.is_ascii_alphanumeric() && b.is_ascii_alphanumeric() {
+                    true => format!("{}{}", a, b),
+                    false => {
+                        let hex = hex(first);
+                        format!("{}{}", &hex[..1], &hex[hex.len() - 1..])
+                    }
+                }
+            },
+            _ => "00".to_string()
+        };
+        let prefix = format!("{}{}{}{:02}{:02}{}",
+                             protocol, version, sni,
+                             ::std::cmp::min(ciphers.len(), 99),
+                             ::std::cmp::min(extensions.len(), 99),
+                             alpn);
+
+        ciphers.sort();
+        extensions.retain(|&e| e != EXT_SERVER_NAME && e != EXT_ALPN);
+        extensions.sort();
+        let mut extension_string = join_hex(&extensions);
+        let algorithms = self.signature_algorithms();
+        if !algorithms.is_empty() {
+            extension_string.push('_');
+            extension_string.push_str(&join_hex(&algorithms));
+        }
+        format!("{}_{}_{}", prefix, truncated_hash(&join_hex(&ciphers)), truncated_hash(&extension_string))
+    }
+}
+
+/// The length of a handshake message including its header, once the header has arrived
+fn message_len(handshake: &[u8]) -> Option<usize> {
+    match handshake.len() >= 4 {
+        true => Some(4 + ((handshake[1] as usize) << 16 | be16(handshake, 2) as usize)),
+        false => None
+    }
+}
+
+fn parse_body(body: &[u8]) -> Option<ClientHello> {
+    let mut body = Reader::new(body);
+    let version = body.u16()?;
+    let random = body.bytes(32)?.to_vec();
+    let session_id = body.vec8()?.to_vec();
+    let cipher_suites = body.vec16()?;
+    if cipher_suites.len() % 2 != 0 {
+        return None;
+    }
+    let compression_methods = body.vec8()?.to_vec();
+    let mut extensions = Vec::new();
+    // Extensions may be left out altogether, as they were before TLS 1.2
+    if !body.is_empty() {
+        let mut list = Reader::new(body.vec16()?);
+        while !list.is_empty() {
+            extensions.push(Extension { kind: list.u16()?, data: list.vec16()?.to_vec() });
+        }
+    }
+    Some(ClientHello {
+        version: version,
+        random: random,
+        session_id: session_id,
+        cipher_suites: u16_list(Some(cipher_suites)),
+        compression_methods: compression_methods,
+        extensions: extensions,
+    })
+}
+
+fn u16_list(data: Option<&[u8]>) -> Vec<u16> {
+    match data {
+        Some(data) => data.chunks(2).filter(|c| c.len() == 2).map(|c| be16(c, 0)).collect(),
+        None => Vec::new()
+    }
+}
+
+fn join_decimal(values: &[u16]) -> String {
+    let values: Vec<String> = values.iter().filter(|&&v| !is_grease(v)).map(|v| v.to_string()).collect();
+    values.join("-")
+}
+
+fn join_hex(values: &[u16]) -> String {
+    let values: Vec<String> = values.iter().map(|v| format!("{:04x}", v)).collect();
+    values.join(",")
+}
+
+/// The first 12 hex digits of the SHA-256 of `string`, or zeros if it is empty
+///
+/// Signature algorithms alone are still hashed, as in the reference implementation.
+fn truncated_hash(string: &str) -> String {
+    match string.is_empty() {
+        true => "000000000000".to_string(),
+        false => hex(&sha256(string.as_bytes()))[..12].to_string()
+    }
+}
@@ -1,3 +1,6 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
 // `as_mut` and `as_ref` are not stable, so are reproduced here to avoid the compiler error.
 // Copypasta from <https://github.com/rust-lang/rust/blob/f46c4e158d395cf6e186bf6afdf1705c12071cbe/src/libcore/ptr.rs#L370-376>
 
@@ -18,3 +21,152 @@ pub unsafe fn as_ref<'a, T: ?Sized>(ptr: &*const T) -> Option<&'a T> where T: Si
         Some(&**ptr)
     }
 }
+
+/// Read a big-endian `u16` at `offset`
+#[inline]
+pub fn be16(data: &[u8], offset: usize) -> u16 {
+    (data[offset] as u16) << 8 | data[offset + 1] as u16
+}
+
+/// Read a big-endian `u32` at `offset`
+#[inline]
+pub fn be32(data: &[u8], offset: usize) -> u32 {
+    (be16(data, offset) as u32) << 16 | be16(data, offset + 2) as u32
+}
+
+/// Write a big-endian `u16` at `offset`
+#[inline]
+pub fn write16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset] = (value >> 8) as u8;
+    data[offset + 1] = value as u8;
+}
+
+/// Write a big-endian `u32` at `offset`
+#[inline]
+pub fn write32(data: &mut [u8], offset: usize, value: u32) {
+    write16(data, offset, (value >> 16) as u16);
+    write16(data, offset + 2, value as u16);
+}
+
+/// A cursor over big-endian fields, for parsing length-prefixed protocols
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data: data, pos: 0 }
+    }
+
+    /// The number of bytes read
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return None;
+        }
+        self.pos += len;
+        Some(&self.data[self.pos - len..self.pos])
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| be16(b, 0))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| be32(b, 0))
+    }
+
+    /// Read a field prefixed with its length in one byte
+    pub fn vec8(&mut self) -> Option<&'a [u8]> {
+        match self.u8() {
+            Some(len) => self.bytes(len as usize),
+            None => None
+        }
+    }
+
+    /// Read a field prefixed with its length in two bytes
+    pub fn vec16(&mut self) -> Option<&'a [u8]> {
+        match self.u16() {
+            Some(len) => self.bytes(len as usize),
+            None => None
+        }
+    }
+}
+
+/// Format bytes as lowercase hex
+pub fn hex(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len() * 2);
+    for b in data {
+        out.push_str(&format!("{:02x}", b));
+    }
+    out
+}
+
+/// An address as an integer, IPv4 addresses in the low 32 bits
+pub fn ip_bits(addr: &IpAddr) -> u128 {
+    match *addr {
+        IpAddr::V4(a) => u32::from(a) as u128,
+        IpAddr::V6(a) => u128::from(a),
+    }
+}
+
+/// A `Duration` in seconds
+pub fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+/// A `Duration` of non-negative `seconds`
+pub fn duration(seconds: f64) -> Duration {
+    Duration::new(seconds as u64, ((seconds - seconds.floor()) * 1e9) as u32)
+}
+
+/// A small, fast pseudo-random generator, xorshift64*
+///
+/// Not suitable for anything an attacker should not predict.
+pub struct Rng { state: u64 }
+
+impl Rng {
+    /// A generator with a fixed seed, for reproducible sequences
+    pub fn new(seed: u64) -> Rng {
+        // Scramble the seed with a SplitMix64 step, so similar seeds diverge and zero is usable
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Rng { state: (z ^ (z >> 31)) | 1 }
+    }
+
+    /// A generator seeded from the clock
+    pub fn from_time() -> Rng {
+        let since = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH).unwrap_or_default();
+        Rng::new(since.as_secs() ^ (since.subsec_nanos() as u64) << 32)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A float uniformly distributed over [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Whether an event of probability `p` happens
+    pub fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && self.next_f64() < p
+    }
+}
@@ -0,0 +1,78 @@
+//! Tests against the kernel's NFQUEUE, run as root with `cargo test --features netns`
+//!
+//! Each test is skipped, rather than failed, when not run as root or when `ip` or `nft` is missing.
+#![cfg(feature = "netns")]
+
+extern crate libc;
+extern crate libnfqueue as nfq;
+
+use std::process::Command;
+use nfq::message::Message;
+use nfq::netns::Namespace;
+use nfq::queue::Verdict;
+
+/// A new namespace, or `None` with the reason printed if it cannot be made here
+fn namespace() -> Option<Namespace> {
+    if unsafe { libc::geteuid() } != 0 {
+        println!("skipped: not running as root");
+        return None;
+    }
+    for &(program, version) in [("ip", "-V"), ("nft", "--version")].iter() {
+        if Command::new(program).arg(version).output().is_err() {
+            println!("skipped: `{}` not found", program);
+            return None;
+        }
+    }
+    Some(Namespace::new().unwrap())
+}
+
+#[test]
+fn accept() {
+    let ns = match namespace() {
+        Some(ns) => ns,
+        None => return
+    };
+    ns.queue(0, "udp dport 9000").unwrap();
+    let _server = ns.serve(0, || |_: &Message| Verdict::Accept).unwrap();
+
+    assert_eq!(ns.send_udp(9000, b"ping").unwrap(), Some(b"ping".to_vec()));
+}
+
+#[test]
+fn drop() {
+    let ns = match namespace() {
+        Some(ns) => ns,
+        None => return
+    };
+    ns.queue(0, "udp dport 9000").unwrap();
+    let _server = ns.serve(0, || |_: &Message| Verdict::Drop).unwrap();
+
+    assert_eq!(ns.send_udp(9000, b"ping").unwrap(), None);
+    assert_eq!(ns.send_udp(9001, b"ping").unwrap(), Some(b"ping".to_vec()));
+}
+
+#[test]
+fn unbound() {
+    let ns = match namespace() {
+        Some(ns) => ns,
+        None => return
+    };
+    ns.queue(1, "udp dport 9000").unwrap();
+
+    assert_eq!(ns.send_udp(9000, b"ping").unwrap(), None);
+}
+
+#[test]
+fn rewrite() {
+    let ns = match namespace() {
+        Some(ns) => ns,
+        None => return
+    };
+    ns.queue(0, "udp dport 9000").unwrap();
+    let _server = ns.serve(0, || |message: &Message| {
+        message.mangle().unwrap().set_payload(b"pong").unwrap();
+        Verdict::Accept
+    }).unwrap();
+
+    assert_eq!(ns.send_udp(9000, b"ping").unwrap(), Some(b"pong".to_vec()));
+}